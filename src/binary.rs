@@ -0,0 +1,422 @@
+//! 紧凑的二进制编码，与 serde 无关。
+//!
+//! 每个节点以一个标签字节开头，随后是它的字段：
+//!
+//! - 自然数与长度为 LEB128 变长整数，有符号的系数分子与指数先做 zigzag 编码；
+//! - 变量名为长度加 UTF-8 字节；
+//! - 和式与积式为操作数个数，每个操作数为符号字节（0 正，1 负）加表达式；
+//! - 有理式为分子与分母的项，每项为系数的分子与分母、因子个数，每个因子为变量名加指数。
+//!
+//! 有理式总是先规范化再编码，相等的有理式编码相同；解码时未规范化的有理式会被规范化。
+
+use crate::{CanonicalTerm, Expr, InvariantError, MAX_EXPONENT, Operand, RationalExpression, RawTerm, Sign};
+use num_rational::Ratio;
+use std::{collections::VecDeque, fmt};
+
+const CONSTANT: u8 = 0;
+const VARIABLE: u8 = 1;
+const SUM: u8 = 2;
+const PRODUCT: u8 = 3;
+const RATIONAL: u8 = 4;
+const POW: u8 = 5;
+const MOD: u8 = 6;
+const ABS: u8 = 7;
+const MAX: u8 = 8;
+const HOLE: u8 = 9;
+
+/// Nesting limit of decoded expressions, so a corrupt buffer cannot overflow the stack.
+const MAX_DEPTH: usize = 256;
+
+/// Reason a buffer could not be decoded, see [`Expr::from_bytes`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The buffer ended in the middle of an expression.
+    UnexpectedEnd,
+    /// An unknown tag or sign byte at the offset.
+    InvalidTag { position: usize, tag: u8 },
+    /// A number at the offset does not fit the integer type.
+    Overflow { position: usize },
+    /// A variable name at the offset is not valid UTF-8.
+    InvalidUtf8 { position: usize },
+    /// A coefficient at the offset has a zero denominator, or the rational form at the offset
+    /// has a zero denominator.
+    ZeroDenominator { position: usize },
+    /// The expression at the offset is nested too deeply.
+    TooDeep { position: usize },
+    /// Bytes are left after the expression, starting at the offset.
+    TrailingBytes { position: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnexpectedEnd => write!(f, "unexpected end of buffer"),
+            Self::InvalidTag { position, tag } => write!(f, "invalid tag {tag} at {position}"),
+            Self::Overflow { position } => write!(f, "number at {position} is too large"),
+            Self::InvalidUtf8 { position } => write!(f, "name at {position} is not valid UTF-8"),
+            Self::ZeroDenominator { position } => write!(f, "coefficient at {position} has a zero denominator"),
+            Self::TooDeep { position } => write!(f, "expression at {position} is nested too deeply"),
+            Self::TrailingBytes { position } => write!(f, "trailing bytes at {position}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl Expr {
+    /// Encodes the expression in the compact binary format of the [module documentation](self).
+    /// Rational nodes are normalized first, so equal rational forms have the same encoding
+    /// whatever the order their terms were built in.
+    ///
+    /// ```rust
+    /// # use symbolic_expr::Expr;
+    /// let expr = (Expr::var("a") + 1) * 3 / (Expr::var("b") + 1);
+    /// let decoded = Expr::from_bytes(&expr.to_bytes()).unwrap();
+    /// assert_eq!(decoded.to_string(), expr.to_string());
+    /// ```
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf);
+        buf
+    }
+
+    /// Decodes an expression encoded by [`Expr::to_bytes`], which must fill the whole buffer.
+    /// Rational nodes that are not normalized are accepted and normalized on load, as by
+    /// [`RationalExpression::new_normalized`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut decoder = Decoder { bytes, position: 0 };
+        let expr = decoder.expr(0)?;
+        match decoder.position {
+            position if position < bytes.len() => Err(DecodeError::TrailingBytes { position }),
+            _ => Ok(expr),
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let operands = |tag, operands: &VecDeque<Operand>, buf: &mut Vec<u8>| {
+            buf.push(tag);
+            write_varint(buf, operands.len() as u64);
+            for Operand { ty, expr } in operands {
+                buf.push(match ty {
+                    Sign::Positive => 0,
+                    Sign::Negative => 1,
+                });
+                expr.encode(buf);
+            }
+        };
+        match self {
+            &Self::Constant(value) => {
+                buf.push(CONSTANT);
+                write_varint(buf, value as u64);
+            }
+            Self::Variable(name) => {
+                buf.push(VARIABLE);
+                write_str(buf, name);
+            }
+            Self::Sum(ops) => operands(SUM, ops, buf),
+            Self::Product(ops) => operands(PRODUCT, ops, buf),
+            Self::Rational(rational) => {
+                let rational = rational.normalize();
+                buf.push(RATIONAL);
+                write_terms(buf, &rational.numer);
+                write_terms(buf, &rational.denom);
+            }
+            Self::Pow(base, exponent) => {
+                buf.push(POW);
+                base.encode(buf);
+                write_varint(buf, *exponent as u64);
+            }
+            Self::Mod(lhs, modulus) => {
+                buf.push(MOD);
+                lhs.encode(buf);
+                modulus.encode(buf);
+            }
+            Self::Abs(operand) => {
+                buf.push(ABS);
+                operand.encode(buf);
+            }
+            Self::Max(lhs, rhs) => {
+                buf.push(MAX);
+                lhs.encode(buf);
+                rhs.encode(buf);
+            }
+            Self::Hole => buf.push(HOLE),
+        }
+    }
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn write_signed(buf: &mut Vec<u8>, value: isize) {
+    let value = value as i64;
+    write_varint(buf, ((value << 1) ^ (value >> 63)) as u64);
+}
+
+fn write_str(buf: &mut Vec<u8>, s: &str) {
+    write_varint(buf, s.len() as u64);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn write_terms(buf: &mut Vec<u8>, terms: &[CanonicalTerm]) {
+    write_varint(buf, terms.len() as u64);
+    for term in terms {
+        write_signed(buf, *term.coef.numer());
+        write_signed(buf, *term.coef.denom());
+        write_varint(buf, term.factors.len() as u64);
+        for factor in &term.factors {
+            write_str(buf, &factor.base);
+            write_signed(buf, factor.exponent);
+        }
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Decoder<'_> {
+    fn expr(&mut self, depth: usize) -> Result<Expr, DecodeError> {
+        if depth > MAX_DEPTH {
+            return Err(DecodeError::TooDeep { position: self.position });
+        }
+        let position = self.position;
+        let expr = match self.byte()? {
+            CONSTANT => Expr::Constant(self.usize()?),
+            VARIABLE => Expr::Variable(self.string()?),
+            tag @ (SUM | PRODUCT) => {
+                let len = self.usize()?;
+                let mut operands = VecDeque::new();
+                for _ in 0..len {
+                    let position = self.position;
+                    let ty = match self.byte()? {
+                        0 => Sign::Positive,
+                        1 => Sign::Negative,
+                        tag => return Err(DecodeError::InvalidTag { position, tag }),
+                    };
+                    operands.push_back(Operand { ty, expr: self.expr(depth + 1)? });
+                }
+                if tag == SUM { Expr::Sum(operands) } else { Expr::Product(operands) }
+            }
+            RATIONAL => {
+                let numer = self.terms()?;
+                let denom = self.terms()?;
+                let rational = RationalExpression::new_normalized(numer, denom).map_err(|e| match e {
+                    InvariantError::ZeroDenominator => DecodeError::ZeroDenominator { position },
+                    _ => DecodeError::Overflow { position },
+                })?;
+                Expr::Rational(rational)
+            }
+            POW => {
+                let base = self.expr(depth + 1)?;
+                let position = self.position;
+                let exponent = u32::try_from(self.varint()?)
+                    .ok()
+                    .filter(|&exponent| exponent as isize <= MAX_EXPONENT)
+                    .ok_or(DecodeError::Overflow { position })?;
+                base.pow(exponent)
+            }
+            MOD => self.expr(depth + 1)?.modulo(self.expr(depth + 1)?),
+            ABS => self.expr(depth + 1)?.abs(),
+            MAX => self.expr(depth + 1)?.max(self.expr(depth + 1)?),
+            HOLE => Expr::Hole,
+            tag => return Err(DecodeError::InvalidTag { position, tag }),
+        };
+        Ok(expr)
+    }
+
+    fn terms(&mut self) -> Result<Vec<RawTerm>, DecodeError> {
+        let len = self.usize()?;
+        let mut terms = Vec::new();
+        for _ in 0..len {
+            let position = self.position;
+            let numer = self.signed()?;
+            let denom = self.signed()?;
+            if denom == 0 {
+                return Err(DecodeError::ZeroDenominator { position });
+            }
+            // The sign of `isize::MIN` cannot be moved to the other part when reducing
+            if numer == isize::MIN || denom == isize::MIN {
+                return Err(DecodeError::Overflow { position });
+            }
+            let len = self.usize()?;
+            let mut factors = Vec::new();
+            for _ in 0..len {
+                let base = self.string()?;
+                let position = self.position;
+                let exponent = self.signed()?;
+                if exponent.unsigned_abs() > MAX_EXPONENT.unsigned_abs() {
+                    return Err(DecodeError::Overflow { position });
+                }
+                factors.push((base, exponent));
+            }
+            terms.push((Ratio::new(numer, denom), factors));
+        }
+        Ok(terms)
+    }
+
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        let &byte = self.bytes.get(self.position).ok_or(DecodeError::UnexpectedEnd)?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn varint(&mut self) -> Result<u64, DecodeError> {
+        let position = self.position;
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            let bits = u64::from(byte & 0x7f);
+            if bits << shift >> shift != bits {
+                return Err(DecodeError::Overflow { position });
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(DecodeError::Overflow { position })
+    }
+
+    fn usize(&mut self) -> Result<usize, DecodeError> {
+        let position = self.position;
+        usize::try_from(self.varint()?).map_err(|_| DecodeError::Overflow { position })
+    }
+
+    fn signed(&mut self) -> Result<isize, DecodeError> {
+        let position = self.position;
+        let value = self.varint()?;
+        let value = (value >> 1) as i64 ^ -((value & 1) as i64);
+        isize::try_from(value).map_err(|_| DecodeError::Overflow { position })
+    }
+
+    fn string(&mut self) -> Result<String, DecodeError> {
+        let len = self.usize()?;
+        let position = self.position;
+        let end = position.checked_add(len).filter(|&end| end <= self.bytes.len()).ok_or(DecodeError::UnexpectedEnd)?;
+        let s = std::str::from_utf8(&self.bytes[position..end]).map_err(|_| DecodeError::InvalidUtf8 { position })?;
+        self.position = end;
+        Ok(s.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_round_trip() {
+        let a = Expr::var("a");
+        let b = Expr::var("batch_größe");
+
+        let exprs = [
+            Expr::from(0),
+            Expr::from(usize::MAX),
+            a.clone(),
+            (a.clone() + 1) * 3 / (b.clone() + 1),
+            a.clone() - b.clone() * 2 + 300,
+            Expr::Sum(VecDeque::new()),
+            (a.clone() * b.clone() / 2 + a.clone() / 3).canonicalize().unwrap().into(),
+            (a.clone() + b.clone()).pow(3),
+            a.clone().modulo(b.clone() + 1).max((a.clone() - b.clone()).abs()),
+            a.clone() * Expr::Hole,
+        ];
+        let values = HashMap::from([("a", 6), ("batch_größe", 4)]);
+        for expr in exprs {
+            let bytes = expr.to_bytes();
+            println!("asserting {expr} round-trips through {} bytes", bytes.len());
+            let decoded = Expr::from_bytes(&bytes).unwrap();
+            assert!(decoded.structurally_eq(&expr));
+            assert_eq!(decoded.to_string(), expr.to_string());
+            assert_eq!(decoded.checked_substitute(&values), expr.checked_substitute(&values));
+        }
+    }
+
+    #[test]
+    fn test_corrupt_buffer() {
+        let expr = (Expr::var("a") + 1) * 3 / (Expr::var("b") + 1);
+        let bytes = expr.to_bytes();
+
+        // Test truncation, trailing bytes and unknown tags
+        for len in 0..bytes.len() {
+            println!("asserting a truncation to {len} bytes fails");
+            assert!(Expr::from_bytes(&bytes[..len]).is_err());
+        }
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(Expr::from_bytes(&trailing).unwrap_err(), DecodeError::TrailingBytes { position: bytes.len() });
+        assert_eq!(Expr::from_bytes(&[42]).unwrap_err(), DecodeError::InvalidTag { position: 0, tag: 42 });
+        assert_eq!(Expr::from_bytes(&[SUM, 1, 2, CONSTANT, 0]).unwrap_err(), DecodeError::InvalidTag { position: 2, tag: 2 });
+
+        // Test malformed fields
+        assert_eq!(Expr::from_bytes(&[VARIABLE, 1, 0xff]).unwrap_err(), DecodeError::InvalidUtf8 { position: 2 });
+        assert_eq!(Expr::from_bytes(&[VARIABLE, 5, b'a']).unwrap_err(), DecodeError::UnexpectedEnd);
+        let overflow = [CONSTANT, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f];
+        assert_eq!(Expr::from_bytes(&overflow).unwrap_err(), DecodeError::Overflow { position: 1 });
+        assert_eq!(Expr::from_bytes(&[RATIONAL, 1, 2, 0, 0, 0]).unwrap_err(), DecodeError::ZeroDenominator { position: 2 });
+        let mut min = vec![RATIONAL, 1];
+        write_signed(&mut min, isize::MIN);
+        write_signed(&mut min, -1);
+        min.extend([0, 1, 2, 2, 0]);
+        assert_eq!(Expr::from_bytes(&min).unwrap_err(), DecodeError::Overflow { position: 2 });
+        let mut min = vec![RATIONAL, 1, 2];
+        write_signed(&mut min, isize::MIN);
+        min.extend([0, 1, 2, 2, 0]);
+        assert_eq!(Expr::from_bytes(&min).unwrap_err(), DecodeError::Overflow { position: 2 });
+        let mut sum = vec![RATIONAL, 2];
+        for _ in 0..2 {
+            write_signed(&mut sum, isize::MAX);
+            sum.extend([2, 0]);
+        }
+        sum.extend([1, 2, 2, 0]);
+        assert_eq!(Expr::from_bytes(&sum).unwrap_err(), DecodeError::Overflow { position: 0 });
+        assert!(matches!(Expr::from_bytes(&[ABS; 1000]).unwrap_err(), DecodeError::TooDeep { .. }));
+
+        // Test exponents out of range
+        let within = Expr::var("a").pow(MAX_EXPONENT as u32);
+        assert!(Expr::from_bytes(&within.to_bytes()).unwrap().structurally_eq(&within));
+        let mut pow = within.to_bytes();
+        pow.truncate(pow.len() - 3);
+        pow.extend([0x81, 0x80, 0x40]);
+        assert_eq!(Expr::from_bytes(&pow).unwrap_err(), DecodeError::Overflow { position: 4 });
+        let factor = [RATIONAL, 1, 2, 2, 1, 1, b'a', 0x82, 0x80, 0x80, 0x01, 1, 2, 2, 0];
+        assert_eq!(Expr::from_bytes(&factor).unwrap_err(), DecodeError::Overflow { position: 7 });
+        let mut factor = vec![RATIONAL, 1, 2, 2, 1, 1, b'a'];
+        write_signed(&mut factor, isize::MIN);
+        factor.extend([1, 2, 2, 0]);
+        assert_eq!(Expr::from_bytes(&factor).unwrap_err(), DecodeError::Overflow { position: 7 });
+    }
+
+    #[test]
+    fn test_normalized_encoding() {
+        let [a, b] = ["a", "b"].map(Expr::var);
+        let term = |coef, factors: &[(&str, isize)]| (Ratio::from_integer(coef), factors.iter().map(|&(base, e)| (base.to_string(), e)).collect::<Vec<_>>());
+
+        // Test the same rational form built three ways has the same encoding
+        let canonical = Expr::from((a.clone() * 2 + b.clone()).canonicalize().unwrap());
+        let reordered = RationalExpression::new_validated(vec![term(2, &[("b", 1)]), term(4, &[("a", 1)])], vec![term(2, &[])]).unwrap();
+        let merged = RationalExpression::new_normalized(vec![term(1, &[("a", 2), ("a", -1)]), term(1, &[("b", 1)]), term(1, &[("a", 1)])], vec![term(1, &[])]).unwrap();
+        let bytes = canonical.to_bytes();
+        for rational in [reordered, merged] {
+            let expr = Expr::Rational(rational);
+            println!("asserting {expr} encodes like {canonical}");
+            assert_eq!(expr.to_bytes(), bytes);
+        }
+        assert_eq!(Expr::from((b.clone() + a.clone() + a.clone()).canonicalize().unwrap()).to_bytes(), bytes);
+
+        // Test an unnormalized payload is normalized on load
+        let payload = [RATIONAL, 3, 4, 2, 1, 1, b'b', 2, 0, 2, 0, 8, 2, 1, 1, b'a', 2, 1, 4, 2, 0];
+        let decoded = Expr::from_bytes(&payload).unwrap();
+        assert_eq!(decoded.validate(), Ok(()));
+        assert!(decoded.structurally_eq(&canonical));
+        assert_eq!(decoded.to_bytes(), bytes);
+        assert_eq!(Expr::from_bytes(&[RATIONAL, 1, 2, 2, 0, 2, 2, 2, 1, 1, b'a', 2, 1, 2, 1, 1, b'a', 2]).unwrap_err(), DecodeError::ZeroDenominator { position: 0 });
+    }
+}
@@ -0,0 +1,162 @@
+//! 按位置绑定变量的表达式，求值时不再查找变量名。
+
+use crate::{CanonicalTerm, EvalError, Expr, eval::ratio_to_usize};
+use num_rational::Ratio;
+use num_traits::{CheckedAdd, CheckedDiv, CheckedMul};
+use std::fmt;
+
+/// Reason an expression could not be bound, see [`Expr::bind_order`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BindError {
+    /// A variable of the expression is not in the order.
+    MissingVariable(String),
+    /// The expression cannot be converted to a rational form.
+    NotRational,
+}
+
+impl fmt::Display for BindError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MissingVariable(name) => write!(f, "variable \"{name}\" is not in the order"),
+            Self::NotRational => write!(f, "expression has no rational form"),
+        }
+    }
+}
+
+impl std::error::Error for BindError {}
+
+/// The normal form of an expression with variables replaced by their positions in a value slice.
+///
+/// ```rust
+/// # use symbolic_expr::Expr;
+/// let expr = (Expr::var("a") + 1) * 3 / (Expr::var("b") + 1);
+/// let bound = expr.bind_order(&["b", "a"]).unwrap();
+/// assert_eq!(bound.eval(&[5, 7]), Ok(4));
+/// ```
+#[derive(Clone, Debug)]
+pub struct BoundExpr {
+    numer: Vec<BoundTerm>,
+    denom: Vec<BoundTerm>,
+}
+
+#[derive(Clone, Debug)]
+struct BoundTerm {
+    coef: Ratio<isize>,
+    /// Slot index and exponent of each factor.
+    factors: Vec<(usize, isize)>,
+}
+
+impl Expr {
+    /// Binds the variables to their positions in `order`, for evaluation from a slice of values.
+    /// Names in `order` that the expression does not use are allowed.
+    pub fn bind_order(&self, order: &[&str]) -> Result<BoundExpr, BindError> {
+        let rational = self.to_rational().ok_or(BindError::NotRational)?.normalize();
+        let bind = |terms: &[CanonicalTerm]| {
+            terms
+                .iter()
+                .map(|term| {
+                    let factors = term
+                        .factors
+                        .iter()
+                        .map(|factor| match order.iter().position(|name| *name == factor.base) {
+                            Some(slot) => Ok((slot, factor.exponent)),
+                            None => Err(BindError::MissingVariable(factor.base.clone())),
+                        })
+                        .collect::<Result<_, _>>()?;
+                    Ok(BoundTerm { coef: term.coef, factors })
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(BoundExpr {
+            numer: bind(&rational.numer)?,
+            denom: bind(&rational.denom)?,
+        })
+    }
+}
+
+impl BoundExpr {
+    /// Evaluates the expression with the values of the variables in the bound order, requiring
+    /// a non-negative integer result, same as [`Expr::checked_substitute`].
+    ///
+    /// # Panics
+    ///
+    /// If `vals` is shorter than the order the expression was bound with.
+    pub fn eval(&self, vals: &[u64]) -> Result<u64, EvalError> {
+        let value = ratio_to_usize(self.eval_ratio(vals)?)?;
+        u64::try_from(value).map_err(|_| EvalError::Overflow)
+    }
+
+    /// Evaluates the expression exactly, without requiring an integer result.
+    ///
+    /// # Panics
+    ///
+    /// If `vals` is shorter than the order the expression was bound with.
+    pub fn eval_ratio(&self, vals: &[u64]) -> Result<Ratio<isize>, EvalError> {
+        let sum = |terms: &[BoundTerm]| {
+            terms.iter().try_fold(Ratio::from_integer(0), |acc, term| {
+                acc.checked_add(&term.eval(vals)?).ok_or(EvalError::Overflow)
+            })
+        };
+        let numer = sum(&self.numer)?;
+        let denom = sum(&self.denom)?;
+        if denom == Ratio::from_integer(0) {
+            return Err(EvalError::DivisionByZero);
+        }
+        numer.checked_div(&denom).ok_or(EvalError::Overflow)
+    }
+}
+
+impl BoundTerm {
+    fn eval(&self, vals: &[u64]) -> Result<Ratio<isize>, EvalError> {
+        let mut result = self.coef;
+        for &(slot, exponent) in &self.factors {
+            let value = isize::try_from(vals[slot]).map_err(|_| EvalError::Overflow)?;
+            let power = num_traits::checked_pow(Ratio::from_integer(value), exponent.unsigned_abs()).ok_or(EvalError::Overflow)?;
+            result = if exponent > 0 {
+                result.checked_mul(&power).ok_or(EvalError::Overflow)?
+            } else if power == Ratio::from_integer(0) {
+                return Err(EvalError::DivisionByZero);
+            } else {
+                result.checked_div(&power).ok_or(EvalError::Overflow)?
+            };
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_bind_order() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+
+        // Test evaluation matches substitute
+        let expr = (a.clone() * b.clone() + c.clone() * 2) / (a.clone() + 1);
+        let bound = expr.bind_order(&["c", "unused", "a", "b"]).unwrap();
+        for (va, vb, vc) in [(1, 4, 3), (3, 8, 2), (5, 1, 9), (0, 7, 0)] {
+            let values = HashMap::from([("a", va), ("b", vb), ("c", vc)]);
+            println!("asserting bound evaluation of {expr} at {values:?}");
+            let expected = expr.checked_substitute(&values);
+            assert_eq!(bound.eval(&[vc as u64, 0, va as u64, vb as u64]).ok(), expected.map(|v| v as u64));
+        }
+        assert_eq!(bound.eval(&[1, 0, 1, 1]), Err(EvalError::NonDivisible));
+        assert_eq!(bound.eval_ratio(&[1, 0, 1, 1]), Ok(Ratio::new(3, 2)));
+        assert_eq!(bound.eval(&[u64::MAX, 0, 1, 1]), Err(EvalError::Overflow));
+
+        // Test division by zero and negative results
+        let bound = (a.clone() / (b.clone() - c.clone())).bind_order(&["a", "b", "c"]).unwrap();
+        assert_eq!(bound.eval(&[4, 3, 3]), Err(EvalError::DivisionByZero));
+        assert_eq!(bound.eval(&[4, 1, 3]), Err(EvalError::Negative));
+        assert_eq!(bound.eval(&[4, 5, 3]), Ok(2));
+
+        // Test errors
+        assert_eq!(expr.bind_order(&["a", "b"]).unwrap_err(), BindError::MissingVariable("c".to_string()));
+        assert_eq!(a.clone().modulo(b.clone()).bind_order(&["a", "b"]).unwrap_err(), BindError::NotRational);
+        assert_eq!(Expr::from(3).bind_order(&[]).unwrap().eval(&[]), Ok(3));
+    }
+}
@@ -0,0 +1,496 @@
+//! 形状约束的 JSON 交换格式。
+//!
+//! 文档中的表达式以文本形式保存，见 [`Expr`] 的 [`FromStr`](std::str::FromStr) 实现。
+//! 有理式总是先规范化再写出，读入时同样规范化。
+
+use crate::{Error, EvalError, Expr, Operand, RationalExpression};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt,
+};
+
+/// Rational nodes are written in their normal form, see [`RationalExpression`].
+impl Serialize for Expr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&normalize_rationals(self))
+    }
+}
+
+impl<'de> Deserialize<'de> for Expr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map_err(|e| serde::de::Error::custom(format!("{e} in \"{text}\"")))
+    }
+}
+
+/// Written as the text of its normal form, so equal expressions are written alike whatever the
+/// order their terms were built in. Text that is not in normal form is normalized on load, and
+/// text without a rational form is rejected.
+///
+/// ```rust
+/// # use symbolic_expr::{Expr, RationalExpression};
+/// let rational: RationalExpression = serde_json::from_str(r#""b + a*2 - b""#).unwrap();
+/// assert_eq!(serde_json::to_string(&rational).unwrap(), r#""2*a""#);
+/// assert!(serde_json::from_str::<RationalExpression>(r#""mod(a, 2)""#).is_err());
+/// ```
+impl Serialize for RationalExpression {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&self.normalize())
+    }
+}
+
+impl<'de> Deserialize<'de> for RationalExpression {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        let canon = text.parse::<Expr>().map_err(Error::from).and_then(|expr| expr.canonicalize());
+        match canon {
+            Ok(canon) => Ok(canon.rational().clone()),
+            Err(e) => Err(serde::de::Error::custom(format!("{e} in \"{text}\""))),
+        }
+    }
+}
+
+/// The expression with its rational nodes normalized.
+fn normalize_rationals(expr: &Expr) -> Expr {
+    let map_operands = |operands: &VecDeque<Operand>| {
+        operands
+            .iter()
+            .map(|Operand { ty, expr }| Operand { ty: *ty, expr: normalize_rationals(expr) })
+            .collect::<VecDeque<_>>()
+    };
+    match expr {
+        Expr::Constant(_) | Expr::Variable(_) | Expr::Hole => expr.clone(),
+        Expr::Sum(operands) => Expr::Sum(map_operands(operands)),
+        Expr::Product(operands) => Expr::Product(map_operands(operands)),
+        Expr::Rational(rational) => Expr::Rational(rational.normalize()),
+        Expr::Pow(base, exponent) => Expr::Pow(Box::new(normalize_rationals(base)), *exponent),
+        Expr::Mod(lhs, modulus) => Expr::Mod(Box::new(normalize_rationals(lhs)), Box::new(normalize_rationals(modulus))),
+        Expr::Abs(operand) => Expr::Abs(Box::new(normalize_rationals(operand))),
+        Expr::Max(lhs, rhs) => Expr::Max(Box::new(normalize_rationals(lhs)), Box::new(normalize_rationals(rhs))),
+    }
+}
+
+/// A shape-constraint interchange document.
+///
+/// ```rust
+/// # use std::collections::HashMap;
+/// # use symbolic_expr::load_constraints;
+/// let set = load_constraints(r#"{
+///     "variables": [{ "name": "n", "min": 1 }],
+///     "constraints": [{ "Eq": ["n * 4", "m"] }]
+/// }"#).unwrap();
+/// assert!(set.check(&HashMap::from([("n", 2), ("m", 8)])).is_empty());
+/// ```
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ConstraintSet {
+    /// Declarations of the input variables.
+    #[serde(default)]
+    pub variables: Vec<VariableDecl>,
+    /// Named expressions, evaluated in order. Later expressions and the constraints may
+    /// refer to them by name.
+    #[serde(default)]
+    pub expressions: Vec<NamedExpr>,
+    /// Constraints that must hold.
+    #[serde(default)]
+    pub constraints: Vec<Constraint>,
+}
+
+/// Declaration of an input variable with optional requirements on its value.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VariableDecl {
+    pub name: String,
+    /// Inclusive lower bound.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<usize>,
+    /// Inclusive upper bound.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<usize>,
+    /// The value must be a multiple of this.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub divisible_by: Option<usize>,
+}
+
+/// An expression with a name.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NamedExpr {
+    pub name: String,
+    pub expr: Expr,
+}
+
+/// A relation between two expressions.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Constraint {
+    /// The values are equal.
+    Eq(Expr, Expr),
+    /// The left value is less than or equal to the right value.
+    Le(Expr, Expr),
+    /// The left value divides the right value.
+    Divides(Expr, Expr),
+}
+
+impl Constraint {
+    fn sides(&self) -> (&Expr, &Expr) {
+        match self {
+            Self::Eq(lhs, rhs) | Self::Le(lhs, rhs) | Self::Divides(lhs, rhs) => (lhs, rhs),
+        }
+    }
+
+    fn holds(&self, lhs: usize, rhs: usize) -> bool {
+        match self {
+            Self::Eq(..) => lhs == rhs,
+            Self::Le(..) => lhs <= rhs,
+            Self::Divides(..) => divides(lhs, rhs),
+        }
+    }
+}
+
+impl fmt::Display for Constraint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Eq(lhs, rhs) => write!(f, "{lhs} == {rhs}"),
+            Self::Le(lhs, rhs) => write!(f, "{lhs} <= {rhs}"),
+            Self::Divides(lhs, rhs) => write!(f, "{lhs} | {rhs}"),
+        }
+    }
+}
+
+/// Whether `rhs` is a multiple of `lhs`, zero divides only zero.
+fn divides(lhs: usize, rhs: usize) -> bool {
+    rhs.checked_rem(lhs).map_or(rhs == 0, |rem| rem == 0)
+}
+
+/// A declaration, named expression or constraint that does not hold under an assignment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    /// The requirement that is violated, rendered as text.
+    pub rendered: String,
+    /// The rendered expressions involved, with their values or the reason evaluation failed.
+    pub values: Vec<(String, Result<usize, EvalError>)>,
+}
+
+/// Reason values could not be inferred.
+#[derive(Clone, Debug)]
+pub enum InferError {
+    /// An equality does not hold for values given or inferred before. `first` is the equality
+    /// that inferred the latest of those values, or None if they were all given.
+    Contradiction { first: Option<Box<Constraint>>, second: Box<Constraint> },
+    /// An equality solved for `variable` gives a value that is not a natural number.
+    NoNaturalSolution { constraint: Box<Constraint>, variable: String },
+}
+
+impl fmt::Display for InferError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Contradiction { first: Some(first), second } => write!(f, "\"{first}\" contradicts \"{second}\""),
+            Self::Contradiction { first: None, second } => write!(f, "\"{second}\" contradicts the known values"),
+            Self::NoNaturalSolution { constraint, variable } => {
+                write!(f, "\"{constraint}\" has no natural solution for \"{variable}\"")
+            }
+        }
+    }
+}
+
+impl std::error::Error for InferError {}
+
+/// Loads a constraint document from JSON.
+pub fn load_constraints(json: &str) -> Result<ConstraintSet, Error> {
+    Ok(serde_json::from_str(json)?)
+}
+
+impl ConstraintSet {
+    /// Emits the document as JSON, readable by [`load_constraints`].
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// Infers the values of unknown variables and named expressions from the known ones.
+    ///
+    /// Named expressions and `Eq` constraints are used as equations. Every equation with a single
+    /// unknown, which it is linear in, is solved with [`Expr::solve_for`] until no more values
+    /// can be inferred. Returns the known and the inferred values; variables that can never be
+    /// resolved, like in cyclic equations, are left out.
+    pub fn infer(&self, known: &HashMap<&str, usize>) -> Result<BTreeMap<String, usize>, InferError> {
+        let equations = self
+            .expressions
+            .iter()
+            .map(|NamedExpr { name, expr }| Constraint::Eq(Expr::var(name), expr.clone()))
+            .chain(self.constraints.iter().filter(|c| matches!(c, Constraint::Eq(..))).cloned())
+            .collect::<Vec<_>>();
+
+        let mut values = known.iter().map(|(&name, &value)| (name.to_string(), value)).collect::<BTreeMap<_, _>>();
+        // The equation each inferred value comes from, in the order of inference
+        let mut inferred = Vec::<(String, usize)>::new();
+        let mut resolved = vec![false; equations.len()];
+        loop {
+            let mut progress = false;
+            for (i, equation) in equations.iter().enumerate() {
+                if resolved[i] {
+                    continue;
+                }
+                let (lhs, rhs) = equation.sides();
+                let diff = lhs.clone() - rhs.clone();
+                let variables = diff.variables_vec();
+                let diff = variables.iter().fold(diff, |diff, name| match values.get(name) {
+                    Some(&value) => diff.replace_variable(name, &Expr::Constant(value)),
+                    None => diff,
+                });
+                let unknown = variables.iter().filter(|name| !values.contains_key(*name)).collect::<Vec<_>>();
+                match &*unknown {
+                    [] => {
+                        resolved[i] = true;
+                        if diff.equivalent(&Expr::Constant(0)) == Some(false) {
+                            let first = inferred
+                                .iter()
+                                .rev()
+                                .find(|(name, _)| variables.contains(name))
+                                .map(|&(_, j)| Box::new(equations[j].clone()));
+                            return Err(InferError::Contradiction { first, second: Box::new(equation.clone()) });
+                        }
+                    }
+                    [variable] => {
+                        let Some(solution) = diff.solve_for(variable) else {
+                            continue;
+                        };
+                        let value = solution.as_constant().ok_or_else(|| InferError::NoNaturalSolution {
+                            constraint: Box::new(equation.clone()),
+                            variable: variable.to_string(),
+                        })?;
+                        values.insert(variable.to_string(), value);
+                        inferred.push((variable.to_string(), i));
+                        resolved[i] = true;
+                        progress = true;
+                    }
+                    _ => {}
+                }
+            }
+            if !progress {
+                break Ok(values);
+            }
+        }
+    }
+
+    /// Evaluates every declaration, named expression and constraint under `env`, returning
+    /// those that do not hold in document order.
+    pub fn check(&self, env: &HashMap<&str, usize>) -> Vec<Violation> {
+        let mut env = env.iter().map(|(&name, &value)| (name, value)).collect::<HashMap<_, _>>();
+        let mut violations = Vec::new();
+
+        for VariableDecl { name, min, max, divisible_by } in &self.variables {
+            let Some(&value) = env.get(&**name) else {
+                violations.push(Violation {
+                    rendered: format!("{name} is declared"),
+                    values: vec![(name.clone(), Err(EvalError::UnknownVariable(name.clone())))],
+                });
+                continue;
+            };
+            let requirements = [
+                min.filter(|&min| value < min).map(|min| format!("{name} >= {min}")),
+                max.filter(|&max| value > max).map(|max| format!("{name} <= {max}")),
+                divisible_by.filter(|&d| !divides(d, value)).map(|d| format!("{d} | {name}")),
+            ];
+            violations.extend(requirements.into_iter().flatten().map(|rendered| Violation {
+                rendered,
+                values: vec![(name.clone(), Ok(value))],
+            }));
+        }
+
+        for NamedExpr { name, expr } in &self.expressions {
+            match expr.substitute_diagnosed(&env) {
+                Ok(value) => {
+                    env.insert(name, value);
+                }
+                Err(e) => violations.push(Violation {
+                    rendered: format!("{name} = {expr}"),
+                    values: vec![(expr.to_string(), Err(e.kind))],
+                }),
+            }
+        }
+
+        for constraint in &self.constraints {
+            let (lhs, rhs) = constraint.sides();
+            let lhs_value = lhs.substitute_diagnosed(&env).map_err(|e| e.kind);
+            let rhs_value = rhs.substitute_diagnosed(&env).map_err(|e| e.kind);
+            let holds = match (&lhs_value, &rhs_value) {
+                (Ok(l), Ok(r)) => constraint.holds(*l, *r),
+                _ => false,
+            };
+            if !holds {
+                violations.push(Violation {
+                    rendered: constraint.to_string(),
+                    values: vec![(lhs.to_string(), lhs_value), (rhs.to_string(), rhs_value)],
+                });
+            }
+        }
+        violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_rational::Ratio;
+
+    const DOCUMENT: &str = r#"{
+        "variables": [
+            { "name": "n", "min": 1 },
+            { "name": "c", "max": 1024, "divisible_by": 8 }
+        ],
+        "expressions": [
+            { "name": "m", "expr": "n * c" },
+            { "name": "half", "expr": "m / 2" }
+        ],
+        "constraints": [
+            { "Eq": ["half * 2", "m"] },
+            { "Le": ["m", "4096"] },
+            { "Divides": ["c", "half + c"] }
+        ]
+    }"#;
+
+    #[test]
+    fn test_empty_compounds_round_trip() {
+        // Empty containers are written as their identities
+        for (expr, text) in [(Expr::Sum(Default::default()), "\"0\""), (Expr::Product(Default::default()), "\"1\"")] {
+            let json = serde_json::to_string(&expr).unwrap();
+            println!("asserting {json} is {text}");
+            assert_eq!(json, text);
+            let back: Expr = serde_json::from_str(&json).unwrap();
+            assert_eq!(back.equivalent(&expr), Some(true));
+        }
+    }
+
+    #[test]
+    fn test_load_constraints() {
+        let set = load_constraints(DOCUMENT).unwrap();
+        assert_eq!(set.variables.len(), 2);
+        assert_eq!(set.expressions[1].expr.to_string(), "m/2");
+        assert_eq!(set.constraints[2].to_string(), "c | half + c");
+
+        // Test the emitted document loads back
+        let reloaded = load_constraints(&set.to_json()).unwrap();
+        assert_eq!(reloaded.to_json(), set.to_json());
+
+        // Test malformed documents and expressions
+        assert!(matches!(load_constraints("{"), Err(Error::Json(_))));
+        let err = load_constraints(r#"{ "constraints": [{ "Eq": ["a +", "b"] }] }"#).unwrap_err();
+        println!("asserting the error mentions the expression: {err}");
+        assert!(err.to_string().contains("\"a +\""));
+    }
+
+    #[test]
+    fn test_infer() {
+        // Test a chain resolves regardless of the order of the constraints
+        let set = load_constraints(r#"{
+            "expressions": [{ "name": "k", "expr": "m + 2" }],
+            "constraints": [
+                { "Eq": ["j * 2", "k"] },
+                { "Eq": ["m", "n * 4"] },
+                { "Le": ["x", "1"] }
+            ]
+        }"#)
+        .unwrap();
+        let values = set.infer(&HashMap::from([("n", 8)])).unwrap();
+        println!("asserting inferred values {values:?}");
+        assert_eq!(
+            values,
+            BTreeMap::from([("n", 8), ("m", 32), ("k", 34), ("j", 17)].map(|(name, value)| (name.to_string(), value))),
+        );
+
+        // Test cycles leave variables unknown
+        let set = load_constraints(r#"{ "constraints": [{ "Eq": ["a", "b + 1"] }, { "Eq": ["b * 2", "a + c"] }] }"#).unwrap();
+        let values = set.infer(&HashMap::new()).unwrap();
+        assert!(values.is_empty());
+        let values = set.infer(&HashMap::from([("c", 1)])).unwrap();
+        assert_eq!(values, BTreeMap::from([("c".to_string(), 1)]));
+        let values = set.infer(&HashMap::from([("b", 2), ("c", 1)])).unwrap();
+        assert_eq!(values["a"], 3);
+
+        // Test contradictions report both constraints
+        let set = load_constraints(r#"{ "constraints": [{ "Eq": ["m", "n * 4"] }, { "Eq": ["n * 5", "m"] }] }"#).unwrap();
+        let err = set.infer(&HashMap::from([("n", 8)])).unwrap_err();
+        println!("asserting {err}");
+        let InferError::Contradiction { first: Some(first), second } = err else { panic!("{err:?}") };
+        assert_eq!(first.to_string(), "m == n*4");
+        assert_eq!(second.to_string(), "n*5 == m");
+        let err = set.infer(&HashMap::from([("n", 8), ("m", 40)])).unwrap_err();
+        assert!(matches!(err, InferError::Contradiction { first: None, .. }));
+
+        // Test solutions that are not natural numbers
+        let set = load_constraints(r#"{ "constraints": [{ "Eq": ["m * 2", "n"] }] }"#).unwrap();
+        let err = set.infer(&HashMap::from([("n", 7)])).unwrap_err();
+        assert!(matches!(err, InferError::NoNaturalSolution { variable, .. } if variable == "m"));
+    }
+
+    #[test]
+    fn test_check_constraints() {
+        let set = load_constraints(DOCUMENT).unwrap();
+
+        // Test a satisfying assignment
+        assert_eq!(set.check(&HashMap::from([("n", 4), ("c", 64)])), []);
+
+        // Test a violating assignment
+        let violations = set.check(&HashMap::from([("n", 0), ("c", 2004)]));
+        println!("asserting violations {violations:?}");
+        assert_eq!(
+            violations.iter().map(|v| &*v.rendered).collect::<Vec<_>>(),
+            ["n >= 1", "c <= 1024", "8 | c"],
+        );
+        assert_eq!(violations[0].values, [("n".to_string(), Ok(0))]);
+        let violations = set.check(&HashMap::from([("n", 3), ("c", 8)]));
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].rendered, "c | half + c");
+        assert_eq!(violations[0].values, [("c".to_string(), Ok(8)), ("half + c".to_string(), Ok(20))]);
+
+        // Test evaluation failures are reported
+        let violations = set.check(&HashMap::from([("c", 8)]));
+        assert_eq!(violations[0].rendered, "n is declared");
+        assert_eq!(violations[1].rendered, "m = n*c");
+        assert_eq!(violations[1].values, [("n*c".to_string(), Err(EvalError::UnknownVariable("n".to_string())))]);
+    }
+
+    #[test]
+    fn test_infer_deterministic() {
+        let set = load_constraints(DOCUMENT).unwrap();
+        let known = [("n", 4), ("c", 16)];
+        let forward = known.into_iter().collect::<HashMap<_, _>>();
+        let reversed = known.into_iter().rev().collect::<HashMap<_, _>>();
+
+        // Test the inferred values serialize the same whatever the order of the known ones
+        let json = serde_json::to_string(&set.infer(&forward).unwrap()).unwrap();
+        println!("asserting inferred values serialize as {json}");
+        assert_eq!(json, r#"{"c":16,"half":32,"m":64,"n":4}"#);
+        assert_eq!(serde_json::to_string(&set.infer(&reversed).unwrap()).unwrap(), json);
+        assert_eq!(set.to_json(), load_constraints(&set.to_json()).unwrap().to_json());
+    }
+
+    #[test]
+    fn test_normalized_serialization() {
+        let [a, b] = ["a", "b"].map(Expr::var);
+        let term = |coef, factors: &[(&str, isize)]| (Ratio::from_integer(coef), factors.iter().map(|&(base, e)| (base.to_string(), e)).collect::<Vec<_>>());
+        let rational = |expr: Expr| match Expr::from(expr.canonicalize().unwrap()) {
+            Expr::Rational(rational) => rational,
+            expr => panic!("{expr} is not a rational form"),
+        };
+
+        // Test the same rational form built three ways is written byte for byte the same
+        let canonical = rational((a.clone() * 2 + b.clone()) / (a.clone() + 1));
+        let reordered = RationalExpression::new_validated(vec![term(2, &[("b", 1)]), term(4, &[("a", 1)])], vec![term(2, &[]), term(2, &[("a", 1)])]).unwrap();
+        let merged = RationalExpression::new_normalized(vec![term(1, &[("a", 2), ("a", -1)]), term(1, &[("b", 1)]), term(1, &[("a", 1)])], vec![term(1, &[("a", 1)]), term(1, &[])]).unwrap();
+        let json = serde_json::to_string(&canonical).unwrap();
+        for built in [reordered, merged] {
+            println!("asserting {built} is written as {json}");
+            assert_eq!(serde_json::to_string(&built).unwrap(), json);
+            assert_eq!(serde_json::to_string(&Expr::Rational(built.clone())).unwrap(), json);
+            assert_eq!(serde_json::to_string(&(Expr::Rational(built) * a.clone())).unwrap(), serde_json::to_string(&(Expr::Rational(canonical.clone()) * a.clone())).unwrap());
+        }
+
+        // Test unnormalized text is normalized on load
+        let loaded: RationalExpression = serde_json::from_str(r#""(4*a + 2*b)/(2 + 2*a) + b - b""#).unwrap();
+        assert_eq!(loaded.validate(), Ok(()));
+        assert!(Expr::Rational(loaded.clone()).structurally_eq(&Expr::Rational(canonical.clone())));
+        assert_eq!(serde_json::to_string(&loaded).unwrap(), json);
+        let err = serde_json::from_str::<RationalExpression>(r#""a + ?""#).unwrap_err();
+        println!("asserting {err}");
+        assert!(err.to_string().contains("unfilled hole"));
+    }
+}
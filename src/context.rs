@@ -0,0 +1,160 @@
+//! 带假设条件的推理上下文。
+
+use crate::{Error, Expr};
+
+/// Assumptions shared by several reasoning steps.
+///
+/// Every assumption defines a variable, which is replaced by the other side before
+/// canonicalization.
+///
+/// ```rust
+/// # use symbolic_expr::{Context, Expr};
+/// let a = Expr::var("a");
+/// let b = Expr::var("b");
+/// let c = Expr::var("c");
+/// let ctx = Context::new().assume(a.clone(), b.clone() * c.clone()).unwrap();
+/// assert_eq!(ctx.equivalent(&(a / c), &b), Some(true));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Context {
+    assumptions: Vec<(Expr, Expr)>,
+    nonzero: Vec<Expr>,
+}
+
+impl Context {
+    /// An empty context, in which reasoning is the same as without one.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the assumption `lhs == rhs`, or fails with [`Error::NotADefinition`] if neither side
+    /// is a variable.
+    pub fn assume(mut self, lhs: Expr, rhs: Expr) -> Result<Self, Error> {
+        let assumption = match (lhs, rhs) {
+            (lhs @ Expr::Variable(_), rhs) => (lhs, rhs),
+            (lhs, rhs @ Expr::Variable(_)) => (rhs, lhs),
+            (lhs, rhs) => return Err(Error::NotADefinition(format!("{lhs} = {rhs}"))),
+        };
+        self.assumptions.push(assumption);
+        Ok(self)
+    }
+
+    /// Adds the assumption `e != 0`, see [`Context::cancel_operands`].
+    pub fn assume_nonzero(mut self, e: Expr) -> Self {
+        self.nonzero.push(e);
+        self
+    }
+
+    /// Removes operands that cancel locally, see [`Expr::cancel_operands`], cancelling `x` and
+    /// `/x` in products only if `x` is structurally equal to an expression assumed non-zero.
+    /// The defined variables are not replaced, so the tree keeps its variables.
+    ///
+    /// ```rust
+    /// # use symbolic_expr::{Context, Expr};
+    /// let [n, k, s] = ["n", "k", "s"].map(Expr::var);
+    /// let ctx = Context::new().assume_nonzero(k.clone());
+    /// assert_eq!(ctx.cancel_operands(&(n.clone() * k.clone() / k.clone())).to_string(), "n");
+    /// assert_eq!(ctx.cancel_operands(&(n.clone() * s.clone() / s.clone())).to_string(), "n*s/s");
+    /// ```
+    pub fn cancel_operands(&self, e: &Expr) -> Expr {
+        e.cancel_operands_with(&|x| self.nonzero.iter().any(|nonzero| nonzero.structurally_eq(x)), false)
+    }
+
+    /// Checks equivalence under the assumptions, see [`Expr::equivalent`].
+    pub fn equivalent(&self, a: &Expr, b: &Expr) -> Option<bool> {
+        self.rewrite(a).equivalent(&self.rewrite(b))
+    }
+
+    /// Applies the assumptions and canonicalizes the result if possible.
+    pub fn simplify(&self, e: &Expr) -> Expr {
+        let rewritten = self.rewrite(e);
+        match rewritten.canonicalize() {
+            Ok(canon) => canon.into(),
+            Err(_) => rewritten,
+        }
+    }
+
+    /// Replaces the defined variables until none is left. Assumptions may refer to variables
+    /// defined by others, so this takes up to one pass per assumption; cyclic definitions
+    /// stop after that.
+    fn rewrite(&self, e: &Expr) -> Expr {
+        let mut e = e.clone();
+        for _ in 0..self.assumptions.len() {
+            let variables = e.variables_owned();
+            let mut defined = self
+                .assumptions
+                .iter()
+                .filter_map(|(lhs, rhs)| match lhs {
+                    Expr::Variable(name) if variables.contains(name) => Some((name, rhs)),
+                    _ => None,
+                })
+                .peekable();
+            if defined.peek().is_none() {
+                break;
+            }
+            e = defined.fold(e, |e, (name, rhs)| e.replace_variable(name, rhs));
+        }
+        e
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+        let d = Expr::var("d");
+
+        let ctx = Context::new().assume(a.clone(), b.clone() * c.clone()).unwrap();
+
+        // Test equivalences under a = bc
+        println!("asserting a/c == b under a = bc");
+        assert_eq!(ctx.equivalent(&(a.clone() / c.clone()), &b), Some(true));
+        assert_eq!(ctx.equivalent(&(a.clone() + b.clone()), &(b.clone() * (c.clone() + 1))), Some(true));
+        assert_eq!(ctx.equivalent(&(a.clone() * a.clone()), &(b.clone() * b.clone() * c.clone() * c.clone())), Some(true));
+        assert_eq!(ctx.equivalent(&a, &b), None);
+        assert_eq!(Context::new().equivalent(&(a.clone() / c.clone()), &b), None);
+        assert!(ctx.simplify(&(a.clone() / b.clone())).structurally_eq(&Expr::from(c.clone().canonicalize().unwrap())));
+
+        // Test assumptions building on each other, in either order
+        let ctx = ctx.assume(b.clone() * 2 + 1, d.clone()).unwrap();
+        println!("asserting d - 1 == 2a/c under a = bc, d = 2b + 1");
+        assert_eq!(ctx.equivalent(&(d.clone() - 1), &(a.clone() * 2 / c.clone())), Some(true));
+        let ctx = Context::new().assume(d.clone(), a.clone() + 1).unwrap().assume(a.clone(), b.clone() * c.clone()).unwrap();
+        assert_eq!(ctx.equivalent(&d, &(b.clone() * c.clone() + 1)), Some(true));
+
+        // Test rational nodes are rewritten
+        let rational = Expr::from((a.clone() * 2).canonicalize().unwrap());
+        assert_eq!(ctx.equivalent(&rational, &(b.clone() * c.clone() * 2)), Some(true));
+    }
+
+    #[test]
+    fn test_context_cancel_operands() {
+        let [n, k, s] = ["n", "k", "s"].map(Expr::var);
+        let ctx = Context::new().assume_nonzero(k.clone()).assume_nonzero(s.clone() + 1);
+
+        // Test only divisors assumed non-zero cancel
+        let expr = n.clone() * k.clone() / k.clone() * s.clone() / s.clone();
+        println!("asserting {expr} cancels k/k only");
+        assert!(ctx.cancel_operands(&expr).structurally_eq(&(n.clone() * s.clone() / s.clone())));
+        let expr = n.clone() * (s.clone() + 1) / (s.clone() + 1);
+        assert!(ctx.cancel_operands(&expr).structurally_eq(&n));
+        assert!(Context::new().cancel_operands(&expr).structurally_eq(&expr));
+
+        // Test sums cancel without assumptions
+        let expr = n.clone() + s.clone() - s.clone();
+        assert!(Context::new().cancel_operands(&expr).structurally_eq(&n));
+    }
+
+    #[test]
+    fn test_context_without_variable() {
+        println!("asserting a + 1 = b*2 is rejected");
+        let err = Context::new().assume(Expr::var("a") + 1, Expr::var("b") * 2).unwrap_err();
+        assert!(matches!(&err, Error::NotADefinition(text) if text == "a + 1 = b*2"));
+        assert_eq!(err.to_string(), "assumption \"a + 1 = b*2\" does not define a variable");
+    }
+}
@@ -0,0 +1,399 @@
+//! 表达式的文本表示。
+
+use crate::{CanonicalTerm, Expr, Factor, Factors, Operand, RationalExpression, Sign};
+use num_integer::Integer;
+use num_rational::Ratio;
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Formatter},
+};
+
+/// Binding strength of a rendered expression, used to decide where parentheses are needed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Precedence {
+    Sum,
+    Product,
+    Pow,
+    Atom,
+}
+
+impl fmt::Display for Expr {
+    /// The alternate flag, `{:#}`, renders [`Expr::sort_operands`] instead.
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if f.alternate() {
+            return write!(f, "{}", self.sort_operands());
+        }
+        match self {
+            Self::Constant(value) => write!(f, "{value}"),
+            Self::Variable(name) => write!(f, "{name}"),
+            Self::Hole => write!(f, "?"),
+            Self::Sum(operands) if operands.is_empty() => write!(f, "0"),
+            Self::Sum(operands) => {
+                for (i, Operand { ty, expr }) in operands.iter().enumerate() {
+                    match (i, ty) {
+                        (0, Sign::Positive) => {}
+                        (0, Sign::Negative) => write!(f, "-")?,
+                        (_, Sign::Positive) => write!(f, " + ")?,
+                        (_, Sign::Negative) => write!(f, " - ")?,
+                    }
+                    let paren = *ty == Sign::Negative && expr.precedence() == Precedence::Sum;
+                    write_operand(f, expr, paren)?;
+                }
+                Ok(())
+            }
+            Self::Product(operands) if operands.is_empty() => write!(f, "1"),
+            Self::Product(operands) => {
+                for (i, Operand { ty, expr }) in operands.iter().enumerate() {
+                    match (i, ty) {
+                        (0, Sign::Positive) => {}
+                        (0, Sign::Negative) => write!(f, "1/")?,
+                        (_, Sign::Positive) => write!(f, "*")?,
+                        (_, Sign::Negative) => write!(f, "/")?,
+                    }
+                    let paren = match expr.precedence() {
+                        Precedence::Sum => true,
+                        Precedence::Product => *ty == Sign::Negative,
+                        _ => false,
+                    };
+                    write_operand(f, expr, paren)?;
+                }
+                Ok(())
+            }
+            Self::Rational(rational) => write!(f, "{rational}"),
+            Self::Pow(base, exponent) => {
+                write_operand(f, base, base.precedence() != Precedence::Atom)?;
+                write!(f, "^{exponent}")
+            }
+            Self::Mod(lhs, modulus) => write!(f, "mod({lhs}, {modulus})"),
+            Self::Max(lhs, rhs) => write!(f, "max({lhs}, {rhs})"),
+            Self::Abs(operand) => write!(f, "abs({operand})"),
+        }
+    }
+}
+
+impl fmt::Display for RationalExpression {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let numer = terms_to_string(&self.numer);
+        if self.denom == [CanonicalTerm::new(1)] {
+            return write!(f, "{numer}");
+        }
+        let denom = terms_to_string(&self.denom);
+        let denom_paren = match &*self.denom {
+            [term] => term_precedence(term) != Precedence::Atom,
+            _ => true,
+        };
+        write!(f, "{}/{}", paren_if(numer, self.numer.len() > 1), paren_if(denom, denom_paren))
+    }
+}
+
+impl Expr {
+    /// Renders the canonical form with light factoring for display.
+    ///
+    /// The content and the common monomial of the numerator and the denominator are pulled out
+    /// (`4*a + 4*a*b` shows as `4*a*(1 + b)`), and the remaining terms are grouped by their
+    /// most frequent variable. This is not a factorization, only a more readable rendering of the
+    /// same rational form. Expressions that cannot be canonicalized are rendered as they are.
+    pub fn display_factored(&self) -> String {
+        let Some(rational) = self.to_rational() else {
+            return self.to_string();
+        };
+        let rational = rational.normalize();
+        if rational.denom.is_empty() {
+            return self.to_string();
+        }
+
+        // Clear fractional coefficients, so they show up as a constant factor of the denominator
+        let lcm = rational.numer.iter().chain(&rational.denom).fold(1, |lcm, term| lcm.lcm(term.coef.denom()));
+        let scale = |terms: &[CanonicalTerm]| {
+            terms
+                .iter()
+                .map(|term| CanonicalTerm { coef: term.coef * lcm, factors: term.factors.clone() })
+                .collect::<Vec<_>>()
+        };
+        let (numer, numer_precedence) = factored(&scale(&rational.numer));
+        let denom = scale(&rational.denom);
+        if denom == [CanonicalTerm::new(1)] {
+            return numer;
+        }
+        let (denom, denom_precedence) = factored(&denom);
+        format!(
+            "{}/{}",
+            paren_if(numer, numer_precedence == Precedence::Sum),
+            paren_if(denom, denom_precedence != Precedence::Atom),
+        )
+    }
+
+    /// Renders the groups of [`Expr::to_mixed_form`] as a sum, so `a + b/2 + c/3` shows as
+    /// written instead of as one fraction. Expressions that are a single group as they are
+    /// render like [`Expr::display_factored`].
+    pub fn display_mixed(&self) -> String {
+        let Some(groups) = self.to_rational().and_then(|rational| rational.normalize().mixed_groups()) else {
+            return self.display_factored();
+        };
+        if groups.is_empty() {
+            return "0".to_string();
+        }
+        let mut s = String::new();
+        for (i, (mut terms, denom)) in groups.into_iter().enumerate() {
+            let negative = terms.iter().all(|term| term.coef < Ratio::from_integer(0));
+            if negative {
+                terms.iter_mut().for_each(CanonicalTerm::neg);
+            }
+            s += match (i, negative) {
+                (0, false) => "",
+                (0, true) => "-",
+                (_, false) => " + ",
+                (_, true) => " - ",
+            };
+            let text = terms_to_string(&terms);
+            s += &match denom {
+                1 if negative && terms.len() > 1 => format!("({text})"),
+                1 => text,
+                _ => format!("{}/{denom}", paren_if(text, terms.len() > 1)),
+            };
+        }
+        s
+    }
+
+    fn precedence(&self) -> Precedence {
+        match self {
+            Self::Constant(_) | Self::Variable(_) | Self::Mod(..) | Self::Abs(_) | Self::Max(..) | Self::Hole => Precedence::Atom,
+            Self::Sum(operands) if operands.is_empty() => Precedence::Atom,
+            Self::Sum(_) => Precedence::Sum,
+            Self::Product(operands) if operands.is_empty() => Precedence::Atom,
+            Self::Product(_) => Precedence::Product,
+            Self::Rational(rational) if rational.denom == [CanonicalTerm::new(1)] => match &*rational.numer {
+                [] => Precedence::Atom,
+                [term] => term_precedence(term),
+                _ => Precedence::Sum,
+            },
+            Self::Rational(rational) => match &*rational.numer {
+                [term] if term.coef < Ratio::from_integer(0) => Precedence::Sum,
+                _ => Precedence::Product,
+            },
+            Self::Pow(..) => Precedence::Pow,
+        }
+    }
+}
+
+fn write_operand(f: &mut Formatter, expr: &Expr, paren: bool) -> fmt::Result {
+    if paren { write!(f, "({expr})") } else { write!(f, "{expr}") }
+}
+
+fn paren_if(s: String, paren: bool) -> String {
+    if paren { format!("({s})") } else { s }
+}
+
+fn term_precedence(term: &CanonicalTerm) -> Precedence {
+    let one = Ratio::from_integer(1);
+    if term.coef < Ratio::from_integer(0) {
+        Precedence::Sum
+    } else if term.factors.is_empty() {
+        if term.coef.is_integer() { Precedence::Atom } else { Precedence::Product }
+    } else {
+        match &*term.factors {
+            [factor] if term.coef == one && factor.exponent == 1 => Precedence::Atom,
+            [factor] if term.coef == one && factor.exponent > 1 => Precedence::Pow,
+            _ => Precedence::Product,
+        }
+    }
+}
+
+/// Renders a sum of terms, constant terms first as sorted by [`CanonicalTerm::combine_like_terms`].
+fn terms_to_string(terms: &[CanonicalTerm]) -> String {
+    if terms.is_empty() {
+        return "0".to_string();
+    }
+    let mut s = String::new();
+    for (i, term) in terms.iter().enumerate() {
+        s += match (i, term.coef < Ratio::from_integer(0)) {
+            (0, false) => "",
+            (0, true) => "-",
+            (_, false) => " + ",
+            (_, true) => " - ",
+        };
+        s += &term_to_string(term, None);
+    }
+    s
+}
+
+/// Renders the absolute value of a term, optionally multiplied by a parenthesized `inner` sum.
+/// Negative exponents and the coefficient denominator are rendered as divisions.
+fn term_to_string(term: &CanonicalTerm, inner: Option<&str>) -> String {
+    fn power(factor: &Factor) -> String {
+        match factor.exponent.abs() {
+            1 => factor.base.clone(),
+            exponent => format!("{}^{exponent}", factor.base),
+        }
+    }
+
+    let mut product = term.factors.iter().filter(|f| f.exponent > 0).map(power).collect::<Vec<_>>();
+    product.extend(inner.map(|inner| format!("({inner})")));
+    let numer = term.coef.numer().abs();
+    if numer != 1 || product.is_empty() {
+        product.insert(0, numer.to_string());
+    }
+    let mut s = product.join("*");
+    if *term.coef.denom() != 1 {
+        s += &format!("/{}", term.coef.denom());
+    }
+    for factor in term.factors.iter().filter(|f| f.exponent < 0) {
+        s += &format!("/{}", power(factor));
+    }
+    s
+}
+
+/// Pulls the content and the common monomial out of `terms` and groups the rest.
+fn factored(terms: &[CanonicalTerm]) -> (String, Precedence) {
+    let Some(first) = terms.first() else {
+        return ("0".to_string(), Precedence::Atom);
+    };
+    let (gcd, lcm) = terms.iter().fold((0, 1), |(gcd, lcm), term| {
+        (gcd.gcd(term.coef.numer()), lcm.lcm(term.coef.denom()))
+    });
+    let mut common = CanonicalTerm { coef: Ratio::new(gcd, lcm), factors: Factors::new() };
+    if terms.iter().all(|term| term.coef < Ratio::from_integer(0)) {
+        common.neg();
+    }
+    for factor in &first.factors {
+        let exponent = terms.iter().map(|term| term.exponent_of(&factor.base)).min().unwrap();
+        if exponent > 0 {
+            common.factors.push(Factor { base: factor.base.clone(), exponent });
+        }
+    }
+
+    let rest = CanonicalTerm::terms_divide_by_term(terms, &common);
+    if rest.len() == 1 {
+        // The common factor is the only term
+        return (terms_to_string(&[common.clone()]), term_precedence(&common));
+    }
+    let inner = grouped(&rest);
+    if common == CanonicalTerm::new(1) {
+        return (inner, Precedence::Sum);
+    }
+    if common.coef < Ratio::from_integer(0) {
+        (format!("-{}", term_to_string(&common, Some(&inner))), Precedence::Sum)
+    } else {
+        (term_to_string(&common, Some(&inner)), Precedence::Product)
+    }
+}
+
+/// Groups the terms containing the most frequent variable, alphabetically first on ties.
+fn grouped(terms: &[CanonicalTerm]) -> String {
+    let mut counts = BTreeMap::<&str, usize>::new();
+    for factor in terms.iter().flat_map(|term| &term.factors).filter(|f| f.exponent > 0) {
+        *counts.entry(&factor.base).or_default() += 1;
+    }
+    let var = match counts.into_iter().rev().max_by_key(|&(_, count)| count) {
+        Some((var, count)) if count > 1 => var,
+        _ => return terms_to_string(terms),
+    };
+
+    let (group, others): (Vec<_>, Vec<_>) = terms.iter().cloned().partition(|term| term.exponent_of(var) > 0);
+    let (group, _) = factored(&group);
+    if others.is_empty() {
+        return group;
+    }
+    let others = grouped(&others);
+    match group.strip_prefix('-') {
+        Some(group) => format!("{others} - {group}"),
+        None => format!("{others} + {group}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+
+        // Test trees keep their structure
+        assert_eq!((a.clone() + b.clone() * 2 - c.clone()).to_string(), "a + b*2 - c");
+        assert_eq!(((a.clone() + 1) * 3 / (b.clone() + 1)).to_string(), "(a + 1)*3/(b + 1)");
+        assert_eq!((a.clone() - (b.clone() - c.clone())).to_string(), "a - b + c");
+        assert_eq!((a.clone() * (b.clone() - c.clone())).to_string(), "a*(b - c)");
+        assert_eq!((a.clone() / (b.clone() * c.clone())).to_string(), "a/b/c");
+        assert_eq!((a.clone() + 1).pow(2).to_string(), "(a + 1)^2");
+        assert_eq!(a.clone().pow(3).to_string(), "a^3");
+
+        // Test rational forms
+        let rational = ((a.clone() * 3 - b.clone()) / (c.clone() + 2)).canonicalize().unwrap();
+        assert_eq!(rational.to_string(), "(3*a/2 - b/2)/(1 + c/2)");
+        assert_eq!(rational.display_factored(), "(3*a - b)/(2 + c)");
+        assert_eq!(((a.clone() + 1) / 2).canonicalize().unwrap().to_string(), "1/2 + a/2");
+        assert_eq!(((a.clone() + 1) / 2).display_factored(), "(1 + a)/2");
+    }
+
+    #[test]
+    fn test_display_factored() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+
+        // Test content and common monomial
+        let expr1 = a.clone() * 4 + a.clone() * b.clone() * 4;
+        println!("asserting {expr1} displays as 4*a*(1 + b)");
+        assert_eq!(expr1.display_factored(), "4*a*(1 + b)");
+
+        // Test a denominator with fractional coefficients cleared
+        let expr2 = (a.clone() * a.clone() * b.clone() + a.clone() * c.clone()) / (b.clone() * 2 + 2);
+        println!("asserting {expr2} displays as a*(a*b + c)/(2*(1 + b))");
+        assert_eq!(expr2.display_factored(), "a*(a*b + c)/(2*(1 + b))");
+
+        // Test grouping by the most frequent variable
+        let expr3 = a.clone() * a.clone() * 2 + a.clone() * b.clone() * 4 + 6;
+        println!("asserting {expr3} displays as 2*(3 + a*(a + 2*b))");
+        assert_eq!(expr3.display_factored(), "2*(3 + a*(a + 2*b))");
+
+        // Test signs
+        assert_eq!((Expr::from(0) - a.clone() - b.clone()).display_factored(), "-(a + b)");
+        assert_eq!((a.clone() - a.clone() * b.clone()).display_factored(), "a*(1 - b)");
+        assert_eq!((a.clone() - a.clone()).display_factored(), "0");
+        assert_eq!((a.clone() / b.clone()).display_factored(), "a/b");
+    }
+
+    #[test]
+    fn test_mixed_form() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+        let check_groups = |expr: &Expr, expected: &[(&str, usize)]| {
+            let groups = expr.to_mixed_form();
+            let rendered = groups.iter().map(|(part, denom)| (part.to_string(), *denom)).collect::<Vec<_>>();
+            let expected = expected.iter().map(|&(part, denom)| (part.to_string(), denom)).collect::<Vec<_>>();
+            assert_eq!(rendered, expected);
+            // Summing the groups gives the expression back
+            let sum = groups.into_iter().fold(Expr::from(0), |sum, (part, denom)| sum + part / denom);
+            assert_eq!(sum.equivalent(expr), Some(true));
+        };
+
+        // Test constant denominators
+        let expr1 = a.clone() + b.clone() / 2 + c.clone() / 3;
+        println!("asserting {expr1} displays as a + b/2 + c/3");
+        check_groups(&expr1, &[("a", 1), ("b", 2), ("c", 3)]);
+        assert_eq!(expr1.display_mixed(), "a + b/2 + c/3");
+        let expr2 = a.clone() * 3 / 2 - b.clone() / 2 + 1 - c.clone() * 2 / 3;
+        check_groups(&expr2, &[("1", 1), ("3*a - b", 2), ("-2*c", 3)]);
+        assert_eq!(expr2.display_mixed(), "1 + (3*a - b)/2 - 2*c/3");
+        assert_eq!((Expr::from(0) - a.clone() / 2).display_mixed(), "-a/2");
+
+        // Test denominators with variables
+        let expr3 = a.clone() + b.clone() / 2 + c.clone() / a.clone();
+        println!("asserting {expr3} displays as a + b/2 + c/a");
+        check_groups(&expr3, &[("a", 1), ("b", 2), ("c/a", 1)]);
+        assert_eq!(expr3.display_mixed(), "a + b/2 + c/a");
+        let expr4 = a.clone() / (b.clone() + 1) + c.clone() / 2;
+        assert_eq!(expr4.to_mixed_form().len(), 1);
+        check_groups(&expr4, &[(&expr4.canonicalize().unwrap().to_string(), 1)]);
+        assert_eq!(expr4.display_mixed(), expr4.display_factored());
+
+        // Test zero and expressions without a rational form
+        assert!((a.clone() - a.clone()).to_mixed_form().is_empty());
+        assert_eq!((a.clone() - a.clone()).display_mixed(), "0");
+        assert_eq!(a.clone().modulo(b.clone()).to_mixed_form().len(), 1);
+    }
+}
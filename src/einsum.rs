@@ -0,0 +1,201 @@
+//! 检查 einsum 表达式中各维度的一致性，并推导输出形状。
+
+use crate::Expr;
+use std::fmt;
+
+/// Why two dimensions do not agree, see [`EinsumError::Mismatch`] and [`ShapeError`](crate::ops::ShapeError).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// The dimensions are known to differ.
+    NotEqual,
+    /// The equivalence of the dimensions is unknown.
+    Unknown,
+}
+
+/// Reason the shapes of an einsum could not be checked.
+#[derive(Clone, Debug)]
+pub enum EinsumError {
+    /// An unexpected character in the spec at the byte offset, or the end of the spec if
+    /// `found` is None.
+    Syntax { position: usize, found: Option<char> },
+    /// The spec has subscripts for `expected` operands, but `found` shapes are given.
+    OperandCount { expected: usize, found: usize },
+    /// The shape of the operand at the index does not have one dimension per subscript.
+    Rank { operand: usize, expected: usize, found: usize },
+    /// A label of the output is not a label of any operand.
+    UnknownOutputLabel(char),
+    /// A label appears more than once in the output.
+    RepeatedOutputLabel(char),
+    /// Two dimensions of the label do not agree, neither being a broadcast `1`.
+    Mismatch { label: char, lhs: Expr, rhs: Expr, verdict: Verdict },
+}
+
+impl fmt::Display for EinsumError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Syntax { position, found: Some(found) } => write!(f, "unexpected {found:?} at {position}"),
+            Self::Syntax { found: None, .. } => write!(f, "unexpected end of spec"),
+            Self::OperandCount { expected, found } => write!(f, "spec has {expected} operands, but {found} shapes are given"),
+            Self::Rank { operand, expected, found } => write!(f, "operand {operand} has rank {found}, expected {expected}"),
+            Self::UnknownOutputLabel(label) => write!(f, "output label '{label}' is not in any operand"),
+            Self::RepeatedOutputLabel(label) => write!(f, "output label '{label}' is repeated"),
+            Self::Mismatch { label, lhs, rhs, verdict: Verdict::NotEqual } => write!(f, "label '{label}' has dimensions {lhs} and {rhs}, which differ"),
+            Self::Mismatch { label, lhs, rhs, verdict: Verdict::Unknown } => {
+                write!(f, "label '{label}' has dimensions {lhs} and {rhs}, which may differ")
+            }
+        }
+    }
+}
+
+impl std::error::Error for EinsumError {}
+
+/// Checks the dimensions of an einsum such as `"bij,bjk->bik"` and returns the output shape.
+///
+/// Labels are ASCII letters, and spaces are ignored. Without `->`, the output has the labels
+/// appearing once, in alphabetical order. All dimensions of a label must be proven equivalent,
+/// except those that are the constant `1`, which broadcast to the others. Dimensions of unknown
+/// equivalence are rejected, see [`einsum_shapes_with`] to accept them.
+///
+/// ```rust
+/// # use symbolic_expr::{Expr, einsum_shapes};
+/// let [b, m, n, k] = ["b", "m", "n", "k"].map(Expr::var);
+/// let lhs = [b.clone(), m.clone(), k.clone() * 2];
+/// let rhs = [b.clone(), k.clone() + k.clone(), n.clone()];
+/// let output = einsum_shapes("bij,bjk->bik", &[&lhs, &rhs]).unwrap();
+/// assert_eq!(output.iter().map(Expr::to_string).collect::<Vec<_>>(), ["b", "m", "n"]);
+/// ```
+pub fn einsum_shapes(spec: &str, inputs: &[&[Expr]]) -> Result<Vec<Expr>, EinsumError> {
+    einsum_shapes_with(spec, inputs, false)
+}
+
+/// [`einsum_shapes`], accepting dimensions of unknown equivalence if `allow_unknown` is set.
+/// The first dimension of such a label is taken for the output.
+pub fn einsum_shapes_with(spec: &str, inputs: &[&[Expr]], allow_unknown: bool) -> Result<Vec<Expr>, EinsumError> {
+    let (operands, output) = parse_spec(spec)?;
+    if operands.len() != inputs.len() {
+        return Err(EinsumError::OperandCount { expected: operands.len(), found: inputs.len() });
+    }
+
+    // The dimension of each label, in order of first appearance
+    let mut dims = Vec::<(char, Expr)>::new();
+    for (operand, (labels, shape)) in operands.iter().zip(inputs).enumerate() {
+        if labels.len() != shape.len() {
+            return Err(EinsumError::Rank { operand, expected: labels.len(), found: shape.len() });
+        }
+        for (&label, dim) in labels.iter().zip(shape.iter()) {
+            let Some((_, known)) = dims.iter_mut().find(|(l, _)| *l == label) else {
+                dims.push((label, dim.clone()));
+                continue;
+            };
+            let verdict = match known.equivalent(dim) {
+                Some(true) => continue,
+                _ if dim.is_one() => continue,
+                _ if known.is_one() => {
+                    *known = dim.clone();
+                    continue;
+                }
+                Some(false) => Verdict::NotEqual,
+                None if allow_unknown => continue,
+                None => Verdict::Unknown,
+            };
+            return Err(EinsumError::Mismatch { label, lhs: known.clone(), rhs: dim.clone(), verdict });
+        }
+    }
+
+    let output = match output {
+        Some(output) => output,
+        None => {
+            let mut once = dims
+                .iter()
+                .map(|&(label, _)| label)
+                .filter(|&label| operands.iter().flatten().filter(|&&l| l == label).count() == 1)
+                .collect::<Vec<_>>();
+            once.sort_unstable();
+            once
+        }
+    };
+    output
+        .iter()
+        .enumerate()
+        .map(|(i, &label)| {
+            if output[..i].contains(&label) {
+                return Err(EinsumError::RepeatedOutputLabel(label));
+            }
+            let (_, dim) = dims.iter().find(|(l, _)| *l == label).ok_or(EinsumError::UnknownOutputLabel(label))?;
+            Ok(dim.clone())
+        })
+        .collect()
+}
+
+/// The labels of an operand or the output, one per dimension.
+type Labels = Vec<char>;
+
+/// Splits the spec into the labels of each operand, and of the output if it has `->`.
+fn parse_spec(spec: &str) -> Result<(Vec<Labels>, Option<Labels>), EinsumError> {
+    let mut operands = vec![Vec::new()];
+    let mut output = None::<Labels>;
+    let mut chars = spec.char_indices().filter(|(_, c)| !c.is_whitespace());
+    while let Some((position, c)) = chars.next() {
+        let labels = output.as_mut().unwrap_or_else(|| operands.last_mut().unwrap());
+        match c {
+            c if c.is_ascii_alphabetic() => labels.push(c),
+            ',' if output.is_none() => operands.push(Vec::new()),
+            '-' if output.is_none() => match chars.next() {
+                Some((_, '>')) => output = Some(Vec::new()),
+                Some((position, found)) => return Err(EinsumError::Syntax { position, found: Some(found) }),
+                None => return Err(EinsumError::Syntax { position: spec.len(), found: None }),
+            },
+            found => return Err(EinsumError::Syntax { position, found: Some(found) }),
+        }
+    }
+    Ok((operands, output))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_einsum_shapes() {
+        let [b, m, n, k] = ["b", "m", "n", "k"].map(Expr::var);
+        let shapes = |output: Vec<Expr>| output.iter().map(Expr::to_string).collect::<Vec<_>>();
+
+        // Test a batched matmul with equivalent inner dimensions
+        let lhs = [b.clone(), m.clone(), k.clone() * 4 / 2];
+        let rhs = [b.clone(), k.clone() * 2, n.clone()];
+        println!("asserting bij,bjk->bik checks j");
+        assert_eq!(shapes(einsum_shapes("bij,bjk->bik", &[&lhs, &rhs]).unwrap()), ["b", "m", "n"]);
+        assert_eq!(shapes(einsum_shapes(" bij , bjk -> kb ", &[&lhs, &rhs]).unwrap()), ["n", "b"]);
+        assert_eq!(shapes(einsum_shapes("bij,bjk", &[&lhs, &rhs]).unwrap()), ["m", "n"]);
+
+        // Test broadcast dimensions of size 1
+        let one = [Expr::from(1), m.clone(), k.clone() * 2];
+        assert_eq!(shapes(einsum_shapes("bij,bjk->bik", &[&one, &rhs]).unwrap()), ["b", "m", "n"]);
+        assert_eq!(shapes(einsum_shapes("ij,ij->ij", &[&[m.clone(), Expr::from(1)], &[m.clone(), n.clone()]]).unwrap()), ["m", "n"]);
+
+        // Test mismatched and unknown inner dimensions
+        let err = einsum_shapes("bij,bjk->bik", &[&[b.clone(), m.clone(), k.clone()], &[b.clone(), k.clone() + 1, n.clone()]]).unwrap_err();
+        println!("asserting {err}");
+        assert!(matches!(&err, EinsumError::Mismatch { label: 'j', verdict: Verdict::NotEqual, .. }));
+        let (unknown_lhs, unknown_rhs) = ([m.clone(), k.clone()], [n.clone(), n.clone()]);
+        let err = einsum_shapes("ij,jk->ik", &[&unknown_lhs, &unknown_rhs]).unwrap_err();
+        let EinsumError::Mismatch { label, lhs: first, rhs: second, verdict } = err else {
+            panic!("expected a mismatch, got {err}");
+        };
+        assert_eq!((label, verdict), ('j', Verdict::Unknown));
+        assert!(first.structurally_eq(&k) && second.structurally_eq(&n));
+        let output = einsum_shapes_with("ij,jk->ik", &[&unknown_lhs, &unknown_rhs], true).unwrap();
+        assert_eq!(shapes(output), ["m", "n"]);
+
+        // Test rank, operand count and spec errors
+        assert!(matches!(
+            einsum_shapes("bij,bjk->bik", &[&lhs, &[b.clone(), k.clone()]]),
+            Err(EinsumError::Rank { operand: 1, expected: 3, found: 2 })
+        ));
+        assert!(matches!(einsum_shapes("ij,jk->ik", &[&lhs]), Err(EinsumError::OperandCount { expected: 2, found: 1 })));
+        assert!(matches!(einsum_shapes("i.j->ij", &[&[m.clone(), n.clone()]]), Err(EinsumError::Syntax { position: 1, found: Some('.') })));
+        assert!(matches!(einsum_shapes("ij-", &[&[m.clone(), n.clone()]]), Err(EinsumError::Syntax { position: 3, found: None })));
+        assert!(matches!(einsum_shapes("ij->ik", &[&[m.clone(), n.clone()]]), Err(EinsumError::UnknownOutputLabel('k'))));
+        assert!(matches!(einsum_shapes("ij->ii", &[&[m.clone(), n.clone()]]), Err(EinsumError::RepeatedOutputLabel('i'))));
+    }
+}
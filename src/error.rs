@@ -0,0 +1,137 @@
+//! 错误类型。
+
+use crate::{EvalError, ParseError};
+use std::fmt;
+
+/// Errors of the fallible operations of this crate.
+#[derive(Debug)]
+pub enum Error {
+    /// The text of an expression is malformed.
+    Parse(ParseError),
+    /// The expression has no rational form, such as an unfolded [`Expr::modulo`](crate::Expr::modulo).
+    NotRational,
+    /// A flat index is not affine in its index variables, see [`Expr::linearize`](crate::Expr::linearize).
+    NotAffine,
+    /// The exponents of the expression could exceed [`MAX_EXPONENT`](crate::MAX_EXPONENT), or
+    /// a power, product or sum of fractions could expand to more than [`MAX_EXPANDED_TERMS`](crate::MAX_EXPANDED_TERMS) terms.
+    TooComplex,
+    /// The expression has an [`Expr::Hole`](crate::Expr::Hole) left to fill.
+    UnfilledHole,
+    /// Variables have neither a value nor a default, in alphabetical order.
+    MissingVariables(Vec<String>),
+    /// The evaluation failed, with the values of the variables and where they came from,
+    /// in alphabetical order.
+    Eval { kind: EvalError, values: Vec<(String, usize, ValueSource)> },
+    /// An assumption `lhs = rhs` defines no variable, see [`Context::assume`](crate::Context::assume).
+    NotADefinition(String),
+    /// A variable name cannot be rendered as an SMT-LIB symbol, see [`Expr::to_smtlib`](crate::Expr::to_smtlib).
+    InvalidSymbol(String),
+    /// An interchange document is malformed.
+    #[cfg(feature = "serde")]
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Parse(e) => write!(f, "failed to parse expression: {e}"),
+            Self::NotRational => write!(f, "expression has no rational form"),
+            Self::NotAffine => write!(f, "index is not affine in the index variables"),
+            Self::TooComplex => write!(f, "expression exceeds the exponent or expanded term limit"),
+            Self::UnfilledHole => write!(f, "expression has an unfilled hole"),
+            Self::MissingVariables(names) => write!(f, "no value or default for {}", names.join(", ")),
+            Self::Eval { kind, values } => {
+                write!(f, "failed to evaluate expression: {kind}")?;
+                for (i, (name, value, source)) in values.iter().enumerate() {
+                    write!(f, "{} {name} = {value} ({source})", if i == 0 { " with" } else { "," })?;
+                }
+                Ok(())
+            }
+            Self::NotADefinition(text) => write!(f, "assumption \"{text}\" does not define a variable"),
+            Self::InvalidSymbol(name) => write!(f, "variable \"{name}\" is not a valid SMT-LIB symbol"),
+            #[cfg(feature = "serde")]
+            Self::Json(e) => write!(f, "failed to load document: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Parse(e) => Some(e),
+            Self::NotRational | Self::NotAffine | Self::TooComplex | Self::UnfilledHole | Self::MissingVariables(_)
+            | Self::NotADefinition(_)
+            | Self::InvalidSymbol(_) => None,
+            Self::Eval { kind, .. } => Some(kind),
+            #[cfg(feature = "serde")]
+            Self::Json(e) => Some(e),
+        }
+    }
+}
+
+/// A violated invariant of the terms of a rational expression,
+/// see [`RationalExpression::new_validated`](crate::RationalExpression::new_validated).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InvariantError {
+    /// The factors of a term are not sorted by variable name.
+    UnsortedFactors,
+    /// A variable appears twice in a term.
+    DuplicateFactor(String),
+    /// A variable has exponent zero.
+    ZeroExponent(String),
+    /// The exponent of a variable is out of the range of [`MAX_EXPONENT`](crate::MAX_EXPONENT).
+    ExponentOutOfRange(String),
+    /// A term has coefficient zero.
+    ZeroCoefficient,
+    /// The denominator is zero.
+    ZeroDenominator,
+    /// Combining the terms overflows a coefficient.
+    CoefficientOverflow,
+}
+
+impl fmt::Display for InvariantError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnsortedFactors => write!(f, "factors of a term are not sorted"),
+            Self::DuplicateFactor(name) => write!(f, "variable \"{name}\" appears twice in a term"),
+            Self::ZeroExponent(name) => write!(f, "variable \"{name}\" has exponent zero"),
+            Self::ExponentOutOfRange(name) => write!(f, "exponent of variable \"{name}\" is out of range"),
+            Self::ZeroCoefficient => write!(f, "a term has coefficient zero"),
+            Self::ZeroDenominator => write!(f, "denominator is zero"),
+            Self::CoefficientOverflow => write!(f, "a coefficient overflows"),
+        }
+    }
+}
+
+impl std::error::Error for InvariantError {}
+
+/// Where the value of a variable came from, see [`Expr::substitute_with_defaults`](crate::Expr::substitute_with_defaults).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueSource {
+    /// The environment.
+    Env,
+    /// The defaults.
+    Default,
+}
+
+impl fmt::Display for ValueSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Env => write!(f, "env"),
+            Self::Default => write!(f, "default"),
+        }
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Self::Parse(e)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e)
+    }
+}
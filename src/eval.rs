@@ -0,0 +1,1279 @@
+//! 带诊断信息的求值。
+
+use crate::{CanonicalTerm, Error, Expr, Operand, RationalExpression, Sign, ValueSource};
+use num_rational::{BigRational, Ratio};
+use num_traits::{CheckedAdd, CheckedDiv, CheckedMul, Zero};
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    fmt,
+};
+
+/// Reason an evaluation failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EvalError {
+    /// An intermediate value does not fit the integer type.
+    Overflow,
+    /// A subtraction or the final value is negative.
+    Negative,
+    /// A division is not exact.
+    NonDivisible,
+    /// A division by zero.
+    DivisionByZero,
+    /// A variable has no value.
+    UnknownVariable(String),
+    /// The expression has an [`Expr::Hole`] left to fill.
+    UnfilledHole,
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Overflow => write!(f, "value overflows"),
+            Self::Negative => write!(f, "value is negative"),
+            Self::NonDivisible => write!(f, "division is not exact"),
+            Self::DivisionByZero => write!(f, "division by zero"),
+            Self::UnknownVariable(name) => write!(f, "variable \"{name}\" has no value"),
+            Self::UnfilledHole => write!(f, "expression has an unfilled hole"),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+/// Where and why an evaluation failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EvalDiagnostic {
+    /// Reason of the failure.
+    pub kind: EvalError,
+    /// Operand indices leading from the root to the sub-expression that failed.
+    pub path: Vec<usize>,
+    /// Value accumulated by the enclosing sum or product before the failing operand was applied.
+    pub accumulator: Option<usize>,
+}
+
+/// The first step of the tree that fails, see [`Expr::substitute_exact_steps`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExactnessError {
+    /// Reason of the failure.
+    pub kind: EvalError,
+    /// The failing step rendered as text, such as `n/2` in `n/2*2 + 1`.
+    pub step: String,
+    /// Values of the two sides of the failing step, if it applies an operand to the value
+    /// accumulated by a sum or product.
+    pub values: Option<(usize, usize)>,
+}
+
+impl fmt::Display for ExactnessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} in \"{}\"", self.kind, self.step)?;
+        match self.values {
+            Some((lhs, rhs)) => write!(f, " with values {lhs} and {rhs}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for ExactnessError {}
+
+/// An expression compiled by [`Expr::compile_f64`].
+pub type CompiledF64 = Box<dyn Fn(&HashMap<&str, f64>) -> Option<f64> + Send + Sync>;
+
+/// Most variable reads and divisions kept by a [`SubstitutionTrace`].
+pub const MAX_TRACE_EVENTS: usize = 256;
+
+/// Most bytes of [`SubstitutionTrace::substituted`] kept before it is cut off with `…`.
+pub const MAX_TRACE_BYTES: usize = 4096;
+
+/// The values that flowed into a substitution, see [`Expr::substitute_traced`].
+///
+/// Displays as the values of the variables, the expression with the values substituted and the
+/// result, such as `n=32, s=2; (32 - 2)/2 = 15`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SubstitutionTrace {
+    /// The variables read, in the order they were first read, with their values.
+    pub variables: Vec<(String, usize)>,
+    /// The divisions of the tree in evaluation order, as `(dividend, divisor)` values.
+    /// Rational nodes are evaluated in their normal form and add no divisions.
+    pub divisions: Vec<(usize, usize)>,
+    /// The expression with the variables replaced by their values, cut off with `…` after
+    /// [`MAX_TRACE_BYTES`].
+    pub substituted: String,
+    /// The value of the expression.
+    pub value: usize,
+    /// Whether reads or divisions beyond [`MAX_TRACE_EVENTS`] were dropped, or the substituted
+    /// expression was cut off.
+    pub truncated: bool,
+}
+
+impl fmt::Display for SubstitutionTrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, (name, value)) in self.variables.iter().enumerate() {
+            write!(f, "{}{name}={value}", if i == 0 { "" } else { ", " })?;
+        }
+        if self.truncated {
+            write!(f, "{}…truncated", if self.variables.is_empty() { "" } else { ", " })?;
+        }
+        write!(f, "; {} = {}", self.substituted, self.value)
+    }
+}
+
+/// Collects a [`SubstitutionTrace`] during the evaluation.
+#[derive(Default)]
+struct Recorder {
+    trace: SubstitutionTrace,
+    events: usize,
+}
+
+impl Recorder {
+    fn read(&mut self, name: &str, value: usize) {
+        if !self.trace.variables.iter().any(|(n, _)| n == name) && self.record() {
+            self.trace.variables.push((name.to_string(), value));
+        }
+    }
+
+    fn divide(&mut self, dividend: usize, divisor: usize) {
+        if self.record() {
+            self.trace.divisions.push((dividend, divisor));
+        }
+    }
+
+    /// Whether there is room for another event, marking the trace truncated if not.
+    fn record(&mut self) -> bool {
+        self.events += 1;
+        self.trace.truncated |= self.events > MAX_TRACE_EVENTS;
+        !self.trace.truncated
+    }
+}
+
+/// A problem found by [`validate_env`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EnvProblem {
+    /// Name of the expression that cannot be evaluated.
+    pub name: String,
+    /// Where and why its evaluation failed.
+    pub diagnostic: EvalDiagnostic,
+}
+
+/// Dry-runs the evaluation of every named expression, collecting all problems instead of
+/// stopping at the first one.
+///
+/// Problems are reported in the order of `exprs`. Every missing variable of an expression is
+/// reported, in evaluation order; other failures are only reported for expressions whose
+/// variables all have values, at most one per expression.
+///
+/// ```rust
+/// # use std::collections::HashMap;
+/// # use symbolic_expr::{EvalError, Expr, validate_env};
+/// let n = Expr::var("n");
+/// let c = Expr::var("c");
+/// let half = c.clone() / 2;
+/// let chunks = n.clone() / c.clone();
+/// let problems = validate_env(&[("half", &half), ("chunks", &chunks)], &HashMap::from([("n", 8), ("c", 3)]));
+/// assert_eq!(problems.len(), 2);
+/// assert_eq!(problems[0].name, "half");
+/// assert_eq!(problems[1].diagnostic.kind, EvalError::NonDivisible);
+/// ```
+pub fn validate_env(exprs: &[(&str, &Expr)], value: &HashMap<&str, usize>) -> Vec<EnvProblem> {
+    let mut problems = Vec::new();
+    for &(name, expr) in exprs {
+        let problem = |diagnostic| EnvProblem { name: name.to_string(), diagnostic };
+        // Missing variables take a placeholder value so that evaluation can go on to find the
+        // next one; failures after that may be caused by the placeholder and are not reported
+        let mut value = value.clone();
+        let mut missing = Vec::new();
+        loop {
+            match expr.substitute_diagnosed(&value) {
+                Ok(_) => break,
+                Err(diagnostic) => match &diagnostic.kind {
+                    EvalError::UnknownVariable(var) => {
+                        let var = expr.variables().into_iter().find(|v| v == var).unwrap();
+                        value.insert(var, 1);
+                        missing.push(problem(diagnostic));
+                    }
+                    _ => {
+                        if missing.is_empty() {
+                            missing.push(problem(diagnostic));
+                        }
+                        break;
+                    }
+                },
+            }
+        }
+        problems.extend(missing);
+    }
+    problems
+}
+
+impl Expr {
+    /// Substitute variables with their values, reporting where the evaluation failed
+    /// instead of panicking.
+    ///
+    /// ```rust
+    /// # use std::collections::HashMap;
+    /// # use symbolic_expr::{EvalError, Expr};
+    /// let expr = Expr::var("a") * Expr::var("b") + 1;
+    /// let err = expr.substitute_diagnosed(&HashMap::from([("a", usize::MAX), ("b", 2)])).unwrap_err();
+    /// assert_eq!(err.kind, EvalError::Overflow);
+    /// assert_eq!(err.path, [0, 1]);
+    /// assert_eq!(err.accumulator, Some(usize::MAX));
+    /// ```
+    pub fn substitute_diagnosed(&self, value: &HashMap<&str, usize>) -> Result<usize, EvalDiagnostic> {
+        self.eval_diagnosed(value, &mut Vec::new(), None)
+    }
+
+    /// Substitute variables with their values, tracing which values flowed into the result for
+    /// debugging a wrong concrete shape, see [`SubstitutionTrace`].
+    ///
+    /// All variables without a value are reported together. If the evaluation fails, the error
+    /// lists the values of the variables of the expression.
+    ///
+    /// ```rust
+    /// # use std::collections::HashMap;
+    /// # use symbolic_expr::Expr;
+    /// let [n, p, k, s] = ["n", "p", "k", "s"].map(Expr::var);
+    /// let expr = (n + p * 2 - k) / s + 1;
+    /// let (value, trace) = expr.substitute_traced(&HashMap::from([("n", 32), ("p", 1), ("k", 2), ("s", 2)])).unwrap();
+    /// assert_eq!(value, 17);
+    /// assert_eq!(trace.divisions, [(32, 2)]);
+    /// assert_eq!(trace.to_string(), "n=32, p=1, k=2, s=2; (32 + 1*2 - 2)/2 + 1 = 17");
+    /// ```
+    pub fn substitute_traced(&self, value: &HashMap<&str, usize>) -> Result<(usize, SubstitutionTrace), Error> {
+        let variables = self.variables();
+        let missing = variables.iter().filter(|name| !value.contains_key(*name)).map(|name| name.to_string()).collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(Error::MissingVariables(missing));
+        }
+        let mut recorder = Recorder::default();
+        let ans = self.eval_diagnosed(value, &mut Vec::new(), Some(&mut recorder)).map_err(|diagnostic| Error::Eval {
+            kind: diagnostic.kind,
+            values: variables.iter().map(|&name| (name.to_string(), value[name], ValueSource::Env)).collect(),
+        })?;
+        let mut trace = recorder.trace;
+        trace.substituted = self.replace_variables(&|name| value.get(name).map(|&value| Self::Constant(value))).to_string();
+        if trace.substituted.len() > MAX_TRACE_BYTES {
+            let end = (0..=MAX_TRACE_BYTES).rev().find(|&i| trace.substituted.is_char_boundary(i)).unwrap_or(0);
+            trace.substituted.truncate(end);
+            trace.substituted.push('…');
+            trace.truncated = true;
+        }
+        trace.value = ans;
+        Ok((ans, trace))
+    }
+
+    /// Substitute variables with values queried from `f`, once per distinct variable in
+    /// alphabetical order, instead of from a prepared map.
+    /// Returns None if `f` has no value for a variable or the evaluation fails, see
+    /// [`Expr::checked_substitute`].
+    pub fn substitute_with<F: FnMut(&str) -> Option<usize>>(&self, mut f: F) -> Option<usize> {
+        let value = self
+            .variables()
+            .into_iter()
+            .map(|name| Some((name, f(name)?)))
+            .collect::<Option<HashMap<_, _>>>()?;
+        self.checked_substitute(&value)
+    }
+
+    /// Strict evaluation: substitute variables with their values following the tree as written,
+    /// requiring every division to be exact and every subtraction to stay non-negative, and
+    /// reporting the first step that is not.
+    ///
+    /// `n/2*2` fails for `n = 5` even though [`Expr::substitute_rational`] gives `5`.
+    ///
+    /// ```rust
+    /// # use std::collections::HashMap;
+    /// # use symbolic_expr::{EvalError, Expr};
+    /// let expr = Expr::var("n") / 2 * 2 + 1;
+    /// let err = expr.substitute_exact_steps(&HashMap::from([("n", 5)])).unwrap_err();
+    /// assert_eq!(err.kind, EvalError::NonDivisible);
+    /// assert_eq!(err.step, "n/2");
+    /// assert_eq!(err.values, Some((5, 2)));
+    /// ```
+    pub fn substitute_exact_steps(&self, value: &HashMap<&str, usize>) -> Result<usize, ExactnessError> {
+        self.substitute_diagnosed(value).map_err(|diagnostic| {
+            let (step, values) = match (diagnostic.accumulator, diagnostic.path.split_last()) {
+                (Some(acc), Some((&i, parent))) => {
+                    let (operands, step) = match self.at_path(parent) {
+                        Self::Sum(operands) => (operands, Self::sum(operands.range(..=i).cloned())),
+                        Self::Product(operands) => (operands, Self::product(operands.range(..=i).cloned())),
+                        _ => unreachable!("only sums and products accumulate values"),
+                    };
+                    // The failing operand itself evaluated before being applied
+                    let operand = operands[i].expr.substitute_diagnosed(value).ok();
+                    (step.to_string(), operand.map(|operand| (acc, operand)))
+                }
+                _ => (self.at_path(&diagnostic.path).to_string(), None),
+            };
+            ExactnessError { kind: diagnostic.kind, step, values }
+        })
+    }
+
+    /// Substitute variables with their values from `env`, or else from `defaults`.
+    ///
+    /// All variables found in neither are reported together. If the evaluation fails, the error
+    /// lists the value of every variable and whether it came from `env` or `defaults`.
+    ///
+    /// ```rust
+    /// # use std::collections::HashMap;
+    /// # use symbolic_expr::Expr;
+    /// let expr = Expr::var("c") / Expr::var("groups");
+    /// let defaults = HashMap::from([("groups", 1)]);
+    /// assert_eq!(expr.substitute_with_defaults(&HashMap::from([("c", 6)]), &defaults).unwrap(), 6);
+    /// let env = HashMap::from([("c", 6), ("groups", 4)]);
+    /// let err = expr.substitute_with_defaults(&env, &defaults).unwrap_err();
+    /// assert_eq!(err.to_string(), "failed to evaluate expression: division is not exact with c = 6 (env), groups = 4 (env)");
+    /// ```
+    pub fn substitute_with_defaults(
+        &self,
+        env: &HashMap<&str, usize>,
+        defaults: &HashMap<&str, usize>,
+    ) -> Result<usize, Error> {
+        let mut values = Vec::new();
+        let mut missing = Vec::new();
+        for name in self.variables() {
+            match (env.get(name), defaults.get(name)) {
+                (Some(&value), _) => values.push((name, value, ValueSource::Env)),
+                (None, Some(&value)) => values.push((name, value, ValueSource::Default)),
+                (None, None) => missing.push(name.to_string()),
+            }
+        }
+        if !missing.is_empty() {
+            return Err(Error::MissingVariables(missing));
+        }
+        let value = values.iter().map(|&(name, value, _)| (name, value)).collect();
+        self.substitute_diagnosed(&value).map_err(|diagnostic| Error::Eval {
+            kind: diagnostic.kind,
+            values: values.into_iter().map(|(name, value, source)| (name.to_string(), value, source)).collect(),
+        })
+    }
+
+    /// Partially substitute variables with their values from `env`, and with `defaults` for the
+    /// variables in `apply_defaults_for`, so that optional dimensions not listed stay symbolic.
+    /// See [`Expr::partial_substitute`].
+    pub fn partial_substitute_defaults(
+        &self,
+        env: &HashMap<&str, usize>,
+        defaults: &HashMap<&str, usize>,
+        apply_defaults_for: &BTreeSet<&str>,
+    ) -> Option<Self> {
+        let mut value = defaults
+            .iter()
+            .filter(|(name, _)| apply_defaults_for.contains(*name))
+            .map(|(&name, &value)| (name, value))
+            .collect::<HashMap<_, _>>();
+        value.extend(env);
+        self.partial_substitute(&value)
+    }
+
+    /// Lenient evaluation: substitute variables with their values in the canonical form,
+    /// requiring only the final value to be a natural number, see [`Expr::substitute_exact_steps`]
+    /// for the strict evaluation. Expressions without a rational form are evaluated as trees.
+    pub fn substitute_rational(&self, value: &HashMap<&str, usize>) -> Result<usize, EvalError> {
+        match self.to_rational() {
+            Some(rational) => rational.checked_substitute(value).and_then(ratio_to_usize),
+            None => self.substitute_diagnosed(value).map_err(|diagnostic| diagnostic.kind),
+        }
+    }
+
+    /// Exact evaluation of the canonical form in big rationals, for values whose intermediate
+    /// products overflow [`substitute_rational`](Self::substitute_rational) even though the
+    /// result is small. Returns None if a variable has no value, the denominator is zero, or
+    /// the expression has no rational form.
+    pub fn substitute_bigratio(&self, value: &HashMap<&str, usize>) -> Option<BigRational> {
+        self.to_rational()?.substitute_big(value)
+    }
+
+    /// Exact evaluation of the canonical form at rational values of the variables, such as
+    /// scale factors like `3/2`. Expressions without a rational form are evaluated as trees,
+    /// which requires the values to be natural numbers, failing with [`EvalError::NonDivisible`]
+    /// otherwise.
+    ///
+    /// ```rust
+    /// # use std::collections::HashMap;
+    /// # use num_rational::Ratio;
+    /// # use symbolic_expr::Expr;
+    /// let expr = Expr::var("n") * Expr::var("scale");
+    /// let value = HashMap::from([("n", Ratio::from_integer(3)), ("scale", Ratio::new(3, 2))]);
+    /// assert_eq!(expr.evaluate_with_ratios(&value), Ok(Ratio::new(9, 2)));
+    /// ```
+    pub fn evaluate_with_ratios(&self, value: &HashMap<&str, Ratio<isize>>) -> Result<Ratio<isize>, EvalError> {
+        if let Some(rational) = self.to_rational() {
+            return rational.checked_substitute(value);
+        }
+        if self.has_holes() {
+            return Err(EvalError::UnfilledHole);
+        }
+        // Only the variables of the expression need natural values
+        let value = self
+            .variables()
+            .into_iter()
+            .filter_map(|name| value.get(name).map(|&value| Ok((name, ratio_to_usize(value).map_err(|_| EvalError::NonDivisible)?))))
+            .collect::<Result<HashMap<_, _>, _>>()?;
+        let ans = self.substitute_diagnosed(&value).map_err(|diagnostic| diagnostic.kind)?;
+        isize::try_from(ans).map(Ratio::from_integer).map_err(|_| EvalError::Overflow)
+    }
+
+    /// [`evaluate_with_ratios`](Self::evaluate_with_ratios), requiring the final value to be a
+    /// natural number, as for a dimension computed with rational scale factors.
+    pub fn evaluate_with_ratios_integral(&self, value: &HashMap<&str, Ratio<isize>>) -> Result<usize, EvalError> {
+        self.evaluate_with_ratios(value).and_then(ratio_to_usize)
+    }
+
+    /// The sub-expression at a path of operand indices, see [`EvalDiagnostic::path`].
+    fn at_path(&self, path: &[usize]) -> &Self {
+        let Some((&i, rest)) = path.split_first() else {
+            return self;
+        };
+        let child = match self {
+            Self::Sum(operands) | Self::Product(operands) => &operands[i].expr,
+            Self::Pow(operand, _) | Self::Abs(operand) => operand,
+            Self::Mod(lhs, _) | Self::Max(lhs, _) if i == 0 => lhs,
+            Self::Mod(_, rhs) | Self::Max(_, rhs) => rhs,
+            _ => unreachable!("path leads into a leaf"),
+        };
+        child.at_path(rest)
+    }
+
+    /// Substitute variables with their values.
+    /// Returns None if the evaluation fails, see [`Expr::substitute_diagnosed`] for the reason.
+    pub fn checked_substitute(&self, value: &HashMap<&str, usize>) -> Option<usize> {
+        self.substitute_diagnosed(value).ok()
+    }
+
+    /// [`checked_substitute`](Self::checked_substitute) at each of the assignments in parallel,
+    /// such as a grid of shape configurations. The results are in the order of the assignments.
+    ///
+    /// ```rust
+    /// # use std::collections::HashMap;
+    /// # use symbolic_expr::Expr;
+    /// let expr = Expr::var("n") * 2 - 1;
+    /// let grid = (0..4).map(|n| HashMap::from([("n", n)])).collect::<Vec<_>>();
+    /// assert_eq!(expr.substitute_batch(&grid), [None, Some(1), Some(3), Some(5)]);
+    /// ```
+    #[cfg(feature = "rayon")]
+    pub fn substitute_batch(&self, assignments: &[HashMap<&str, usize>]) -> Vec<Option<usize>> {
+        use rayon::prelude::*;
+        assignments.par_iter().map(|value| self.checked_substitute(value)).collect()
+    }
+
+    /// Approximate evaluation in `f64` at real values of the variables, e.g. to plot how a
+    /// shape grows. Divisions are real divisions and `mod` gives the non-negative remainder.
+    /// Fails with [`EvalError::Overflow`] if the value is not finite.
+    pub fn evaluate_f64(&self, value: &HashMap<&str, f64>) -> Result<f64, EvalError> {
+        let ans = self.eval_f64(value)?;
+        if ans.is_finite() { Ok(ans) } else { Err(EvalError::Overflow) }
+    }
+
+    /// Compiles the rational form into a closure for [`evaluate_f64`](Self::evaluate_f64) in hot
+    /// loops, which evaluates the terms directly instead of walking the tree on every call.
+    /// The closure returns None if a variable has no value, the denominator is zero or the
+    /// value is not finite. Expressions without a rational form are evaluated as trees.
+    ///
+    /// The rational form is not normalized, so common factors of numerator and denominator
+    /// are not cancelled and `(a*a - 1)/(a - 1)` stays undefined at `a = 1`.
+    ///
+    /// ```rust
+    /// # use std::collections::HashMap;
+    /// # use symbolic_expr::Expr;
+    /// let cost = Expr::var("a") * Expr::var("a") / 2 + 1;
+    /// let f = cost.compile_f64();
+    /// assert_eq!(f(&HashMap::from([("a", 3.0)])), Some(5.5));
+    /// assert_eq!(f(&HashMap::new()), None);
+    /// let a = Expr::var("a");
+    /// let f = ((a.clone() * a.clone() - 1) / (a - 1)).compile_f64();
+    /// assert_eq!(f(&HashMap::from([("a", 1.0)])), None);
+    /// ```
+    pub fn compile_f64(&self) -> CompiledF64 {
+        // Coefficient, and variables with their exponents, of each term
+        type Terms = Vec<(f64, Vec<(String, i32)>)>;
+        fn compile(terms: &[CanonicalTerm]) -> Terms {
+            let coef = |term: &CanonicalTerm| *term.coef.numer() as f64 / *term.coef.denom() as f64;
+            // Exponents are bounded by MAX_EXPONENT, well within i32
+            let factors = |term: &CanonicalTerm| term.factors.iter().map(|f| (f.base.clone(), f.exponent as i32)).collect();
+            terms.iter().map(|term| (coef(term), factors(term))).collect()
+        }
+        fn sum(terms: &Terms, value: &HashMap<&str, f64>) -> Option<f64> {
+            terms.iter().try_fold(0.0, |acc, (coef, factors)| {
+                let term = factors.iter().try_fold(*coef, |acc, (name, exponent)| Some(acc * value.get(&**name)?.powi(*exponent)));
+                Some(acc + term?)
+            })
+        }
+
+        let Some(rational) = self.to_rational() else {
+            let expr = self.clone();
+            return Box::new(move |value| expr.evaluate_f64(value).ok());
+        };
+        let (numer, denom) = (compile(&rational.numer), compile(&rational.denom));
+        Box::new(move |value| {
+            let denom = sum(&denom, value)?;
+            let ans = sum(&numer, value)? / denom;
+            (denom != 0.0 && ans.is_finite()).then_some(ans)
+        })
+    }
+
+    fn eval_f64(&self, value: &HashMap<&str, f64>) -> Result<f64, EvalError> {
+        match self {
+            &Self::Constant(value) => Ok(value as f64),
+            Self::Variable(name) => value.get(&**name).copied().ok_or_else(|| EvalError::UnknownVariable(name.clone())),
+            Self::Sum(operands) => operands.iter().try_fold(0.0, |acc, Operand { ty, expr }| {
+                let value = expr.eval_f64(value)?;
+                Ok(match ty {
+                    Sign::Positive => acc + value,
+                    Sign::Negative => acc - value,
+                })
+            }),
+            Self::Product(operands) => operands.iter().try_fold(1.0, |acc, Operand { ty, expr }| {
+                let value = expr.eval_f64(value)?;
+                match ty {
+                    Sign::Positive => Ok(acc * value),
+                    Sign::Negative if value == 0.0 => Err(EvalError::DivisionByZero),
+                    Sign::Negative => Ok(acc / value),
+                }
+            }),
+            Self::Rational(rational) => rational.evaluate_f64(value),
+            Self::Pow(base, exponent) => Ok(base.eval_f64(value)?.powf(f64::from(*exponent))),
+            Self::Mod(lhs, modulus) => {
+                let lhs = lhs.eval_f64(value)?;
+                let modulus = modulus.eval_f64(value)?;
+                if modulus == 0.0 { Err(EvalError::DivisionByZero) } else { Ok(lhs.rem_euclid(modulus)) }
+            }
+            Self::Abs(operand) => Ok(operand.eval_f64(value)?.abs()),
+            Self::Max(lhs, rhs) => Ok(lhs.eval_f64(value)?.max(rhs.eval_f64(value)?)),
+            Self::Hole => Err(EvalError::UnfilledHole),
+        }
+    }
+
+    /// Checks that both expressions evaluate to the same value at every assignment of
+    /// `points`, with [`checked_substitute`](Self::checked_substitute). Returns false if any
+    /// evaluation fails, even on both sides.
+    pub fn equal_on_grid(&self, other: &Self, points: &[HashMap<&str, usize>]) -> bool {
+        points.iter().all(|value| match self.checked_substitute(value) {
+            Some(lhs) => other.checked_substitute(value) == Some(lhs),
+            None => false,
+        })
+    }
+
+    /// A cheap check that two expressions are probably equal, for the cases
+    /// [`equivalent`](Self::equivalent) cannot decide.
+    ///
+    /// Both expressions are evaluated in `f64` at `samples` pseudo-random points, the same for
+    /// every call, with each variable between 1 and 64. Returns false if the relative difference
+    /// at any point exceeds `tol`, skipping points where either expression divides by zero.
+    /// Expressions that differ only near a few points may not be told apart.
+    ///
+    /// A point where either value overflows `f64` tells nothing about equality and makes the
+    /// result false, even if both sides overflow. So the check is not reflexive: an expression
+    /// such as `a^1000` is not approximately equal to itself.
+    ///
+    /// ```rust
+    /// # use symbolic_expr::Expr;
+    /// let a = Expr::var("a");
+    /// let b = Expr::var("b");
+    /// let lhs = (a.clone() + b.clone()).modulo(b.clone());
+    /// assert!(lhs.approx_equal(&a.clone().modulo(b.clone()), 100, 1e-9));
+    /// assert!(!lhs.approx_equal(&a, 100, 1e-9));
+    /// ```
+    pub fn approx_equal(&self, other: &Self, samples: usize, tol: f64) -> bool {
+        let names = self.variables().into_iter().chain(other.variables()).collect::<BTreeSet<_>>();
+        // SplitMix64, so the points do not depend on a random source
+        let mut state = 0x5eed_u64;
+        let mut next = || {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            1.0 + 63.0 * ((z ^ (z >> 31)) >> 11) as f64 / (1u64 << 53) as f64
+        };
+        (0..samples).all(|_| {
+            let value = names.iter().map(|&name| (name, next())).collect::<HashMap<_, _>>();
+            match (self.evaluate_f64(&value), other.evaluate_f64(&value)) {
+                (Err(EvalError::DivisionByZero), _) | (_, Err(EvalError::DivisionByZero)) => true,
+                (Ok(lhs), Ok(rhs)) => (lhs - rhs).abs() <= tol * lhs.abs().max(rhs.abs()),
+                _ => false,
+            }
+        })
+    }
+
+    /// The tree-walk evaluation behind [`Expr::substitute_diagnosed`], recording the values that
+    /// flow into it in `trace` if given.
+    fn eval_diagnosed(
+        &self,
+        value: &HashMap<&str, usize>,
+        path: &mut Vec<usize>,
+        mut trace: Option<&mut Recorder>,
+    ) -> Result<usize, EvalDiagnostic> {
+        let fail = |kind, path: &[usize], accumulator| EvalDiagnostic {
+            kind,
+            path: path.to_vec(),
+            accumulator,
+        };
+        match self {
+            &Self::Constant(value) => Ok(value),
+            Self::Variable(name) => {
+                let var_value = *value.get(&**name).ok_or_else(|| fail(EvalError::UnknownVariable(name.clone()), path, None))?;
+                if let Some(trace) = trace {
+                    trace.read(name, var_value);
+                }
+                Ok(var_value)
+            }
+            Self::Sum(operands) => operands.iter().enumerate().try_fold(0usize, |acc, (i, Operand { ty, expr })| {
+                path.push(i);
+                let value = expr.eval_diagnosed(value, path, trace.as_deref_mut())?;
+                let ans = match ty {
+                    Sign::Positive => acc.checked_add(value).ok_or(EvalError::Overflow),
+                    Sign::Negative => acc.checked_sub(value).ok_or(EvalError::Negative),
+                };
+                let ans = ans.map_err(|kind| fail(kind, path, Some(acc)))?;
+                path.pop();
+                Ok(ans)
+            }),
+            Self::Product(operands) => operands.iter().enumerate().try_fold(1usize, |acc, (i, Operand { ty, expr })| {
+                path.push(i);
+                let value = expr.eval_diagnosed(value, path, trace.as_deref_mut())?;
+                if let (Sign::Negative, Some(trace)) = (ty, trace.as_deref_mut()) {
+                    trace.divide(acc, value);
+                }
+                let ans = match ty {
+                    Sign::Positive => acc.checked_mul(value).ok_or(EvalError::Overflow),
+                    Sign::Negative if value == 0 => Err(EvalError::DivisionByZero),
+                    Sign::Negative if acc % value != 0 => Err(EvalError::NonDivisible),
+                    Sign::Negative => Ok(acc / value),
+                };
+                let ans = ans.map_err(|kind| fail(kind, path, Some(acc)))?;
+                path.pop();
+                Ok(ans)
+            }),
+            Self::Rational(rational) => {
+                let ans = rational.checked_substitute(value).and_then(ratio_to_usize).map_err(|kind| fail(kind, path, None))?;
+                if let Some(trace) = trace {
+                    self.variables().into_iter().for_each(|name| trace.read(name, value[name]));
+                }
+                Ok(ans)
+            }
+            Self::Pow(base, exponent) => {
+                path.push(0);
+                let value = base.eval_diagnosed(value, path, trace.as_deref_mut())?;
+                path.pop();
+                value
+                    .checked_pow(*exponent)
+                    .ok_or_else(|| fail(EvalError::Overflow, path, None))
+            }
+            Self::Mod(lhs, modulus) => {
+                path.push(0);
+                let lhs = lhs.eval_diagnosed(value, path, trace.as_deref_mut())?;
+                path.pop();
+                path.push(1);
+                let modulus = modulus.eval_diagnosed(value, path, trace.as_deref_mut())?;
+                path.pop();
+                lhs.checked_rem(modulus).ok_or_else(|| fail(EvalError::DivisionByZero, path, None))
+            }
+            Self::Abs(operand) => {
+                path.push(0);
+                let ans = operand.substitute_signed(value).map_err(|kind| fail(kind, path, None))?;
+                path.pop();
+                if let Some(trace) = trace {
+                    operand.variables().into_iter().for_each(|name| trace.read(name, value[name]));
+                }
+                Ok(ans.unsigned_abs())
+            }
+            Self::Max(lhs, rhs) => {
+                path.push(0);
+                let lhs = lhs.eval_diagnosed(value, path, trace.as_deref_mut())?;
+                path.pop();
+                path.push(1);
+                let rhs = rhs.eval_diagnosed(value, path, trace)?;
+                path.pop();
+                Ok(lhs.max(rhs))
+            }
+            Self::Hole => Err(fail(EvalError::UnfilledHole, path, None)),
+        }
+    }
+
+    /// Substitute variables with their values, allowing negative intermediate and final values.
+    /// Divisions must still be exact, and `mod` gives the non-negative remainder.
+    pub(crate) fn substitute_signed(&self, value: &HashMap<&str, usize>) -> Result<isize, EvalError> {
+        let operands = |operands: &VecDeque<Operand>| {
+            operands
+                .iter()
+                .map(|Operand { ty, expr }| Ok((*ty, expr.substitute_signed(value)?)))
+                .collect::<Result<Vec<_>, EvalError>>()
+        };
+        match self {
+            &Self::Constant(value) => isize::try_from(value).map_err(|_| EvalError::Overflow),
+            Self::Variable(name) => {
+                let value = *value.get(&**name).ok_or_else(|| EvalError::UnknownVariable(name.clone()))?;
+                isize::try_from(value).map_err(|_| EvalError::Overflow)
+            }
+            Self::Sum(ops) => operands(ops)?.into_iter().try_fold(0isize, |acc, (ty, value)| {
+                match ty {
+                    Sign::Positive => acc.checked_add(value),
+                    Sign::Negative => acc.checked_sub(value),
+                }
+                .ok_or(EvalError::Overflow)
+            }),
+            Self::Product(ops) => operands(ops)?.into_iter().try_fold(1isize, |acc, (ty, value)| match ty {
+                Sign::Positive => acc.checked_mul(value).ok_or(EvalError::Overflow),
+                Sign::Negative if value == 0 => Err(EvalError::DivisionByZero),
+                // isize::MIN / -1 overflows, also when taking the remainder
+                Sign::Negative => match acc.checked_rem(value) {
+                    Some(0) => acc.checked_div(value).ok_or(EvalError::Overflow),
+                    Some(_) => Err(EvalError::NonDivisible),
+                    None => Err(EvalError::Overflow),
+                },
+            }),
+            Self::Rational(rational) => {
+                let value = rational.checked_substitute(value)?;
+                if value.is_integer() { Ok(value.to_integer()) } else { Err(EvalError::NonDivisible) }
+            }
+            Self::Pow(base, exponent) => base.substitute_signed(value)?.checked_pow(*exponent).ok_or(EvalError::Overflow),
+            Self::Mod(lhs, modulus) => {
+                let lhs = lhs.substitute_signed(value)?;
+                let modulus = modulus.substitute_signed(value)?;
+                lhs.checked_rem_euclid(modulus).ok_or(if modulus == 0 { EvalError::DivisionByZero } else { EvalError::Overflow })
+            }
+            Self::Abs(operand) => operand.substitute_signed(value)?.checked_abs().ok_or(EvalError::Overflow),
+            Self::Max(lhs, rhs) => Ok(lhs.substitute_signed(value)?.max(rhs.substitute_signed(value)?)),
+            Self::Hole => Err(EvalError::UnfilledHole),
+        }
+    }
+}
+
+impl RationalExpression {
+    /// Substitute variables with their values without overflowing.
+    pub(crate) fn checked_substitute<V: ExactValue>(&self, value: &HashMap<&str, V>) -> Result<Ratio<isize>, EvalError> {
+        fn sum<V: ExactValue>(terms: &[CanonicalTerm], value: &HashMap<&str, V>) -> Result<Ratio<isize>, EvalError> {
+            terms.iter().try_fold(Ratio::from_integer(0), |acc, term| {
+                acc.checked_add(&term.checked_substitute(value)?).ok_or(EvalError::Overflow)
+            })
+        }
+
+        let numer = sum(&self.numer, value)?;
+        let denom = sum(&self.denom, value)?;
+        if denom == Ratio::from_integer(0) {
+            return Err(EvalError::DivisionByZero);
+        }
+        numer.checked_div(&denom).ok_or(EvalError::Overflow)
+    }
+
+    fn substitute_big(&self, value: &HashMap<&str, usize>) -> Option<BigRational> {
+        let sum = |terms: &[CanonicalTerm]| terms.iter().try_fold(BigRational::zero(), |acc, term| Some(acc + term.substitute_big(value)?));
+        let denom = sum(&self.denom)?;
+        if denom.is_zero() {
+            return None;
+        }
+        Some(sum(&self.numer)? / denom)
+    }
+
+    fn evaluate_f64(&self, value: &HashMap<&str, f64>) -> Result<f64, EvalError> {
+        let sum = |terms: &[CanonicalTerm]| terms.iter().try_fold(0.0, |acc, term| Ok(acc + term.evaluate_f64(value)?));
+        let denom = sum(&self.denom)?;
+        if denom == 0.0 {
+            return Err(EvalError::DivisionByZero);
+        }
+        Ok(sum(&self.numer)? / denom)
+    }
+}
+
+impl CanonicalTerm {
+    fn evaluate_f64(&self, value: &HashMap<&str, f64>) -> Result<f64, EvalError> {
+        let coef = *self.coef.numer() as f64 / *self.coef.denom() as f64;
+        self.factors.iter().try_fold(coef, |acc, factor| {
+            let var_value = *value
+                .get(factor.base.as_str())
+                .ok_or_else(|| EvalError::UnknownVariable(factor.base.clone()))?;
+            // Exponents are bounded by MAX_EXPONENT, well within i32
+            Ok(acc * var_value.powi(factor.exponent as i32))
+        })
+    }
+
+    fn substitute_big(&self, value: &HashMap<&str, usize>) -> Option<BigRational> {
+        let coef = BigRational::new((*self.coef.numer()).into(), (*self.coef.denom()).into());
+        self.factors.iter().try_fold(coef, |acc, factor| {
+            let var_value = BigRational::from_integer((*value.get(factor.base.as_str())?).into());
+            let power = num_traits::pow(var_value, factor.exponent.unsigned_abs());
+            if factor.exponent > 0 {
+                Some(acc * power)
+            } else if power.is_zero() {
+                None
+            } else {
+                Some(acc / power)
+            }
+        })
+    }
+
+    /// Substitute variables with their values without overflowing.
+    fn checked_substitute<V: ExactValue>(&self, value: &HashMap<&str, V>) -> Result<Ratio<isize>, EvalError> {
+        let mut result = self.coef;
+        for factor in &self.factors {
+            let var_value = *value
+                .get(factor.base.as_str())
+                .ok_or_else(|| EvalError::UnknownVariable(factor.base.clone()))?;
+            let var_value = var_value.to_ratio().ok_or(EvalError::Overflow)?;
+            // Powers by squaring, so large exponents do not loop
+            let power = num_traits::checked_pow(var_value, factor.exponent.unsigned_abs())
+                .ok_or(EvalError::Overflow)?;
+            result = if factor.exponent > 0 {
+                result.checked_mul(&power).ok_or(EvalError::Overflow)?
+            } else if power == Ratio::from_integer(0) {
+                return Err(EvalError::DivisionByZero);
+            } else {
+                result.checked_div(&power).ok_or(EvalError::Overflow)?
+            };
+        }
+        Ok(result)
+    }
+}
+
+/// A value of a variable in the exact evaluation, see [`RationalExpression::checked_substitute`].
+pub(crate) trait ExactValue: Copy {
+    /// The value as a ratio, or None if it does not fit.
+    fn to_ratio(self) -> Option<Ratio<isize>>;
+}
+
+impl ExactValue for usize {
+    fn to_ratio(self) -> Option<Ratio<isize>> {
+        isize::try_from(self).ok().map(Ratio::from_integer)
+    }
+}
+
+impl ExactValue for Ratio<isize> {
+    fn to_ratio(self) -> Option<Ratio<isize>> {
+        Some(self)
+    }
+}
+
+/// Converts an exact result to `usize`, requiring it to be a non-negative integer.
+pub(crate) fn ratio_to_usize(value: Ratio<isize>) -> Result<usize, EvalError> {
+    if !value.is_integer() {
+        Err(EvalError::NonDivisible)
+    } else if *value.numer() < 0 {
+        Err(EvalError::Negative)
+    } else {
+        Ok(value.numer().unsigned_abs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_substitute_diagnosed() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+
+        // Test successful evaluation matches substitute
+        let expr1 = (a.clone() * 2 + b.clone() * 3) / (c.clone() + 4);
+        let values1 = HashMap::from([("a", 5), ("b", 10), ("c", 6)]);
+        assert_eq!(expr1.substitute_diagnosed(&values1), Ok(expr1.substitute(&values1)));
+        assert_eq!(expr1.checked_substitute(&values1), Some(4));
+
+        // Test overflow in a nested product
+        let expr2 = Expr::from(1) + a.clone() * b.clone() * c.clone();
+        let values2 = HashMap::from([("a", usize::MAX / 2), ("b", 1), ("c", 3)]);
+        let err = expr2.substitute_diagnosed(&values2).unwrap_err();
+        println!("asserting 1 + abc overflows at [1, 2]");
+        assert_eq!(err.kind, EvalError::Overflow);
+        assert_eq!(err.path, [1, 2]);
+        assert_eq!(err.accumulator, Some(usize::MAX / 2));
+        assert_eq!(expr2.checked_substitute(&values2), None);
+
+        // Test negative subtraction
+        let expr3 = a.clone() - b.clone();
+        let err = expr3.substitute_diagnosed(&HashMap::from([("a", 2), ("b", 3)])).unwrap_err();
+        assert_eq!(err.kind, EvalError::Negative);
+        assert_eq!(err.path, [1]);
+        assert_eq!(err.accumulator, Some(2));
+
+        // Test non-divisible and zero division
+        let expr4 = (a.clone() + 1) / b.clone();
+        let err = expr4.substitute_diagnosed(&HashMap::from([("a", 2), ("b", 2)])).unwrap_err();
+        assert_eq!(err.kind, EvalError::NonDivisible);
+        assert_eq!(err.path, [1]);
+        assert_eq!(err.accumulator, Some(3));
+        let err = expr4.substitute_diagnosed(&HashMap::from([("a", 2), ("b", 0)])).unwrap_err();
+        assert_eq!(err.kind, EvalError::DivisionByZero);
+
+        // Test unknown variable
+        let err = expr4.substitute_diagnosed(&HashMap::from([("a", 2)])).unwrap_err();
+        assert_eq!(err.kind, EvalError::UnknownVariable("b".to_string()));
+        assert_eq!(err.path, [1]);
+        assert_eq!(err.accumulator, None);
+
+        // Test rational expressions
+        let rational = Expr::Rational(RationalExpression::new(
+            vec![CanonicalTerm::with_var(1, "a".to_string())],
+            vec![CanonicalTerm::new(2)],
+        ));
+        assert_eq!(rational.checked_substitute(&HashMap::from([("a", 6)])), Some(3));
+        let err = rational.substitute_diagnosed(&HashMap::from([("a", 5)])).unwrap_err();
+        assert_eq!(err.kind, EvalError::NonDivisible);
+        assert!(err.path.is_empty());
+        let err = rational.substitute_diagnosed(&HashMap::from([("a", usize::MAX)])).unwrap_err();
+        assert_eq!(err.kind, EvalError::Overflow);
+    }
+
+    #[test]
+    fn test_validate_env() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+        let d = Expr::var("d");
+
+        let fine = a.clone() * b.clone();
+        let odd = (a.clone() + 1) / b.clone();
+        let missing = c.clone() * 2 + d.clone() - a.clone();
+        let zero = a.clone() / (b.clone() - 2);
+        let rational = Expr::from((a.clone() / 4).canonicalize().unwrap());
+        let exprs = [("fine", &fine), ("odd", &odd), ("missing", &missing), ("zero", &zero), ("rational", &rational)];
+        let values = HashMap::from([("a", 2), ("b", 2)]);
+
+        // Test all problems are reported in one pass, in order
+        let problems = validate_env(&exprs, &values);
+        println!("asserting {problems:?}");
+        let summary = problems.iter().map(|p| (p.name.as_str(), p.diagnostic.kind.clone(), p.diagnostic.path.clone())).collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                ("odd", EvalError::NonDivisible, vec![1]),
+                ("missing", EvalError::UnknownVariable("c".to_string()), vec![0, 0]),
+                ("missing", EvalError::UnknownVariable("d".to_string()), vec![1]),
+                ("zero", EvalError::DivisionByZero, vec![1]),
+                ("rational", EvalError::NonDivisible, vec![]),
+            ],
+        );
+        assert_eq!(validate_env(&exprs, &values), problems);
+
+        // Test a valid environment has no problems
+        let values = HashMap::from([("a", 3), ("b", 4), ("c", 1), ("d", 5)]);
+        assert!(validate_env(&exprs[..3], &values).is_empty());
+        assert!(validate_env(&[], &values).is_empty());
+    }
+
+    #[test]
+    fn test_substitute_exact_steps() {
+        let n = Expr::var("n");
+        let m = Expr::var("m");
+
+        // Test an inexact intermediate division
+        let expr1 = n.clone() / 2 * 2 + 1;
+        let values = HashMap::from([("n", 5), ("m", 3)]);
+        let err = expr1.substitute_exact_steps(&values).unwrap_err();
+        println!("asserting {expr1} fails strictly: {err}");
+        assert_eq!(err.kind, EvalError::NonDivisible);
+        assert_eq!(err.step, "n/2");
+        assert_eq!(err.values, Some((5, 2)));
+        assert_eq!(err.to_string(), "division is not exact in \"n/2\" with values 5 and 2");
+        assert_eq!(expr1.substitute_rational(&values), Ok(6));
+        assert_eq!(expr1.substitute_exact_steps(&HashMap::from([("n", 4)])), Ok(5));
+
+        // Test a negative intermediate subtraction
+        let expr2 = (m.clone() - n.clone() + 4) * 2;
+        let err = expr2.substitute_exact_steps(&values).unwrap_err();
+        assert_eq!(err.kind, EvalError::Negative);
+        assert_eq!(err.step, "m - n");
+        assert_eq!(err.values, Some((3, 5)));
+        assert_eq!(expr2.substitute_rational(&values), Ok(4));
+
+        // Test failures that are not a step of a sum or product
+        let err = (n.clone() + m.clone() * Expr::var("k")).substitute_exact_steps(&values).unwrap_err();
+        assert_eq!(err.kind, EvalError::UnknownVariable("k".to_string()));
+        assert_eq!(err.step, "k");
+        assert_eq!(err.values, None);
+        let rational = Expr::from((n.clone() / 2).canonicalize().unwrap());
+        let err = rational.substitute_exact_steps(&values).unwrap_err();
+        assert_eq!(err.step, "n/2");
+        assert_eq!(rational.substitute_rational(&values), Err(EvalError::NonDivisible));
+        assert_eq!(n.clone().modulo(m.clone() - 3).substitute_rational(&values), Err(EvalError::DivisionByZero));
+    }
+
+    #[test]
+    fn test_substitute_with_defaults() {
+        let c = Expr::var("c");
+        let k = Expr::var("k");
+        let dilation = Expr::var("dilation");
+        let groups = Expr::var("groups");
+
+        let expr = (k.clone() - 1) * dilation.clone() + c.clone() / groups.clone();
+        let defaults = HashMap::from([("dilation", 1), ("groups", 1)]);
+
+        // Test env takes precedence over defaults
+        println!("asserting {expr} falls back to the defaults");
+        assert_eq!(expr.substitute_with_defaults(&HashMap::from([("k", 3), ("c", 8)]), &defaults).unwrap(), 10);
+        let env = HashMap::from([("k", 3), ("c", 8), ("dilation", 2), ("groups", 4)]);
+        assert_eq!(expr.substitute_with_defaults(&env, &defaults).unwrap(), 6);
+
+        // Test all missing variables are listed
+        let err = expr.substitute_with_defaults(&HashMap::new(), &defaults).unwrap_err();
+        assert!(matches!(&err, Error::MissingVariables(names) if names == &["c", "k"]));
+        assert_eq!(err.to_string(), "no value or default for c, k");
+
+        // Test evaluation errors tell where the values came from
+        let err = expr.substitute_with_defaults(&HashMap::from([("k", 0), ("c", 8)]), &defaults).unwrap_err();
+        assert!(matches!(&err, Error::Eval { kind: EvalError::Negative, .. }));
+        assert_eq!(
+            err.to_string(),
+            "failed to evaluate expression: value is negative with c = 8 (env), dilation = 1 (default), \
+             groups = 1 (default), k = 0 (env)",
+        );
+
+        // Test defaults are applied to the listed variables only
+        let partial = expr
+            .partial_substitute_defaults(&HashMap::from([("k", 3)]), &defaults, &BTreeSet::from(["dilation"]))
+            .unwrap();
+        assert_eq!(partial.variables(), BTreeSet::from(["c", "groups"]));
+        assert_eq!(partial.equivalent(&(Expr::from(2) + c.clone() / groups.clone())), Some(true));
+        let partial = expr.partial_substitute_defaults(&HashMap::from([("dilation", 3)]), &defaults, &BTreeSet::from(["dilation"]));
+        assert_eq!(partial.unwrap().variables(), BTreeSet::from(["c", "groups", "k"]));
+    }
+
+    #[test]
+    fn test_substitute_with() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let len = Expr::var("len");
+
+        // Test values computed on the fly, queried once per variable
+        let expr = (a.clone() * a.clone() + b.clone()) / len.clone();
+        let mut queries = Vec::new();
+        let value = expr.substitute_with(|name| {
+            queries.push(name.to_string());
+            Some(name.len() * 2)
+        });
+        println!("asserting {expr} is 1 with values from the closure");
+        assert_eq!(value, Some(1));
+        assert_eq!(queries, ["a", "b", "len"]);
+
+        // Test missing values stop the queries
+        let mut queries = 0;
+        assert_eq!(expr.substitute_with(|_| { queries += 1; None }), None);
+        assert_eq!(queries, 1);
+
+        // Test failing evaluations
+        assert_eq!(expr.substitute_with(|name| (name != "b").then_some(2).or(Some(1))), None);
+        assert_eq!((a.clone() * b.clone()).substitute_with(|_| Some(usize::MAX)), None);
+        assert_eq!(Expr::from(4).substitute_with(|_| None), Some(4));
+    }
+
+    #[test]
+    fn test_evaluate_f64() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+
+        // Test real divisions, signed values and every node
+        let value = HashMap::from([("a", 3.0), ("b", 0.5)]);
+        let cases = [
+            (a.clone() / 2 + b.clone(), 2.0),
+            (b.clone() - a.clone(), -2.5),
+            (a.clone().pow(3) * b.clone(), 13.5),
+            (a.clone().modulo(Expr::from(2)).max(b.clone()), 1.0),
+            ((b.clone() - a.clone()).abs(), 2.5),
+            (Expr::from(((a.clone() + 1) / b.clone()).canonicalize().unwrap()), 8.0),
+        ];
+        for (expr, expected) in cases {
+            println!("asserting {expr} is {expected}");
+            assert_eq!(expr.evaluate_f64(&value), Ok(expected));
+        }
+        assert_eq!((a.clone() / (b.clone() - b.clone())).evaluate_f64(&value), Err(EvalError::DivisionByZero));
+        assert_eq!(a.clone().pow(4000).evaluate_f64(&value), Err(EvalError::Overflow));
+        assert_eq!(Expr::var("c").evaluate_f64(&value), Err(EvalError::UnknownVariable("c".to_string())));
+    }
+
+    #[test]
+    fn test_substitute_bigratio() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+
+        // Test intermediate values beyond isize give the small exact result
+        let expr = (a.clone().pow(4) - b.clone().pow(4)) / (a.clone().pow(4) - c.clone().pow(4)) * 6;
+        let value = HashMap::from([("a", 1 << 20), ("b", 3), ("c", 3)]);
+        println!("asserting {expr} is 6 at a = 2^20");
+        assert_eq!(expr.substitute_rational(&value), Err(EvalError::Overflow));
+        assert_eq!(expr.substitute_bigratio(&value), Some(BigRational::from_integer(6.into())));
+        let value = HashMap::from([("a", 1 << 20), ("b", 3), ("c", 1)]);
+        let expected = BigRational::new((((1_i128 << 80) - 81) * 6).into(), ((1_i128 << 80) - 1).into());
+        assert_eq!(expr.substitute_bigratio(&value), Some(expected));
+        assert_eq!((Expr::from(3) / a.clone() / 4).substitute_bigratio(&value), Some(BigRational::new(3.into(), (1 << 22).into())));
+
+        // Test missing variables, zero denominators and expressions without a rational form
+        assert_eq!(expr.substitute_bigratio(&HashMap::from([("a", 2), ("b", 3)])), None);
+        assert_eq!(expr.substitute_bigratio(&HashMap::from([("a", 2), ("b", 3), ("c", 2)])), None);
+        assert_eq!((b.clone() / a.clone()).substitute_bigratio(&HashMap::from([("a", 0), ("b", 3)])), None);
+        assert_eq!(a.clone().modulo(b.clone()).substitute_bigratio(&HashMap::from([("a", 2), ("b", 3)])), None);
+    }
+
+    #[test]
+    fn test_approx_equal() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+
+        // Test equivalent pairs, including ones equivalent cannot decide
+        let equal = [
+            ((a.clone() + b.clone()).pow(2), a.clone() * a.clone() + a.clone() * b.clone() * 2 + b.clone() * b.clone()),
+            ((a.clone() * a.clone() - 1) / (a.clone() - 1), a.clone() + 1),
+            ((a.clone() - b.clone()).abs(), (b.clone() - a.clone()).abs()),
+            (a.clone().max(b.clone()) * 2, a.clone() + b.clone() + (a.clone() - b.clone()).abs()),
+        ];
+        for (lhs, rhs) in equal {
+            println!("asserting {lhs} is approximately {rhs}");
+            assert!(lhs.approx_equal(&rhs, 64, 1e-9));
+        }
+        assert!(!(a.clone() * 2).approx_equal(&(a.clone() * 3), 64, 1e-9));
+        assert!((a.clone() * 1000).approx_equal(&(a.clone() * 1001), 64, 1e-2));
+
+        // Known limitation: the expressions differ only within a millionth of a = 7
+        let spike = (a.clone() - 7).abs().max(Expr::from(1) / 1000000);
+        assert_eq!(spike.evaluate_f64(&HashMap::from([("a", 7.0)])), Ok(1e-6));
+        assert!(spike.approx_equal(&(a.clone() - 7).abs(), 1000, 1e-9));
+
+        // Known limitation: values overflowing on both sides are not equal
+        let huge = a.clone().pow(1000);
+        println!("asserting {huge} is not approximately equal to itself");
+        assert!(!huge.approx_equal(&huge, 64, 1e-9));
+    }
+
+    #[test]
+    fn test_equal_on_grid() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let grid = (1..=4).flat_map(|a| (1..=4).map(move |b| HashMap::from([("a", a), ("b", b)]))).collect::<Vec<_>>();
+
+        // Test algebraically equal expressions, including ones equivalent cannot decide
+        let lhs = (a.clone() + b.clone()).pow(2);
+        println!("asserting {lhs} agrees with its expansion on the grid");
+        assert!(lhs.equal_on_grid(&(a.clone() * a.clone() + a.clone() * b.clone() * 2 + b.clone() * b.clone()), &grid));
+        let modulo = (a.clone() + b.clone() * 3).modulo(b.clone());
+        assert_eq!(modulo.equivalent(&a.clone().modulo(b.clone())), None);
+        assert!(modulo.equal_on_grid(&a.clone().modulo(b.clone()), &grid));
+
+        // Test different expressions and failing evaluations
+        assert!(!(a.clone() * b.clone()).equal_on_grid(&(a.clone() + b.clone()), &grid));
+        assert!((a.clone() * b.clone()).equal_on_grid(&(a.clone() + b.clone()), &grid[5..6]));
+        assert!(!(a.clone() - b.clone()).equal_on_grid(&(a.clone() - b.clone()), &grid));
+        assert!(!a.equal_on_grid(&Expr::var("c"), &grid));
+        assert!(a.equal_on_grid(&b, &[]));
+    }
+
+    #[test]
+    fn test_evaluate_with_ratios() {
+        let n = Expr::var("n");
+        let scale = Expr::var("scale");
+        let value = |n: isize| HashMap::from([("n", Ratio::from_integer(n)), ("scale", Ratio::new(3, 2))]);
+
+        // Test rational scale factors evaluate exactly, integral or not
+        let expr = n.clone() * scale.clone();
+        println!("asserting {expr} is 6 at n = 4 and 9/2 at n = 3");
+        assert_eq!(expr.evaluate_with_ratios(&value(4)), Ok(Ratio::from_integer(6)));
+        assert_eq!(expr.evaluate_with_ratios_integral(&value(4)), Ok(6));
+        assert_eq!(expr.evaluate_with_ratios(&value(3)), Ok(Ratio::new(9, 2)));
+        assert_eq!(expr.evaluate_with_ratios_integral(&value(3)), Err(EvalError::NonDivisible));
+        assert_eq!((n.clone() / scale.clone()).evaluate_with_ratios(&value(3)), Ok(Ratio::from_integer(2)));
+        assert_eq!((n.clone() - scale.clone() * 4).evaluate_with_ratios_integral(&value(3)), Err(EvalError::Negative));
+
+        // Test evaluation errors
+        assert_eq!(expr.evaluate_with_ratios(&HashMap::new()), Err(EvalError::UnknownVariable("n".to_string())));
+        let zero = HashMap::from([("n", Ratio::from_integer(0)), ("scale", Ratio::new(3, 2))]);
+        assert_eq!((scale.clone() / n.clone()).evaluate_with_ratios(&zero), Err(EvalError::DivisionByZero));
+
+        // Test expressions without a rational form need natural values
+        let modulo = (n.clone() * 2).modulo(scale.clone());
+        assert_eq!(modulo.evaluate_with_ratios(&value(3)), Err(EvalError::NonDivisible));
+        let value = HashMap::from([("n", Ratio::from_integer(5)), ("scale", Ratio::from_integer(4))]);
+        assert_eq!(modulo.evaluate_with_ratios_integral(&value), Ok(2));
+        let unused = HashMap::from([("n", Ratio::from_integer(5)), ("scale", Ratio::from_integer(4)), ("unused", Ratio::new(1, 3))]);
+        assert_eq!(modulo.evaluate_with_ratios_integral(&unused), Ok(2));
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_substitute_batch() {
+        let [n, c, k] = ["n", "c", "k"].map(Expr::var);
+        let expr = (n.clone() * c.clone() - k.clone()) / 2 + k.clone().modulo(c.clone());
+
+        // Test the parallel results match the serial evaluation in order
+        let grid = (0..8)
+            .flat_map(|n| (0..4).flat_map(move |c| (0..6).map(move |k| HashMap::from([("n", n), ("c", c), ("k", k)]))))
+            .collect::<Vec<_>>();
+        let batch = expr.substitute_batch(&grid);
+        println!("asserting {expr} at {} assignments", grid.len());
+        assert_eq!(batch, grid.iter().map(|value| expr.checked_substitute(value)).collect::<Vec<_>>());
+        assert!(batch.contains(&None) && batch.iter().any(Option::is_some));
+        assert_eq!(expr.substitute_batch(&[]), []);
+    }
+
+    #[test]
+    fn test_substitute_traced() {
+        let [n, p, k, s] = ["n", "p", "k", "s"].map(Expr::var);
+
+        // Test the variables read and the divisions of a convolution output size
+        let expr = (n.clone() + p.clone() * 2 - k.clone()) / s.clone() + 1;
+        let value = HashMap::from([("n", 32), ("p", 1), ("k", 2), ("s", 2), ("unused", 7)]);
+        let (ans, trace) = expr.substitute_traced(&value).unwrap();
+        println!("asserting {expr} traces as {trace}");
+        assert_eq!(ans, 17);
+        let names = trace.variables.iter().map(|(name, _)| name.as_str()).collect::<BTreeSet<_>>();
+        assert_eq!(names, BTreeSet::from(["k", "n", "p", "s"]));
+        assert_eq!(trace.variables[0], ("n".to_string(), 32));
+        assert_eq!(trace.divisions, [(32, 2)]);
+        assert!(!trace.truncated);
+        assert_eq!(trace.to_string(), "n=32, p=1, k=2, s=2; (32 + 1*2 - 2)/2 + 1 = 17");
+
+        // Test variables read twice are listed once, also inside rational nodes
+        let canon = Expr::from((n.clone() * n.clone() / s.clone()).canonicalize().unwrap());
+        let (ans, trace) = (canon + n.clone() / s.clone()).substitute_traced(&value).unwrap();
+        assert_eq!(ans, 512 + 16);
+        assert_eq!(trace.variables, [("n".to_string(), 32), ("s".to_string(), 2)]);
+        assert_eq!(trace.divisions, [(32, 2)]);
+
+        // Test long traces are truncated
+        let long = Expr::sum((0..MAX_TRACE_EVENTS).map(|i| (Expr::var(format!("v{i}")) / 1).positive()));
+        let names = (0..MAX_TRACE_EVENTS).map(|i| format!("v{i}")).collect::<Vec<_>>();
+        let (ans, trace) = long.substitute_traced(&names.iter().map(|name| (name.as_str(), 1)).collect()).unwrap();
+        assert_eq!(ans, MAX_TRACE_EVENTS);
+        assert!(trace.truncated);
+        assert_eq!(trace.variables.len() + trace.divisions.len(), MAX_TRACE_EVENTS);
+        assert!(trace.to_string().contains(", …truncated; "));
+
+        // Test the substituted expression of a large expression is cut off
+        let large = Expr::sum((0..10_000).map(|i| (Expr::var(format!("w{i}")) * (i + 2)).positive()));
+        let names = (0..10_000).map(|i| format!("w{i}")).collect::<Vec<_>>();
+        let (ans, trace) = large.substitute_traced(&names.iter().map(|name| (name.as_str(), 1)).collect()).unwrap();
+        assert_eq!(ans, (2..10_002).sum::<usize>());
+        assert!(trace.truncated);
+        assert!(trace.substituted.len() <= MAX_TRACE_BYTES + '…'.len_utf8());
+        assert!(trace.substituted.starts_with("1*2 + 1*3 + ") && trace.substituted.ends_with('…'));
+
+        // Test missing variables and failed evaluations
+        let err = expr.substitute_traced(&HashMap::from([("n", 32)])).unwrap_err();
+        assert!(matches!(&err, Error::MissingVariables(names) if names == &["k", "p", "s"]));
+        let err = expr.substitute_traced(&HashMap::from([("n", 32), ("p", 0), ("k", 3), ("s", 2)])).unwrap_err();
+        assert!(matches!(&err, Error::Eval { kind: EvalError::NonDivisible, values } if values.len() == 4));
+    }
+
+    #[test]
+    fn test_compile_f64() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+
+        // Test the closure of (a + 1)/(b + 1) against a reference computation
+        let expr = (a.clone() + 1) / (b.clone() + 1);
+        let f = expr.compile_f64();
+        for (a_value, b_value) in [(0.0, 0.0), (3.0, 1.0), (2.5, 0.25), (1e6, 7.0), (-3.0, 2.0)] {
+            let value = HashMap::from([("a", a_value), ("b", b_value)]);
+            let expected = (a_value + 1.0) / (b_value + 1.0);
+            println!("asserting {expr} is {expected} at a = {a_value}, b = {b_value}");
+            assert!((f(&value).unwrap() - expected).abs() <= 1e-12 * expected.abs().max(1.0));
+            assert!((f(&value).unwrap() - expr.evaluate_f64(&value).unwrap()).abs() <= 1e-9);
+        }
+
+        // Test division by zero and missing variables
+        assert_eq!(f(&HashMap::from([("a", 1.0), ("b", -1.0)])), None);
+        assert_eq!(f(&HashMap::from([("a", 1.0)])), None);
+
+        // Test expressions without a rational form fall back to the tree
+        let f = (a.clone().modulo(b.clone()) + 1).compile_f64();
+        assert_eq!(f(&HashMap::from([("a", 7.0), ("b", 3.0)])), Some(2.0));
+        assert_eq!(f(&HashMap::from([("a", 7.0), ("b", 0.0)])), None);
+    }
+}
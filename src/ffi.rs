@@ -0,0 +1,240 @@
+//! C 语言接口，供其他运行时解析与求值表达式。
+//!
+//! Expressions are created by [`symexpr_parse`] or [`symexpr_from_bytes`] and released by
+//! [`symexpr_free`], strings
+//! returned by [`symexpr_to_string`] are released by [`symexpr_string_free`]. No function
+//! unwinds into the caller, panics are reported as [`SYMEXPR_PANIC`] or a null pointer.
+
+use crate::{EvalError, Expr};
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString, c_char},
+    panic::{AssertUnwindSafe, catch_unwind},
+    ptr::null_mut,
+};
+
+/// The evaluation succeeded.
+pub const SYMEXPR_OK: i32 = 0;
+/// A pointer is null, or a name is not valid UTF-8.
+pub const SYMEXPR_INVALID_ARGUMENT: i32 = -1;
+/// A variable of the expression has no value.
+pub const SYMEXPR_UNKNOWN_VARIABLE: i32 = -2;
+/// An intermediate value does not fit the integer type.
+pub const SYMEXPR_OVERFLOW: i32 = -3;
+/// A subtraction or the final value is negative.
+pub const SYMEXPR_NEGATIVE: i32 = -4;
+/// A division is not exact.
+pub const SYMEXPR_NON_DIVISIBLE: i32 = -5;
+/// A division by zero.
+pub const SYMEXPR_DIVISION_BY_ZERO: i32 = -6;
+/// The library panicked.
+pub const SYMEXPR_PANIC: i32 = -7;
+/// The expression has a hole `?` left to fill.
+pub const SYMEXPR_UNFILLED_HOLE: i32 = -8;
+
+/// Parses `len` bytes of UTF-8 text into an expression, see [`Expr::from_str`](std::str::FromStr).
+/// Returns null if the text is malformed.
+///
+/// # Safety
+///
+/// `bytes` must be valid for reads of `len` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn symexpr_parse(bytes: *const u8, len: usize) -> *mut Expr {
+    if bytes.is_null() {
+        return null_mut();
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(bytes, len) };
+    catch_unwind(|| {
+        let text = std::str::from_utf8(bytes).ok()?;
+        text.parse::<Expr>().ok()
+    })
+    .ok()
+    .flatten()
+    .map_or(null_mut(), |expr| Box::into_raw(Box::new(expr)))
+}
+
+/// Decodes `len` bytes encoded by [`Expr::to_bytes`] into an expression, see [`Expr::from_bytes`].
+/// Returns null if the buffer is malformed.
+///
+/// # Safety
+///
+/// `bytes` must be valid for reads of `len` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn symexpr_from_bytes(bytes: *const u8, len: usize) -> *mut Expr {
+    if bytes.is_null() {
+        return null_mut();
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(bytes, len) };
+    catch_unwind(|| Expr::from_bytes(bytes).ok())
+        .ok()
+        .flatten()
+        .map_or(null_mut(), |expr| Box::into_raw(Box::new(expr)))
+}
+
+/// Evaluates the expression with `n` named values, writing the result to `out`.
+/// Returns [`SYMEXPR_OK`] or a negative error code, in which case `out` is not written.
+///
+/// # Safety
+///
+/// `expr` must come from [`symexpr_parse`] or [`symexpr_from_bytes`] and not be freed, `names` and `values` must be valid
+/// for reads of `n` elements, every name must be a nul-terminated string and `out` must be
+/// valid for a write.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn symexpr_eval(
+    expr: *const Expr,
+    names: *const *const c_char,
+    values: *const u64,
+    n: usize,
+    out: *mut u64,
+) -> i32 {
+    if expr.is_null() || out.is_null() || (n != 0 && (names.is_null() || values.is_null())) {
+        return SYMEXPR_INVALID_ARGUMENT;
+    }
+    let expr = unsafe { &*expr };
+    let (names, values) = if n == 0 {
+        (&[][..], &[][..])
+    } else {
+        unsafe { (std::slice::from_raw_parts(names, n), std::slice::from_raw_parts(values, n)) }
+    };
+    let mut env = HashMap::with_capacity(n);
+    for (&name, &value) in names.iter().zip(values) {
+        if name.is_null() {
+            return SYMEXPR_INVALID_ARGUMENT;
+        }
+        let Ok(name) = unsafe { CStr::from_ptr(name) }.to_str() else {
+            return SYMEXPR_INVALID_ARGUMENT;
+        };
+        let Ok(value) = usize::try_from(value) else {
+            return SYMEXPR_OVERFLOW;
+        };
+        env.insert(name, value);
+    }
+    let result = catch_unwind(AssertUnwindSafe(|| expr.substitute_diagnosed(&env)));
+    match result {
+        Ok(Ok(value)) => match u64::try_from(value) {
+            Ok(value) => {
+                unsafe { out.write(value) };
+                SYMEXPR_OK
+            }
+            Err(_) => SYMEXPR_OVERFLOW,
+        },
+        Ok(Err(diagnostic)) => match diagnostic.kind {
+            EvalError::UnknownVariable(_) => SYMEXPR_UNKNOWN_VARIABLE,
+            EvalError::Overflow => SYMEXPR_OVERFLOW,
+            EvalError::Negative => SYMEXPR_NEGATIVE,
+            EvalError::NonDivisible => SYMEXPR_NON_DIVISIBLE,
+            EvalError::DivisionByZero => SYMEXPR_DIVISION_BY_ZERO,
+            EvalError::UnfilledHole => SYMEXPR_UNFILLED_HOLE,
+        },
+        Err(_) => SYMEXPR_PANIC,
+    }
+}
+
+/// Renders the expression as a nul-terminated string, to be released by
+/// [`symexpr_string_free`]. Returns null if `expr` is null.
+///
+/// # Safety
+///
+/// `expr` must come from [`symexpr_parse`] or [`symexpr_from_bytes`] and not be freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn symexpr_to_string(expr: *const Expr) -> *mut c_char {
+    if expr.is_null() {
+        return null_mut();
+    }
+    let expr = unsafe { &*expr };
+    // Rendered names come from parsed text, which has no nul characters
+    catch_unwind(AssertUnwindSafe(|| CString::new(expr.to_string()).ok()))
+        .ok()
+        .flatten()
+        .map_or(null_mut(), CString::into_raw)
+}
+
+/// Releases an expression. Null is ignored.
+///
+/// # Safety
+///
+/// `expr` must come from [`symexpr_parse`] or [`symexpr_from_bytes`] and not be freed already.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn symexpr_free(expr: *mut Expr) {
+    if !expr.is_null() {
+        let _ = catch_unwind(AssertUnwindSafe(|| drop(unsafe { Box::from_raw(expr) })));
+    }
+}
+
+/// Releases a string. Null is ignored.
+///
+/// # Safety
+///
+/// `s` must come from [`symexpr_to_string`] and not be freed already.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn symexpr_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(unsafe { CString::from_raw(s) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expr: *const Expr, env: &[(&CStr, u64)]) -> Result<u64, i32> {
+        let names = env.iter().map(|(name, _)| name.as_ptr()).collect::<Vec<_>>();
+        let values = env.iter().map(|(_, value)| *value).collect::<Vec<_>>();
+        let mut out = 0;
+        match unsafe { symexpr_eval(expr, names.as_ptr(), values.as_ptr(), env.len(), &mut out) } {
+            SYMEXPR_OK => Ok(out),
+            code => Err(code),
+        }
+    }
+
+    #[test]
+    fn test_ffi() {
+        let text = "(a + 1)*3/(b + 1)";
+        let expr = unsafe { symexpr_parse(text.as_ptr(), text.len()) };
+        assert!(!expr.is_null());
+
+        // Test rendering
+        let s = unsafe { symexpr_to_string(expr) };
+        println!("asserting {text} renders back");
+        assert_eq!(unsafe { CStr::from_ptr(s) }.to_str(), Ok(text));
+        unsafe { symexpr_string_free(s) };
+
+        // Test evaluation and error codes
+        assert_eq!(eval(expr, &[(c"a", 7), (c"b", 5)]), Ok(4));
+        assert_eq!(eval(expr, &[(c"a", 7)]), Err(SYMEXPR_UNKNOWN_VARIABLE));
+        assert_eq!(eval(expr, &[(c"a", 6), (c"b", 5)]), Err(SYMEXPR_NON_DIVISIBLE));
+        assert_eq!(eval(expr, &[(c"a", u64::MAX), (c"b", 5)]), Err(SYMEXPR_OVERFLOW));
+        let mut out = 0;
+        assert_eq!(unsafe { symexpr_eval(expr, null_mut(), null_mut(), 1, &mut out) }, SYMEXPR_INVALID_ARGUMENT);
+        assert_eq!(unsafe { symexpr_eval(expr, null_mut(), null_mut(), 0, null_mut()) }, SYMEXPR_INVALID_ARGUMENT);
+        assert_eq!(eval(null_mut(), &[]), Err(SYMEXPR_INVALID_ARGUMENT));
+        unsafe { symexpr_free(expr) };
+
+        let text = "a - b + 1/(c - c)";
+        let expr = unsafe { symexpr_parse(text.as_ptr(), text.len()) };
+        assert_eq!(eval(expr, &[(c"a", 1), (c"b", 2), (c"c", 0)]), Err(SYMEXPR_NEGATIVE));
+        assert_eq!(eval(expr, &[(c"a", 2), (c"b", 1), (c"c", 0)]), Err(SYMEXPR_DIVISION_BY_ZERO));
+        unsafe { symexpr_free(expr) };
+
+        // Test malformed input and null pointers
+        let text = "a + ";
+        assert!(unsafe { symexpr_parse(text.as_ptr(), text.len()) }.is_null());
+        let bytes = [b'a', 0xff];
+        assert!(unsafe { symexpr_parse(bytes.as_ptr(), bytes.len()) }.is_null());
+        assert!(unsafe { symexpr_parse(std::ptr::null(), 0) }.is_null());
+        let deep = "(".repeat(200_000) + "a" + &")".repeat(200_000);
+        assert!(unsafe { symexpr_parse(deep.as_ptr(), deep.len()) }.is_null());
+        assert!(unsafe { symexpr_to_string(std::ptr::null()) }.is_null());
+        unsafe { symexpr_free(null_mut()) };
+        unsafe { symexpr_string_free(null_mut()) };
+
+        // Test the binary encoding
+        let bytes = ("(a + 1)*3/(b + 1)".parse::<Expr>().unwrap()).to_bytes();
+        let expr = unsafe { symexpr_from_bytes(bytes.as_ptr(), bytes.len()) };
+        println!("asserting the decoded expression evaluates like the parsed one");
+        assert_eq!(eval(expr, &[(c"a", 7), (c"b", 5)]), Ok(4));
+        unsafe { symexpr_free(expr) };
+        assert!(unsafe { symexpr_from_bytes(bytes.as_ptr(), bytes.len() - 1) }.is_null());
+        assert!(unsafe { symexpr_from_bytes(std::ptr::null(), 0) }.is_null());
+    }
+}
@@ -1,14 +1,100 @@
 //! 简单的符号运算系统，用于将形状符号化。
 //!
 //! 考虑到形状运算的实际情况，只支持多项式的运算。
+//!
+//! 接受 `HashMap` 的接口只按名字查找，结果与其迭代顺序无关；返回的映射与名字列表均按字母序排列。
 
 use std::{
-    collections::{BTreeSet, HashMap, VecDeque},
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque, hash_map::Entry},
     fmt::Display,
     ops::{Add, Div, Mul, Neg, Sub},
 };
+use num_integer::Integer;
 use num_rational::Ratio;
 
+mod binary;
+mod bound;
+#[cfg(feature = "serde")]
+mod constraints;
+mod context;
+mod display;
+mod einsum;
+mod error;
+mod eval;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod ops;
+mod parse;
+mod scaled;
+mod shape;
+mod smtlib;
+mod staged;
+mod target;
+mod units;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use binary::DecodeError;
+pub use bound::{BindError, BoundExpr};
+#[cfg(feature = "serde")]
+pub use constraints::{Constraint, ConstraintSet, InferError, NamedExpr, VariableDecl, Violation, load_constraints};
+pub use context::Context;
+pub use einsum::{EinsumError, Verdict, einsum_shapes, einsum_shapes_with};
+pub use error::{Error, InvariantError, ValueSource};
+pub use eval::{CompiledF64, EnvProblem, EvalDiagnostic, EvalError, ExactnessError, MAX_TRACE_BYTES, MAX_TRACE_EVENTS, SubstitutionTrace, validate_env};
+pub use parse::ParseError;
+pub use scaled::{NonExact, ScaledExpr};
+pub use shape::{shape_factor_matching, shapes_factor_equivalent, total_size};
+pub use smtlib::equivalence_query;
+pub use staged::StagedExpr;
+pub use target::TargetOp;
+pub use units::{ByteSize, BytesPerElem, ElemCount};
+
+/// 用类似 Rust 的语法构造表达式。
+///
+/// Supports identifiers, which become variables, unsigned integer literals, which become
+/// constants, the binary operators `+ - * /` with Rust precedence, and parentheses.
+/// Other tokens, including unary minus and method calls, are not supported.
+///
+/// ```rust
+/// # use std::collections::HashMap;
+/// # use symbolic_expr::{Expr, expr};
+/// let expr = expr!((a + 1) * 3 / (b + 1));
+/// assert_eq!(expr.substitute(&HashMap::from([("a", 7), ("b", 5)])), 4);
+/// ```
+#[macro_export]
+macro_rules! expr {
+    (@ ($($out:tt)*)) => { $($out)* };
+    (@ ($($out:tt)*) + $($rest:tt)*) => { $crate::expr!(@ ($($out)* +) $($rest)*) };
+    (@ ($($out:tt)*) - $($rest:tt)*) => { $crate::expr!(@ ($($out)* -) $($rest)*) };
+    (@ ($($out:tt)*) * $($rest:tt)*) => { $crate::expr!(@ ($($out)* *) $($rest)*) };
+    (@ ($($out:tt)*) / $($rest:tt)*) => { $crate::expr!(@ ($($out)* /) $($rest)*) };
+    (@ ($($out:tt)*) ($($inner:tt)+) $($rest:tt)*) => {
+        $crate::expr!(@ ($($out)* ($crate::expr!($($inner)+))) $($rest)*)
+    };
+    (@ ($($out:tt)*) $name:ident $($rest:tt)*) => {
+        $crate::expr!(@ ($($out)* $crate::Expr::var(stringify!($name))) $($rest)*)
+    };
+    (@ ($($out:tt)*) $value:literal $($rest:tt)*) => {
+        $crate::expr!(@ ($($out)* $crate::Expr::Constant($value)) $($rest)*)
+    };
+    ($($tokens:tt)+) => { $crate::expr!(@ () $($tokens)+) };
+}
+
+/// Largest absolute exponent of a variable in a rational form. Expressions whose exponents
+/// could exceed it are not canonicalized, see [`Error::TooComplex`].
+pub const MAX_EXPONENT: isize = 1 << 20;
+
+/// Most terms a power or product of polynomials, or the common denominator of a sum, may expand
+/// to in a rational form. Expressions that could expand further are not canonicalized, see
+/// [`Error::TooComplex`].
+pub const MAX_EXPANDED_TERMS: usize = 1 << 12;
+
+/// Most steps of [`Expr::match_up_to_rename`], each pairing a variable or verifying a
+/// complete pairing.
+const MAX_RENAME_STEPS: usize = 2048;
+
 /// 形状的一个维度，或参与维度运算的值。
 ///
 /// ```rust
@@ -20,23 +106,44 @@ use num_rational::Ratio;
 /// let expr = (a + _1 - 2) * 3 / (b + 1);
 /// assert_eq!(expr.substitute(&HashMap::from([("a", 8), ("b", 6)])), 3);
 /// ```
+///
+/// An empty [`Expr::Sum`] means `0` and an empty [`Expr::Product`] means `1` everywhere,
+/// including display, so serialized text never holds an empty container. A sum is evaluated
+/// left to right over natural numbers, so a sum whose only operand is negative, like `-a`,
+/// evaluates to `0` for `a = 0` and is negative otherwise.
+///
+/// An [`Expr::Hole`] is a place not filled yet. Expressions built from it keep it, so they fail
+/// to evaluate or canonicalize with an unfilled hole error until [`Expr::fill_holes`] is called,
+/// and are never [equivalent](Expr::equivalent) to anything.
 #[derive(Clone, Debug)]
 pub enum Expr {
     /// 常量
     Constant(usize),
     /// 变量
     Variable(String),
-    /// 和式
+    /// 和式，空的和式为 0
     Sum(VecDeque<Operand>),
-    /// 积式
+    /// 积式，空的积式为 1
     Product(VecDeque<Operand>),
     /// 已转换为有理式的表达式
     Rational(RationalExpression),
+    /// 整数次幂
+    Pow(Box<Expr>, u32),
+    /// 取模
+    Mod(Box<Expr>, Box<Expr>),
+    /// 绝对值
+    Abs(Box<Expr>),
+    /// 最大值
+    Max(Box<Expr>, Box<Expr>),
+    /// 尚未填充的空位
+    Hole,
 }
 
+/// The default expression is a [`Expr::Hole`], so a dimension taken out with
+/// [`std::mem::take`] and never put back fails loudly instead of reading as `0`.
 impl Default for Expr {
     fn default() -> Self {
-        Self::Constant(0)
+        Self::Hole
     }
 }
 
@@ -46,10 +153,68 @@ impl Expr {
         Self::Variable(symbol.to_string())
     }
 
+    /// 整数次幂。
+    ///
+    /// Canonicalization expands the power by repeated squaring, see [`RationalExpression::pow`].
+    pub fn pow(self, exponent: u32) -> Self {
+        Self::Pow(Box::new(self), exponent)
+    }
+
+    /// 取模。
+    ///
+    /// The node has no rational form, so it stays symbolic until both sides are known,
+    /// except for `x mod x` which is folded to `0`.
+    pub fn modulo(self, modulus: Self) -> Self {
+        Self::Mod(Box::new(self), Box::new(modulus))
+    }
+
+    /// 绝对值。
+    ///
+    /// The operand is evaluated with signed intermediate values, so `abs(3 - 5)` is `2`.
+    /// Like [`Expr::modulo`], the node has no rational form and stays symbolic until the
+    /// operand is known.
+    pub fn abs(self) -> Self {
+        Self::Abs(Box::new(self))
+    }
+
+    /// 最大值。
+    ///
+    /// Like [`Expr::modulo`], the node has no rational form and stays symbolic until both
+    /// sides are known, except for equivalent sides which are folded to one of them.
+    pub fn max(self, other: Self) -> Self {
+        Self::Max(Box::new(self), Box::new(other))
+    }
+
+    /// 倒数。
+    ///
+    /// A rational node is inverted in place, anything else becomes `1/self`.
+    /// Returns None if the expression is provably zero.
+    ///
+    /// ```rust
+    /// # use symbolic_expr::Expr;
+    /// let (a, b) = (Expr::var("a"), Expr::var("b"));
+    /// assert_eq!((a.clone() / b.clone()).recip().unwrap().equivalent(&(b / a.clone())), Some(true));
+    /// assert!((a.clone() - a).recip().is_none());
+    /// ```
+    pub fn recip(self) -> Option<Self> {
+        match self {
+            Self::Rational(rational) => {
+                let mut rational = rational.normalize();
+                if rational.numer.is_empty() {
+                    return None;
+                }
+                rational.invert();
+                Some(Self::Rational(rational))
+            }
+            expr if expr.equivalent(&Self::Constant(0)) == Some(true) => None,
+            expr => Some(Self::Constant(1) / expr),
+        }
+    }
+
     /// 维度作为正操作数。
     pub fn positive(self) -> Operand {
         Operand {
-            ty: Type::Positive,
+            ty: Sign::Positive,
             expr: self,
         }
     }
@@ -57,11 +222,199 @@ impl Expr {
     /// 维度作为负操作数。
     pub fn negative(self) -> Operand {
         Operand {
-            ty: Type::Negative,
+            ty: Sign::Negative,
             expr: self,
         }
     }
 
+    /// 和式。
+    ///
+    /// An empty sum is normalized to `Constant(0)`.
+    pub fn sum(operands: impl IntoIterator<Item = Operand>) -> Self {
+        let operands = operands.into_iter().collect::<VecDeque<_>>();
+        if operands.is_empty() { Self::Constant(0) } else { Self::Sum(operands) }
+    }
+
+    /// 积式。
+    ///
+    /// An empty product is normalized to `Constant(1)`.
+    pub fn product(operands: impl IntoIterator<Item = Operand>) -> Self {
+        let operands = operands.into_iter().collect::<VecDeque<_>>();
+        if operands.is_empty() { Self::Constant(1) } else { Self::Product(operands) }
+    }
+
+    /// The top-level terms of a sum with their signs, or None if the expression is not an
+    /// [`Expr::Sum`].
+    ///
+    /// ```rust
+    /// # use symbolic_expr::{Expr, Sign};
+    /// let expr = Expr::var("a") - Expr::var("b") * 2 + 3;
+    /// let terms = expr.as_sum_terms().unwrap();
+    /// let rebuilt = Expr::sum(terms.into_iter().map(|(sign, term)| match sign {
+    ///     Sign::Positive => term.clone().positive(),
+    ///     Sign::Negative => term.clone().negative(),
+    /// }));
+    /// assert_eq!(rebuilt.equivalent(&expr), Some(true));
+    /// ```
+    pub fn as_sum_terms(&self) -> Option<Vec<(Sign, &Expr)>> {
+        match self {
+            Self::Sum(operands) => Some(operands.iter().map(|op| (op.ty, &op.expr)).collect()),
+            _ => None,
+        }
+    }
+
+    /// The top-level terms of a sum as `(is_positive, term)` pairs in order, or None if the
+    /// expression is not an [`Expr::Sum`]. Same as [`Expr::as_sum_terms`] with owned terms.
+    ///
+    /// ```rust
+    /// # use symbolic_expr::Expr;
+    /// let signs = (Expr::var("a") - Expr::var("b")).sum_signs().unwrap();
+    /// assert_eq!(signs.iter().map(|(positive, _)| *positive).collect::<Vec<_>>(), [true, false]);
+    /// ```
+    pub fn sum_signs(&self) -> Option<Vec<(bool, Expr)>> {
+        let terms = self.as_sum_terms()?;
+        Some(terms.into_iter().map(|(sign, term)| (sign == Sign::Positive, term.clone())).collect())
+    }
+
+    /// The top-level factors of a product, [`Sign::Positive`] to multiply and
+    /// [`Sign::Negative`] to divide, or None if the expression is not an [`Expr::Product`].
+    ///
+    /// ```rust
+    /// # use symbolic_expr::{Expr, Sign};
+    /// let expr = Expr::var("a") * Expr::var("b") / 2;
+    /// let factors = expr.as_product_factors().unwrap();
+    /// let rebuilt = Expr::product(factors.into_iter().map(|(sign, factor)| match sign {
+    ///     Sign::Positive => factor.clone().positive(),
+    ///     Sign::Negative => factor.clone().negative(),
+    /// }));
+    /// assert_eq!(rebuilt.equivalent(&expr), Some(true));
+    /// ```
+    pub fn as_product_factors(&self) -> Option<Vec<(Sign, &Expr)>> {
+        match self {
+            Self::Product(operands) => Some(operands.iter().map(|op| (op.ty, &op.expr)).collect()),
+            _ => None,
+        }
+    }
+
+    /// The top-level terms of a sum, or the expression itself as a single positive term.
+    pub fn into_sum_terms(self) -> Vec<(Sign, Expr)> {
+        match self {
+            Self::Sum(operands) => operands.into_iter().map(|op| (op.ty, op.expr)).collect(),
+            expr => vec![(Sign::Positive, expr)],
+        }
+    }
+
+    /// 线性式 `sum(coeffs[v] * v) + constant`。
+    ///
+    /// Variables are added in alphabetical order, zero coefficients are skipped and negative
+    /// ones are subtracted after the positive terms, so `{a: 2, b: -1}` with `3` gives
+    /// `2*a + 3 - b`.
+    pub fn from_linear(coeffs: &HashMap<&str, isize>, constant: isize) -> Self {
+        let mut coeffs = coeffs.iter().map(|(&var, &coef)| (var, coef)).filter(|&(_, coef)| coef != 0).collect::<Vec<_>>();
+        coeffs.sort_unstable();
+        let term = |var: &str, coef: isize| match coef.unsigned_abs() {
+            1 => Self::var(var),
+            coef => Self::from(coef) * Self::var(var),
+        };
+        let positive = coeffs.iter().filter(|&&(_, coef)| coef > 0).map(|&(var, coef)| term(var, coef).positive());
+        let negative = coeffs.iter().filter(|&&(_, coef)| coef < 0).map(|&(var, coef)| term(var, coef).negative());
+        let constant = (constant != 0).then(|| {
+            let abs = Self::from(constant.unsigned_abs());
+            if constant > 0 { abs.positive() } else { abs.negative() }
+        });
+        let mut operands = positive.collect::<Vec<_>>();
+        operands.extend(constant.clone().filter(|c| c.ty == Sign::Positive));
+        operands.extend(negative);
+        operands.extend(constant.filter(|c| c.ty == Sign::Negative));
+        match operands.len() {
+            1 if operands[0].ty == Sign::Positive => operands.pop().unwrap().expr,
+            _ => Self::sum(operands),
+        }
+    }
+
+    /// An empty sum, which is `0`, with room for `capacity` operands, to be filled in place with
+    /// [`Expr::push_add`] and [`Expr::push_sub`].
+    pub fn with_capacity_sum(capacity: usize) -> Self {
+        Self::Sum(VecDeque::with_capacity(capacity))
+    }
+
+    /// Adds `rhs` in place, same as `*self = self + rhs` but without re-wrapping a growing sum.
+    pub fn push_add(&mut self, rhs: Self) {
+        self.push_operand(rhs, Sign::Positive, true)
+    }
+
+    /// Subtracts `rhs` in place, same as `*self = self - rhs` but without re-wrapping a growing sum.
+    pub fn push_sub(&mut self, rhs: Self) {
+        self.push_operand(rhs, Sign::Negative, true)
+    }
+
+    /// Multiplies by `rhs` in place, same as `*self = self * rhs` but without re-wrapping a growing product.
+    pub fn push_mul(&mut self, rhs: Self) {
+        self.push_operand(rhs, Sign::Positive, false)
+    }
+
+    /// Divides by `rhs` in place, same as `*self = self / rhs` but without re-wrapping a growing product.
+    pub fn push_div(&mut self, rhs: Self) {
+        self.push_operand(rhs, Sign::Negative, false)
+    }
+
+    /// Appends `rhs` to the sum (or product) operands, converting the receiver once if needed.
+    /// The resulting structure is the same as the one built by the operators.
+    fn push_operand(&mut self, rhs: Self, ty: Sign, sum: bool) {
+        if !matches!((&*self, sum), (Self::Sum(_), true) | (Self::Product(_), false)) {
+            let lhs = std::mem::take(self).positive();
+            *self = if sum { Self::Sum([lhs].into()) } else { Self::Product([lhs].into()) };
+        }
+        let (Self::Sum(operands) | Self::Product(operands)) = self else {
+            unreachable!()
+        };
+        match rhs {
+            Self::Sum(rhs) if sum => operands.extend(rhs.into_iter().map(|op| if ty == Sign::Negative { -op } else { op })),
+            Self::Product(rhs) if !sum => operands.extend(rhs.into_iter().map(|op| if ty == Sign::Negative { -op } else { op })),
+            rhs => operands.push_back(Operand { ty, expr: rhs }),
+        }
+    }
+
+    /// Builds the flat index `sum(index * stride)` of the `(index, stride)` pairs.
+    ///
+    /// Returns [`Error::NotAffine`] if the index is not affine in the index variables, see
+    /// [`Expr::is_affine_in`], which is only checked when a stride has an index variable.
+    ///
+    /// ```rust
+    /// # use std::collections::HashMap;
+    /// # use symbolic_expr::Expr;
+    /// let width = Expr::var("w");
+    /// let index = Expr::linearize(&[("i", width), ("j", Expr::from(1))]).unwrap();
+    /// assert_eq!(index.substitute(&HashMap::from([("i", 2), ("j", 3), ("w", 10)])), 23);
+    /// assert!(Expr::linearize(&[("i", Expr::var("j")), ("j", Expr::from(1))]).is_err());
+    /// ```
+    pub fn linearize(indices: &[(&str, Self)]) -> Result<Self, Error> {
+        if indices.is_empty() {
+            return Ok(Self::Constant(0));
+        }
+        let mut ans = Self::with_capacity_sum(indices.len());
+        for (index, stride) in indices {
+            ans.push_add(Self::var(index) * stride.clone());
+        }
+        let vars = indices.iter().map(|&(index, _)| index).collect::<Vec<_>>();
+        let strides_free = indices.iter().all(|(_, stride)| {
+            let variables = stride.variables();
+            !vars.iter().any(|var| variables.contains(var))
+        });
+        if !strides_free && ans.is_affine_in(&vars) != Some(true) {
+            return Err(Error::NotAffine);
+        }
+        Ok(ans)
+    }
+
+    /// Evaluates the flat index of [`Expr::linearize`] with checked arithmetic.
+    /// Returns None if a value is missing or the evaluation fails.
+    pub fn linear_index(indices: &[(&str, Self)], value: &HashMap<&str, usize>) -> Option<usize> {
+        indices.iter().try_fold(0usize, |acc, (index, stride)| {
+            acc.checked_add(value.get(index)?.checked_mul(stride.checked_substitute(value)?)?)
+        })
+    }
+
     /// 统计表达式中出现的变量名。
     pub fn variables(&self) -> BTreeSet<&str> {
         let mut ans = BTreeSet::new();
@@ -69,23 +422,72 @@ impl Expr {
         ans
     }
 
+    /// 统计表达式中出现的变量名，不借用表达式。
+    pub fn variables_owned(&self) -> BTreeSet<String> {
+        let mut ans = BTreeSet::new();
+        self.visit_variables(&mut |name| {
+            if !ans.contains(name) {
+                ans.insert(name.to_string());
+            }
+        });
+        ans
+    }
+
+    /// 按字典序排列、去重的变量名。
+    pub fn variables_vec(&self) -> Vec<String> {
+        self.variables_owned().into_iter().collect()
+    }
+
+    /// Whether the value of the expression does not depend on any of `vars`, such as for
+    /// hoisting a shape computation out of a loop. An expression without the variables is free
+    /// of them; otherwise it is free of them if they cancel in the normal form, so `(i - i) + n`
+    /// is free of `i`.
+    ///
+    /// Like [`equivalent`](Self::equivalent), canonicalization cancels common factors, so `i*n/i`
+    /// is free of `i` although it is not defined for `i = 0`.
+    /// Returns None if the variables appear and the expression cannot be canonicalized.
+    ///
+    /// ```rust
+    /// # use symbolic_expr::Expr;
+    /// let [i, n] = ["i", "n"].map(Expr::var);
+    /// assert_eq!((i.clone() - i.clone() + n.clone()).free_of(&["i"]), Some(true));
+    /// assert_eq!((i.clone() * n.clone()).free_of(&["i", "j"]), Some(false));
+    /// ```
+    pub fn free_of(&self, vars: &[&str]) -> Option<bool> {
+        let variables = self.variables();
+        if !vars.iter().any(|var| variables.contains(var)) {
+            return Some(true);
+        }
+        let canon = self.canonicalize().ok()?;
+        let variables = canon.variables();
+        Some(!vars.iter().any(|var| variables.contains(var)))
+    }
+
     /// 遍历表达式，递归地将变量名添加到集合。
     pub fn append_variables<'s>(&'s self, set: &mut BTreeSet<&'s str>) {
+        self.visit_variables(&mut |name| {
+            set.insert(name);
+        })
+    }
+
+    /// Calls `f` on every occurrence of a variable, the single traversal behind all variable queries.
+    fn visit_variables<'s>(&'s self, f: &mut impl FnMut(&'s str)) {
         match self {
-            Self::Constant(_) => {}
-            Self::Variable(name) => {
-                set.insert(name);
-            }
+            Self::Constant(_) | Self::Hole => {}
+            Self::Variable(name) => f(name),
             Self::Sum(operands) | Self::Product(operands) => {
-                operands.iter().for_each(|op| op.expr.append_variables(set))
+                operands.iter().for_each(|op| op.expr.visit_variables(f))
             }
-            Self::Rational(rational) => {
-                rational.numer.iter().for_each(|term| term.factors.iter().for_each(|factor| {
-                    set.insert(&factor.base);
-                }));
-                rational.denom.iter().for_each(|term| term.factors.iter().for_each(|factor| {
-                    set.insert(&factor.base);
-                }));
+            Self::Rational(rational) => rational
+                .numer
+                .iter()
+                .chain(&rational.denom)
+                .flat_map(|term| &term.factors)
+                .for_each(|factor| f(&factor.base)),
+            Self::Pow(base, _) | Self::Abs(base) => base.visit_variables(f),
+            Self::Mod(lhs, rhs) | Self::Max(lhs, rhs) => {
+                lhs.visit_variables(f);
+                rhs.visit_variables(f);
             }
         }
     }
@@ -99,15 +501,17 @@ impl Expr {
             Self::Sum(operands) => operands.iter().fold(0, |acc, Operand { ty, expr }| {
                 let value = expr.substitute(value);
                 match ty {
-                    Type::Positive => acc + value,
-                    Type::Negative => acc.checked_sub(value).unwrap(),
+                    Sign::Positive => acc + value,
+                    Sign::Negative => acc
+                        .checked_sub(value)
+                        .unwrap_or_else(|| panic!("\"{self}\" is negative")),
                 }
             }),
             Self::Product(operands) => operands.iter().fold(1, |acc, Operand { ty, expr }| {
                 let value = expr.substitute(value);
                 match ty {
-                    Type::Positive => acc * value,
-                    Type::Negative => {
+                    Sign::Positive => acc * value,
+                    Sign::Negative => {
                         assert_eq!(acc % value, 0);
                         acc / value
                     }
@@ -121,48 +525,693 @@ impl Expr {
                 assert_eq!(result.denom(), &1, "rational expression must evaluate to a whole number");
                 result.numer().unsigned_abs()
             }
+            Self::Pow(base, exponent) => base.substitute(value).pow(*exponent),
+            Self::Mod(lhs, modulus) => {
+                let lhs = lhs.substitute(value);
+                let modulus = modulus.substitute(value);
+                assert_ne!(modulus, 0, "modulo by zero");
+                lhs % modulus
+            }
+            Self::Abs(operand) => operand
+                .substitute_signed(value)
+                .unwrap_or_else(|e| panic!("failed to evaluate \"{operand}\": {e}"))
+                .unsigned_abs(),
+            Self::Max(lhs, rhs) => lhs.substitute(value).max(rhs.substitute(value)),
+            Self::Hole => panic!("expression has an unfilled hole"),
+        }
+    }
+
+
+
+    /// Replaces every occurrence of the variable `name` with `with`.
+    ///
+    /// Rational nodes containing the variable are converted back to trees first.
+    pub fn replace_variable(&self, name: &str, with: &Self) -> Self {
+        self.replace_variables(&|var| (var == name).then(|| with.clone()))
+    }
+
+    /// Replaces every variable for which `with` returns an expression, in a single pass.
+    fn replace_variables(&self, with: &impl Fn(&str) -> Option<Self>) -> Self {
+        let replace_operands = |operands: &VecDeque<Operand>| {
+            operands
+                .iter()
+                .map(|Operand { ty, expr }| Operand { ty: *ty, expr: expr.replace_variables(with) })
+                .collect::<VecDeque<_>>()
+        };
+        match self {
+            Self::Variable(var) => with(var).unwrap_or_else(|| self.clone()),
+            Self::Constant(_) | Self::Hole => self.clone(),
+            Self::Sum(operands) => Self::sum(replace_operands(operands)),
+            Self::Product(operands) => Self::product(replace_operands(operands)),
+            Self::Rational(rational) => {
+                let contains = rational.numer.iter().chain(&rational.denom).any(|term| term.factors.iter().any(|factor| with(&factor.base).is_some()));
+                if contains { rational.to_tree().replace_variables(with) } else { self.clone() }
+            }
+            Self::Pow(base, exponent) => base.replace_variables(with).pow(*exponent),
+            Self::Mod(lhs, modulus) => lhs.replace_variables(with).modulo(modulus.replace_variables(with)),
+            Self::Abs(operand) => operand.replace_variables(with).abs(),
+            Self::Max(lhs, rhs) => lhs.replace_variables(with).max(rhs.replace_variables(with)),
+        }
+    }
+
+    /// Replaces every constant leaf `c` with `f(c)`, such as scaling the constants written in
+    /// elements into bytes. Variables, exponents and the structure of the tree are kept.
+    ///
+    /// Rational nodes are converted back to trees first, so their coefficients become leaves;
+    /// see [`map_coefficients`](Self::map_coefficients) to map the coefficients of the normal form.
+    ///
+    /// ```rust
+    /// # use symbolic_expr::Expr;
+    /// let expr = (Expr::var("n") + 4) / 2;
+    /// assert_eq!(expr.map_constants(|c| c * 2).to_string(), "(n + 8)/4");
+    /// ```
+    pub fn map_constants(&self, f: impl Fn(usize) -> usize) -> Self {
+        self.map_constants_with(&f)
+    }
+
+    fn map_constants_with(&self, f: &impl Fn(usize) -> usize) -> Self {
+        let map_operands = |operands: &VecDeque<Operand>| {
+            operands
+                .iter()
+                .map(|Operand { ty, expr }| Operand { ty: *ty, expr: expr.map_constants_with(f) })
+                .collect::<VecDeque<_>>()
+        };
+        match self {
+            &Self::Constant(value) => Self::Constant(f(value)),
+            Self::Variable(_) | Self::Hole => self.clone(),
+            Self::Sum(operands) => Self::Sum(map_operands(operands)),
+            Self::Product(operands) => Self::Product(map_operands(operands)),
+            Self::Rational(rational) => rational.to_tree().map_constants_with(f),
+            Self::Pow(base, exponent) => Self::Pow(Box::new(base.map_constants_with(f)), *exponent),
+            Self::Mod(lhs, modulus) => Self::Mod(Box::new(lhs.map_constants_with(f)), Box::new(modulus.map_constants_with(f))),
+            Self::Abs(operand) => Self::Abs(Box::new(operand.map_constants_with(f))),
+            Self::Max(lhs, rhs) => Self::Max(Box::new(lhs.map_constants_with(f)), Box::new(rhs.map_constants_with(f))),
+        }
+    }
+
+    /// Replaces the coefficient `c` of every term of the normal form, in the numerator and the
+    /// denominator, with `f(c)`. Terms mapped to zero are dropped.
+    /// Returns None if the expression has no rational form or the denominator becomes zero.
+    ///
+    /// The coefficients are those of the normal form, whose leading denominator coefficient is
+    /// `1`, so `(n + 4)/2` has the coefficients `2` and `1/2` over `1`.
+    ///
+    /// ```rust
+    /// # use num_rational::Ratio;
+    /// # use symbolic_expr::Expr;
+    /// let expr = (Expr::var("n") + 4) / 2;
+    /// assert_eq!(expr.map_coefficients(|c| c.ceil()).unwrap().to_string(), "2 + n");
+    /// assert_eq!(expr.map_coefficients(|c| c * c).unwrap().to_string(), "4 + n/4");
+    /// assert_eq!(expr.map_coefficients(|c| c - Ratio::new(1, 2)).unwrap().to_string(), "3");
+    /// ```
+    pub fn map_coefficients(&self, f: impl Fn(Ratio<isize>) -> Ratio<isize>) -> Option<Self> {
+        let rational = self.to_rational()?.normalize();
+        let map = |terms: Vec<CanonicalTerm>| {
+            terms
+                .into_iter()
+                .map(|term| CanonicalTerm { coef: f(term.coef), factors: term.factors })
+                .filter(|term| term.coef != Ratio::from_integer(0))
+                .collect::<Vec<_>>()
+        };
+        let (numer, denom) = (map(rational.numer), map(rational.denom));
+        if denom.is_empty() {
+            return None;
+        }
+        Some(Self::from(RationalExpression::new(numer, denom).normalize()))
+    }
+
+    /// Checks the invariants of the rational nodes of the expression: the factors of every term
+    /// are sorted by variable name without repeats, coefficients and exponents are non-zero,
+    /// exponents are within [`MAX_EXPONENT`], and denominators are not zero.
+    ///
+    /// Every operation of the crate keeps them, so this is for expressions decoded from outside
+    /// or built from raw parts.
+    pub fn validate(&self) -> Result<(), InvariantError> {
+        match self {
+            Self::Constant(_) | Self::Variable(_) | Self::Hole => Ok(()),
+            Self::Sum(operands) | Self::Product(operands) => operands.iter().try_for_each(|op| op.expr.validate()),
+            Self::Rational(rational) => rational.validate(),
+            Self::Pow(operand, _) | Self::Abs(operand) => operand.validate(),
+            Self::Mod(lhs, rhs) | Self::Max(lhs, rhs) => lhs.validate().and_then(|()| rhs.validate()),
+        }
+    }
+
+    /// Whether the expression has an [`Expr::Hole`] left to fill.
+    pub fn has_holes(&self) -> bool {
+        match self {
+            Self::Hole => true,
+            Self::Constant(_) | Self::Variable(_) | Self::Rational(_) => false,
+            Self::Sum(operands) | Self::Product(operands) => operands.iter().any(|op| op.expr.has_holes()),
+            Self::Pow(operand, _) | Self::Abs(operand) => operand.has_holes(),
+            Self::Mod(lhs, rhs) | Self::Max(lhs, rhs) => lhs.has_holes() || rhs.has_holes(),
+        }
+    }
+
+    /// Replaces every [`Expr::Hole`] with `with`.
+    ///
+    /// ```rust
+    /// # use std::collections::HashMap;
+    /// # use symbolic_expr::Expr;
+    /// let mut dims = vec![Expr::var("n") * 4];
+    /// let taken = std::mem::take(&mut dims[0]);
+    /// let total = dims[0].clone() + 1;
+    /// assert!(total.has_holes());
+    /// assert_eq!(total.fill_holes(taken).substitute(&HashMap::from([("n", 2)])), 9);
+    /// ```
+    pub fn fill_holes(&self, with: Self) -> Self {
+        self.fill_holes_ref(&with)
+    }
+
+    fn fill_holes_ref(&self, with: &Self) -> Self {
+        let fill_operands = |operands: &VecDeque<Operand>| {
+            operands
+                .iter()
+                .map(|Operand { ty, expr }| Operand { ty: *ty, expr: expr.fill_holes_ref(with) })
+                .collect::<VecDeque<_>>()
+        };
+        match self {
+            Self::Hole => with.clone(),
+            Self::Constant(_) | Self::Variable(_) | Self::Rational(_) => self.clone(),
+            Self::Sum(operands) => Self::Sum(fill_operands(operands)),
+            Self::Product(operands) => Self::Product(fill_operands(operands)),
+            Self::Pow(base, exponent) => base.fill_holes_ref(with).pow(*exponent),
+            Self::Mod(lhs, modulus) => lhs.fill_holes_ref(with).modulo(modulus.fill_holes_ref(with)),
+            Self::Abs(operand) => operand.fill_holes_ref(with).abs(),
+            Self::Max(lhs, rhs) => lhs.fill_holes_ref(with).max(rhs.fill_holes_ref(with)),
+        }
+    }
+
+    /// Passes every variable name through `f` and canonicalizes the result, so variables mapped
+    /// to the same name are merged: lowercasing `Batch + batch` gives `2*batch`.
+    ///
+    /// Expressions without a rational form are renamed without canonicalization.
+    pub fn normalize_names(&self, f: impl Fn(&str) -> String) -> Self {
+        let renamed = self.rename_variables(&f);
+        match renamed.canonicalize() {
+            Ok(canon) => canon.into(),
+            Err(_) => renamed,
         }
     }
 
+    /// Finds a one-to-one renaming of the variables of `self` that makes it equivalent to
+    /// `other`, so `(n0 + 2)*c0` matches `(n1 + 2)*c1` with `{n0: n1, c0: c1}`.
+    ///
+    /// Candidates are paired by how they appear in the normal forms, their exponents and the
+    /// coefficients of their terms, and every complete pairing is verified by renaming. When
+    /// several renamings work, as for symmetric expressions, any one of them is returned.
+    /// Returns None if there is no renaming, either side has no rational form, or the search
+    /// gives up after a fixed number of steps, which bounds its time however many variables
+    /// share a signature.
+    pub fn match_up_to_rename(&self, other: &Self) -> Option<BTreeMap<String, String>> {
+        let mut steps = MAX_RENAME_STEPS;
+        self.match_up_to_rename_within(other, &mut steps)
+    }
+
+    /// [`Expr::match_up_to_rename`] taking its steps from `steps`, which is left with the
+    /// steps not taken.
+    fn match_up_to_rename_within(&self, other: &Self, steps: &mut usize) -> Option<BTreeMap<String, String>> {
+        /// Returns None once the steps are used up, unwinding the whole search.
+        fn search(
+            candidates: &[(String, Vec<String>)],
+            map: &mut BTreeMap<String, String>,
+            steps: &mut usize,
+            verify: &mut impl FnMut(&BTreeMap<String, String>) -> bool,
+        ) -> Option<bool> {
+            if *steps == 0 {
+                return None;
+            }
+            *steps -= 1;
+            let Some(((var, targets), rest)) = candidates.split_first() else {
+                return Some(verify(map));
+            };
+            for target in targets {
+                if map.values().any(|used| used == target) {
+                    continue;
+                }
+                map.insert(var.clone(), target.clone());
+                if search(rest, map, steps, verify)? {
+                    return Some(true);
+                }
+                map.remove(var);
+            }
+            Some(false)
+        }
+
+        let lhs = self.canonicalize().ok()?;
+        let rhs = other.canonicalize().ok()?;
+        let lhs_signatures = lhs.rational().variable_signatures();
+        let rhs_signatures = rhs.rational().variable_signatures();
+        if lhs_signatures.len() != rhs_signatures.len() {
+            return None;
+        }
+        let candidates = lhs_signatures
+            .iter()
+            .map(|(var, signature)| {
+                let targets = rhs_signatures.iter().filter(|&(_, s)| s == signature).map(|(t, _)| t.clone());
+                (var.clone(), targets.collect::<Vec<_>>())
+            })
+            .collect::<Vec<_>>();
+
+        let mut verify = |map: &BTreeMap<String, String>| {
+            lhs.rename_variables(&|name| map[name].clone()).canonicalize().is_ok_and(|renamed| renamed == rhs)
+        };
+        let mut map = BTreeMap::new();
+        search(&candidates, &mut map, steps, &mut verify)?.then_some(map)
+    }
 
+    /// Renames every variable through `f` in the tree, converting rational nodes to trees.
+    fn rename_variables(&self, f: &impl Fn(&str) -> String) -> Self {
+        let rename_operands = |operands: &VecDeque<Operand>| {
+            operands
+                .iter()
+                .map(|Operand { ty, expr }| Operand { ty: *ty, expr: expr.rename_variables(f) })
+                .collect::<VecDeque<_>>()
+        };
+        match self {
+            Self::Constant(_) | Self::Hole => self.clone(),
+            Self::Variable(name) => Self::Variable(f(name)),
+            Self::Sum(operands) => Self::sum(rename_operands(operands)),
+            Self::Product(operands) => Self::product(rename_operands(operands)),
+            Self::Rational(rational) => rational.to_tree().rename_variables(f),
+            Self::Pow(base, exponent) => base.rename_variables(f).pow(*exponent),
+            Self::Mod(lhs, modulus) => lhs.rename_variables(f).modulo(modulus.rename_variables(f)),
+            Self::Abs(operand) => operand.rename_variables(f).abs(),
+            Self::Max(lhs, rhs) => lhs.rename_variables(f).max(rhs.rename_variables(f)),
+        }
+    }
 
     /// Checks if two Expr expressions are permanently mathematically equivalent.
+    ///
+    /// Like the rest of the canonicalization, common factors of numerator and denominator
+    /// are cancelled, so `(a² - b²)/(a - b)` is equivalent to `a + b`. Expressions dividing
+    /// by an identically zero denominator are undefined, and their equivalence is unknown,
+    /// also to themselves.
+    ///
     /// Returns:
     /// - `Some(true)` if the expressions are equivalent.
     /// - `Some(false)` if the expressions are not equivalent.
     /// - `None` if the permanent equivalence of the expressions is unknown.
+    ///
+    /// Expressions with `mod`, `abs` or `max` nodes have no rational form, and are known to be
+    /// equivalent only if they are the same tree up to the order of operands, see
+    /// [`sort_operands`](Self::sort_operands), so `max(a, b) + 1` is equivalent to `1 + max(b, a)`.
     pub fn equivalent(&self, other: &Self) -> Option<bool> {
+        if self.has_holes() || other.has_holes() {
+            return None;
+        }
+        // Cheap checks first, canonicalization is expensive for large expressions
+        match (self, other) {
+            (Self::Constant(a), Self::Constant(b)) => return Some(a == b),
+            _ if self.structurally_eq(other) => return (!self.has_zero_divisor()).then_some(true),
+            // |x| == |y| whenever x == y or x == -y, and is unknown otherwise
+            (Self::Abs(a), Self::Abs(b)) => {
+                let same = a.equivalent(b) == Some(true);
+                let opposite = (&**a + &**b).equivalent(&Self::Constant(0)) == Some(true);
+                return (same || opposite).then_some(true);
+            }
+            // max(x, y) == max(y, x), and is unknown otherwise
+            (Self::Max(a, b), Self::Max(c, d)) => {
+                let same = a.equivalent(c) == Some(true) && b.equivalent(d) == Some(true);
+                let swapped = a.equivalent(d) == Some(true) && b.equivalent(c) == Some(true);
+                return (same || swapped).then_some(true);
+            }
+            _ => {}
+        }
+        let (Some(lhs), Some(rhs)) = (self.to_rational(), other.to_rational()) else {
+            // Without a rational form, only the same tree up to the order of operands is known,
+            // unless dividing by zero leaves it undefined
+            if self.has_zero_divisor() || other.has_zero_divisor() {
+                return None;
+            }
+            return self.sort_operands().structurally_eq(&other.sort_operands()).then_some(true);
+        };
+        lhs.equivalent(&rhs)
+    }
+
+    /// Returns the constant `k` with `self - other == k`, or None if the difference has
+    /// variables or is unknown, with the same caveats as [`equivalent`](Self::equivalent).
+    ///
+    /// ```rust
+    /// # use num_rational::Ratio;
+    /// # use symbolic_expr::Expr;
+    /// let a = Expr::var("a");
+    /// assert_eq!((a.clone() + 5).constant_difference(&(a.clone() + 2)), Some(Ratio::from_integer(3)));
+    /// assert_eq!((a.clone() + Expr::var("b")).constant_difference(&a), None);
+    /// ```
+    pub fn constant_difference(&self, other: &Self) -> Option<Ratio<isize>> {
+        if self.structurally_eq(other) && !self.has_zero_divisor() {
+            return Some(Ratio::from_integer(0));
+        }
+        self.to_rational()?.constant_difference(&other.to_rational()?)
+    }
+
+    /// The canonical `self + other` as a plain tree, see [`sub_simplified`](Self::sub_simplified).
+    pub fn add_simplified(&self, other: &Self) -> Self {
+        self.combine_simplified(other, |lhs, rhs| lhs.add(&rhs), |lhs, rhs| lhs + rhs)
+    }
+
+    /// The canonical `self - other` as a plain tree of sums and products rather than a
+    /// [`Rational`](Self::Rational) node, for displaying derived quantities such as slack.
+    /// Falls back to the unsimplified difference if either side has no rational form.
+    ///
+    /// ```rust
+    /// # use symbolic_expr::Expr;
+    /// let n = Expr::var("n");
+    /// assert_eq!((n.clone() * 4 + 8).sub_simplified(&(n.clone() * 3)).to_string(), "8 + n");
+    /// ```
+    pub fn sub_simplified(&self, other: &Self) -> Self {
+        self.combine_simplified(
+            other,
+            |lhs, mut rhs| {
+                rhs.neg();
+                lhs.add(&rhs)
+            },
+            |lhs, rhs| lhs - rhs,
+        )
+    }
+
+    /// The canonical `self * other` as a plain tree, see [`sub_simplified`](Self::sub_simplified).
+    pub fn mul_simplified(&self, other: &Self) -> Self {
+        self.combine_simplified(other, |lhs, rhs| Some(lhs.mul(&rhs)), |lhs, rhs| lhs * rhs)
+    }
+
+    fn combine_simplified(
+        &self,
+        other: &Self,
+        combine: impl FnOnce(RationalExpression, RationalExpression) -> Option<RationalExpression>,
+        fallback: impl FnOnce(Self, Self) -> Self,
+    ) -> Self {
+        match (self.to_rational(), other.to_rational()) {
+            (Some(lhs), Some(rhs)) => combine(lhs, rhs).map_or_else(|| fallback(self.clone(), other.clone()), |rational| rational.to_tree()),
+            _ => fallback(self.clone(), other.clone()),
+        }
+    }
+
+    /// Checks equivalence like [`equivalent`](Self::equivalent) after renaming the variables of
+    /// both expressions through `aliases`, for the same dimensions named differently.
+    /// Variables without an alias keep their names.
+    ///
+    /// ```rust
+    /// # use std::collections::HashMap;
+    /// # use symbolic_expr::Expr;
+    /// let aliases = HashMap::from([("N", "batch")]);
+    /// let lhs = Expr::var("N") + 1;
+    /// assert_eq!(lhs.equivalent_with_aliases(&(Expr::var("batch") + 1), &aliases), Some(true));
+    /// ```
+    pub fn equivalent_with_aliases(&self, other: &Self, aliases: &HashMap<&str, &str>) -> Option<bool> {
+        let rename = |name: &str| aliases.get(name).copied().unwrap_or(name).to_string();
+        self.rename_variables(&rename).equivalent(&other.rename_variables(&rename))
+    }
+
+    /// Checks if two expressions have the same tree: the same variants with structurally
+    /// equal operands of the same signs in the same order.
+    /// Structural equality implies equivalence, but not the other way around.
+    pub fn structurally_eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Constant(a), Self::Constant(b)) => a == b,
+            (Self::Variable(a), Self::Variable(b)) => a == b,
+            (Self::Sum(a), Self::Sum(b)) | (Self::Product(a), Self::Product(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.ty == b.ty && a.expr.structurally_eq(&b.expr))
+            }
+            (Self::Rational(a), Self::Rational(b)) => a == b,
+            (Self::Pow(a, m), Self::Pow(b, n)) => m == n && a.structurally_eq(b),
+            (Self::Mod(a, m), Self::Mod(b, n)) => a.structurally_eq(b) && m.structurally_eq(n),
+            (Self::Abs(a), Self::Abs(b)) => a.structurally_eq(b),
+            (Self::Max(a, m), Self::Max(b, n)) => a.structurally_eq(b) && m.structurally_eq(n),
+            (Self::Hole, Self::Hole) => true,
+            _ => false,
+        }
+    }
 
-        let diff = self.clone() - other.clone();
-        let diff_rational = match diff {
-            Self::Rational(r) => r,
-            _ => RationalExpression::from_dim(&diff)?,
+    /// Recursively sorts the operands of sums and products, and the sides of `max`, into a
+    /// deterministic order, so commuted inputs give the same tree.
+    ///
+    /// Operands keep their signs. Positive operands come before negative ones, so `a - b` stays
+    /// `a - b` and every subtraction or division still follows the operands it applies to.
+    /// Within each group operands are ordered by variant, then variable name or constant value,
+    /// then their rendering. The alternate flag of [`Display`] renders the sorted form.
+    ///
+    /// Unlike [`canonicalize`](Self::canonicalize), this keeps the structure of the tree: nothing
+    /// is distributed or combined, so `(a + 1)*2` stays a product of a sum and a constant.
+    ///
+    /// ```rust
+    /// # use symbolic_expr::Expr;
+    /// let (a, b, c) = (Expr::var("a"), Expr::var("b"), Expr::var("c"));
+    /// assert_eq!(format!("{:#}", c.clone() - a.clone() + b.clone()), "b + c - a");
+    /// assert_eq!((b + c - a).sort_operands().to_string(), "b + c - a");
+    /// ```
+    #[doc(alias = "normalize")]
+    pub fn sort_operands(&self) -> Self {
+        fn rank(expr: &Expr) -> u8 {
+            match expr {
+                Expr::Variable(_) => 0,
+                Expr::Pow(..) => 1,
+                Expr::Product(_) => 2,
+                Expr::Sum(_) => 3,
+                Expr::Rational(_) => 4,
+                Expr::Mod(..) => 5,
+                Expr::Abs(_) => 6,
+                Expr::Max(..) => 7,
+                Expr::Constant(_) => 8,
+                Expr::Hole => 9,
+            }
+        }
+        fn compare(a: &Expr, b: &Expr) -> std::cmp::Ordering {
+            rank(a).cmp(&rank(b)).then_with(|| match (a, b) {
+                (Expr::Variable(a), Expr::Variable(b)) => a.cmp(b),
+                (Expr::Constant(a), Expr::Constant(b)) => a.cmp(b),
+                _ => a.to_string().cmp(&b.to_string()),
+            })
+        }
+        let sort = |operands: &VecDeque<Operand>| {
+            let mut operands = operands
+                .iter()
+                .map(|Operand { ty, expr }| Operand { ty: *ty, expr: expr.sort_operands() })
+                .collect::<Vec<_>>();
+            operands.sort_by(|a, b| {
+                let negative = |op: &Operand| op.ty == Sign::Negative;
+                negative(a).cmp(&negative(b)).then_with(|| compare(&a.expr, &b.expr))
+            });
+            operands.into()
         };
-        // Check if there's a non-zero constant term and all other terms are zero
-        let mut has_nonzero_constant = false;
-        let mut has_nonzero_variable_term = false;
-        
-        for term in diff_rational.numer.iter() {
-            if term.is_constant() {
-                if term.coef != Ratio::new(0, 1) {
-                    has_nonzero_constant = true;
+        match self {
+            Self::Constant(_) | Self::Variable(_) | Self::Rational(_) | Self::Hole => self.clone(),
+            Self::Sum(operands) => Self::Sum(sort(operands)),
+            Self::Product(operands) => Self::Product(sort(operands)),
+            Self::Pow(base, exponent) => base.sort_operands().pow(*exponent),
+            Self::Mod(lhs, modulus) => lhs.sort_operands().modulo(modulus.sort_operands()),
+            Self::Abs(operand) => operand.sort_operands().abs(),
+            Self::Max(lhs, rhs) => {
+                let (lhs, rhs) = (lhs.sort_operands(), rhs.sort_operands());
+                if compare(&lhs, &rhs) == std::cmp::Ordering::Greater { rhs.max(lhs) } else { lhs.max(rhs) }
+            }
+        }
+    }
+
+    /// Removes operands that cancel locally, keeping the rest of the tree as it is, so that
+    /// `m + (a - a)` becomes `m` and `n*(k/k)` becomes `n` without canonicalizing.
+    ///
+    /// Inside a sum, `+x` and `-x` pairs of [structurally equal](Self::structurally_eq) operands
+    /// are dropped, as are `0` and small operands proven equal to zero. Inside a product, `x`
+    /// and `/x` pairs are dropped only if `x` is a non-zero constant or `assume_nonzero` is set,
+    /// since `k/k` is not defined for `k = 0`; `1` is always dropped, and small operands proven
+    /// equal to one only if they do not divide, or `assume_nonzero` is set.
+    /// See [`Context::cancel_operands`](crate::Context::cancel_operands) to assume specific
+    /// expressions are non-zero.
+    ///
+    /// Where the expression is defined, the result has the same value.
+    ///
+    /// ```rust
+    /// # use symbolic_expr::Expr;
+    /// let [n, k] = ["n", "k"].map(Expr::var);
+    /// let expr = n.clone() * (k.clone() / k.clone()) + (k.clone() - k.clone());
+    /// assert_eq!(expr.cancel_operands(false).to_string(), "n*k/k");
+    /// assert_eq!(expr.cancel_operands(true).to_string(), "n");
+    /// ```
+    pub fn cancel_operands(&self, assume_nonzero: bool) -> Self {
+        self.cancel_operands_with(&|_| assume_nonzero, assume_nonzero)
+    }
+
+    /// [`cancel_operands`](Self::cancel_operands), cancelling product pairs of the operands for
+    /// which `nonzero` holds.
+    pub(crate) fn cancel_operands_with(&self, nonzero: &impl Fn(&Self) -> bool, assume_nonzero: bool) -> Self {
+        // Most nodes of an operand checked against the identity by canonicalization
+        const QUICK_CHECK_NODES: usize = 32;
+
+        fn fits(expr: &Expr, budget: &mut usize) -> bool {
+            let Some(rest) = budget.checked_sub(1) else {
+                return false;
+            };
+            *budget = rest;
+            match expr {
+                Expr::Sum(operands) | Expr::Product(operands) => operands.iter().all(|op| fits(&op.expr, budget)),
+                Expr::Pow(base, _) | Expr::Abs(base) => fits(base, budget),
+                Expr::Mod(lhs, rhs) | Expr::Max(lhs, rhs) => fits(lhs, budget) && fits(rhs, budget),
+                Expr::Constant(_) | Expr::Variable(_) | Expr::Rational(_) | Expr::Hole => true,
+            }
+        }
+
+        let cancel = |operands: &VecDeque<Operand>, identity: usize| {
+            let mut operands = operands
+                .iter()
+                .map(|Operand { ty, expr }| Operand { ty: *ty, expr: expr.cancel_operands_with(nonzero, assume_nonzero) })
+                .collect::<Vec<_>>();
+            operands.retain(|Operand { expr, .. }| match expr {
+                Self::Constant(value) => *value != identity,
+                Self::Variable(_) | Self::Hole => true,
+                _ => {
+                    let mut budget = QUICK_CHECK_NODES;
+                    let proven = (assume_nonzero || !expr.has_division())
+                        && fits(expr, &mut budget)
+                        && expr.equivalent(&Self::Constant(identity)) == Some(true);
+                    !proven
+                }
+            });
+            let mut i = 0;
+            while i < operands.len() {
+                let Operand { ty, expr } = &operands[i];
+                let cancels = *ty == Sign::Negative
+                    && (identity == 0 || expr.as_constant().is_some_and(|value| value != 0) || nonzero(expr));
+                let pair = cancels.then(|| operands.iter().position(|op| op.ty == Sign::Positive && op.expr.structurally_eq(expr))).flatten();
+                match pair {
+                    Some(j) => {
+                        operands.remove(i.max(j));
+                        operands.remove(i.min(j));
+                        i = 0;
+                    }
+                    None => i += 1,
                 }
-            } else if term.coef != Ratio::new(0, 1) {
-                has_nonzero_variable_term = true;
+            }
+            match operands.len() {
+                0 => Self::Constant(identity),
+                1 if operands[0].ty == Sign::Positive => operands.pop().unwrap().expr,
+                _ if identity == 0 => Self::Sum(operands.into()),
+                _ => Self::Product(operands.into()),
+            }
+        };
+        match self {
+            Self::Constant(_) | Self::Variable(_) | Self::Rational(_) | Self::Hole => self.clone(),
+            Self::Sum(operands) => cancel(operands, 0),
+            Self::Product(operands) => cancel(operands, 1),
+            Self::Pow(base, exponent) => base.cancel_operands_with(nonzero, assume_nonzero).pow(*exponent),
+            Self::Mod(lhs, modulus) => {
+                let lhs = lhs.cancel_operands_with(nonzero, assume_nonzero);
+                lhs.modulo(modulus.cancel_operands_with(nonzero, assume_nonzero))
+            }
+            Self::Abs(operand) => operand.cancel_operands_with(nonzero, assume_nonzero).abs(),
+            Self::Max(lhs, rhs) => {
+                let lhs = lhs.cancel_operands_with(nonzero, assume_nonzero);
+                lhs.max(rhs.cancel_operands_with(nonzero, assume_nonzero))
             }
         }
-        if !has_nonzero_constant && !has_nonzero_variable_term {
-            Some(true)
-        } else if has_nonzero_constant && !has_nonzero_variable_term {
-            Some(false)
-        } else {
-            None
+    }
+
+    /// Whether the expression divides by an identically zero expression, such as `a/(b - b)`
+    /// or `mod(a, 0)`, which leaves it undefined everywhere.
+    fn has_zero_divisor(&self) -> bool {
+        // Each divisor is canonicalized once, a rational form has no zero divisor inside since
+        // canonicalization rejects them, so only divisors without one are searched further
+        let divides_by_zero = |divisor: &Self| match divisor.to_rational() {
+            Some(rational) => CanonicalTerm::combine_like_terms(rational.numer).is_empty(),
+            None => divisor.has_zero_divisor(),
+        };
+        if !self.has_division() {
+            return false;
+        }
+        match self {
+            Self::Constant(_) | Self::Variable(_) | Self::Hole => false,
+            Self::Sum(operands) => operands.iter().any(|op| op.expr.has_zero_divisor()),
+            Self::Product(operands) => operands.iter().any(|op| match op.ty {
+                Sign::Positive => op.expr.has_zero_divisor(),
+                Sign::Negative => divides_by_zero(&op.expr),
+            }),
+            // Rational nodes are canonical, so a zero denominator has no terms
+            Self::Rational(rational) => rational.denom.is_empty(),
+            Self::Pow(base, _) | Self::Abs(base) => base.has_zero_divisor(),
+            Self::Mod(lhs, modulus) => lhs.has_zero_divisor() || divides_by_zero(modulus),
+            Self::Max(lhs, rhs) => lhs.has_zero_divisor() || rhs.has_zero_divisor(),
+        }
+    }
+
+    /// Whether evaluating the expression divides by a value that may be zero: a division by
+    /// an operand, a symbolic denominator or a `mod`.
+    fn has_division(&self) -> bool {
+        match self {
+            Self::Constant(_) | Self::Variable(_) | Self::Hole => false,
+            Self::Sum(operands) => operands.iter().any(|op| op.expr.has_division()),
+            Self::Product(operands) => operands.iter().any(|op| op.ty == Sign::Negative || op.expr.has_division()),
+            Self::Rational(rational) => !rational.denom.iter().all(CanonicalTerm::is_constant),
+            Self::Pow(base, _) | Self::Abs(base) => base.has_division(),
+            Self::Mod(..) => true,
+            Self::Max(lhs, rhs) => lhs.has_division() || rhs.has_division(),
         }
     }
 
     /// Partially substitute variables with their values.
-    /// Returns None if any substituted variable results in a non-integer value.
+    /// Returns None if any substituted variable results in a non-integer value, or a power
+    /// cannot be computed, see [`RationalExpression::partial_substitute`].
     pub fn partial_substitute(&self, value: &HashMap<&str, usize>) -> Option<Self> {
+        self.partial_substitute_with(value, MAX_EXPONENT.unsigned_abs())
+    }
+
+    /// [`Expr::partial_substitute`], returning None if a substituted factor has an exponent
+    /// beyond `max_exponent` rather than [`MAX_EXPONENT`].
+    ///
+    /// ```rust
+    /// # use std::collections::HashMap;
+    /// # use symbolic_expr::Expr;
+    /// let expr = Expr::var("a").pow(100) + Expr::var("b");
+    /// let value = HashMap::from([("a", 1)]);
+    /// assert_eq!(expr.partial_substitute_with(&value, 100).unwrap().to_string(), "1 + b");
+    /// assert!(expr.partial_substitute_with(&value, 99).is_none());
+    /// ```
+    pub fn partial_substitute_with(&self, value: &HashMap<&str, usize>, max_exponent: usize) -> Option<Self> {
+        // Nodes without a rational form are substituted in the tree
+        match self {
+            Self::Hole => return Some(Self::Hole),
+            Self::Mod(lhs, modulus) => {
+                let lhs = lhs.partial_substitute_with(value, max_exponent)?;
+                let modulus = modulus.partial_substitute_with(value, max_exponent)?;
+                return match (lhs.as_constant(), modulus.as_constant()) {
+                    (Some(lhs), Some(modulus)) if modulus != 0 => Some(Self::Constant(lhs % modulus)),
+                    _ => Some(lhs.modulo(modulus)),
+                };
+            }
+            Self::Abs(operand) => {
+                let operand = operand.partial_substitute_with(value, max_exponent)?;
+                if operand.variables().is_empty() {
+                    // The operand may be negative, so it is folded with signed evaluation
+                    if let Ok(value) = operand.substitute_signed(&HashMap::new()) {
+                        return Some(Self::Constant(value.unsigned_abs()));
+                    }
+                }
+                return Some(operand.abs());
+            }
+            Self::Max(lhs, rhs) => {
+                let lhs = lhs.partial_substitute_with(value, max_exponent)?;
+                let rhs = rhs.partial_substitute_with(value, max_exponent)?;
+                return Some(match (lhs.as_constant(), rhs.as_constant()) {
+                    (Some(a), Some(b)) => Self::Constant(a.max(b)),
+                    _ if lhs.equivalent(&rhs) == Some(true) => lhs,
+                    _ => lhs.max(rhs),
+                });
+            }
+            Self::Sum(operands) | Self::Product(operands) if self.contains_opaque() => {
+                let operands = operands
+                    .iter()
+                    .map(|Operand { ty, expr }| Some(Operand { ty: *ty, expr: expr.partial_substitute_with(value, max_exponent)? }))
+                    .collect::<Option<_>>()?;
+                return Some(match self {
+                    Self::Sum(_) => Self::Sum(operands),
+                    _ => Self::Product(operands),
+                });
+            }
+            Self::Pow(base, exponent) if base.contains_opaque() => {
+                return Some(base.partial_substitute_with(value, max_exponent)?.pow(*exponent));
+            }
+            _ => {}
+        }
+
         // Convert to rational form first for better handling of complex expressions
         let rational = match self {
             Self::Rational(r) => r.clone(),
@@ -170,56 +1219,870 @@ impl Expr {
         };
 
         // Perform partial substitution on the rational expression
-        let substituted = rational.partial_substitute(value)?;
+        let substituted = rational.partial_substitute_with(value, max_exponent)?;
 
         // Convert back to Dim
         Some(Self::from(substituted))
     }
-}
 
-impl PartialEq for Expr {
-    fn eq(&self, other: &Self) -> bool {
-        self.equivalent(other) == Some(true)
+    /// Partially substitute variables with rational values, so `2*a` with `a = 1/2` is `1`.
+    /// Returns None if the expression has no rational form, or under the conditions of
+    /// [`RationalExpression::partial_substitute_ratio`].
+    ///
+    /// ```rust
+    /// # use std::collections::HashMap;
+    /// # use num_rational::Ratio;
+    /// # use symbolic_expr::Expr;
+    /// let expr = Expr::var("a") * 2 + Expr::var("b");
+    /// let partial = expr.partial_substitute_ratio(&HashMap::from([("a", Ratio::new(1, 2))])).unwrap();
+    /// assert_eq!(partial.to_string(), "1 + b");
+    /// ```
+    pub fn partial_substitute_ratio(&self, value: &HashMap<&str, Ratio<isize>>) -> Option<Self> {
+        let rational = self.to_rational()?.partial_substitute_ratio(value)?;
+        Some(Self::from(rational))
     }
 
-    #[allow(clippy::partialeq_ne_impl)]
-    fn ne(&self, other: &Self) -> bool {
-        self.equivalent(other) == Some(false)
+    /// Simplifies the expression assuming `var` equals `value`, leaving the other variables
+    /// symbolic, for specializing an expression to a known configuration.
+    /// Returns None under the same conditions as [`Expr::partial_substitute`].
+    ///
+    /// ```rust
+    /// # use symbolic_expr::Expr;
+    /// let expr = Expr::var("a") * Expr::var("b") + Expr::var("c");
+    /// assert_eq!(expr.specialize("a", 1).unwrap().to_string(), "b + c");
+    /// ```
+    pub fn specialize(&self, var: &str, value: usize) -> Option<Self> {
+        self.partial_substitute(&HashMap::from([(var, value)]))
     }
-}
 
-#[derive(Clone, Copy, Debug, PartialEq)]
-enum Type {
-    Positive,
-    Negative,
-}
+    /// Simplifies the expression assuming each variable equals its value, see [`Expr::specialize`].
+    /// A later entry for the same variable overrides an earlier one.
+    pub fn specialize_all(&self, values: &[(&str, usize)]) -> Option<Self> {
+        self.partial_substitute(&values.iter().copied().collect())
+    }
 
-impl Type {
-    pub fn rev(self) -> Self {
+    /// The dimension two broadcast dimensions result in, following NumPy: a dimension of `1`
+    /// stretches to the other one. Returns None if both are known to be distinct constants
+    /// other than `1`, and `max(self, other)` if the result depends on the variables.
+    ///
+    /// ```rust
+    /// # use symbolic_expr::Expr;
+    /// let n = Expr::var("n");
+    /// assert_eq!(n.broadcast(&Expr::from(1)).unwrap().to_string(), "n");
+    /// assert!(Expr::from(3).broadcast(&Expr::from(4)).is_none());
+    /// ```
+    pub fn broadcast(&self, other: &Self) -> Option<Self> {
+        match (self.as_constant(), other.as_constant()) {
+            (Some(1), _) => Some(other.clone()),
+            (_, Some(1)) => Some(self.clone()),
+            _ if self.equivalent(other) == Some(true) => Some(self.clone()),
+            (Some(_), Some(_)) => None,
+            _ => Some(self.clone().max(other.clone())),
+        }
+    }
+
+    /// Whether the expression contains nodes without a rational form, such as [`Expr::Mod`].
+    fn contains_opaque(&self) -> bool {
         match self {
-            Self::Positive => Self::Negative,
-            Self::Negative => Self::Positive,
+            Self::Constant(_) | Self::Variable(_) | Self::Rational(_) => false,
+            Self::Sum(operands) | Self::Product(operands) => operands.iter().any(|op| op.expr.contains_opaque()),
+            Self::Pow(base, _) => base.contains_opaque(),
+            Self::Mod(..) | Self::Abs(_) | Self::Max(..) | Self::Hole => true,
         }
     }
-}
 
-#[derive(Clone, Debug)]
-pub struct Operand {
-    ty: Type,
-    expr: Expr,
-}
+    /// Whether the expression is provably equivalent to `1`, such as a broadcast dimension.
+    fn is_one(&self) -> bool {
+        self.equivalent(&Self::Constant(1)) == Some(true)
+    }
 
-impl Operand {
-    pub fn rev_assign(&mut self) {
-        self.ty = self.ty.rev()
+    /// The value of an expression without variables, if it evaluates to a natural number.
+    fn as_constant(&self) -> Option<usize> {
+        match self {
+            &Self::Constant(value) => Some(value),
+            _ if self.variables().is_empty() => self.checked_substitute(&HashMap::new()),
+            _ => None,
+        }
     }
-}
 
-impl Neg for Operand {
-    type Output = Self;
+    /// The content of the expression: the gcd of all numerator coefficients over the
+    /// constant denominator, so that `(4a + 6)` has content `2` and `(4a + 6) / 3` has content `2/3`.
+    ///
+    /// The content is always non-negative and is `0` for an expression equivalent to zero.
+    /// When the denominator is not a constant, including a monomial such as `2b` that does
+    /// not cancel, there is no meaningful split and the content is `1`.
+    pub fn content(&self) -> Ratio<isize> {
+        self.to_rational()
+            .and_then(|rational| rational.content())
+            .unwrap_or(Ratio::from_integer(1))
+    }
 
-    fn neg(self) -> Self::Output {
-        let Self { ty, expr } = self;
+    /// The primitive part of the expression, i.e. the expression divided by its [content](Self::content).
+    ///
+    /// `(4a + 6)` has primitive part `2a + 3`. The zero expression is its own primitive part.
+    pub fn primitive_part(&self) -> Self {
+        let Some(rational) = self.to_rational() else {
+            return self.clone();
+        };
+        let Some(content) = rational.content() else {
+            return self.clone();
+        };
+        if content == Ratio::from_integer(0) {
+            return Self::from(RationalExpression::new_zero());
+        }
+        let mut rational = rational.simplify();
+        rational.numer.iter_mut().for_each(|term| term.coef /= content);
+        Self::from(rational)
+    }
+
+    /// Views the expression as a polynomial in `var`, returning the coefficient expressions
+    /// indexed by exponent, so that the expression equals `sum(coefs[i] * var^i)`.
+    /// Other variables become part of the coefficients, exponents absent from the
+    /// polynomial have the coefficient `Constant(0)`, and zero has no coefficients at all.
+    ///
+    /// Returns None if the denominator of the normal form is not a constant, such as for `a/b`,
+    /// whose coefficient `1/b` is not a polynomial.
+    ///
+    /// ```rust
+    /// # use symbolic_expr::Expr;
+    /// let [a, b, c, x] = ["a", "b", "c", "x"].map(Expr::var);
+    /// let expr = a.clone() * x.clone() * x.clone() + b.clone() * x + c.clone();
+    /// let coefs = expr.as_univariate("x").unwrap();
+    /// assert!(coefs[0] == c && coefs[1] == b && coefs[2] == a);
+    /// ```
+    pub fn as_univariate(&self, var: &str) -> Option<Vec<Self>> {
+        let rational = self.to_rational()?.normalize();
+        if !matches!(&*rational.denom, [term] if term.factors.is_empty()) {
+            return None;
+        }
+        let rational = rational.simplify();
+        let mut coefs = Vec::<Vec<CanonicalTerm>>::new();
+        for term in CanonicalTerm::combine_like_terms(rational.numer) {
+            let exponent = usize::try_from(term.exponent_of(var)).ok()?;
+            if coefs.len() <= exponent {
+                coefs.resize(exponent + 1, Vec::new());
+            }
+            let mut term = term;
+            term.factors.retain(|f| f.base != var);
+            coefs[exponent].push(term);
+        }
+        Some(
+            coefs
+                .into_iter()
+                .map(|terms| {
+                    if terms.is_empty() {
+                        Self::Constant(0)
+                    } else {
+                        Self::from(RationalExpression::new(terms, vec![CanonicalTerm::new(1)]))
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    /// Splits the canonical form into `(part, denominator)` groups whose sum of `part/denominator`
+    /// is the expression, keeping the smallest constant denominators: `a + b/2 + c/3` gives
+    /// `[(a, 1), (b, 2), (c, 3)]` rather than `(6*a + 3*b + 2*c)/6`.
+    ///
+    /// Groups are ordered by denominator. Terms divided by variables form groups of their own
+    /// with denominator `1`, after the others. An expression whose denominator has several
+    /// terms, or no rational form, is a single group as it is.
+    pub fn to_mixed_form(&self) -> Vec<(Self, usize)> {
+        let Some(rational) = self.to_rational() else {
+            return vec![(self.clone(), 1)];
+        };
+        let rational = rational.normalize();
+        match rational.mixed_groups() {
+            Some(groups) => groups
+                .into_iter()
+                .map(|(terms, denom)| (Self::from(RationalExpression::new(terms, vec![CanonicalTerm::new(1)])), denom))
+                .collect(),
+            None => vec![(Self::from(rational), 1)],
+        }
+    }
+
+    /// Factors the highest common power of `var` out of the numerator terms, returning the
+    /// exponent and the remaining expression, so that the expression equals `var^exponent * rest`.
+    /// `a*a*b + a*c` gives `(1, a*b + c)`.
+    ///
+    /// Terms without `var` count as exponent `0`, and a negative exponent is returned when
+    /// every term divides by `var`. The exponent is `0` for zero and for expressions that
+    /// cannot be converted to a rational form, which are returned unchanged.
+    pub fn factor_out(&self, var: &str) -> (isize, Self) {
+        let Some(rational) = self.to_rational() else {
+            return (0, self.clone());
+        };
+        let mut rational = rational.simplify();
+        rational.numer = CanonicalTerm::combine_like_terms(rational.numer);
+        let Some(exponent) = rational.numer.iter().map(|term| term.exponent_of(var)).min() else {
+            return (0, Self::from(rational));
+        };
+        let power = CanonicalTerm {
+            coef: Ratio::from_integer(1),
+            factors: Factors::from_iter([Factor { base: var.to_string(), exponent }]),
+        };
+        rational.numer = CanonicalTerm::terms_divide_by_term(&rational.numer, &power);
+        (exponent, Self::from(rational))
+    }
+
+    /// Whether the expression is a single term, a coefficient times a product of powers
+    /// of variables, with no additive structure after canonicalization.
+    ///
+    /// Variables may appear in the denominator, `a*b/c` is a monomial while `a + b` is not.
+    /// Constants, including zero, are monomials. Expressions without a rational form are not.
+    pub fn is_monomial(&self) -> bool {
+        self.to_rational().is_some_and(|rational| {
+            let rational = rational.normalize();
+            match &*rational.denom {
+                [_] => rational.numer.len() <= 1,
+                // The denominator may still divide the numerator
+                denom => CanonicalTerm::terms_divide_exact(&rational.numer, denom).is_some_and(|q| q.len() <= 1),
+            }
+        })
+    }
+
+    /// Whether the expression is a polynomial after canonicalization, with no variable left
+    /// in the denominator. `(a*a + a*b)/(a + b)` is, since the denominator divides out.
+    ///
+    /// Returns None if the expression cannot be converted to a rational form, including when
+    /// canonicalizing it exceeds the budget of [`MAX_EXPONENT`] and [`MAX_EXPANDED_TERMS`], see
+    /// [`Error::TooComplex`].
+    pub fn is_polynomial(&self) -> Option<bool> {
+        Some(self.polynomial_terms()?.is_some())
+    }
+
+    /// Whether the expression is a polynomial of total degree at most `1` after
+    /// canonicalization, `a*2 + b + 1` is while `a*b` is not. See [`Expr::is_polynomial`].
+    pub fn is_affine(&self) -> Option<bool> {
+        self.is_affine_where(|_| true)
+    }
+
+    /// Whether the expression is a polynomial of degree at most `1` in `vars` after
+    /// canonicalization, the other variables standing for unknown constants: `i*n + j` is
+    /// affine in `i` and `j`, while `i*j` and `i/n` are not. See [`Expr::is_affine`].
+    ///
+    /// ```rust
+    /// # use symbolic_expr::Expr;
+    /// let [i, j, n] = ["i", "j", "n"].map(Expr::var);
+    /// assert_eq!((i.clone() * n.clone() + j.clone()).is_affine_in(&["i", "j"]), Some(true));
+    /// assert_eq!((i.clone() * j.clone()).is_affine_in(&["i", "j"]), Some(false));
+    /// ```
+    pub fn is_affine_in(&self, vars: &[&str]) -> Option<bool> {
+        self.is_affine_where(|var| vars.contains(&var))
+    }
+
+    /// Whether every term of the polynomial has total degree at most `1` in the variables
+    /// for which `counted` holds.
+    fn is_affine_where(&self, counted: impl Fn(&str) -> bool) -> Option<bool> {
+        let terms = self.polynomial_terms()?;
+        Some(terms.is_some_and(|terms| {
+            terms.iter().all(|term| term.factors.iter().filter(|f| counted(&f.base)).map(|f| f.exponent).sum::<isize>() <= 1)
+        }))
+    }
+
+    /// The terms of the normal form when its denominator is a constant, divided by it.
+    /// The constant term, if any, comes first.
+    /// Returns None if the denominator has variables or the expression has no rational form.
+    ///
+    /// ```rust
+    /// # use num_rational::Ratio;
+    /// # use symbolic_expr::Expr;
+    /// let terms = ((Expr::var("a") + 1) / 2).terms().unwrap();
+    /// assert!(terms[0].factors.is_empty());
+    /// assert_eq!(terms[1].coef, Ratio::new(1, 2));
+    /// assert_eq!(terms[1].factors, [("a".to_string(), 1)]);
+    /// ```
+    pub fn terms(&self) -> Option<Vec<Term>> {
+        let rational = self.to_rational()?.normalize();
+        let denom = match &*rational.denom {
+            [denom] if denom.is_constant() => denom.coef,
+            _ => return None,
+        };
+        let terms = rational.numer_terms().map(Term::from);
+        Some(terms.map(|term| Term { coef: term.coef / denom, ..term }).collect())
+    }
+
+    /// The coefficients `(cx, cy, k)` with `cx*x + cy*y + k` equivalent to the expression, for
+    /// finding how two variables are tied by an expression equal to zero, such as `(1, -4, 0)`
+    /// for `i - 4*j`. A coefficient is zero if its variable is absent, and if `x` and `y` are
+    /// the same variable its coefficient is `cx`.
+    /// Returns None if another variable appears, the expression is not affine in `x` and `y`,
+    /// or its terms are not defined, see [`Expr::terms`].
+    ///
+    /// ```rust
+    /// # use num_rational::Ratio;
+    /// # use symbolic_expr::Expr;
+    /// let expr = Expr::var("i") - Expr::var("j") * 4;
+    /// let (cx, cy, k) = expr.linear_relation("i", "j").unwrap();
+    /// assert_eq!((cx, cy, k), (Ratio::from_integer(1), Ratio::from_integer(-4), Ratio::from_integer(0)));
+    /// ```
+    pub fn linear_relation(&self, x: &str, y: &str) -> Option<(Ratio<isize>, Ratio<isize>, Ratio<isize>)> {
+        let zero = Ratio::from_integer(0);
+        let (mut cx, mut cy, mut k) = (zero, zero, zero);
+        for term in self.terms()? {
+            let coef = match &*term.factors {
+                [] => &mut k,
+                [(var, 1)] if var == x => &mut cx,
+                [(var, 1)] if var == y => &mut cy,
+                _ => return None,
+            };
+            *coef = term.coef;
+        }
+        Some((cx, cy, k))
+    }
+
+    /// Splits the expression into its constant term and the rest, rebuilt as a tree of sums
+    /// and products, so that `symbolic + constant` is equivalent to the expression.
+    /// An expression without a constant term splits into `0` and itself, and a constant into
+    /// itself and `0`. Returns None if the denominator has variables or the expression has no
+    /// rational form, since the constant term is then not separable.
+    ///
+    /// ```rust
+    /// # use num_rational::Ratio;
+    /// # use symbolic_expr::Expr;
+    /// let (constant, symbolic) = (Expr::var("n") * Expr::var("c") + 128).split_constant().unwrap();
+    /// assert_eq!(constant, Ratio::from_integer(128));
+    /// assert_eq!(symbolic.to_string(), "c*n");
+    /// ```
+    pub fn split_constant(&self) -> Option<(Ratio<isize>, Self)> {
+        let rational = self.to_rational()?.normalize();
+        let denom = match &*rational.denom {
+            [denom] if denom.is_constant() => denom.coef,
+            _ => return None,
+        };
+        let (constant, symbolic): (Vec<_>, Vec<_>) = rational.numer.into_iter().partition(CanonicalTerm::is_constant);
+        let constant = constant.first().map_or(Ratio::from_integer(0), |term| term.coef / denom);
+        Some((constant, RationalExpression::new(symbolic, rational.denom).to_tree()))
+    }
+
+    /// A guaranteed minimum of the expression when every variable is at least its value in
+    /// `mins`, or at least `0` if it has none, see [`lower_bound_with_default`](Self::lower_bound_with_default).
+    ///
+    /// ```rust
+    /// # use std::collections::HashMap;
+    /// # use symbolic_expr::Expr;
+    /// let expr = Expr::var("a") + Expr::var("b") + 5;
+    /// assert_eq!(expr.lower_bound(&HashMap::from([("a", 2), ("b", 3)])), Some(10));
+    /// ```
+    pub fn lower_bound(&self, mins: &HashMap<&str, usize>) -> Option<usize> {
+        self.lower_bound_with_default(mins, 0)
+    }
+
+    /// A guaranteed minimum of the expression when every variable is at least its value in
+    /// `mins`, or at least `default_min` if it has none, such as `1` for the dimensions of a
+    /// shape. The minimum is rounded down.
+    ///
+    /// The expression only grows with its variables if every term of its normal form has a
+    /// non-negative coefficient and the denominator is a positive constant, so the minimum is
+    /// its value at the lower bounds. Returns None otherwise, as a negative coefficient may
+    /// leave it unbounded below, and if the expression has no rational form or overflows.
+    pub fn lower_bound_with_default(&self, mins: &HashMap<&str, usize>, default_min: usize) -> Option<usize> {
+        let rational = self.to_rational()?.normalize();
+        let denom = match &*rational.denom {
+            [denom] if denom.is_constant() && denom.coef > Ratio::from_integer(0) => denom.coef,
+            _ => return None,
+        };
+        let mut sum = Ratio::from_integer(0);
+        for term in &rational.numer {
+            if term.coef < Ratio::from_integer(0) {
+                return None;
+            }
+            let mut value = CanonicalTerm { coef: term.coef, factors: Factors::new() };
+            for factor in &term.factors {
+                let min = mins.get(&*factor.base).copied().unwrap_or(default_min);
+                value.mul_power(Ratio::from_integer(isize::try_from(min).ok()?), factor.exponent, MAX_EXPONENT.unsigned_abs())?;
+            }
+            sum = num_traits::CheckedAdd::checked_add(&sum, &value.coef)?;
+        }
+        let min = num_traits::CheckedDiv::checked_div(&sum, &denom)?;
+        usize::try_from(min.floor().to_integer()).ok()
+    }
+
+    /// The terms of the normal form as a polynomial, or `Some(None)` if the denominator
+    /// does not divide the numerator.
+    fn polynomial_terms(&self) -> Option<Option<Vec<CanonicalTerm>>> {
+        // Rational nodes are normalized in place of being cloned first
+        let rational = match self {
+            Self::Rational(rational) => rational.normalize(),
+            _ => RationalExpression::from_dim(self)?.normalize(),
+        };
+        Some(CanonicalTerm::terms_divide_exact(&rational.numer, &rational.denom))
+    }
+
+    /// Whether the expression is an integer for every assignment of positive integers.
+    ///
+    /// Polynomials with integer coefficients are. With a constant denominator `d`, the value
+    /// modulo `d` only depends on the variables modulo `d`, so every residue is checked:
+    /// `a/2` is not integer valued while `a*(a + 1)/2` is. Returns None if the denominator
+    /// contains variables that do not divide out, there are too many residues to check, or the
+    /// expression has no rational form.
+    pub fn is_integer_valued(&self) -> Option<bool> {
+        const MAX_RESIDUES: usize = 4096;
+
+        let rational = self.to_rational()?.normalize();
+        let lcm = rational.numer.iter().chain(&rational.denom).fold(1, |lcm, term| lcm.lcm(term.coef.denom()));
+        let scale = |terms: &[CanonicalTerm]| {
+            terms.iter().map(|term| CanonicalTerm { coef: term.coef * lcm, factors: term.factors.clone() }).collect::<Vec<_>>()
+        };
+        let (numer, denom) = (scale(&rational.numer), scale(&rational.denom));
+        if CanonicalTerm::terms_divide_exact(&numer, &denom)
+            .is_some_and(|terms| terms.iter().all(|term| term.coef.is_integer()))
+        {
+            return Some(true);
+        }
+        let [denom] = &*denom else {
+            return None;
+        };
+        if !denom.is_constant() {
+            return None;
+        }
+        let modulus = denom.coef.to_integer().unsigned_abs();
+        let variables = self.variables_vec();
+        let count = u32::try_from(variables.len()).ok().and_then(|n| modulus.checked_pow(n))?;
+        if count > MAX_RESIDUES {
+            return None;
+        }
+        let numer = RationalExpression::new(numer, vec![CanonicalTerm::new(1)]);
+        for i in 0..count {
+            // The i-th assignment of residues 1..=modulus to the variables
+            let values = variables
+                .iter()
+                .enumerate()
+                .map(|(k, var)| (var.as_str(), i / modulus.pow(k as u32) % modulus + 1))
+                .collect::<HashMap<_, _>>();
+            let value = numer.checked_substitute(&values).ok()?;
+            if value.to_integer() % modulus as isize != 0 {
+                return Some(false);
+            }
+        }
+        Some(true)
+    }
+
+    /// Whether `self` is proven to divide `other` for every assignment of the variables, that is
+    /// the quotient is a polynomial with integer coefficients. `a` divides `a*b` and `2` divides
+    /// `2*a + 4`, but `2` is not proven to divide `a*(a + 1)`.
+    ///
+    /// Returns false if either side cannot be converted to a rational form, or `self` is zero.
+    pub fn divides(&self, other: &Self) -> bool {
+        let (Some(divisor), Some(dividend)) = (self.to_rational(), other.to_rational()) else {
+            return false;
+        };
+        dividend.polynomial_quotient(&divisor).is_some_and(|terms| terms.iter().all(|term| term.coef.is_integer()))
+    }
+
+    /// The constant `k` such that `self == k * other`, so `2*a + 2*b` is proportional to `a + b`
+    /// with `k = 2` and zero is proportional to anything non-zero with `k = 0`.
+    ///
+    /// Returns None if the quotient is not a constant, `other` is zero, or either side has no
+    /// rational form.
+    pub fn proportional_to(&self, other: &Self) -> Option<Ratio<isize>> {
+        match &*self.to_rational()?.polynomial_quotient(&other.to_rational()?)? {
+            [] => Some(Ratio::from_integer(0)),
+            [term] if term.is_constant() => Some(term.coef),
+            _ => None,
+        }
+    }
+
+    /// The divisions of the tree that are not proven exact by [`Expr::divides`], as
+    /// `(numerator, divisor)` pairs in evaluation order.
+    ///
+    /// The numerator of a negative product operand is the product of the operands before it,
+    /// so `(a/b)*c` reports `(a, b)` while `a*b/a` reports nothing. Rational nodes are
+    /// checked as their tree, which divides once by the scaled denominator.
+    pub fn inexact_divisions(&self) -> Vec<(Self, Self)> {
+        let mut divisions = Vec::new();
+        self.collect_inexact_divisions(&mut divisions);
+        divisions
+    }
+
+    /// Whether the value of the expression is an integer under the strict evaluation, see
+    /// [`Expr::substitute_exact_steps`], such as for generating the runtime divisibility
+    /// checks of the divisions. The obligations are those of [`inexact_divisions`](Self::inexact_divisions).
+    ///
+    /// ```rust
+    /// # use symbolic_expr::{Expr, Integrality};
+    /// let n = Expr::var("n");
+    /// assert!(matches!((n.clone() * 4 / 2).integrality(), Integrality::Always));
+    /// let Integrality::IfDivisible(divisions) = (n.clone() / 2 + 1).integrality() else { unreachable!() };
+    /// assert_eq!(divisions.len(), 1);
+    /// ```
+    pub fn integrality(&self) -> Integrality {
+        if self.has_holes() {
+            return Integrality::Unknown;
+        }
+        match self.inexact_divisions() {
+            divisions if divisions.is_empty() => Integrality::Always,
+            divisions => Integrality::IfDivisible(divisions),
+        }
+    }
+
+    fn collect_inexact_divisions(&self, divisions: &mut Vec<(Self, Self)>) {
+        match self {
+            Self::Constant(_) | Self::Variable(_) | Self::Hole => {}
+            Self::Sum(operands) => operands.iter().for_each(|op| op.expr.collect_inexact_divisions(divisions)),
+            Self::Product(operands) => {
+                for (i, Operand { ty, expr }) in operands.iter().enumerate() {
+                    expr.collect_inexact_divisions(divisions);
+                    if *ty == Sign::Negative {
+                        let numerator = match (i, &operands[0]) {
+                            (1, Operand { ty: Sign::Positive, expr }) => expr.clone(),
+                            _ => Self::product(operands.range(..i).cloned()),
+                        };
+                        if !expr.divides(&numerator) {
+                            divisions.push((numerator, expr.clone()));
+                        }
+                    }
+                }
+            }
+            Self::Rational(rational) => rational.to_tree().collect_inexact_divisions(divisions),
+            Self::Pow(base, _) | Self::Abs(base) => base.collect_inexact_divisions(divisions),
+            Self::Mod(lhs, rhs) | Self::Max(lhs, rhs) => {
+                lhs.collect_inexact_divisions(divisions);
+                rhs.collect_inexact_divisions(divisions);
+            }
+        }
+    }
+
+    /// Multiplies the expression by a denominator from [`common_denominator`] and canonicalizes
+    /// the product, which clears the fractions of the expression.
+    /// Expressions without a rational form are multiplied as trees.
+    pub fn scale_to_integer(&self, common_denom: &Self) -> Self {
+        let product = self * common_denom;
+        match product.canonicalize() {
+            Ok(canon) => canon.into(),
+            Err(_) => product,
+        }
+    }
+
+    /// Rewrites the expression as a polynomial with integer coefficients over `denom`, without
+    /// cancelling the fraction, so that a group of expressions can share a divisor.
+    /// Returns None if `denom` is zero or has no rational form, or the expression times `denom`
+    /// is not such a polynomial, that is the denominator of the expression does not divide `denom`.
+    ///
+    /// ```rust
+    /// # use symbolic_expr::Expr;
+    /// let expr = Expr::var("a") / 2;
+    /// assert_eq!(expr.over_denominator(&Expr::from(6)).unwrap().to_string(), "3*a/6");
+    /// assert!(expr.over_denominator(&Expr::from(3)).is_none());
+    /// ```
+    pub fn over_denominator(&self, denom: &Self) -> Option<Self> {
+        if denom.to_rational()?.normalize().numer.is_empty() {
+            return None;
+        }
+        let numer = (self * denom).polynomial_terms()??;
+        if !numer.iter().all(|term| term.coef.is_integer()) {
+            return None;
+        }
+        let mut denom = denom.clone();
+        denom.shrink_to_fit();
+        // A single division, in place of dividing by each operand of a product
+        let numer = RationalExpression::new(numer, vec![CanonicalTerm::new(1)]).to_tree();
+        Some(Self::Product([numer.positive(), denom.negative()].into()))
+    }
+
+    /// The partial derivative with respect to `var`, treating the other variables as constants.
+    /// Returns None if the expression has no rational form.
+    ///
+    /// ```rust
+    /// # use symbolic_expr::Expr;
+    /// let (a, b) = (Expr::var("a"), Expr::var("b"));
+    /// let expr = a.clone() * a.clone() * b.clone() + a.clone();
+    /// let derivative = expr.derivative("a").unwrap();
+    /// assert_eq!(derivative.equivalent(&(a * b * 2 + 1)), Some(true));
+    /// ```
+    pub fn derivative(&self, var: &str) -> Option<Self> {
+        Some(Self::from(self.to_rational()?.normalize().derivative(var)))
+    }
+
+    /// The partial derivatives with respect to every variable, see [`Expr::derivative`].
+    /// Returns None if the expression has no rational form.
+    pub fn gradient(&self) -> Option<BTreeMap<String, Self>> {
+        self.variables()
+            .into_iter()
+            .map(|var| Some((var.to_string(), self.derivative(var)?)))
+            .collect()
+    }
+
+    /// Solves `self == 0` for `var`, if the expression is linear in `var`.
+    ///
+    /// The solution is in terms of the other variables, `2*a + b - 6` gives `(6 - b)/2` for `a`.
+    /// Returns None if `var` does not appear in the numerator of the normal form, or appears
+    /// with another power, or if the solution makes the denominator zero, as `a = 3` does for
+    /// `(a - 3)/(a*a - 9)`.
+    pub fn solve_for(&self, var: &str) -> Option<Self> {
+        let rational = self.to_rational()?.normalize();
+        let mut constant = Vec::new();
+        let mut linear = Vec::new();
+        for term in rational.numer {
+            match term.exponent_of(var) {
+                0 => constant.push(term),
+                1 => linear.push(term.divide(&CanonicalTerm::with_var(1, var.to_string()))),
+                _ => return None,
+            }
+        }
+        if linear.is_empty() {
+            return None;
+        }
+        constant.iter_mut().for_each(CanonicalTerm::neg);
+        let solution = Self::from(RationalExpression::new(constant, linear));
+        let denom = Self::from(RationalExpression::new(rational.denom, vec![CanonicalTerm::new(1)]));
+        let denom = denom.replace_variable(var, &solution).to_rational()?.normalize();
+        (!denom.numer.is_empty()).then_some(solution)
+    }
+
+    /// The highest power of `var` in the numerator of the normal form, so
+    /// `numerator_degree_in(var) < denominator_degree_in(var)` checks that the expression
+    /// is a proper rational function in `var`.
+    ///
+    /// Zero and expressions without a rational form have degree `0`.
+    pub fn numerator_degree_in(&self, var: &str) -> isize {
+        self.to_rational().map_or(0, |rational| RationalExpression::degree_in(&rational.normalize().numer, var))
+    }
+
+    /// The highest power of `var` in the denominator of the normal form, `2` in `b` for `a/(b*b)`.
+    /// See [`Expr::numerator_degree_in`].
+    pub fn denominator_degree_in(&self, var: &str) -> isize {
+        self.to_rational().map_or(0, |rational| RationalExpression::degree_in(&rational.normalize().denom, var))
+    }
+
+    /// Compares how fast the expressions grow as `var` grows: by the degree of the numerator
+    /// minus the degree of the denominator in `var`, then by the ratio of their leading
+    /// coefficients. Leading coefficients are assumed positive.
+    ///
+    /// Returns None if the degrees tie and either leading coefficient has other variables,
+    /// or if either expression has no rational form.
+    ///
+    /// ```rust
+    /// # use std::cmp::Ordering;
+    /// # use symbolic_expr::Expr;
+    /// let (s, h) = (Expr::var("s"), Expr::var("h"));
+    /// let attention = s.clone() * s.clone() * h.clone();
+    /// assert_eq!(attention.compare_growth(&(s.clone() * h.clone() * 64), "s"), Some(Ordering::Greater));
+    /// assert_eq!((s.clone() * h.clone()).compare_growth(&(s.clone() * Expr::var("w")), "s"), None);
+    /// ```
+    pub fn compare_growth(&self, other: &Self, var: &str) -> Option<std::cmp::Ordering> {
+        let (degree, coef) = self.to_rational()?.growth_in(var)?;
+        let (other_degree, other_coef) = other.to_rational()?.growth_in(var)?;
+        match degree.cmp(&other_degree) {
+            std::cmp::Ordering::Equal => Some(coef?.cmp(&other_coef?)),
+            ordering => Some(ordering),
+        }
+    }
+
+    /// The distinct exponents of `var` across the terms of the normal form where it appears,
+    /// `{-1, 1, 2}` for `a + a*a + 1/a`.
+    ///
+    /// A single-term denominator is divided into the numerator terms, so its exponents show as
+    /// negative exponents of the terms. Terms of a denominator with several terms contribute
+    /// their exponents negated. Expressions without a rational form have no exponents.
+    pub fn exponents_of(&self, var: &str) -> BTreeSet<isize> {
+        let Some(rational) = self.to_rational() else {
+            return BTreeSet::new();
+        };
+        let rational = rational.normalize();
+        let exponents = match &*rational.denom {
+            [denom] => rational.numer.iter().map(|term| term.divide(denom).exponent_of(var)).collect::<BTreeSet<_>>(),
+            denom => {
+                let numer = rational.numer.iter().map(|term| term.exponent_of(var));
+                numer.chain(denom.iter().map(|term| -term.exponent_of(var))).collect()
+            }
+        };
+        exponents.into_iter().filter(|&exponent| exponent != 0).collect()
+    }
+
+    /// The variables in the denominator of the normal form, `{b}` for `a/b`, so factors that
+    /// cancel like `a` in `a*b/a` are not reported. See [`Expr::numerator_variables`].
+    ///
+    /// Expressions without a rational form report all their variables, in both sets.
+    pub fn denominator_variables(&self) -> BTreeSet<String> {
+        self.fraction_variables(|rational| &rational.denom)
+    }
+
+    /// The variables in the numerator of the normal form, `{a, b}` for `a*b/c`.
+    /// A variable may appear on both sides, like `a` in `(a + 1)/(a + 2)`.
+    pub fn numerator_variables(&self) -> BTreeSet<String> {
+        self.fraction_variables(|rational| &rational.numer)
+    }
+
+    fn fraction_variables(&self, side: impl Fn(&RationalExpression) -> &Vec<CanonicalTerm>) -> BTreeSet<String> {
+        let Some(rational) = self.to_rational() else {
+            return self.variables_owned();
+        };
+        side(&rational.normalize()).iter().flat_map(|term| &term.factors).map(|f| f.base.clone()).collect()
+    }
+
+    /// Converts the expression to its normal form, see [`CanonExpr`].
+    /// Returns [`Error::NotRational`] if the expression cannot be converted to a rational form,
+    /// [`Error::TooComplex`] if its exponents could exceed [`MAX_EXPONENT`] or a power, product or
+    /// sum of fractions could expand to more than [`MAX_EXPANDED_TERMS`] terms, and [`Error::UnfilledHole`] if it has
+    /// an [`Expr::Hole`].
+    ///
+    /// Powers of the same base multiplied together are merged before they are expanded, so
+    /// `(a + b)^2 * (a + b)^3` expands `(a + b)^5` once.
+    ///
+    /// Canonicalization is idempotent: canonicalizing the expression of a normal form gives
+    /// a structurally equal normal form.
+    pub fn canonicalize(&self) -> Result<CanonExpr, Error> {
+        let rational = self.to_rational().ok_or_else(|| {
+            if self.has_holes() {
+                Error::UnfilledHole
+            } else if self.exponent_bound() > MAX_EXPONENT.unsigned_abs() || self.expands_too_much() {
+                Error::TooComplex
+            } else {
+                Error::NotRational
+            }
+        })?;
+        let rational = rational.normalize();
+        debug_assert_eq!(rational.validate(), Ok(()), "{rational}");
+        Ok(CanonExpr(Self::Rational(rational)))
+    }
+
+    /// Canonicalizes the expression in place, demoting the normal form back to a tree of sums
+    /// and products when its denominator is a constant, which takes less memory and displays
+    /// more cleanly. Otherwise the normal form is kept with its buffers shrunk.
+    /// Expressions without a rational form are left unchanged.
+    ///
+    /// ```rust
+    /// # use symbolic_expr::Expr;
+    /// let mut expr = Expr::from((Expr::var("a") * 2 - Expr::var("a") + 1).canonicalize().unwrap());
+    /// expr.shrink_to_fit();
+    /// assert!(matches!(expr, Expr::Sum(_)));
+    /// assert_eq!(expr.to_string(), "1 + a");
+    /// ```
+    pub fn shrink_to_fit(&mut self) {
+        let Ok(CanonExpr(Self::Rational(mut rational))) = self.canonicalize() else {
+            return;
+        };
+        *self = match &*rational.denom {
+            [denom] if denom.factors.is_empty() => rational.to_tree(),
+            _ => {
+                rational.numer.shrink_to_fit();
+                rational.denom.shrink_to_fit();
+                Self::Rational(rational)
+            }
+        };
+    }
+
+    /// A bound of the absolute exponents of the rational form, which only grow by multiplying
+    /// the operands, cross-multiplying fractions and raising to powers.
+    fn exponent_bound(&self) -> usize {
+        let sum = |operands: &VecDeque<Operand>| operands.iter().fold(0, |acc: usize, op| acc.saturating_add(op.expr.exponent_bound()));
+        match self {
+            Self::Constant(_) | Self::Hole => 0,
+            Self::Variable(_) => 1,
+            Self::Sum(operands) | Self::Product(operands) => sum(operands),
+            Self::Rational(rational) => {
+                let terms = rational.numer.iter().chain(&rational.denom);
+                let bound = terms.flat_map(|term| &term.factors).map(|f| f.exponent.unsigned_abs()).max();
+                bound.unwrap_or(0)
+            }
+            Self::Pow(base, exponent) => base.exponent_bound().saturating_mul(*exponent as usize),
+            Self::Abs(operand) => operand.exponent_bound(),
+            Self::Mod(lhs, rhs) | Self::Max(lhs, rhs) => lhs.exponent_bound().saturating_add(rhs.exponent_bound()),
+        }
+    }
+
+    /// Whether a power, product or sum of fractions in the expression, with the powers of
+    /// products merged, could expand to more than [`MAX_EXPANDED_TERMS`] terms.
+    fn expands_too_much(&self) -> bool {
+        match self {
+            Self::Constant(_) | Self::Variable(_) | Self::Rational(_) | Self::Hole => false,
+            Self::Sum(operands) => {
+                operands.iter().any(|op| op.expr.expands_too_much()) || {
+                    // Adding fractions cross-multiplies the denominators, so a sum has at most the
+                    // product of the denominator term counts times its numerator terms
+                    let terms = operands.iter().try_fold((0usize, 1usize), |(numer, denom), op| {
+                        let rational = op.expr.to_rational()?;
+                        Some((numer.saturating_add(rational.numer.len()), denom.saturating_mul(rational.denom.len())))
+                    });
+                    terms.is_some_and(|(numer, denom)| denom > 1 && numer.max(1).saturating_mul(denom) > MAX_EXPANDED_TERMS)
+                }
+            }
+            Self::Product(operands) => {
+                let operands = merge_powers(operands);
+                operands.iter().any(|(_, expr)| expr.expands_too_much()) || {
+                    // A product has at most as many terms as the product of the term counts, and
+                    // only grows when two factors have several terms
+                    let counts = operands.iter().map(|(_, expr)| Some(expr.to_rational()?.expanded_terms(1))).collect::<Option<Vec<_>>>();
+                    counts.is_some_and(|counts| {
+                        counts.iter().filter(|&&count| count > 1).count() > 1
+                            && counts.iter().fold(1usize, |product, &count| product.saturating_mul(count)) > MAX_EXPANDED_TERMS
+                    })
+                }
+            }
+            Self::Pow(base, exponent) => {
+                base.expands_too_much() || base.to_rational().is_some_and(|base| base.expanded_terms(*exponent) > MAX_EXPANDED_TERMS)
+            }
+            Self::Abs(operand) => operand.expands_too_much(),
+            Self::Mod(lhs, rhs) | Self::Max(lhs, rhs) => lhs.expands_too_much() || rhs.expands_too_much(),
+        }
+    }
+
+    /// Converts the expression to its rational form.
+    fn to_rational(&self) -> Option<RationalExpression> {
+        match self {
+            Self::Rational(r) => Some(r.clone()),
+            _ => RationalExpression::from_dim(self),
+        }
+    }
+}
+
+impl PartialEq for Expr {
+    fn eq(&self, other: &Self) -> bool {
+        self.equivalent(other) == Some(true)
+    }
+
+    #[allow(clippy::partialeq_ne_impl)]
+    fn ne(&self, other: &Self) -> bool {
+        self.equivalent(other) == Some(false)
+    }
+}
+
+/// 操作数的符号，和式中为加减，积式中为乘除。
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sign {
+    Positive,
+    Negative,
+}
+
+impl Sign {
+    pub fn rev(self) -> Self {
+        match self {
+            Self::Positive => Self::Negative,
+            Self::Negative => Self::Positive,
+        }
+    }
+}
+
+/// 表达式的值是否一定为整数，见 [`Expr::integrality`]。
+#[derive(Clone, Debug)]
+pub enum Integrality {
+    /// Every division is proven exact, so the value is an integer.
+    Always,
+    /// The value is an integer if each `(numerator, divisor)` division is exact, which is
+    /// not proven and must be checked at runtime.
+    IfDivisible(Vec<(Expr, Expr)>),
+    /// The expression has holes, so its divisions are not known yet.
+    Unknown,
+}
+
+#[derive(Clone, Debug)]
+pub struct Operand {
+    ty: Sign,
+    expr: Expr,
+}
+
+impl Operand {
+    pub fn rev_assign(&mut self) {
+        self.ty = self.ty.rev()
+    }
+}
+
+impl Neg for Operand {
+    type Output = Self;
+
+    fn neg(self) -> Self::Output {
+        let Self { ty, expr } = self;
         Self { ty: ty.rev(), expr }
     }
 }
@@ -242,6 +2105,21 @@ impl From<String> for Expr {
     }
 }
 
+/// A rational constant, such as a ratio computed outside of the expression.
+///
+/// ```rust
+/// # use num_rational::Ratio;
+/// # use symbolic_expr::Expr;
+/// assert_eq!(Expr::from(Ratio::new(3, 2)).to_string(), "3/2");
+/// assert_eq!(Expr::from(Ratio::new(-4, 2)).to_string(), "-2");
+/// ```
+impl From<Ratio<isize>> for Expr {
+    fn from(value: Ratio<isize>) -> Self {
+        let rational = RationalExpression::new(vec![CanonicalTerm::new(*value.numer())], vec![CanonicalTerm::new(*value.denom())]);
+        Self::from(rational.normalize())
+    }
+}
+
 macro_rules! impl_op {
     ($op:ty; $fn:ident; positive: $variant: ident) => {
         impl $op for Expr {
@@ -298,6 +2176,36 @@ macro_rules! impl_op {
         }
     };
 
+    ($op:ident; $fn:ident; ref) => {
+        impl $op<&Expr> for &Expr {
+            type Output = Expr;
+            fn $fn(self, rhs: &Expr) -> Self::Output {
+                self.clone().$fn(rhs.clone())
+            }
+        }
+
+        impl $op<&Expr> for Expr {
+            type Output = Self;
+            fn $fn(self, rhs: &Expr) -> Self::Output {
+                self.$fn(rhs.clone())
+            }
+        }
+
+        impl $op<Expr> for &Expr {
+            type Output = Expr;
+            fn $fn(self, rhs: Expr) -> Self::Output {
+                self.clone().$fn(rhs)
+            }
+        }
+
+        impl $op<usize> for &Expr {
+            type Output = Expr;
+            fn $fn(self, rhs: usize) -> Self::Output {
+                self.clone().$fn(Expr::Constant(rhs))
+            }
+        }
+    };
+
     ($op:ident; $fn:ident; usize) => {
         impl $op<usize> for Expr {
             type Output = Self;
@@ -318,23 +2226,98 @@ impl_op!(Sub; sub; usize);
 impl_op!(Mul; mul; usize);
 impl_op!(Div; div; usize);
 
+impl_op!(Add; add; ref);
+impl_op!(Sub; sub; ref);
+impl_op!(Mul; mul; ref);
+impl_op!(Div; div; ref);
+
+/// `lhs + rhs`, or None if either is unknown.
+pub fn opt_add(lhs: Option<Expr>, rhs: Option<Expr>) -> Option<Expr> {
+    Some(lhs? + rhs?)
+}
+
+/// `lhs - rhs`, or None if either is unknown.
+pub fn opt_sub(lhs: Option<Expr>, rhs: Option<Expr>) -> Option<Expr> {
+    Some(lhs? - rhs?)
+}
+
+/// `lhs * rhs`, or None if either is unknown.
+pub fn opt_mul(lhs: Option<Expr>, rhs: Option<Expr>) -> Option<Expr> {
+    Some(lhs? * rhs?)
+}
+
+/// `lhs / rhs`, or None if either is unknown.
+pub fn opt_div(lhs: Option<Expr>, rhs: Option<Expr>) -> Option<Expr> {
+    Some(lhs? / rhs?)
+}
+
+/// The sum of the expressions, `Constant(0)` if there are none, or None if any is unknown.
+pub fn sum_opts(exprs: impl IntoIterator<Item = Option<Expr>>) -> Option<Expr> {
+    let exprs = exprs.into_iter().collect::<Option<Vec<_>>>()?;
+    Some(exprs.into_iter().reduce(Add::add).unwrap_or(Expr::Constant(0)))
+}
+
+/// The product of the expressions, `Constant(1)` if there are none, or None if any is unknown.
+pub fn product_opts(exprs: impl IntoIterator<Item = Option<Expr>>) -> Option<Expr> {
+    let exprs = exprs.into_iter().collect::<Option<Vec<_>>>()?;
+    Some(exprs.into_iter().reduce(Mul::mul).unwrap_or(Expr::Constant(1)))
+}
+
+/// `lhs + rhs`, or the error of `lhs`, then of `rhs`.
+pub fn res_add<E>(lhs: Result<Expr, E>, rhs: Result<Expr, E>) -> Result<Expr, E> {
+    Ok(lhs? + rhs?)
+}
+
+/// `lhs - rhs`, or the error of `lhs`, then of `rhs`.
+pub fn res_sub<E>(lhs: Result<Expr, E>, rhs: Result<Expr, E>) -> Result<Expr, E> {
+    Ok(lhs? - rhs?)
+}
+
+/// `lhs * rhs`, or the error of `lhs`, then of `rhs`.
+pub fn res_mul<E>(lhs: Result<Expr, E>, rhs: Result<Expr, E>) -> Result<Expr, E> {
+    Ok(lhs? * rhs?)
+}
+
+/// `lhs / rhs`, or the error of `lhs`, then of `rhs`.
+pub fn res_div<E>(lhs: Result<Expr, E>, rhs: Result<Expr, E>) -> Result<Expr, E> {
+    Ok(lhs? / rhs?)
+}
+
+/// The sum of the expressions, `Constant(0)` if there are none, or the first error.
+pub fn sum_results<E>(exprs: impl IntoIterator<Item = Result<Expr, E>>) -> Result<Expr, E> {
+    let exprs = exprs.into_iter().collect::<Result<Vec<_>, E>>()?;
+    Ok(exprs.into_iter().reduce(Add::add).unwrap_or(Expr::Constant(0)))
+}
+
+/// The product of the expressions, `Constant(1)` if there are none, or the first error.
+pub fn product_results<E>(exprs: impl IntoIterator<Item = Result<Expr, E>>) -> Result<Expr, E> {
+    let exprs = exprs.into_iter().collect::<Result<Vec<_>, E>>()?;
+    Ok(exprs.into_iter().reduce(Mul::mul).unwrap_or(Expr::Constant(1)))
+}
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 struct Factor {
     base: String,
     exponent: isize,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+/// Factors of a term, stored inline for the common terms of at most two factors.
+#[cfg(feature = "smallvec")]
+type Factors = smallvec::SmallVec<[Factor; 2]>;
+#[cfg(not(feature = "smallvec"))]
+type Factors = Vec<Factor>;
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 struct CanonicalTerm {
     coef: Ratio<isize>,
-    factors: Vec<Factor>,  // sorted factors representing the term
+    factors: Factors,  // sorted factors representing the term
 }
 
 impl CanonicalTerm {
     fn new(coef: isize) -> Self {
         Self {
             coef: Ratio::new(coef, 1),
-            factors: Vec::new(),
+            factors: Factors::new(),
         }
     }
 
@@ -342,11 +2325,65 @@ impl CanonicalTerm {
         self.coef = -self.coef;
     }
 
+    /// Multiplies the coefficient by `value` raised to `exponent`, by repeated squaring.
+    /// Returns None if the exponent exceeds `max_exponent`, `value` is zero with a negative
+    /// exponent, or the coefficient overflows.
+    fn mul_power(&mut self, value: Ratio<isize>, exponent: isize, max_exponent: usize) -> Option<()> {
+        if exponent.unsigned_abs() > max_exponent {
+            return None;
+        }
+        let power = num_traits::checked_pow(value, exponent.unsigned_abs())?;
+        self.coef = if exponent > 0 {
+            num_traits::CheckedMul::checked_mul(&self.coef, &power)?
+        } else {
+            num_traits::CheckedDiv::checked_div(&self.coef, &power)?
+        };
+        Some(())
+    }
+
+    fn validate(&self) -> Result<(), InvariantError> {
+        for pair in self.factors.windows(2) {
+            match pair[0].base.cmp(&pair[1].base) {
+                std::cmp::Ordering::Less => {}
+                std::cmp::Ordering::Equal => return Err(InvariantError::DuplicateFactor(pair[0].base.clone())),
+                std::cmp::Ordering::Greater => return Err(InvariantError::UnsortedFactors),
+            }
+        }
+        if let Some(factor) = self.factors.iter().find(|f| f.exponent == 0) {
+            return Err(InvariantError::ZeroExponent(factor.base.clone()));
+        }
+        if let Some(factor) = self.factors.iter().find(|f| f.exponent.unsigned_abs() > MAX_EXPONENT.unsigned_abs()) {
+            return Err(InvariantError::ExponentOutOfRange(factor.base.clone()));
+        }
+        if self.coef == Ratio::from_integer(0) {
+            return Err(InvariantError::ZeroCoefficient);
+        }
+        Ok(())
+    }
+
     fn with_var(coef: isize, var: String) -> Self {
         Self {
             coef: Ratio::new(coef, 1),
-            factors: vec![Factor { base: var, exponent: 1 }],
+            factors: Factors::from_iter([Factor { base: var, exponent: 1 }]),
+        }
+    }
+
+    /// The derivative with respect to `var`, None if the term does not contain it.
+    fn derivative(&self, var: &str) -> Option<Self> {
+        let exponent = self.exponent_of(var);
+        if exponent == 0 {
+            return None;
         }
+        let power_rule = Self {
+            coef: Ratio::from_integer(exponent),
+            factors: Factors::from_iter([Factor { base: var.to_string(), exponent: -1 }]),
+        };
+        Some(self.multiply(&power_rule))
+    }
+
+    /// The exponent of `base` in this term, `0` if it does not appear.
+    fn exponent_of(&self, base: &str) -> isize {
+        self.factors.iter().filter(|f| f.base == base).map(|f| f.exponent).sum()
     }
 
     // Helper function to check if a term is constant
@@ -364,7 +2401,7 @@ impl CanonicalTerm {
         
         // Sort and combine like factors
         factors.sort_by(|a, b| a.base.cmp(&b.base));
-        let mut combined = Vec::new();
+        let mut combined = Factors::new();
         let mut current: Option<Factor> = None;
         
         for factor in factors {
@@ -406,7 +2443,7 @@ impl CanonicalTerm {
         
         // Sort and combine like factors
         factors.sort_by(|a, b| a.base.cmp(&b.base));
-        let mut combined = Vec::new();
+        let mut combined = Factors::new();
         let mut current: Option<Factor> = None;
         
         for factor in factors {
@@ -436,82 +2473,77 @@ impl CanonicalTerm {
     }
 
     /// Combine like terms by adding coefficients of terms with the same variables.
-    pub fn combine_like_terms(mut terms: Vec<CanonicalTerm>) -> Vec<CanonicalTerm> {
-        // Remove factors with zero exponents from each term
-        terms.iter_mut().for_each(|term| {
-            term.factors.retain(|factor| factor.exponent != 0);
-        });
+    pub fn combine_like_terms(terms: Vec<CanonicalTerm>) -> Vec<CanonicalTerm> {
+        let mut acc = TermAccumulator::default();
+        terms.into_iter().for_each(|term| acc.add(term));
+        acc.into_terms()
+    }
 
-        // Sort terms so that constant terms come first
-        terms.sort_by(|a, b| {
-            let a_is_constant = a.is_constant();
-            let b_is_constant = b.is_constant();
-            
-            // If one term is constant and the other isn't, constant comes first
-            match (a_is_constant, b_is_constant) {
-                (true, false) => std::cmp::Ordering::Less,
-                (false, true) => std::cmp::Ordering::Greater,
-                _ => {
-                    // For non-constant terms, sort by their factors
-                    let mut a_vars: Vec<_> = a.factors.iter().collect();
-                    let mut b_vars: Vec<_> = b.factors.iter().collect();
-                    a_vars.sort();
-                    b_vars.sort();
-                    a_vars.cmp(&b_vars)
-                }
-            }
-        });
-        
-        let mut result = Vec::new();
-        let mut current: Option<CanonicalTerm> = None;
-        
-        for term in terms {
-            match &mut current {
-                Some(prev) if (prev.factors == term.factors) || (prev.is_constant() && term.is_constant()) => {
-                    prev.coef += term.coef;
-                }
-                _ => {
-                    if let Some(prev) = current.take() {
-                        if prev.coef != Ratio::new(0, 1) {
-                            result.push(prev);
-                        }
-                    }
-                    current = Some(term);
-                }
-            }
-        }
-        
-        if let Some(prev) = current {
-            if prev.coef != Ratio::new(0, 1) {
-                result.push(prev);
-            }
-        }
-        
-        result
+    /// [`CanonicalTerm::combine_like_terms`], returning None if a coefficient overflows.
+    fn checked_combine_like_terms(terms: Vec<CanonicalTerm>) -> Option<Vec<CanonicalTerm>> {
+        let mut acc = TermAccumulator::default();
+        terms.into_iter().all(|term| acc.checked_add(term)).then(|| acc.into_terms())
     }
 
     fn sum_terms(terms: &[Self], other: &[Self]) -> Vec<Self> {
-        let mut result = terms.to_owned();
-        result.extend(other.to_owned());
-        CanonicalTerm::combine_like_terms(result)
+        let mut acc = TermAccumulator::default();
+        terms.iter().chain(other).for_each(|term| acc.add(term.clone()));
+        acc.into_terms()
     }
 
-    fn multiply_terms(terms: &Vec<Self>, other: &Vec<Self>) -> Vec<Self> {
-        let mut result_terms = Vec::new();
-        for term1 in terms {
-            for term2 in other {
-                result_terms.push(term1.multiply(term2));
-            }
+    fn multiply_terms(terms: &[Self], other: &[Self]) -> Vec<Self> {
+        let mut acc = TermAccumulator::default();
+        acc.add_product_of(terms, other);
+        acc.into_terms()
+    }
+
+    /// [`CanonicalTerm::multiply_terms`], returning None if the product collects more than
+    /// `max_terms` terms. A side with a single term only scales the other and is not limited.
+    fn multiply_terms_within(terms: &[Self], other: &[Self], max_terms: usize) -> Option<Vec<Self>> {
+        if terms.len() <= 1 || other.len() <= 1 {
+            return Some(Self::multiply_terms(terms, other));
         }
-        CanonicalTerm::combine_like_terms(result_terms)
+        let mut acc = TermAccumulator::with_budget(max_terms);
+        acc.add_product_of(terms, other).then(|| acc.into_terms())
     }
 
-    fn terms_divide_by_term(terms: &Vec<Self>, dividend: &Self) -> Vec<Self> {
-        let mut result_terms = Vec::new();
-        for term in terms {
-            result_terms.push(term.divide(dividend));
+    /// Divides polynomial `terms` by polynomial `divisor` if the division is exact.
+    /// Both must not contain negative exponents.
+    fn terms_divide_exact(terms: &[Self], divisor: &[Self]) -> Option<Vec<Self>> {
+        let lead = divisor.iter().max_by(|a, b| a.lex_cmp(b))?;
+        let mut remainder = CanonicalTerm::combine_like_terms(terms.to_vec());
+        let mut quotient = Vec::new();
+        // The leading term of the remainder strictly decreases, so this terminates
+        while let Some(lead_remainder) = remainder.iter().max_by(|a, b| a.lex_cmp(b)) {
+            let term = lead_remainder.divide(lead);
+            if term.factors.iter().any(|f| f.exponent < 0) {
+                return None;
+            }
+            let product = divisor.iter().map(|d| {
+                let mut product = d.multiply(&term);
+                product.neg();
+                product
+            });
+            remainder = CanonicalTerm::sum_terms(&remainder, &product.collect::<Vec<_>>());
+            quotient.push(term);
         }
-        CanonicalTerm::combine_like_terms(result_terms)
+        Some(CanonicalTerm::combine_like_terms(quotient))
+    }
+
+    /// Compares monomials in lexicographic order of their exponents, variables taken alphabetically.
+    fn lex_cmp(&self, other: &Self) -> std::cmp::Ordering {
+        let bases = self.factors.iter().chain(&other.factors).map(|f| &*f.base).collect::<BTreeSet<_>>();
+        bases
+            .into_iter()
+            .map(|base| self.exponent_of(base).cmp(&other.exponent_of(base)))
+            .find(|ord| ord.is_ne())
+            .unwrap_or(std::cmp::Ordering::Equal)
+    }
+
+    fn terms_divide_by_term(terms: &[Self], dividend: &Self) -> Vec<Self> {
+        let mut acc = TermAccumulator::default();
+        terms.iter().for_each(|term| acc.add(term.divide(dividend)));
+        acc.into_terms()
     }
 
 }
@@ -546,7 +2578,66 @@ impl Ord for Factor {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+/// Sums terms by their factors without collecting them first, so products of long sums do
+/// not allocate every partial product and sort them.
+///
+/// Terms come out as [`CanonicalTerm::combine_like_terms`] orders them: the constant term
+/// first, then by factors, without terms whose coefficients cancel.
+#[derive(Default)]
+struct TermAccumulator {
+    terms: BTreeMap<Factors, Ratio<isize>>,
+    /// Most distinct terms collected by [`TermAccumulator::add_product_of`], unbounded if None.
+    budget: Option<usize>,
+}
+
+impl TermAccumulator {
+    fn with_budget(budget: usize) -> Self {
+        Self { budget: Some(budget), ..Self::default() }
+    }
+
+    fn add(&mut self, term: CanonicalTerm) {
+        let (coef, term) = self.entry(term);
+        *coef += term;
+    }
+
+    /// [`TermAccumulator::add`], returning false and leaving the sum unchanged if the
+    /// coefficient overflows.
+    fn checked_add(&mut self, term: CanonicalTerm) -> bool {
+        let (coef, term) = self.entry(term);
+        num_traits::CheckedAdd::checked_add(&*coef, &term).map(|sum| *coef = sum).is_some()
+    }
+
+    /// The coefficient the term is added to, and the coefficient of the term.
+    fn entry(&mut self, mut term: CanonicalTerm) -> (&mut Ratio<isize>, Ratio<isize>) {
+        term.factors.retain(|factor| factor.exponent != 0);
+        term.factors.sort();
+        (self.terms.entry(term.factors).or_insert_with(|| Ratio::from_integer(0)), term.coef)
+    }
+
+    /// Adds every product of a term of `lhs` and a term of `rhs`.
+    /// Returns false, leaving the sum partial, as soon as there are more terms than the budget.
+    fn add_product_of(&mut self, lhs: &[CanonicalTerm], rhs: &[CanonicalTerm]) -> bool {
+        for a in lhs {
+            for b in rhs {
+                self.add(a.multiply(b));
+                if self.budget.is_some_and(|budget| self.terms.len() > budget) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    fn into_terms(self) -> Vec<CanonicalTerm> {
+        self.terms
+            .into_iter()
+            .filter(|(_, coef)| *coef != Ratio::from_integer(0))
+            .map(|(factors, coef)| CanonicalTerm { coef, factors })
+            .collect()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub struct RationalExpression {
     numer: Vec<CanonicalTerm>,
     denom: Vec<CanonicalTerm>,
@@ -554,13 +2645,15 @@ pub struct RationalExpression {
 
 impl RationalExpression {
     fn new_zero() -> Self {
-        Self { numer: vec![CanonicalTerm::new(0)], denom: vec![CanonicalTerm::new(1)] }
+        Self { numer: Vec::new(), denom: vec![CanonicalTerm::new(1)] }
     }
 
     fn new_one() -> Self {
         Self { numer: vec![CanonicalTerm::new(1)], denom: vec![CanonicalTerm::new(1)] }
     }
 
+    /// Builds the expression from terms as they are. The terms must have sorted, distinct
+    /// factors with non-zero exponents; [`RationalExpression::new_validated`] checks this.
     fn new(numer: Vec<CanonicalTerm>, denom: Vec<CanonicalTerm>) -> Self {
         assert!(!denom.is_empty(), "Denominator cannot be empty in RationalExpression");
         Self { numer, denom }
@@ -570,10 +2663,153 @@ impl RationalExpression {
         self.numer.iter_mut().for_each(CanonicalTerm::neg);
     }
 
+    /// The sum over the product of the denominators.
+    /// Returns None if a cross product has more than [`MAX_EXPANDED_TERMS`] terms.
+    fn add(&self, other: &Self) -> Option<Self> {
+        let multiply = |lhs: &[CanonicalTerm], rhs: &[CanonicalTerm]| CanonicalTerm::multiply_terms_within(lhs, rhs, MAX_EXPANDED_TERMS);
+        Some(Self::new(
+            CanonicalTerm::sum_terms(&multiply(&self.numer, &other.denom)?, &multiply(&other.numer, &self.denom)?),
+            multiply(&self.denom, &other.denom)?,
+        ))
+    }
+
+    fn mul(&self, other: &Self) -> Self {
+        Self::new(CanonicalTerm::multiply_terms(&self.numer, &other.numer), CanonicalTerm::multiply_terms(&self.denom, &other.denom))
+    }
+
+    /// The terms of `self/other` if the normal form of the quotient is a polynomial, behind
+    /// [`Expr::divides`] and [`Expr::proportional_to`]. Returns None if `other` is zero.
+    fn polynomial_quotient(&self, other: &Self) -> Option<Vec<CanonicalTerm>> {
+        let numer = CanonicalTerm::multiply_terms(&self.numer, &other.denom);
+        let denom = CanonicalTerm::multiply_terms(&self.denom, &other.numer);
+        let quotient = Self { numer, denom }.normalize();
+        if quotient.denom.is_empty() {
+            return None;
+        }
+        CanonicalTerm::terms_divide_exact(&quotient.numer, &quotient.denom)
+    }
+
+    /// The derivative with respect to `var` by the quotient rule, normalized.
+    fn derivative(&self, var: &str) -> Self {
+        let derive = |terms: &[CanonicalTerm]| terms.iter().filter_map(|term| term.derivative(var)).collect::<Vec<_>>();
+        let (numer, denom) = (derive(&self.numer), derive(&self.denom));
+        if denom.is_empty() {
+            return Self::new(numer, self.denom.clone()).normalize();
+        }
+        let mut result = CanonicalTerm::multiply_terms(&numer, &self.denom);
+        let mut minus = CanonicalTerm::multiply_terms(&self.numer, &denom);
+        minus.iter_mut().for_each(CanonicalTerm::neg);
+        result.extend(minus);
+        Self::new(result, CanonicalTerm::multiply_terms(&self.denom, &self.denom)).normalize()
+    }
+
     fn invert(&mut self) {
         std::mem::swap(&mut self.numer, &mut self.denom);
     }
 
+    /// Brings the expression into its normal form: numerator and denominator are polynomials
+    /// with combined like terms and no common monomial factor, and the leading denominator
+    /// coefficient is `1`. Zero is normalized to `0/1`.
+    fn normalize(&self) -> Self {
+        self.normalize_with(|terms| Some(CanonicalTerm::combine_like_terms(terms)), |coef, lead| Some(coef / lead)).unwrap()
+    }
+
+    /// [`RationalExpression::normalize`], returning None if a coefficient overflows.
+    fn checked_normalize(&self) -> Option<Self> {
+        self.normalize_with(CanonicalTerm::checked_combine_like_terms, |coef, lead| num_traits::CheckedDiv::checked_div(&coef, &lead))
+    }
+
+    /// Normalizes with the given ways to combine like terms and to divide coefficients.
+    fn normalize_with(
+        &self,
+        combine: impl Fn(Vec<CanonicalTerm>) -> Option<Vec<CanonicalTerm>>,
+        divide: impl Fn(Ratio<isize>, Ratio<isize>) -> Option<Ratio<isize>>,
+    ) -> Option<Self> {
+        let numer = combine(self.numer.clone())?;
+        if numer.is_empty() {
+            return Some(Self::new(vec![], vec![CanonicalTerm::new(1)]));
+        }
+        let denom = combine(self.denom.clone())?;
+        if denom.is_empty() {
+            // Denominator is identically zero, there is nothing to normalize against
+            return Some(Self { numer, denom });
+        }
+
+        // Move negative exponents to the other side and cancel common monomial factors
+        // by dividing every term by the minimum power of each variable
+        let terms = numer.iter().chain(&denom);
+        let mut shift = CanonicalTerm::new(1);
+        for term in terms.clone() {
+            for factor in &term.factors {
+                let min = terms.clone().map(|term| term.exponent_of(&factor.base)).min().unwrap();
+                if min != 0 && !shift.factors.iter().any(|f| f.base == factor.base) {
+                    shift.factors.push(Factor { base: factor.base.clone(), exponent: min });
+                }
+            }
+        }
+        let mut numer = CanonicalTerm::terms_divide_by_term(&numer, &shift);
+        let mut denom = CanonicalTerm::terms_divide_by_term(&denom, &shift);
+
+        // Scale so that the leading denominator coefficient is 1
+        let lead = denom[0].coef;
+        for term in numer.iter_mut().chain(&mut denom) {
+            term.coef = divide(term.coef, lead)?;
+        }
+        Some(Self::new(numer, denom))
+    }
+
+    /// Checks if two rational expressions are permanently equivalent by cross-multiplying
+    /// their normal forms, see [`Expr::equivalent`].
+    fn equivalent(&self, other: &Self) -> Option<bool> {
+        let (diff, _) = self.difference(other)?;
+        match &*diff {
+            [] => Some(true),
+            [term] if term.is_constant() => Some(false),
+            _ => None,
+        }
+    }
+
+    /// The value of `self - other` if it is a constant.
+    fn constant_difference(&self, other: &Self) -> Option<Ratio<isize>> {
+        let (diff, denoms) = self.difference(other)?;
+        match &*diff {
+            [] => Some(Ratio::from_integer(0)),
+            [term] if term.is_constant() => denoms.iter().try_fold(term.coef, |acc, denom| match &**denom {
+                [denom] if denom.is_constant() => num_traits::CheckedDiv::checked_div(&acc, &denom.coef),
+                _ => None,
+            }),
+            _ => None,
+        }
+    }
+
+    /// The numerator of `self - other`, with the denominators it is not divisible by.
+    /// Returns None if either expression has an identically zero denominator.
+    fn difference(&self, other: &Self) -> Option<(Vec<CanonicalTerm>, Vec<Vec<CanonicalTerm>>)> {
+        let lhs = self.normalize();
+        let rhs = other.normalize();
+        // An identically zero denominator leaves the expression undefined everywhere
+        if lhs.denom.is_empty() || rhs.denom.is_empty() {
+            return None;
+        }
+
+        // Normal forms have no negative exponents, so the cross difference is a polynomial
+        let mut cross = CanonicalTerm::multiply_terms(&rhs.numer, &lhs.denom);
+        cross.iter_mut().for_each(CanonicalTerm::neg);
+        let mut diff = CanonicalTerm::sum_terms(&CanonicalTerm::multiply_terms(&lhs.numer, &rhs.denom), &cross);
+        if diff.is_empty() {
+            return Some((diff, Vec::new()));
+        }
+        // Cancel the denominators the difference is divisible by, the rest can not be zero if constant
+        let mut denoms = Vec::new();
+        for denom in [lhs.denom, rhs.denom] {
+            match CanonicalTerm::terms_divide_exact(&diff, &denom) {
+                Some(quotient) => diff = quotient,
+                None => denoms.push(denom),
+            }
+        }
+        Some((diff, denoms))
+    }
+
     fn simplify(&self) -> Self {
         // If denominator has only one term, we can simplify by dividing each numerator term
         if self.denom.len() == 1 {
@@ -601,7 +2837,36 @@ impl RationalExpression {
         }
     }
 
+    /// Gcd of the numerator coefficients over the lcm of their denominators.
+    /// Returns None if the denominator is not a constant once its monomial is divided out.
+    fn content(&self) -> Option<Ratio<isize>> {
+        if self.denom.len() != 1 {
+            return None;
+        }
+        // With a single-term denominator, simplify divides it into the numerator;
+        // variables left with negative exponents mean the denominator is not constant
+        let simplified = self.simplify();
+        if simplified.numer.iter().any(|term| term.factors.iter().any(|f| f.exponent < 0)) {
+            return None;
+        }
+        let (gcd, lcm) = simplified.numer.iter().fold((0, 1), |(gcd, lcm), term| {
+            (gcd.gcd(term.coef.numer()), lcm.lcm(term.coef.denom()))
+        });
+        Some(Ratio::new(gcd, lcm))
+    }
+
+    /// Converts an expression to rational form.
+    /// Returns None if the expression divides by an identically zero expression, its
+    /// exponents could exceed [`MAX_EXPONENT`], or a power, product or sum of fractions expands
+    /// to more than [`MAX_EXPANDED_TERMS`] terms.
     fn from_dim(dim: &Expr) -> Option<Self> {
+        if dim.exponent_bound() > MAX_EXPONENT.unsigned_abs() {
+            return None;
+        }
+        Self::from_tree(dim)
+    }
+
+    fn from_tree(dim: &Expr) -> Option<Self> {
         match dim {
             Expr::Constant(value) => Some(Self::new(vec![CanonicalTerm::new(*value as isize)], vec![CanonicalTerm::new(1)])),
             Expr::Variable(name) => Some(Self::new(vec![CanonicalTerm::with_var(1, name.clone())], vec![CanonicalTerm::new(1)])),
@@ -609,68 +2874,261 @@ impl RationalExpression {
                 let mut result = RationalExpression::new_zero();
                 for operand in operands {
                     let sign = match operand.ty {
-                        Type::Positive => 1,
-                        Type::Negative => -1,
+                        Sign::Positive => 1,
+                        Sign::Negative => -1,
                     };
-                    let mut rational = RationalExpression::from_dim(&operand.expr)?;
+                    let mut rational = Self::from_tree(&operand.expr)?;
                     if sign == -1 {
                         rational.neg();
                     }
-                    result = RationalExpression::new(
-                        CanonicalTerm::sum_terms(&CanonicalTerm::multiply_terms(&result.numer, &rational.denom), &CanonicalTerm::multiply_terms(&rational.numer, &result.denom)),
-                        CanonicalTerm::multiply_terms(&result.denom, &rational.denom),
-                    );
+                    result = result.add(&rational)?;
                 }
                 Some(result)
             }
             Expr::Product(operands) => {
                 let mut result = RationalExpression::new_one();
 
-                for operand in operands {
-                    let sign = match operand.ty {
-                        Type::Positive => 1,
-                        Type::Negative => -1,
+                for (ty, expr) in merge_powers(operands) {
+                    let sign = match ty {
+                        Sign::Positive => 1,
+                        Sign::Negative => -1,
                     };
-                    let mut rational = RationalExpression::from_dim(&operand.expr)?;
+                    let mut rational = Self::from_tree(&expr)?;
                     if sign == -1 {
+                        // Division by an identically zero expression is undefined
+                        if CanonicalTerm::combine_like_terms(rational.numer.clone()).is_empty() {
+                            return None;
+                        }
                         rational.invert();
                     }
 
                     if rational.denom.len() > 1 {
                         result = RationalExpression::new(
-                            CanonicalTerm::multiply_terms(&result.numer, &rational.numer),
-                            CanonicalTerm::multiply_terms(&result.denom, &rational.denom),
+                            CanonicalTerm::multiply_terms_within(&result.numer, &rational.numer, MAX_EXPANDED_TERMS)?,
+                            CanonicalTerm::multiply_terms_within(&result.denom, &rational.denom, MAX_EXPANDED_TERMS)?,
                         );
                     } else {
-                        result.numer = CanonicalTerm::multiply_terms(&result.numer, &rational.numer);
+                        result.numer = CanonicalTerm::multiply_terms_within(&result.numer, &rational.numer, MAX_EXPANDED_TERMS)?;
                         result.numer = CanonicalTerm::terms_divide_by_term(&result.numer, &rational.denom[0]);
                     }
                 }
                 Some(result)
             }
             Expr::Rational(rational) => Some(rational.clone()),
+            Expr::Pow(base, exponent) => {
+                let base = Self::from_tree(base)?;
+                if base.expanded_terms(*exponent) > MAX_EXPANDED_TERMS {
+                    return None;
+                }
+                Some(base.pow(*exponent))
+            }
+            Expr::Mod(lhs, modulus) => {
+                let lhs = Self::from_tree(lhs)?;
+                let modulus = Self::from_tree(modulus)?;
+                if lhs.equivalent(&modulus) == Some(true) {
+                    return Some(Self::new_zero());
+                }
+                // Fold known constants, anything else has no rational form
+                let (lhs, modulus) = (lhs.constant()?, modulus.constant()?);
+                if !lhs.is_integer() || !modulus.is_integer() || *modulus.numer() <= 0 {
+                    return None;
+                }
+                let value = lhs.numer().mod_floor(modulus.numer());
+                Some(Self::new(vec![CanonicalTerm::new(value)], vec![CanonicalTerm::new(1)]))
+            }
+            Expr::Abs(operand) => {
+                // Only a known constant has a rational form
+                let value = num_traits::Signed::abs(&Self::from_tree(operand)?.constant()?);
+                Some(Self::new(vec![CanonicalTerm { coef: value, factors: Factors::new() }], vec![CanonicalTerm::new(1)]))
+            }
+            Expr::Max(lhs, rhs) => {
+                let lhs = Self::from_tree(lhs)?;
+                let rhs = Self::from_tree(rhs)?;
+                if lhs.equivalent(&rhs) == Some(true) {
+                    return Some(lhs);
+                }
+                // Fold known constants, anything else has no rational form
+                let value = lhs.constant()?.max(rhs.constant()?);
+                Some(Self::new(vec![CanonicalTerm { coef: value, factors: Factors::new() }], vec![CanonicalTerm::new(1)]))
+            }
+            Expr::Hole => None,
+        }
+    }
+
+    /// Converts the expression back into a tree of sums and products, positive terms first.
+    ///
+    /// The tree divides only once, by the denominator of the normal form scaled to integer
+    /// coefficients, so it evaluates exactly where the rational form evaluates to an integer.
+    fn to_tree(&self) -> Expr {
+        fn term_tree(term: &CanonicalTerm) -> Expr {
+            let numer = term.coef.numer().unsigned_abs();
+            let mut operands = term
+                .factors
+                .iter()
+                .map(|factor| match factor.exponent {
+                    1 => Expr::var(&factor.base),
+                    exponent => Expr::var(&factor.base).pow(exponent as u32),
+                })
+                .collect::<Vec<_>>();
+            if numer != 1 || operands.is_empty() {
+                operands.insert(0, Expr::Constant(numer));
+            }
+            operands.into_iter().reduce(Mul::mul).unwrap()
+        }
+
+        fn terms_tree(terms: &[CanonicalTerm]) -> Expr {
+            let (positive, negative): (Vec<_>, Vec<_>) = terms.iter().partition(|t| t.coef > Ratio::from_integer(0));
+            let positive = positive.into_iter().map(term_tree).reduce(Add::add);
+            let negative = negative.into_iter().map(term_tree).reduce(Add::add);
+            match (positive, negative) {
+                (None, None) => Expr::Constant(0),
+                (Some(positive), None) => positive,
+                (positive, Some(negative)) => positive.unwrap_or(Expr::Constant(0)) - negative,
+            }
+        }
+
+        let rational = self.normalize();
+        let lcm = rational.numer.iter().chain(&rational.denom).fold(1, |lcm, term| lcm.lcm(term.coef.denom()));
+        let scale = |terms: &[CanonicalTerm]| {
+            terms.iter().map(|term| CanonicalTerm { coef: term.coef * lcm, factors: term.factors.clone() }).collect::<Vec<_>>()
+        };
+        let numer = terms_tree(&scale(&rational.numer));
+        let denom = scale(&rational.denom);
+        if denom == [CanonicalTerm::new(1)] {
+            numer
+        } else {
+            numer / terms_tree(&denom)
+        }
+    }
+
+    /// How every variable appears in the normal form: for each term containing it, the side,
+    /// its exponent, the coefficient and the number of factors, sorted.
+    fn variable_signatures(&self) -> BTreeMap<String, VariableSignature> {
+        let mut signatures = BTreeMap::<String, Vec<_>>::new();
+        for (denom, term) in self.numer.iter().map(|t| (false, t)).chain(self.denom.iter().map(|t| (true, t))) {
+            for factor in &term.factors {
+                let signature = (denom, factor.exponent, term.coef, term.factors.len());
+                signatures.entry(factor.base.clone()).or_default().push(signature);
+            }
+        }
+        signatures.values_mut().for_each(|signature| signature.sort_unstable());
+        signatures
+    }
+
+    /// Groups the terms of a normal form by the denominator of their coefficient, scaling them
+    /// to integer coefficients, see [`Expr::to_mixed_form`].
+    /// Returns None if the denominator has several terms.
+    fn mixed_groups(&self) -> Option<Vec<(Vec<CanonicalTerm>, usize)>> {
+        let [denom] = &*self.denom else {
+            return None;
+        };
+        let mut groups = BTreeMap::<usize, Vec<CanonicalTerm>>::new();
+        let mut divided = Vec::new();
+        for term in self.numer.iter().map(|term| term.divide(denom)) {
+            if term.factors.iter().any(|f| f.exponent < 0) {
+                divided.push((vec![term], 1));
+            } else {
+                let scale = *term.coef.denom();
+                let scaled = CanonicalTerm { coef: term.coef * scale, factors: term.factors };
+                groups.entry(scale.unsigned_abs()).or_default().push(scaled);
+            }
+        }
+        Some(groups.into_iter().map(|(denom, terms)| (terms, denom)).chain(divided).collect())
+    }
+
+    /// The highest power of `var` in `terms`, which are normalized and have no negative exponents.
+    fn degree_in(terms: &[CanonicalTerm], var: &str) -> isize {
+        terms.iter().map(|term| term.exponent_of(var)).max().unwrap_or(0)
+    }
+
+    /// The degree in `var` of the normal form, numerator minus denominator, with the ratio of
+    /// their leading coefficients if it is constant. Zero has the lowest degree.
+    /// Returns None if the denominator is identically zero.
+    fn growth_in(&self, var: &str) -> Option<(isize, Option<Ratio<isize>>)> {
+        let leading = |terms: &[CanonicalTerm]| {
+            let degree = Self::degree_in(terms, var);
+            let mut top = terms.iter().filter(|term| term.exponent_of(var) == degree);
+            let coef = match (top.next(), top.next()) {
+                (Some(term), None) if term.factors.iter().all(|f| f.base == var) => Some(term.coef),
+                _ => None,
+            };
+            (degree, coef)
+        };
+
+        let rational = self.normalize();
+        if rational.denom.is_empty() {
+            return None;
+        }
+        if rational.numer.is_empty() {
+            return Some((isize::MIN, Some(Ratio::from_integer(0))));
+        }
+        let (numer_degree, numer_coef) = leading(&rational.numer);
+        let (denom_degree, denom_coef) = leading(&rational.denom);
+        Some((numer_degree - denom_degree, numer_coef.zip(denom_coef).map(|(numer, denom)| numer / denom)))
+    }
+
+    /// The value of an expression without variables.
+    fn constant(&self) -> Option<Ratio<isize>> {
+        let normalized = self.normalize();
+        match (&*normalized.numer, &*normalized.denom) {
+            ([], _) => Some(Ratio::from_integer(0)),
+            ([numer], [denom]) if numer.is_constant() && denom.is_constant() => Some(numer.coef / denom.coef),
+            _ => None,
+        }
+    }
+
+    /// A bound of the number of terms of the numerator or denominator of the `k`-th power: a sum
+    /// of `t` terms raised to `k` has at most `C(k + t - 1, t - 1)` monomials.
+    fn expanded_terms(&self, k: u32) -> usize {
+        let monomials = |terms: usize| {
+            let mut count = 1usize;
+            for i in 1..terms {
+                // count is C(k + i - 1, i - 1), so the division is exact
+                count = count.saturating_mul(k as usize + i) / i;
+                if count > MAX_EXPANDED_TERMS {
+                    break;
+                }
+            }
+            count
+        };
+        monomials(self.numer.len()).max(monomials(self.denom.len()))
+    }
+
+    /// Raises the expression to the `k`-th power.
+    ///
+    /// The term lists are squared repeatedly and like terms are combined after each
+    /// multiplication, so only `O(log k)` multiplications of term lists are needed.
+    pub fn pow(&self, k: u32) -> Self {
+        fn pow_terms(terms: &[CanonicalTerm], mut k: u32) -> Vec<CanonicalTerm> {
+            let mut result = vec![CanonicalTerm::new(1)];
+            let mut base = terms.to_vec();
+            while k > 0 {
+                if k & 1 == 1 {
+                    result = CanonicalTerm::multiply_terms(&result, &base);
+                }
+                k >>= 1;
+                if k > 0 {
+                    base = CanonicalTerm::multiply_terms(&base, &base);
+                }
+            }
+            result
         }
+
+        Self::new(pow_terms(&self.numer, k), pow_terms(&self.denom, k))
     }
 
     /// Substitute variables with their values in the rational expression.
-    /// Returns None if any variable in the expression is not found in the substitution map.
+    /// Returns None if any variable in the expression is not found in the substitution map,
+    /// or a power cannot be computed, see [`RationalExpression::partial_substitute`].
     pub fn substitute(&self, value: &HashMap<&str, usize>) -> Option<Ratio<isize>> {
         // Helper function to substitute a single term
         fn substitute_term(term: &CanonicalTerm, value: &HashMap<&str, usize>) -> Option<Ratio<isize>> {
-            let mut result = term.coef;
+            let mut result = CanonicalTerm { coef: term.coef, factors: Factors::new() };
             for factor in &term.factors {
-                let var_value = value.get(factor.base.as_str())?;
-                if factor.exponent > 0 {
-                    for _ in 0..factor.exponent {
-                        result *= Ratio::from_integer(*var_value as isize);
-                    }
-                } else {
-                    for _ in 0..-factor.exponent {
-                        result /= Ratio::from_integer(*var_value as isize);
-                    }
-                }
+                let var_value = isize::try_from(*value.get(factor.base.as_str())?).ok()?;
+                result.mul_power(Ratio::from_integer(var_value), factor.exponent, MAX_EXPONENT.unsigned_abs())?;
             }
-            Some(result)
+            Some(result.coef)
         }
 
         // Substitute numerator terms
@@ -689,49 +3147,216 @@ impl RationalExpression {
         Some(numer_value / denom_value)
     }
 
-    /// Partially substitute variables with their values.
-    /// Returns None if any substituted variable results in a non-integer value.
+    /// Partially substitute variables with their values, see [`RationalExpression::partial_substitute_ratio`].
+    /// Returns None if a substituted factor has an exponent beyond [`MAX_EXPONENT`], a zero
+    /// value under a negative exponent, or a coefficient overflows. Powers are computed by
+    /// repeated squaring, so large exponents fail fast instead of looping.
     pub fn partial_substitute(&self, value: &HashMap<&str, usize>) -> Option<Self> {
-        // Helper function to substitute a single term
-        fn substitute_term(term: &CanonicalTerm, value: &HashMap<&str, usize>) -> Option<CanonicalTerm> {
-            let mut result = term.clone();
-            for factor in &mut result.factors {
-                if let Some(&var_value) = value.get(factor.base.as_str()) {
-                    if factor.exponent > 0 {
-                        for _ in 0..factor.exponent {
-                            result.coef *= Ratio::from_integer(var_value as isize);
-                        }
-                    } else {
-                        for _ in 0..-factor.exponent {
-                            result.coef /= Ratio::from_integer(var_value as isize);
-                        }
-                    }
-                    // Remove the substituted factor
-                    factor.exponent = 0;
+        self.partial_substitute_with(value, MAX_EXPONENT.unsigned_abs())
+    }
+
+    /// [`RationalExpression::partial_substitute`] with the exponent limit `max_exponent`
+    /// rather than [`MAX_EXPONENT`].
+    pub fn partial_substitute_with(&self, value: &HashMap<&str, usize>, max_exponent: usize) -> Option<Self> {
+        // Only the variables of the expression need to fit in isize
+        let value = self
+            .numer
+            .iter()
+            .chain(&self.denom)
+            .flat_map(|term| &term.factors)
+            .filter_map(|factor| {
+                let (&name, &value) = value.get_key_value(factor.base.as_str())?;
+                Some(isize::try_from(value).ok().map(|value| (name, Ratio::from_integer(value))))
+            })
+            .collect::<Option<HashMap<_, _>>>()?;
+        self.partial_substitute_ratio_with(&value, max_exponent)
+    }
+
+    /// Partially substitute variables with rational values, keeping the result exact.
+    /// Returns None if the denominator becomes zero, a factor with a negative exponent is
+    /// substituted with zero, a substituted factor has an exponent beyond [`MAX_EXPONENT`],
+    /// or a coefficient overflows.
+    pub fn partial_substitute_ratio(&self, value: &HashMap<&str, Ratio<isize>>) -> Option<Self> {
+        self.partial_substitute_ratio_with(value, MAX_EXPONENT.unsigned_abs())
+    }
+
+    /// [`RationalExpression::partial_substitute_ratio`] with the exponent limit `max_exponent`
+    /// rather than [`MAX_EXPONENT`].
+    pub fn partial_substitute_ratio_with(&self, value: &HashMap<&str, Ratio<isize>>, max_exponent: usize) -> Option<Self> {
+        let substitute = |terms: &[CanonicalTerm]| {
+            let mut acc = TermAccumulator::default();
+            for term in terms {
+                let mut result = CanonicalTerm { coef: term.coef, factors: Factors::new() };
+                for factor in &term.factors {
+                    let Some(var_value) = value.get(&*factor.base) else {
+                        result.factors.push(factor.clone());
+                        continue;
+                    };
+                    result.mul_power(*var_value, factor.exponent, max_exponent)?;
+                }
+                if !acc.checked_add(result) {
+                    return None;
                 }
             }
-            // Remove factors with zero exponents
-            result.factors.retain(|f| f.exponent != 0);
-            Some(result)
+            Some(acc.into_terms())
+        };
+        let numer = substitute(&self.numer)?;
+        let denom = substitute(&self.denom)?;
+        if denom.is_empty() {
+            return None;
         }
+        // Simplifying divides the numerator by a single denominator term
+        if let [denom] = &*denom
+            && numer.iter().any(|term| num_traits::CheckedDiv::checked_div(&term.coef, &denom.coef).is_none())
+        {
+            return None;
+        }
+        Some(Self::new(numer, denom).simplify())
+    }
 
-        let mut new_numer = Vec::new();
-        for term in &self.numer {
-            new_numer.push(substitute_term(term, value)?);
+    /// The numerator terms, in the deterministic canonical order: constant terms first,
+    /// then ordered by their factors.
+    ///
+    /// The terms are those of this expression as stored; use [`Expr::canonicalize`] to get
+    /// the normalized form.
+    ///
+    /// ```rust
+    /// # use symbolic_expr::Expr;
+    /// let a = Expr::var("a");
+    /// let b = Expr::var("b");
+    /// let canon = (a.clone() * a.clone() * b.clone() * 2 + 3).canonicalize().unwrap();
+    /// let Expr::Rational(rational) = &*canon else { unreachable!() };
+    ///
+    /// // Highest terms first
+    /// let terms = rational.numer_terms().rev().map(|term| {
+    ///     let coef = term.coef().to_string();
+    ///     let powers = term.factors().map(|(base, exponent)| match exponent {
+    ///         1 => base.to_string(),
+    ///         _ => format!("{base}^{exponent}"),
+    ///     });
+    ///     std::iter::once(coef).chain(powers).collect::<Vec<_>>().join("*")
+    /// });
+    /// assert_eq!(terms.collect::<Vec<_>>().join(" + "), "2*a^2*b + 3");
+    /// ```
+    pub fn numer_terms(&self) -> impl DoubleEndedIterator<Item = TermView<'_>> + ExactSizeIterator {
+        self.numer.iter().map(TermView)
+    }
+
+    /// The denominator terms, in the same order as [`RationalExpression::numer_terms`].
+    pub fn denom_terms(&self) -> impl DoubleEndedIterator<Item = TermView<'_>> + ExactSizeIterator {
+        self.denom.iter().map(TermView)
+    }
+
+    /// Builds the expression from terms, checking that the factors of every term are sorted
+    /// by variable name without repeats, that coefficients and exponents are non-zero, that
+    /// exponents are within [`MAX_EXPONENT`], and that the denominator is not zero. The terms are kept as they are, see [`RationalExpression::new_normalized`]
+    /// to accept any terms.
+    ///
+    /// ```rust
+    /// # use num_rational::Ratio;
+    /// # use symbolic_expr::{Expr, InvariantError, RationalExpression};
+    /// let term = |coef, factors: &[(&str, isize)]| {
+    ///     (Ratio::from_integer(coef), factors.iter().map(|&(base, e)| (base.to_string(), e)).collect())
+    /// };
+    /// let rational = RationalExpression::new_validated(vec![term(2, &[("a", 1), ("b", 1)])], vec![term(1, &[])]);
+    /// assert_eq!(Expr::Rational(rational.unwrap()).to_string(), "2*a*b");
+    /// let unsorted = RationalExpression::new_validated(vec![term(2, &[("b", 1), ("a", 1)])], vec![term(1, &[])]);
+    /// assert_eq!(unsorted.unwrap_err(), InvariantError::UnsortedFactors);
+    /// ```
+    pub fn new_validated(numer: Vec<RawTerm>, denom: Vec<RawTerm>) -> Result<Self, InvariantError> {
+        let build = |terms: Vec<RawTerm>| {
+            terms
+                .into_iter()
+                .map(|(coef, factors)| {
+                    let factors = factors.into_iter().map(|(base, exponent)| Factor { base, exponent }).collect();
+                    CanonicalTerm { coef, factors }
+                })
+                .collect()
+        };
+        // Built directly, the checks replace the assertions of `new`
+        let rational = Self { numer: build(numer), denom: build(denom) };
+        rational.validate()?;
+        Ok(rational)
+    }
+
+    /// Checks the invariants of the terms, see [`Expr::validate`].
+    pub fn validate(&self) -> Result<(), InvariantError> {
+        for term in self.numer.iter().chain(&self.denom) {
+            term.validate()?;
         }
+        if CanonicalTerm::combine_like_terms(self.denom.clone()).is_empty() {
+            return Err(InvariantError::ZeroDenominator);
+        }
+        Ok(())
+    }
 
-        let mut new_denom = Vec::new();
-        for term in &self.denom {
-            new_denom.push(substitute_term(term, value)?);
+    /// Builds the expression from any terms, merging repeated factors and dropping zero
+    /// exponents, then normalizes it. Fails if the denominator is zero, a coefficient overflows
+    /// while combining the terms, or an exponent is out of range before or after merging.
+    pub fn new_normalized(numer: Vec<RawTerm>, denom: Vec<RawTerm>) -> Result<Self, InvariantError> {
+        let build = |terms: Vec<RawTerm>| {
+            terms
+                .into_iter()
+                .map(|(coef, factors)| {
+                    let term = CanonicalTerm { coef, factors: Factors::new() };
+                    let term = factors.into_iter().try_fold(term, |term, (base, exponent)| {
+                        if exponent.unsigned_abs() > MAX_EXPONENT.unsigned_abs() {
+                            return Err(InvariantError::ExponentOutOfRange(base));
+                        }
+                        let factor = CanonicalTerm { coef: Ratio::from_integer(1), factors: Factors::from_iter([Factor { base, exponent }]) };
+                        Ok(term.multiply(&factor))
+                    })?;
+                    match term.factors.iter().find(|f| f.exponent.unsigned_abs() > MAX_EXPONENT.unsigned_abs()) {
+                        Some(f) => Err(InvariantError::ExponentOutOfRange(f.base.clone())),
+                        None => Ok(term),
+                    }
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let numer = build(numer)?;
+        let denom = build(denom)?;
+        // The terms may come from untrusted input, so their sums are checked
+        let combined = CanonicalTerm::checked_combine_like_terms(denom.clone()).ok_or(InvariantError::CoefficientOverflow)?;
+        if combined.is_empty() {
+            return Err(InvariantError::ZeroDenominator);
         }
+        Self::new(numer, denom).checked_normalize().ok_or(InvariantError::CoefficientOverflow)
+    }
+}
+
+/// A term as a coefficient and `(variable, exponent)` powers, the input of
+/// [`RationalExpression::new_validated`].
+pub type RawTerm = (Ratio<isize>, Vec<(String, isize)>);
+
+/// A read-only view of a term of a [`RationalExpression`]: a coefficient times a product of powers.
+#[derive(Clone, Copy, Debug)]
+pub struct TermView<'a>(&'a CanonicalTerm);
 
-        // Combine like terms and simplify
-        let result = Self::new(
-            CanonicalTerm::combine_like_terms(new_numer),
-            CanonicalTerm::combine_like_terms(new_denom)
-        ).simplify();
+impl<'a> TermView<'a> {
+    /// The coefficient of the term.
+    pub fn coef(&self) -> Ratio<isize> {
+        self.0.coef
+    }
+
+    /// The powers of the term as `(variable, exponent)`, ordered by variable name.
+    /// Exponents are non-zero, and negative in terms that are not normalized.
+    pub fn factors(&self) -> impl DoubleEndedIterator<Item = (&'a str, isize)> + ExactSizeIterator + 'a {
+        self.0.factors.iter().map(|factor| (&*factor.base, factor.exponent))
+    }
+}
 
-        Some(result)
+/// An owned term of a polynomial: a coefficient times a product of powers, see [`Expr::terms`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Term {
+    /// The coefficient of the term.
+    pub coef: Ratio<isize>,
+    /// The powers as `(variable, exponent)`, ordered by variable name, with positive exponents.
+    pub factors: Vec<(String, isize)>,
+}
+
+impl From<TermView<'_>> for Term {
+    fn from(view: TermView) -> Self {
+        Self { coef: view.coef(), factors: view.factors().map(|(base, exponent)| (base.to_string(), exponent)).collect() }
     }
 }
 
@@ -750,53 +3375,307 @@ impl Ord for RationalExpression {
     }
 }
 
+/// The operands of a product with the powers of the same sum or power base and the same sign
+/// merged, so that `(a + b)^2 * (a + b)^3` is converted as `(a + b)^5`.
+fn merge_powers(operands: &VecDeque<Operand>) -> Vec<(Sign, Cow<'_, Expr>)> {
+    fn as_power(expr: &Expr) -> Option<(&Expr, u32)> {
+        match expr {
+            Expr::Pow(base, exponent) => Some((base, *exponent)),
+            Expr::Sum(_) => Some((expr, 1)),
+            _ => None,
+        }
+    }
+
+    let mut merged = Vec::<(Sign, Cow<Expr>)>::with_capacity(operands.len());
+    for Operand { ty, expr } in operands {
+        if let Some((base, exponent)) = as_power(expr) {
+            let same = merged.iter().position(|(sign, prev)| {
+                *sign == *ty && as_power(prev).is_some_and(|(prev_base, prev_exponent)| prev_base.structurally_eq(base) && prev_exponent.checked_add(exponent).is_some())
+            });
+            if let Some(i) = same {
+                let (prev_base, prev_exponent) = as_power(&merged[i].1).unwrap();
+                merged[i].1 = Cow::Owned(Expr::Pow(Box::new(prev_base.clone()), prev_exponent + exponent));
+                continue;
+            }
+        }
+        merged.push((*ty, Cow::Borrowed(expr)));
+    }
+    merged
+}
+
+/// Side, exponent, coefficient and factor count of the terms containing a variable,
+/// see [`RationalExpression::variable_signatures`].
+type VariableSignature = Vec<(bool, isize, Ratio<isize>, usize)>;
+
 impl From<RationalExpression> for Expr {
     fn from(rational: RationalExpression) -> Self {
-        Expr::Rational(rational.simplify())
+        let rational = rational.simplify();
+        debug_assert_eq!(rational.validate(), Ok(()), "{rational}");
+        Expr::Rational(rational)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// An expression in normal form, with total equality, hashing and ordering.
+///
+/// [`Expr`]'s `PartialEq` is mathematical equivalence, which is not decidable in general and
+/// therefore cannot claim `Eq`. `CanonExpr` instead compares the normalized rational form
+/// structurally: expressions that normalize to the same form are equal, all others are
+/// ordered by the deterministic order of their normal forms.
+///
+/// ```rust
+/// # use std::collections::HashSet;
+/// # use symbolic_expr::Expr;
+/// let a = Expr::var("a");
+/// let set = HashSet::from([
+///     ((a.clone() + 1) * 2).canonicalize().unwrap(),
+///     (a.clone() * 2 + 2).canonicalize().unwrap(),
+/// ]);
+/// assert_eq!(set.len(), 1);
+/// ```
+#[derive(Clone, Debug)]
+pub struct CanonExpr(Expr);
 
-    #[test]
-    fn test_dim_example() {
-        let a = Expr::var("a");
-        let b = Expr::var("b");
-        let _1 = Expr::from(1);
-        let expr = (a + _1 - 2) * 3 / (b + 1);
-        assert_eq!(expr.substitute(&HashMap::from([("a", 8), ("b", 6)])), 3);
+impl CanonExpr {
+    fn rational(&self) -> &RationalExpression {
+        match &self.0 {
+            Expr::Rational(rational) => rational,
+            _ => unreachable!("canonical expressions are always rational"),
+        }
     }
+}
 
-    #[test]
-    fn test_dim_equivalence() {
-        // Test constant equivalence
-        assert_eq!(Expr::from(1).equivalent(&Expr::from(1)), Some(true));
-        assert_eq!(Expr::from(1).equivalent(&Expr::from(2)), Some(false));
+impl PartialEq for CanonExpr {
+    fn eq(&self, other: &Self) -> bool {
+        self.rational() == other.rational()
+    }
+}
 
-        // Test variable equivalence
-        let a = Expr::var("a");
-        let b = Expr::var("b");
-        println!("asserting a != b");
-        assert_eq!(a.equivalent(&b), None);
+impl Eq for CanonExpr {}
 
-        // Test sum equivalence
-        let expr1 = a.clone() + 1;
-        let expr2 = a.clone() + 1;
-        let expr3 = a.clone() + 2;
-        println!("asserting a + 1 == a + 1");
-        assert!(expr1 == expr2);
-        assert_eq!(expr1.equivalent(&expr2), Some(true));
-        println!("asserting a + 1 != a + 2");
-        assert!(expr1 != expr3);
-        assert_eq!(expr1.equivalent(&expr3), Some(false));
+impl std::hash::Hash for CanonExpr {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.rational().hash(state)
+    }
+}
 
-        // Test product equivalence
-        let expr4 = a.clone() * 2;
-        let expr5 = a.clone() * 2;
-        let expr6 = a.clone() * 3;
-        println!("asserting a * 2 == a * 2");
+impl PartialOrd for CanonExpr {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for CanonExpr {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.rational().cmp(other.rational())
+    }
+}
+
+impl std::ops::Deref for CanonExpr {
+    type Target = Expr;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<CanonExpr> for Expr {
+    fn from(canon: CanonExpr) -> Self {
+        canon.0
+    }
+}
+
+/// A denominator shared by all expressions, so that each of them times it is a polynomial
+/// with integer coefficients, see [`Expr::scale_to_integer`].
+///
+/// Constant denominators are combined by their lcm, so `[a/2, b/3]` gives `6`. The primitive
+/// parts of other denominators are multiplied, skipping those that already divide the product.
+/// A product too large to expand, see [`Error::TooComplex`], is kept as a product.
+/// Expressions without a rational form are taken as integers.
+///
+/// ```rust
+/// # use symbolic_expr::{Expr, common_denominator};
+/// let a = Expr::var("a");
+/// let b = Expr::var("b");
+/// let exprs = [a / 2, b / 3];
+/// let denom = common_denominator(&exprs);
+/// assert_eq!(denom.to_string(), "6");
+/// assert_eq!(exprs[0].scale_to_integer(&denom).to_string(), "3*a");
+/// ```
+pub fn common_denominator(exprs: &[Expr]) -> Expr {
+    let mut constant = 1;
+    let mut product = Expr::Constant(1);
+    for rational in exprs.iter().filter_map(Expr::to_rational) {
+        let rational = rational.normalize();
+        let lcm = rational.numer.iter().chain(&rational.denom).fold(1, |lcm, term| lcm.lcm(term.coef.denom()));
+        let denom = rational.denom.iter().map(|term| CanonicalTerm { coef: term.coef * lcm, factors: term.factors.clone() });
+        let denom = Expr::from(RationalExpression::new(denom.collect(), vec![CanonicalTerm::new(1)]));
+        let content = denom.content();
+        constant = constant.lcm(content.numer());
+        let primitive = denom.primitive_part();
+        if !primitive.divides(&product) {
+            let unreduced = product * primitive;
+            product = unreduced.canonicalize().map_or(unreduced, Expr::from);
+        }
+    }
+    match product.as_constant() {
+        Some(1) => Expr::Constant(constant.unsigned_abs()),
+        _ => {
+            let unreduced = Expr::Constant(constant.unsigned_abs()) * product;
+            unreduced.canonicalize().map_or(unreduced, Expr::from)
+        }
+    }
+}
+
+/// Groups expressions by their normal form, see [`CanonExpr`].
+///
+/// Returns one representative per class, the first expression of the class in input order,
+/// along with the indices of all expressions in the class. Classes are ordered by their first
+/// occurrence. Expressions that cannot be canonicalized form classes of their own.
+///
+/// ```rust
+/// # use symbolic_expr::{Expr, dedup_equivalent};
+/// let a = Expr::var("a");
+/// let b = Expr::var("b");
+/// let classes = dedup_equivalent(vec![a.clone() + b.clone(), b.clone() + a.clone(), a - b]);
+/// let indices = classes.iter().map(|(_, indices)| indices.clone()).collect::<Vec<_>>();
+/// assert_eq!(indices, [vec![0, 1], vec![2]]);
+/// ```
+pub fn dedup_equivalent(exprs: Vec<Expr>) -> Vec<(Expr, Vec<usize>)> {
+    let mut classes = Vec::<(Expr, Vec<usize>)>::new();
+    let mut buckets = HashMap::<CanonExpr, usize>::new();
+    for (i, expr) in exprs.into_iter().enumerate() {
+        match expr.canonicalize() {
+            Ok(canon) => match buckets.entry(canon) {
+                Entry::Occupied(entry) => classes[*entry.get()].1.push(i),
+                Entry::Vacant(entry) => {
+                    entry.insert(classes.len());
+                    classes.push((expr, vec![i]));
+                }
+            },
+            Err(_) => classes.push((expr, vec![i])),
+        }
+    }
+    classes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Expressions shared by the property tests, such as `test_canonicalize_idempotent` and
+    /// `test_operations_keep_invariants`: the shapes written in the tests, followed by random
+    /// expressions with nodes of every kind.
+    fn corpus() -> Vec<Expr> {
+        let [a, b, c] = ["a", "b", "c"].map(Expr::var);
+        let written = [
+            Expr::from(0),
+            Expr::from(7),
+            a.clone(),
+            a.clone() - a.clone(),
+            (a.clone() + 1) * 3 / (b.clone() + 1),
+            a.clone() * 2 / 2,
+            a.clone() / 2 + b.clone() / 3,
+            (a.clone() * 3 - b.clone()) / (c.clone() + 2),
+            (a.clone() * a.clone() - b.clone() * b.clone()) / (a.clone() - b.clone()),
+            (a.clone() * a.clone() + a.clone() * b.clone()) / (a.clone() + b.clone()),
+            a.clone() * b.clone() / c.clone(),
+            a.clone() / (b.clone() * b.clone()),
+            a.clone() / b.clone() / c.clone() * 6,
+            (a.clone() + 1) / (a.clone() * 2 + 2),
+            (a.clone() + b.clone()).pow(3) - a.clone().pow(3),
+            (a.clone() - b.clone() * 2) / (b.clone() * 4 - a.clone() * 2),
+            (a.clone() * b.clone() + a.clone() * c.clone()) / (b.clone() * 2 + 2),
+            Expr::from(1) / (a.clone() + 1) + Expr::from(1) / (b.clone() + 1),
+            (a.clone() - 1) * (a.clone() + 1) / (c.clone() * 3),
+            (Expr::from(3) - 5).abs() * a.clone(),
+            a.clone().modulo(a.clone()) + b.clone(),
+            Expr::from(((a.clone() * 3 - b.clone()) / 2).canonicalize().unwrap()) * c.clone(),
+            (a.clone() * a.clone() + a.clone() * b.clone()) / (a.clone() * c.clone() + a.clone()),
+            (a.clone() + b.clone() - c.clone()) * (a.clone() - 1) / 2,
+            (a.clone() * b.clone() * c.clone() + b.clone() * 3) / (b.clone() * 2),
+            (a.clone() + b.clone()).pow(3) - a.clone() * b.clone() * 3,
+            a.clone() * 2 + b.clone() + 1,
+            a.clone().pow(2) - 1,
+            (a.clone() * 4 + 6) / (b.clone() + 1),
+            a.clone() * 4 / (b.clone() * 2),
+        ];
+        let mut seed = 2024_usize;
+        let mut next = || {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            seed >> 33
+        };
+        let random = (0..400)
+            .map(|_| random_expr(&mut next, 3))
+            .collect::<Vec<_>>();
+        written.into_iter().chain(random).collect()
+    }
+
+    fn random_expr(next: &mut impl FnMut() -> usize, depth: usize) -> Expr {
+        let leaf = |next: &mut dyn FnMut() -> usize| match next() % 2 {
+            0 => Expr::Constant(next() % 4),
+            _ => Expr::var(["a", "b", "c"][next() % 3]),
+        };
+        if depth == 0 {
+            return leaf(next);
+        }
+        match next() % 11 {
+            0 | 1 => random_expr(next, depth - 1) + random_expr(next, depth - 1),
+            2 => random_expr(next, depth - 1) - random_expr(next, depth - 1),
+            3 | 4 => random_expr(next, depth - 1) * random_expr(next, depth - 1),
+            5 => random_expr(next, depth - 1) / random_expr(next, depth - 1),
+            6 => {
+                let exponent = next() as u32 % 3;
+                random_expr(next, depth - 1).pow(exponent)
+            }
+            7 => random_expr(next, depth - 1).modulo(random_expr(next, depth - 1)),
+            8 => random_expr(next, depth - 1).abs().max(random_expr(next, depth - 1)),
+            9 => {
+                let expr = random_expr(next, depth - 1);
+                expr.canonicalize().map_or(expr, Expr::from)
+            }
+            _ => leaf(next),
+        }
+    }
+
+    #[test]
+    fn test_dim_example() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let _1 = Expr::from(1);
+        let expr = (a + _1 - 2) * 3 / (b + 1);
+        assert_eq!(expr.substitute(&HashMap::from([("a", 8), ("b", 6)])), 3);
+    }
+
+    #[test]
+    fn test_dim_equivalence() {
+        // Test constant equivalence
+        assert_eq!(Expr::from(1).equivalent(&Expr::from(1)), Some(true));
+        assert_eq!(Expr::from(1).equivalent(&Expr::from(2)), Some(false));
+
+        // Test variable equivalence
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        println!("asserting a != b");
+        assert_eq!(a.equivalent(&b), None);
+
+        // Test sum equivalence
+        let expr1 = a.clone() + 1;
+        let expr2 = a.clone() + 1;
+        let expr3 = a.clone() + 2;
+        println!("asserting a + 1 == a + 1");
+        assert!(expr1 == expr2);
+        assert_eq!(expr1.equivalent(&expr2), Some(true));
+        println!("asserting a + 1 != a + 2");
+        assert!(expr1 != expr3);
+        assert_eq!(expr1.equivalent(&expr3), Some(false));
+
+        // Test product equivalence
+        let expr4 = a.clone() * 2;
+        let expr5 = a.clone() * 2;
+        let expr6 = a.clone() * 3;
+        println!("asserting a * 2 == a * 2");
         assert!(expr4 == expr5);
         assert_eq!(expr4.equivalent(&expr5), Some(true));
         println!("asserting a * 2 != a * 3");
@@ -1048,7 +3927,7 @@ mod tests {
             vec![CanonicalTerm::new(1)]
         );
         let dim3: Expr = rational3.into();
-        let expected3 = Expr::Sum(VecDeque::from([Operand { ty: Type::Negative, expr: Expr::Constant(3) }]));
+        let expected3 = Expr::Sum(VecDeque::from([Operand { ty: Sign::Negative, expr: Expr::Constant(3) }]));
         assert!(dim3 == expected3);
 
         // Test simple division
@@ -1075,18 +3954,18 @@ mod tests {
             vec![
                 CanonicalTerm {
                     coef: Ratio::new(1, 1),
-                    factors: vec![
+                    factors: Factors::from_iter([
                         Factor { base: "a".to_string(), exponent: 2 },
                         Factor { base: "b".to_string(), exponent: 1 }
-                    ]
+                    ])
                 }
             ],
             vec![
                 CanonicalTerm {
                     coef: Ratio::new(1, 1),
-                    factors: vec![
+                    factors: Factors::from_iter([
                         Factor { base: "c".to_string(), exponent: 2 }
-                    ]
+                    ])
                 }
             ]
         );
@@ -1098,10 +3977,10 @@ mod tests {
             vec![
                 CanonicalTerm {
                     coef: Ratio::new(1, 1),
-                    factors: vec![
+                    factors: Factors::from_iter([
                         Factor { base: "a".to_string(), exponent: 1 },
                         Factor { base: "b".to_string(), exponent: -1 }
-                    ]
+                    ])
                 }
             ],
             vec![CanonicalTerm::new(1)]
@@ -1279,18 +4158,18 @@ mod tests {
             vec![
                 CanonicalTerm {
                     coef: Ratio::new(1, 1),
-                    factors: vec![
+                    factors: Factors::from_iter([
                         Factor { base: "a".to_string(), exponent: 2 },
                         Factor { base: "b".to_string(), exponent: 1 }
-                    ]
+                    ])
                 }
             ],
             vec![
                 CanonicalTerm {
                     coef: Ratio::new(1, 1),
-                    factors: vec![
+                    factors: Factors::from_iter([
                         Factor { base: "c".to_string(), exponent: 2 }
-                    ]
+                    ])
                 }
             ]
         );
@@ -1303,10 +4182,10 @@ mod tests {
             vec![
                 CanonicalTerm {
                     coef: Ratio::new(1, 1),
-                    factors: vec![
+                    factors: Factors::from_iter([
                         Factor { base: "a".to_string(), exponent: 1 },
                         Factor { base: "b".to_string(), exponent: -1 }
-                    ]
+                    ])
                 }
             ],
             vec![CanonicalTerm::new(1)]
@@ -1414,6 +4293,9 @@ mod tests {
         );
         let values4 = HashMap::from([("a", 6)]);
         let result4 = rational.partial_substitute(&values4).unwrap();
+        let unused = HashMap::from([("a", 6), ("unused", usize::MAX)]);
+        assert_eq!(rational.partial_substitute(&unused), Some(result4.clone()));
+        assert_eq!(rational.partial_substitute(&HashMap::from([("a", usize::MAX)])), None);
         println!("asserting (2a + 3b)/6 == 12 + 3b (a = 6)");
         assert_eq!(result4, RationalExpression::new(
             vec![
@@ -1428,18 +4310,18 @@ mod tests {
             vec![
                 CanonicalTerm {
                     coef: Ratio::new(1, 1),
-                    factors: vec![
+                    factors: Factors::from_iter([
                         Factor { base: "a".to_string(), exponent: 2 },
                         Factor { base: "b".to_string(), exponent: 1 }
-                    ]
+                    ])
                 }
             ],
             vec![
                 CanonicalTerm {
                     coef: Ratio::new(1, 1),
-                    factors: vec![
+                    factors: Factors::from_iter([
                         Factor { base: "c".to_string(), exponent: 2 }
-                    ]
+                    ])
                 }
             ]
         );
@@ -1450,15 +4332,15 @@ mod tests {
             vec![
                 CanonicalTerm {
                     coef: Ratio::new(16, 1),
-                    factors: vec![
+                    factors: Factors::from_iter([
                         Factor { base: "b".to_string(), exponent: 1 }
-                    ]
+                    ])
                 }
             ],
             vec![
                 CanonicalTerm {
                     coef: Ratio::new(4, 1),
-                    factors: vec![]
+                    factors: Factors::new()
                 }
             ]
         ).simplify());
@@ -1496,16 +4378,16 @@ mod tests {
             CanonicalTerm::new(1),
             CanonicalTerm {
                 coef: Ratio::new(2, 1),
-                factors: vec![
+                factors: Factors::from_iter([
                     Factor { base: "a".to_string(), exponent: 0 },
                     Factor { base: "b".to_string(), exponent: 0 },
-                ],
+                ]),
             },
             CanonicalTerm {
                 coef: Ratio::new(3, 1),
-                factors: vec![
+                factors: Factors::from_iter([
                     Factor { base: "c".to_string(), exponent: 0 },
-                ],
+                ]),
             },
         ];
         let result2 = CanonicalTerm::combine_like_terms(terms2);
@@ -1545,17 +4427,17 @@ mod tests {
             CanonicalTerm::with_var(2, "a".to_string()),
             CanonicalTerm {
                 coef: Ratio::new(1, 1),
-                factors: vec![
+                factors: Factors::from_iter([
                     Factor { base: "a".to_string(), exponent: 1 },
                     Factor { base: "b".to_string(), exponent: 1 },
-                ],
+                ]),
             },
             CanonicalTerm {
                 coef: Ratio::new(2, 1),
-                factors: vec![
+                factors: Factors::from_iter([
                     Factor { base: "a".to_string(), exponent: 1 },
                     Factor { base: "b".to_string(), exponent: 1 },
-                ],
+                ]),
             },
         ];
         let result5 = CanonicalTerm::combine_like_terms(terms5);
@@ -1574,24 +4456,24 @@ mod tests {
         let terms6 = vec![
             CanonicalTerm {
                 coef: Ratio::new(1, 1),
-                factors: vec![
+                factors: Factors::from_iter([
                     Factor { base: "a".to_string(), exponent: 0 },
                     Factor { base: "b".to_string(), exponent: 1 },
-                ],
+                ]),
             },
             CanonicalTerm {
                 coef: Ratio::new(2, 1),
-                factors: vec![
+                factors: Factors::from_iter([
                     Factor { base: "a".to_string(), exponent: 1 },
                     Factor { base: "b".to_string(), exponent: 0 },
-                ],
+                ]),
             },
             CanonicalTerm {
                 coef: Ratio::new(3, 1),
-                factors: vec![
+                factors: Factors::from_iter([
                     Factor { base: "a".to_string(), exponent: 0 },
                     Factor { base: "b".to_string(), exponent: 0 },
-                ],
+                ]),
             },
         ];
         let result6 = CanonicalTerm::combine_like_terms(terms6);
@@ -1606,4 +4488,2178 @@ mod tests {
         assert_eq!(result6[2].factors.len(), 1);
         assert_eq!(result6[2].factors[0].base, "b");
     }
+
+    #[test]
+    fn test_content_and_primitive_part() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+
+        // Test integer content
+        let expr1 = a.clone() * 4 + 6;
+        println!("asserting content(4a + 6) == 2");
+        assert_eq!(expr1.content(), Ratio::new(2, 1));
+        assert!(expr1.primitive_part() == a.clone() * 2 + 3);
+
+        // Test content with a constant denominator
+        let expr2 = (a.clone() * 4 + 6) / 3;
+        println!("asserting content((4a + 6)/3) == 2/3");
+        assert_eq!(expr2.content(), Ratio::new(2, 3));
+        assert!(expr2.primitive_part() == a.clone() * 2 + 3);
+
+        // Test fractional coefficients
+        let expr3 = a.clone() / 2 + b.clone() / 3;
+        println!("asserting content(a/2 + b/3) == 1/6");
+        assert_eq!(expr3.content(), Ratio::new(1, 6));
+        assert!(expr3.primitive_part() == a.clone() * 3 + b.clone() * 2);
+
+        // Test a primitive expression
+        let expr4 = a.clone() * 2 + b.clone() * 3;
+        assert_eq!(expr4.content(), Ratio::new(1, 1));
+        assert!(expr4.primitive_part() == expr4);
+
+        // Test a non-constant denominator
+        let expr5 = (a.clone() * 4 + 6) / (b.clone() + 1);
+        println!("asserting content((4a + 6)/(b + 1)) == 1");
+        assert_eq!(expr5.content(), Ratio::new(1, 1));
+        assert!(expr5.primitive_part() == expr5);
+
+        // Test a single-monomial denominator
+        let expr6 = a.clone() * 4 / (b.clone() * 2);
+        println!("asserting content(4a/(2b)) == 1");
+        assert_eq!(expr6.content(), Ratio::new(1, 1));
+        assert!(expr6.primitive_part() == expr6);
+        let expr7 = a.clone() * b.clone() * 4 / (b.clone() * 2);
+        println!("asserting content(4ab/(2b)) == 2");
+        assert_eq!(expr7.content(), Ratio::new(2, 1));
+
+        // Test zero
+        let zero = a.clone() - a.clone();
+        assert_eq!(zero.content(), Ratio::new(0, 1));
+        assert!(zero.primitive_part() == Expr::from(0));
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        use std::collections::HashSet;
+
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+
+        // Test equal normal forms hash to the same element
+        let set = HashSet::from([
+            ((a.clone() + 1) * 2).canonicalize().unwrap(),
+            (a.clone() * 2 + 2).canonicalize().unwrap(),
+        ]);
+        println!("asserting {{(a + 1) * 2, a * 2 + 2}} has one element");
+        assert_eq!(set.len(), 1);
+
+        // Test division built in different ways
+        let built = (a.clone() / b.clone()).canonicalize().unwrap();
+        let rational = Expr::Rational(RationalExpression::new(
+            vec![CanonicalTerm::with_var(2, "a".to_string())],
+            vec![CanonicalTerm::with_var(2, "b".to_string())],
+        ))
+        .canonicalize()
+        .unwrap();
+        println!("asserting a / b == 2a / 2b");
+        assert_eq!(built, rational);
+
+        // Test common monomial factors cancel
+        let expr1 = ((a.clone() * a.clone() + a.clone() * b.clone()) / (a.clone() * c.clone() + a.clone())).canonicalize().unwrap();
+        let expr2 = ((a.clone() + b.clone()) / (c.clone() + 1)).canonicalize().unwrap();
+        println!("asserting (a² + ab)/(ac + a) == (a + b)/(c + 1)");
+        assert_eq!(expr1, expr2);
+
+        // Test different expressions are ordered consistently
+        let one = (a.clone() + 1).canonicalize().unwrap();
+        let two = (a.clone() + 2).canonicalize().unwrap();
+        assert_ne!(one, two);
+        assert_eq!(one.cmp(&two), two.cmp(&one).reverse());
+
+        // Test conversion back to Expr
+        let expr: Expr = one.clone().into();
+        assert!(expr == a.clone() + 1);
+        assert!(*one == a.clone() + 1);
+    }
+
+    #[test]
+    fn test_equivalence_with_denominators() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+
+        // Test identically zero denominators
+        let zero = b.clone() - b.clone();
+        let expr1 = a.clone() / zero.clone();
+        let expr2 = c.clone() / zero.clone();
+        println!("asserting a/(b - b) and c/(b - b) are undecidable");
+        assert_eq!(expr1.equivalent(&expr2), None);
+        assert!(!(expr1 == expr2));
+        assert!(!(expr1 != expr2));
+        assert_eq!((zero.clone() / zero.clone()).equivalent(&Expr::from(0)), None);
+        assert_eq!((a.clone() / 0).equivalent(&a), None);
+
+        // Test cancellation of a common polynomial factor
+        let expr3 = (a.clone() * a.clone() - b.clone() * b.clone()) / (a.clone() - b.clone());
+        let expr4 = a.clone() + b.clone();
+        println!("asserting (a² - b²)/(a - b) == a + b");
+        assert_ne!(expr3.equivalent(&expr4), Some(false));
+        assert_eq!(expr3.equivalent(&expr4), Some(true));
+
+        // Test multi-term denominators
+        let expr5 = Expr::from(1) / (a.clone() + 1);
+        let expr6 = Expr::from(2) / (a.clone() + 1);
+        println!("asserting 1/(a + 1) != 2/(a + 1)");
+        assert_eq!(expr5.equivalent(&expr6), Some(false));
+
+        let expr7 = (a.clone() + 1) / (a.clone() + 2);
+        let expr8 = (a.clone() * 2 + 2) / (a.clone() * 2 + 4);
+        println!("asserting (a + 1)/(a + 2) == (2a + 2)/(2a + 4)");
+        assert_eq!(expr7.equivalent(&expr8), Some(true));
+
+        let expr9 = a.clone() / (b.clone() + c.clone());
+        let expr10 = b.clone() / (a.clone() + c.clone());
+        println!("asserting a/(b + c) and b/(a + c) are undecidable");
+        assert_eq!(expr9.equivalent(&expr10), None);
+    }
+
+    #[test]
+    fn test_dedup_equivalent() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+
+        // Test grouping of commuted sums
+        let classes = dedup_equivalent(vec![a.clone() + b.clone(), b.clone() + a.clone(), a.clone() - b.clone()]);
+        println!("asserting [a + b, b + a, a - b] has two classes");
+        assert_eq!(classes.len(), 2);
+        assert!(classes[0].0 == a.clone() + b.clone());
+        assert_eq!(classes[0].1, [0, 1]);
+        assert!(classes[1].0 == a.clone() - b.clone());
+        assert_eq!(classes[1].1, [2]);
+
+        // Test expressions that cannot be canonicalized stay apart
+        let undefined = a.clone() / (b.clone() - b.clone());
+        let classes = dedup_equivalent(vec![undefined.clone(), a.clone() * 2, undefined, a.clone() + a.clone()]);
+        assert_eq!(classes.len(), 3);
+        assert_eq!(classes[0].1, [0]);
+        assert_eq!(classes[1].1, [1, 3]);
+        assert_eq!(classes[2].1, [2]);
+
+        // Test empty input
+        assert!(dedup_equivalent(vec![]).is_empty());
+    }
+
+    #[test]
+    fn test_push_operators() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+
+        // Test in-place operations build the same structure as the operators
+        let mut pushed = a.clone();
+        pushed.push_add(b.clone());
+        pushed.push_sub(c.clone() + 1);
+        pushed.push_mul(Expr::from(2));
+        pushed.push_div(b.clone() * c.clone());
+        let built = ((a.clone() + b.clone() - (c.clone() + 1)) * 2) / (b.clone() * c.clone());
+        println!("asserting pushed == ((a + b - (c + 1)) * 2) / (b * c)");
+        assert_eq!(format!("{pushed:?}"), format!("{built:?}"));
+        assert!(pushed == built);
+
+        // Test accumulating many terms
+        const N: usize = 100_000;
+        let mut total = Expr::with_capacity_sum(N);
+        let mut reference = Expr::from(0);
+        for i in 0..N {
+            let term = Expr::var(format!("x{}", i % 100)) * (i % 7);
+            total.push_add(term.clone());
+            reference = reference + term;
+        }
+        let values = (0..100).map(|i| format!("x{i}")).collect::<Vec<_>>();
+        let values = values.iter().enumerate().map(|(i, name)| (&**name, i)).collect::<HashMap<_, _>>();
+        println!("asserting sum of {N} pushed terms == sum of {N} added terms");
+        assert_eq!(total.substitute(&values), reference.substitute(&values));
+        let Expr::Sum(operands) = &total else { panic!("expected a sum") };
+        assert_eq!(operands.len(), N);
+    }
+
+    #[test]
+    fn test_as_univariate() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+        let x = Expr::var("x");
+
+        // Test a quadratic with symbolic coefficients
+        let expr1 = a.clone() * x.clone() * x.clone() + b.clone() * x.clone() + c.clone();
+        let coefs1 = expr1.as_univariate("x").unwrap();
+        println!("asserting ax² + bx + c has coefficients [c, b, a]");
+        assert_eq!(coefs1.len(), 3);
+        assert!(coefs1[0] == c);
+        assert!(coefs1[1] == b);
+        assert!(coefs1[2] == a);
+
+        // Test absent exponents are zero
+        let expr2 = x.clone() * x.clone() * x.clone() * 2 + (a.clone() + 1) * x.clone();
+        let coefs2 = expr2.as_univariate("x").unwrap();
+        println!("asserting 2x³ + (a + 1)x has coefficients [0, a + 1, 0, 2]");
+        assert_eq!(coefs2.len(), 4);
+        assert!(matches!(coefs2[0], Expr::Constant(0)));
+        assert!(coefs2[1] == a.clone() + 1);
+        assert!(matches!(coefs2[2], Expr::Constant(0)));
+        assert!(coefs2[3] == Expr::from(2));
+
+        // Test constant denominators
+        let expr3 = (x.clone() * 3 + 1) / 2;
+        let coefs3 = expr3.as_univariate("x").unwrap();
+        assert_eq!(coefs3.len(), 2);
+        assert!(coefs3[0] == Expr::from(1) / 2);
+        assert!(coefs3[1] == Expr::from(3) / 2);
+
+        // Test a variable that does not appear
+        let coefs4 = expr1.as_univariate("y").unwrap();
+        assert_eq!(coefs4.len(), 1);
+        assert!(coefs4[0] == expr1);
+
+        // Test unsupported forms
+        assert!((a.clone() / x.clone()).as_univariate("x").is_none());
+        println!("asserting (3x + 1)/2b is not a polynomial");
+        assert!(((x.clone() * 3 + 1) / (b.clone() * 2)).as_univariate("x").is_none());
+        assert!((x.clone() / b.clone()).as_univariate("x").is_none());
+        assert!((x.clone() / (a.clone() + 1)).as_univariate("x").is_none());
+        assert!((x.clone() - x.clone()).as_univariate("x").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_constant_difference() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+
+        // Test constant offsets, in both directions and through fractions
+        let cases = [
+            (a.clone() + 5, a.clone() + 2, Some(Ratio::from_integer(3))),
+            (a.clone() + 2, a.clone() + 5, Some(Ratio::from_integer(-3))),
+            (a.clone() * 2 + b.clone(), b.clone() + a.clone() * 2, Some(Ratio::from_integer(0))),
+            ((a.clone() + 1) / 2, a.clone() / 2, Some(Ratio::new(1, 2))),
+            ((a.clone() * a.clone() - 1) / (a.clone() - 1), a.clone(), Some(Ratio::from_integer(1))),
+            (Expr::from(7), Expr::from(4), Some(Ratio::from_integer(3))),
+            (a.clone() + b.clone(), a.clone(), None),
+            (a.clone() * 2, a.clone(), None),
+            (a.clone().modulo(b.clone()) + 1, a.clone().modulo(b.clone()), None),
+        ];
+        for (lhs, rhs, expected) in cases {
+            println!("asserting {lhs} - ({rhs}) is {expected:?}");
+            assert_eq!(lhs.constant_difference(&rhs), expected);
+        }
+        let undefined = a.clone() / (b.clone() - b.clone());
+        assert_eq!(undefined.constant_difference(&undefined), None);
+        assert_eq!(a.clone().modulo(Expr::from(0)).constant_difference(&a.clone().modulo(Expr::from(0))), None);
+        assert_eq!(a.clone().modulo(b.clone()).constant_difference(&a.clone().modulo(b.clone())), Some(Ratio::from_integer(0)));
+        assert_eq!((undefined.clone() + 1).constant_difference(&undefined), None);
+    }
+
+    #[test]
+    fn test_equivalence_fast_path() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+
+        // Test structural equality
+        assert!((a.clone() + b.clone()).structurally_eq(&(a.clone() + b.clone())));
+        assert!(!(a.clone() + b.clone()).structurally_eq(&(b.clone() + a.clone())));
+        assert!(!(a.clone() + b.clone()).structurally_eq(&(a.clone() - b.clone())));
+        assert!(!(a.clone() + b.clone()).structurally_eq(&(a.clone() * b.clone())));
+        assert!(!Expr::from(1).structurally_eq(&a));
+
+        // Test identical expressions are equivalent without expanding 2^40 terms
+        let huge = (0..40).fold(Expr::from(1), |acc, i| acc * (Expr::var(format!("x{i}")) + 1));
+        println!("asserting a product of 40 binomials is equivalent to itself");
+        assert_eq!(huge.equivalent(&huge.clone()), Some(true));
+        assert!(huge == huge.clone());
+
+        // Test the fast path keeps the results of the slow path
+        assert_eq!(Expr::from(3).equivalent(&Expr::from(3)), Some(true));
+        assert_eq!(Expr::from(3).equivalent(&Expr::from(4)), Some(false));
+        assert_eq!(a.equivalent(&a), Some(true));
+        assert_eq!(a.equivalent(&b), None);
+        assert_eq!((a.clone() + b.clone()).equivalent(&(b.clone() + a.clone())), Some(true));
+        let undefined = a.clone() / (b.clone() - b.clone());
+        assert_eq!(undefined.equivalent(&undefined), None);
+        assert_eq!(undefined.equivalent(&(b.clone() / (b.clone() - b.clone()))), None);
+        assert_eq!((a.clone() / 0).equivalent(&(a.clone() / 0)), None);
+        let nested = a.clone().modulo(b.clone() / (a.clone() / (b.clone() - b.clone())));
+        assert_eq!(nested.equivalent(&nested), None);
+        let defined = a.clone().modulo(b.clone() / (a.clone() + 1)).abs();
+        assert_eq!(defined.equivalent(&defined), Some(true));
+        assert_eq!((a.clone() * b.clone() / 2).equivalent(&(a.clone() * b.clone() / 2)), Some(true));
+    }
+
+    #[test]
+    fn test_linearize() {
+        let h = Expr::var("h");
+        let w = Expr::var("w");
+
+        // Test a 2D row-major index
+        let index = Expr::linearize(&[("i", w.clone()), ("j", Expr::from(1))]).unwrap();
+        let values = HashMap::from([("i", 3), ("j", 4), ("h", 8), ("w", 16)]);
+        println!("asserting i * w + j == 52 (i = 3, j = 4, w = 16)");
+        assert_eq!(index.substitute(&values), 52);
+        assert!(index == Expr::var("i") * w.clone() + Expr::var("j"));
+
+        // Test the checked evaluation
+        let indices = [("c", h.clone() * w.clone()), ("i", w.clone()), ("j", Expr::from(1))];
+        let values = HashMap::from([("c", 2), ("i", 3), ("j", 4), ("h", 8), ("w", 16)]);
+        assert_eq!(Expr::linear_index(&indices, &values), Some(2 * 128 + 3 * 16 + 4));
+        assert_eq!(Expr::linear_index(&indices, &values), Expr::linearize(&indices).unwrap().checked_substitute(&values));
+        assert_eq!(Expr::linear_index(&indices, &HashMap::from([("i", 3), ("j", 4)])), None);
+        let huge = HashMap::from([("c", usize::MAX), ("i", 0), ("j", 0), ("h", 8), ("w", 16)]);
+        assert_eq!(Expr::linear_index(&indices, &huge), None);
+
+        // Test empty indices
+        assert!(matches!(Expr::linearize(&[]), Ok(Expr::Constant(0))));
+    }
+
+    #[test]
+    fn test_linearize_non_affine() {
+        let [i, w] = ["i", "w"].map(Expr::var);
+
+        // Test strides depending on the index variables
+        let err = Expr::linearize(&[("i", w.clone()), ("j", i.clone())]).unwrap_err();
+        println!("asserting a stride of i makes the index not affine: {err}");
+        assert!(matches!(err, Error::NotAffine));
+        assert_eq!(err.to_string(), "index is not affine in the index variables");
+        assert!(matches!(Expr::linearize(&[("i", i.clone() * w.clone())]), Err(Error::NotAffine)));
+        assert!(matches!(Expr::linearize(&[("i", w.clone() / (i.clone() + 1))]), Err(Error::NotAffine)));
+        assert!(matches!(Expr::linearize(&[("i", w.clone()), ("j", w.clone().modulo(i.clone()))]), Err(Error::NotAffine)));
+
+        // Test strides whose index variables cancel
+        let index = Expr::linearize(&[("i", w.clone() + i.clone() - i.clone())]).unwrap();
+        assert_eq!(index.equivalent(&(i.clone() * w.clone())), Some(true));
+        assert!(Expr::linearize(&[("i", w.clone().modulo(Expr::from(4)))]).is_ok());
+    }
+
+    #[test]
+    fn test_pow() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+
+        // Test power nodes evaluate and compare
+        let square = (a.clone() + b.clone()).pow(2);
+        let values = HashMap::from([("a", 2), ("b", 3), ("c", 4)]);
+        println!("asserting (a + b)^2 == 25 (a = 2, b = 3)");
+        assert_eq!(square.substitute(&values), 25);
+        assert_eq!(square.checked_substitute(&values), Some(25));
+        assert!(square == a.clone() * a.clone() + a.clone() * b.clone() * 2 + b.clone() * b.clone());
+        assert!(square.structurally_eq(&(a.clone() + b.clone()).pow(2)));
+        assert!(!square.structurally_eq(&(a.clone() + b.clone()).pow(3)));
+        assert_eq!(square.variables(), BTreeSet::from(["a", "b"]));
+        assert!(a.clone().pow(0) == Expr::from(1));
+        assert!((a.clone() / b.clone()).pow(2) == (a.clone() * a.clone()) / (b.clone() * b.clone()));
+
+        // Test multinomial expansion by repeated squaring
+        let base = a.clone() + b.clone() + c.clone();
+        let rational = RationalExpression::from_dim(&base.clone().pow(6)).unwrap();
+        println!("asserting (a + b + c)^6 has 28 terms");
+        assert_eq!(rational.numer.len(), 28);
+        let repeated = (0..6).fold(Expr::from(1), |acc, _| acc * base.clone());
+        let repeated = RationalExpression::from_dim(&repeated).unwrap();
+        let mut seed = 12345_usize;
+        for _ in 0..16 {
+            let mut next = || {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (seed >> 33) % 100
+            };
+            let values = HashMap::from([("a", next()), ("b", next()), ("c", next())]);
+            assert_eq!(rational.substitute(&values), repeated.substitute(&values));
+        }
+
+        // Test overflow is diagnosed at the power node
+        let err = (Expr::from(1) + a.clone().pow(64)).substitute_diagnosed(&values).unwrap_err();
+        assert_eq!(err.kind, EvalError::Overflow);
+        assert_eq!(err.path, [1]);
+    }
+
+    #[test]
+    fn test_factor_out() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+
+        // Test a common linear factor
+        let (exponent, rest) = (a.clone() * a.clone() * b.clone() + a.clone() * c.clone()).factor_out("a");
+        println!("asserting a²b + ac == a * (ab + c)");
+        assert_eq!(exponent, 1);
+        assert!(rest == a.clone() * b.clone() + c.clone());
+
+        // Test a higher common power and a denominator
+        let (exponent, rest) = ((a.clone() * a.clone() * a.clone() + a.clone() * a.clone() * 2) / (b.clone() + 1)).factor_out("a");
+        println!("asserting (a³ + 2a²)/(b + 1) == a² * (a + 2)/(b + 1)");
+        assert_eq!(exponent, 2);
+        assert!(rest == (a.clone() + 2) / (b.clone() + 1));
+
+        // Test no common factor
+        let (exponent, rest) = (a.clone() + 1).factor_out("a");
+        assert_eq!(exponent, 0);
+        assert!(rest == a.clone() + 1);
+        let (exponent, rest) = (b.clone() * c.clone()).factor_out("a");
+        assert_eq!(exponent, 0);
+        assert!(rest == b.clone() * c.clone());
+
+        // Test a negative exponent
+        let (exponent, rest) = ((b.clone() + c.clone()) / (a.clone() * a.clone())).factor_out("a");
+        println!("asserting (b + c)/a² == a^-2 * (b + c)");
+        assert_eq!(exponent, -2);
+        assert!(rest == b.clone() + c.clone());
+
+        // Test zero
+        let (exponent, rest) = (a.clone() - a.clone()).factor_out("a");
+        assert_eq!(exponent, 0);
+        assert!(rest == Expr::from(0));
+    }
+
+    #[test]
+    fn test_reference_operators() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+
+        // Test borrowed operands build the same trees as cloned ones
+        println!("asserting &a + &b is a + b");
+        assert!((&a + &b).structurally_eq(&(a.clone() + b.clone())));
+        assert!((&a - &b).structurally_eq(&(a.clone() - b.clone())));
+        assert!((&a * &b).structurally_eq(&(a.clone() * b.clone())));
+        assert!((&a / &b).structurally_eq(&(a.clone() / b.clone())));
+
+        // Test mixing borrowed and owned operands and constants
+        let expr = (&a + 1) * &b / (&c - &a);
+        assert!(expr.structurally_eq(&((a.clone() + 1) * b.clone() / (c.clone() - a.clone()))));
+        assert!(expr == (a.clone() * b.clone() + b.clone()) / (c.clone() - a.clone()));
+        assert!((&a * (&b + &c)) == &a * &b + &a * &c);
+        assert_eq!(expr.substitute(&HashMap::from([("a", 2), ("b", 4), ("c", 5)])), 4);
+    }
+
+    #[test]
+    fn test_variables_owned() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+
+        let exprs = [
+            Expr::from(3),
+            a.clone(),
+            c.clone() + a.clone() - b.clone(),
+            c.clone() * b.clone() / (a.clone() + 1),
+            (b.clone() + a.clone()).pow(2),
+            ((c.clone() + b.clone()) / (a.clone() * 2)).canonicalize().unwrap().into(),
+        ];
+        for expr in &exprs {
+            // Test owned results match the borrowed ones
+            let borrowed = expr.variables();
+            println!("asserting owned variables of {expr} are {borrowed:?}");
+            let owned = expr.variables_owned();
+            assert!(owned.iter().map(String::as_str).eq(borrowed.iter().copied()));
+            assert!(expr.variables_vec().iter().map(String::as_str).eq(borrowed.iter().copied()));
+        }
+
+        // Test the snapshot outlives a mutation of the expression
+        let mut expr = a.clone() + b.clone();
+        let snapshot = expr.variables_vec();
+        expr.push_mul(c.clone());
+        assert_eq!(snapshot, ["a", "b"]);
+        assert_eq!(expr.variables_vec(), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_modulo() {
+        let a = Expr::var("a");
+        let n = Expr::var("n");
+        let offset = Expr::var("offset");
+
+        // Test numeric evaluation
+        let wrapped = (a.clone() + offset.clone()).modulo(n.clone());
+        let values = HashMap::from([("a", 6), ("offset", 3), ("n", 4)]);
+        println!("asserting (6 + 3) mod 4 == 1");
+        assert_eq!(wrapped.substitute(&values), 1);
+        assert_eq!(wrapped.checked_substitute(&values), Some(1));
+        assert_eq!(
+            wrapped.substitute_diagnosed(&HashMap::from([("a", 6), ("offset", 3), ("n", 0)])).unwrap_err().kind,
+            EvalError::DivisionByZero,
+        );
+        assert_eq!(wrapped.variables(), BTreeSet::from(["a", "n", "offset"]));
+
+        // Test partial substitution folds only when both sides are known
+        let partial = wrapped.partial_substitute(&HashMap::from([("a", 6), ("offset", 3)])).unwrap();
+        assert!(matches!(partial, Expr::Mod(..)));
+        assert_eq!(partial.substitute(&HashMap::from([("n", 5)])), 4);
+        let folded = (wrapped.clone() * 2 + 1).partial_substitute(&values).unwrap();
+        assert_eq!(folded, Expr::from(3));
+
+        // Test x mod x is simplified, other unresolved mods are unknown
+        println!("asserting (a + 1) mod (1 + a) == 0");
+        assert_eq!((a.clone() + 1).modulo(Expr::from(1) + a.clone()).equivalent(&Expr::from(0)), Some(true));
+        assert_eq!((a.clone().modulo(a.clone()) + n.clone()).equivalent(&n), Some(true));
+        assert_eq!(Expr::from(7).modulo(Expr::from(3)).equivalent(&Expr::from(1)), Some(true));
+        assert_eq!(wrapped.equivalent(&Expr::from(0)), None);
+        assert_eq!(wrapped.equivalent(&wrapped.clone()), Some(true));
+    }
+
+    #[test]
+    fn test_term_views() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+
+        let canon = ((a.clone() * b.clone() + 1) / (b.clone() * 2 + a.clone() * 4)).canonicalize().unwrap();
+        let Expr::Rational(rational) = &*canon else { unreachable!() };
+
+        // Test the views reflect the normalized form in canonical order
+        let numer = rational.numer_terms().map(|t| (t.coef(), t.factors().collect::<Vec<_>>())).collect::<Vec<_>>();
+        let denom = rational.denom_terms().map(|t| (t.coef(), t.factors().collect::<Vec<_>>())).collect::<Vec<_>>();
+        println!("asserting terms of {}", *canon);
+        assert_eq!(numer, [(Ratio::new(1, 4), vec![]), (Ratio::new(1, 4), vec![("a", 1), ("b", 1)])]);
+        assert_eq!(denom, [(Ratio::new(1, 1), vec![("a", 1)]), (Ratio::new(1, 2), vec![("b", 1)])]);
+
+        // Test the views evaluate to the expression
+        let eval = |terms: Vec<TermView>| {
+            terms.iter().map(|t| t.factors().fold(t.coef(), |acc, (_, e)| acc * Ratio::from_integer(3isize.pow(e as u32)))).sum::<Ratio<isize>>()
+        };
+        let value = eval(rational.numer_terms().collect()) / eval(rational.denom_terms().collect());
+        assert_eq!(value, Ratio::new(10, 18));
+    }
+
+    #[test]
+    fn test_degree_in() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+
+        // Test a monomial denominator
+        let expr1 = a.clone() / (b.clone() * b.clone());
+        println!("asserting a/b² has degrees 1 and 2 in b");
+        assert_eq!(expr1.numerator_degree_in("a"), 1);
+        assert_eq!(expr1.denominator_degree_in("a"), 0);
+        assert_eq!(expr1.numerator_degree_in("b"), 0);
+        assert_eq!(expr1.denominator_degree_in("b"), 2);
+
+        // Test common factors are cancelled before measuring
+        let expr2 = (a.clone() * a.clone() * a.clone() + a.clone() * b.clone()) / (a.clone() * a.clone() + a.clone());
+        println!("asserting (a³ + ab)/(a² + a) == (a² + b)/(a + 1)");
+        assert_eq!(expr2.numerator_degree_in("a"), 2);
+        assert_eq!(expr2.denominator_degree_in("a"), 1);
+        assert_eq!(expr2.numerator_degree_in("b"), 1);
+        assert!(expr2.numerator_degree_in("a") >= expr2.denominator_degree_in("a"));
+
+        // Test a proper rational function
+        let expr3 = (a.clone() + 1) / (a.clone().pow(2) + b.clone());
+        assert!(expr3.numerator_degree_in("a") < expr3.denominator_degree_in("a"));
+
+        // Test constants and zero
+        assert_eq!(Expr::from(5).numerator_degree_in("a"), 0);
+        assert_eq!((a.clone() - a.clone()).numerator_degree_in("a"), 0);
+    }
+
+    #[test]
+    fn test_compare_growth() {
+        use std::cmp::Ordering;
+
+        let s = Expr::var("s");
+        let h = Expr::var("h");
+
+        let cases = [
+            // Test different degrees, whatever the coefficients
+            (s.clone() * s.clone() * h.clone(), s.clone() * h.clone() * 64, Some(Ordering::Greater)),
+            (s.clone() * h.clone() + 1000, s.clone().pow(2) / 4, Some(Ordering::Less)),
+            (s.clone() * s.clone() / (s.clone() + 1), Expr::from(7), Some(Ordering::Greater)),
+            // Test degree ties with constant leading coefficients
+            (s.clone() * 3 + h.clone(), s.clone() * 2 + 100, Some(Ordering::Greater)),
+            (s.clone() * s.clone() / s.clone(), s.clone(), Some(Ordering::Equal)),
+            ((s.clone() * 4 + 1) / (s.clone() * 2), Expr::from(2), Some(Ordering::Equal)),
+            (Expr::from(3) - 3, Expr::from(1) / (s.clone() + 1), Some(Ordering::Less)),
+            (Expr::from(3) - 3, s.clone() - s.clone(), Some(Ordering::Equal)),
+            // Test degree ties with symbolic leading coefficients
+            (s.clone() * h.clone(), s.clone() * Expr::var("w"), None),
+            (s.clone() * h.clone(), s.clone() * 2, None),
+            (s.clone().modulo(h.clone()), s.clone(), None),
+        ];
+        for (lhs, rhs, expected) in cases {
+            println!("asserting {lhs} compared to {rhs} in s is {expected:?}");
+            assert_eq!(lhs.compare_growth(&rhs, "s"), expected);
+            assert_eq!(rhs.compare_growth(&lhs, "s"), expected.map(Ordering::reverse));
+        }
+    }
+
+    #[test]
+    fn test_replace_variable() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+
+        // Test trees keep their structure
+        let expr1 = (a.clone() + 1) * a.clone().modulo(c.clone()) / b.clone().pow(2);
+        let replaced = expr1.replace_variable("a", &(b.clone() + c.clone()));
+        println!("asserting {expr1} with a = b + c is {replaced}");
+        assert_eq!(replaced.to_string(), "(b + c + 1)*mod(b + c, c)/b^2");
+        assert!(expr1.replace_variable("d", &b).structurally_eq(&expr1));
+
+        // Test rational nodes are converted back to trees
+        let expr2 = Expr::from(((a.clone() * 3 - b.clone()) / (a.clone() * 2)).canonicalize().unwrap());
+        let replaced = expr2.replace_variable("b", &c);
+        assert!(!matches!(replaced, Expr::Rational(_)));
+        assert!(replaced == (a.clone() * 3 - c.clone()) / (a.clone() * 2));
+        assert_eq!(replaced.substitute(&HashMap::from([("a", 2), ("c", 2)])), 1);
+        assert_eq!(replaced.to_string(), "(3*a - c)/2/a");
+    }
+
+    #[test]
+    fn test_is_monomial() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+
+        // Test products and quotients
+        println!("asserting a*b/c is a monomial");
+        assert!((a.clone() * b.clone() / c.clone()).is_monomial());
+        assert!((a.clone() * 3 / 2).is_monomial());
+        assert!(a.clone().pow(4).is_monomial());
+        assert!(Expr::from(7).is_monomial());
+        assert!((a.clone() - a.clone()).is_monomial());
+
+        // Test additive structure
+        assert!(!(a.clone() + b.clone()).is_monomial());
+        assert!(!(a.clone() / (b.clone() + 1)).is_monomial());
+        assert!(!(a.clone() + 1).pow(2).is_monomial());
+        assert!(!a.clone().modulo(b.clone()).is_monomial());
+
+        // Test cancelling sums
+        println!("asserting (a + b) - a is the monomial b");
+        assert!((a.clone() + b.clone() - a.clone()).is_monomial());
+        assert!(((a.clone() * a.clone() + a.clone() * b.clone()) / (a.clone() + b.clone())).is_monomial());
+    }
+
+    #[test]
+    fn test_solve_for() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+
+        // Test linear equations
+        let solution = (a.clone() * 2 + b.clone() - 6).solve_for("a").unwrap();
+        println!("asserting 2a + b - 6 == 0 gives a = {solution}");
+        assert!(solution == (Expr::from(6) - b.clone()) / 2);
+        let solution = ((a.clone() + 1) / (b.clone() + 1) - 3).solve_for("a").unwrap();
+        assert!(solution == b.clone() * 3 + 2);
+        let solution = (a.clone() * b.clone() - b.clone() * b.clone()).solve_for("a").unwrap();
+        assert!(solution == b.clone());
+
+        // Test non-linear or missing variables
+        assert!((a.clone() * a.clone() - 4).solve_for("a").is_none());
+        assert!((b.clone() - 4).solve_for("a").is_none());
+        assert!((a.clone() - a.clone()).solve_for("a").is_none());
+        assert!(a.clone().modulo(b.clone()).solve_for("a").is_none());
+
+        // Test solutions that make the denominator zero
+        let expr = (a.clone() - 3) / (a.clone() * a.clone() - 9);
+        println!("asserting {expr} == 0 has no solution");
+        assert!(expr.solve_for("a").is_none());
+        let expr = (a.clone() - b.clone()) / (a.clone() - b.clone() * b.clone());
+        assert!(expr.solve_for("a").unwrap() == b.clone());
+    }
+
+    #[test]
+    fn test_empty_compounds() {
+        let a = Expr::var("a");
+        let empty_sum = Expr::Sum(VecDeque::new());
+        let empty_product = Expr::Product(VecDeque::new());
+
+        // Test the constructors normalize empty compounds
+        println!("asserting empty sums are 0 and empty products are 1");
+        assert!(matches!(Expr::sum([]), Expr::Constant(0)));
+        assert!(matches!(Expr::product([]), Expr::Constant(1)));
+        assert!(Expr::sum([a.clone().positive()]).structurally_eq(&Expr::Sum([a.clone().positive()].into())));
+        assert!(Expr::product([a.clone().positive(), a.clone().negative()]) == Expr::from(1));
+
+        // Test substitute
+        let values = HashMap::new();
+        assert_eq!(empty_sum.substitute(&values), 0);
+        assert_eq!(empty_product.substitute(&values), 1);
+        assert_eq!(empty_sum.checked_substitute(&values), Some(0));
+        assert_eq!(empty_product.checked_substitute(&values), Some(1));
+        assert_eq!((empty_product.clone() * a.clone()).substitute(&HashMap::from([("a", 5)])), 5);
+
+        // Test variables
+        assert!(empty_sum.variables().is_empty());
+        assert!(empty_product.variables().is_empty());
+
+        // Test equivalent
+        assert_eq!(empty_sum.equivalent(&Expr::from(0)), Some(true));
+        assert_eq!(empty_product.equivalent(&Expr::from(1)), Some(true));
+        assert_eq!(empty_sum.equivalent(&empty_product), Some(false));
+        assert_eq!((a.clone() + empty_sum.clone()).equivalent(&a), Some(true));
+        assert!(empty_sum.replace_variable("a", &a).structurally_eq(&Expr::from(0)));
+
+        // Test display and canonicalization
+        assert_eq!(empty_sum.to_string(), "0");
+        assert_eq!(empty_product.to_string(), "1");
+        assert_eq!(Expr::Product([empty_sum.clone().positive()].into()).to_string(), "0");
+        assert_eq!(empty_sum.canonicalize().unwrap().to_string(), "0");
+        assert_eq!(empty_product.canonicalize().unwrap().to_string(), "1");
+    }
+
+    #[test]
+    fn test_single_negative_sum() {
+        let neg = Expr::Sum([Expr::var("a").negative()].into());
+        println!("asserting {neg} is 0 only for a = 0");
+        assert_eq!(neg.to_string(), "-a");
+        assert_eq!(neg.substitute(&HashMap::from([("a", 0)])), 0);
+        assert_eq!(neg.checked_substitute(&HashMap::from([("a", 0)])), Some(0));
+        assert_eq!(neg.checked_substitute(&HashMap::from([("a", 3)])), None);
+        let err = neg.substitute_diagnosed(&HashMap::from([("a", 3)])).unwrap_err();
+        assert_eq!(err.kind, EvalError::Negative);
+        assert_eq!(neg.equivalent(&(Expr::from(0) - Expr::var("a"))), Some(true));
+    }
+
+    #[test]
+    #[should_panic(expected = "\"-a\" is negative")]
+    fn test_single_negative_sum_panics() {
+        Expr::Sum([Expr::var("a").negative()].into()).substitute(&HashMap::from([("a", 3)]));
+    }
+
+    #[test]
+    fn test_from_linear() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+
+        // Test a two-variable affine expression
+        let expr = Expr::from_linear(&HashMap::from([("b", -1), ("a", 2), ("c", 0)]), 3);
+        println!("asserting {expr} is 2*a + 3 - b");
+        assert_eq!(expr.to_string(), "2*a + 3 - b");
+        assert_eq!(expr.equivalent(&(a.clone() * 2 + 3 - b.clone())), Some(true));
+        assert_eq!(expr.substitute(&HashMap::from([("a", 4), ("b", 5)])), 6);
+        assert_eq!(Expr::from_linear(&HashMap::from([("a", 1)]), 0).to_string(), "a");
+        assert_eq!(Expr::from_linear(&HashMap::from([("a", 1)]), -2).to_string(), "a - 2");
+        assert_eq!(Expr::from_linear(&HashMap::from([("a", -3)]), 0).to_string(), "-3*a");
+        assert!(Expr::from_linear(&HashMap::new(), 0).structurally_eq(&Expr::from(0)));
+        assert!(Expr::from_linear(&HashMap::new(), 7).structurally_eq(&Expr::from(7)));
+
+        // Test the round trip through the coefficients of affine expressions
+        let exprs = [
+            a.clone() * 2 + b.clone() * 3 + 1,
+            (a.clone() * 4 + 6 - b.clone() * 2) / 2,
+            a.clone() + b.clone() - a.clone() * 3,
+            a.clone() - a.clone(),
+        ];
+        for expr in exprs {
+            let variables = expr.variables_vec();
+            let mut coeffs = HashMap::new();
+            let mut constant = expr.clone();
+            for var in &variables {
+                let coefs = constant.as_univariate(var).unwrap();
+                assert!(coefs.len() <= 2);
+                let coef = coefs.get(1).map_or(Ratio::from_integer(0), |c| c.canonicalize().unwrap().rational().constant().unwrap());
+                coeffs.insert(var.as_str(), coef.to_integer());
+                constant = coefs.into_iter().next().unwrap_or(Expr::Constant(0));
+            }
+            let constant = constant.canonicalize().unwrap().rational().constant().unwrap().to_integer();
+            let linear = Expr::from_linear(&coeffs, constant);
+            println!("asserting {linear} is equivalent to {expr}");
+            assert_eq!(linear.equivalent(&expr), Some(true));
+        }
+    }
+
+    #[test]
+    fn test_is_polynomial() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+
+        let cases = [
+            (a.clone() * 2 + b.clone() + 1, true, true),
+            (a.clone() * b.clone(), true, false),
+            (a.clone() / b.clone(), false, false),
+            (a.clone() * 2 / 2, true, true),
+            (a.clone() / 2 + 1, true, true),
+            ((a.clone() * a.clone() + a.clone() * b.clone()) / (a.clone() + b.clone()), true, true),
+            (a.clone() * b.clone() / b.clone(), true, true),
+            (a.clone().pow(2) - 1, true, false),
+            (Expr::from(0), true, true),
+            (Expr::from(7), true, true),
+            (Expr::from((a.clone() * b.clone() + 1).canonicalize().unwrap()), true, false),
+        ];
+        for (expr, polynomial, affine) in cases {
+            println!("asserting {expr} is_polynomial {polynomial} is_affine {affine}");
+            assert_eq!(expr.is_polynomial(), Some(polynomial));
+            assert_eq!(expr.is_affine(), Some(affine));
+        }
+
+        // Test expressions without a rational form
+        let modulo = a.clone().modulo(b.clone());
+        assert_eq!(modulo.is_polynomial(), None);
+        assert_eq!(modulo.is_affine(), None);
+
+        // Test expressions beyond the canonicalization budget
+        let huge = (a.clone() + b.clone() + 1).pow(200);
+        println!("asserting {huge} is beyond the budget");
+        assert_eq!(huge.is_polynomial(), None);
+        assert_eq!(huge.is_affine(), None);
+        let huge = a.clone().pow(1 << 20) * a.clone();
+        assert_eq!(huge.is_polynomial(), None);
+    }
+
+    #[test]
+    fn test_inexact_divisions() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+
+        // Test divides
+        assert!(a.divides(&(a.clone() * b.clone())));
+        assert!(Expr::from(2).divides(&(a.clone() * 2 + 4)));
+        assert!((a.clone() + b.clone()).divides(&(a.clone() * a.clone() - b.clone() * b.clone())));
+        assert!(!Expr::from(2).divides(&(a.clone() * (a.clone() + 1))));
+        assert!(!b.divides(&a));
+        assert!(!Expr::from(0).divides(&a));
+
+        // Test a division that is not exact
+        let expr1 = a.clone() / b.clone() * c.clone();
+        let divisions = expr1.inexact_divisions();
+        println!("asserting {expr1} reports a/b");
+        assert_eq!(divisions.len(), 1);
+        assert!(divisions[0].0.structurally_eq(&a) && divisions[0].1.structurally_eq(&b));
+
+        // Test exact divisions
+        assert!((a.clone() * b.clone() / a.clone()).inexact_divisions().is_empty());
+        assert!(((a.clone() * 6 + 3) / 3).inexact_divisions().is_empty());
+        assert!((a.clone() + b.clone()).inexact_divisions().is_empty());
+
+        // Test nested and rational divisions
+        let expr2 = (a.clone() / 2 + b.clone() * c.clone() / c.clone()).modulo(c.clone() / a.clone());
+        let divisions = expr2.inexact_divisions();
+        println!("asserting {expr2} reports a/2 and c/a");
+        assert_eq!(divisions.len(), 2);
+        assert!(divisions[0].0.structurally_eq(&a) && divisions[0].1.structurally_eq(&Expr::from(2)));
+        assert!(divisions[1].0.structurally_eq(&c) && divisions[1].1.structurally_eq(&a));
+        let rational = Expr::from(((a.clone() + 1) / 2).canonicalize().unwrap());
+        let divisions = rational.inexact_divisions();
+        assert_eq!(divisions.len(), 1);
+        assert_eq!(divisions[0].1.to_string(), "2");
+    }
+
+    #[test]
+    fn test_expr_macro() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+
+        // Test the macro builds the same tree as the operators
+        let expr = expr!((a + 1) * 3 / (b + 1));
+        println!("asserting {expr} is (a + 1)*3/(b + 1)");
+        assert!(expr.structurally_eq(&((a.clone() + 1) * 3 / (b.clone() + 1))));
+        assert_eq!(expr.equivalent(&((a.clone() * 3 + 3) / (b.clone() + 1))), Some(true));
+
+        // Test precedence, nesting and single tokens
+        assert!(expr!(a + b * c - 2).structurally_eq(&(a.clone() + b.clone() * c.clone() - 2)));
+        assert!(expr!(a - (b - (c / 4))).structurally_eq(&(a.clone() - (b.clone() - c.clone() / 4))));
+        assert!(expr!(a).structurally_eq(&a));
+        assert!(expr!(42).structurally_eq(&Expr::from(42)));
+        assert!(expr!((a)).structurally_eq(&a));
+    }
+
+    #[test]
+    fn test_fraction_variables() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+        let set = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<BTreeSet<_>>();
+
+        // Test variables on each side of the normal form
+        let expr1 = a.clone() / b.clone();
+        println!("asserting {expr1} has b in the denominator");
+        assert_eq!(expr1.denominator_variables(), set(&["b"]));
+        assert_eq!(expr1.numerator_variables(), set(&["a"]));
+        let expr2 = a.clone() * b.clone() / c.clone();
+        assert_eq!(expr2.denominator_variables(), set(&["c"]));
+        assert_eq!(expr2.numerator_variables(), set(&["a", "b"]));
+
+        // Test cancelled and shared variables
+        let expr3 = a.clone() * b.clone() / a.clone();
+        assert!(expr3.denominator_variables().is_empty());
+        assert_eq!(expr3.numerator_variables(), set(&["b"]));
+        let expr4 = (a.clone() + 1) / (a.clone() + b.clone());
+        assert_eq!(expr4.denominator_variables(), set(&["a", "b"]));
+        assert_eq!(expr4.numerator_variables(), set(&["a"]));
+        assert!((a.clone() - a.clone()).numerator_variables().is_empty());
+
+        // Test expressions without a rational form
+        let expr5 = a.clone().modulo(b.clone());
+        assert_eq!(expr5.denominator_variables(), set(&["a", "b"]));
+        assert_eq!(expr5.numerator_variables(), set(&["a", "b"]));
+    }
+
+    #[test]
+    fn test_abs() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let x = Expr::var("x");
+
+        // Test signed evaluation of the operand
+        let expr1 = (Expr::from(3) - 5).abs();
+        println!("asserting {expr1} is 2");
+        assert_eq!(expr1.substitute(&HashMap::new()), 2);
+        assert_eq!(expr1.checked_substitute(&HashMap::new()), Some(2));
+        assert_eq!(expr1.equivalent(&Expr::from(2)), Some(true));
+        let expr2 = (a.clone() - b.clone()).abs() * 2;
+        assert_eq!(expr2.substitute(&HashMap::from([("a", 3), ("b", 7)])), 8);
+        assert_eq!(expr2.substitute(&HashMap::from([("a", 7), ("b", 3)])), 8);
+        assert_eq!((a.clone() - b.clone() * 3).abs().checked_substitute(&HashMap::from([("a", 1)])), None);
+        let min = Expr::sum([a.clone().negative(), a.clone().negative()]);
+        let overflow = Expr::product([min.positive(), Expr::sum([Expr::from(1).negative()]).negative()]).abs();
+        let value = HashMap::from([("a", 1 << (usize::BITS - 2))]);
+        assert_eq!(overflow.checked_substitute(&value), None);
+        let err = overflow.substitute_with_defaults(&value, &HashMap::new()).unwrap_err();
+        assert!(matches!(err, Error::Eval { kind: EvalError::Overflow, .. }), "{err}");
+
+        // Test variables and partial substitution
+        assert_eq!(expr2.variables(), BTreeSet::from(["a", "b"]));
+        let partial = expr2.partial_substitute(&HashMap::from([("a", 2), ("b", 9)])).unwrap();
+        assert_eq!(partial.as_constant(), Some(14));
+        let partial = expr2.partial_substitute(&HashMap::from([("a", 2)])).unwrap();
+        assert_eq!(partial.substitute(&HashMap::from([("b", 5)])), 6);
+
+        // Test equivalence
+        let neg_x = Expr::from(0) - x.clone();
+        println!("asserting abs(x) == abs(-x)");
+        assert_eq!(x.clone().abs().equivalent(&neg_x.clone().abs()), Some(true));
+        assert_eq!((a.clone() - b.clone()).abs().equivalent(&(b.clone() - a.clone()).abs()), Some(true));
+        assert_eq!(x.clone().abs().equivalent(&(x.clone() * 2).abs()), None);
+        assert_eq!(x.clone().abs().equivalent(&x), None);
+
+        // Test rendering and parsing
+        assert_eq!(expr2.to_string(), "abs(a - b)*2");
+        assert!("abs(a - b)*2".parse::<Expr>().unwrap().structurally_eq(&expr2));
+        assert_eq!(expr1.to_smtlib(true).unwrap(), "(abs (- 3 5))");
+    }
+
+    #[test]
+    fn test_canonicalize_idempotent() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+
+        let mut checked = 0;
+        for expr in corpus() {
+            // Test canonicalizing a canonical form gives an identical structure
+            let Ok(canon) = expr.canonicalize() else {
+                continue;
+            };
+            checked += 1;
+            println!("asserting canonicalization of {expr} is idempotent");
+            let again = Expr::from(canon.clone()).canonicalize().unwrap();
+            assert!(again.structurally_eq(&canon), "{} != {}", *again, *canon);
+            let through_rational = Expr::from(canon.rational().clone()).canonicalize().unwrap();
+            assert!(through_rational.structurally_eq(&canon), "{} != {}", *through_rational, *canon);
+        }
+        println!("asserting {checked} expressions of the corpus were checked");
+        assert!(checked > 150);
+
+        // Test expressions without a rational form
+        assert!(matches!(a.clone().modulo(b.clone()).canonicalize(), Err(Error::NotRational)));
+    }
+
+    #[test]
+    fn test_common_denominator() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+
+        // Test constant denominators combine by lcm
+        let exprs = [a.clone() / 2, b.clone() / 3];
+        let denom = common_denominator(&exprs);
+        println!("asserting the common denominator of a/2, b/3 is {denom}");
+        assert!(denom.structurally_eq(&Expr::from(6)));
+        assert_eq!(exprs[0].scale_to_integer(&denom).to_string(), "3*a");
+        assert_eq!(exprs[1].scale_to_integer(&denom).to_string(), "2*b");
+        assert!(common_denominator(&[a.clone() / 4, b.clone() / 6, c.clone()]).structurally_eq(&Expr::from(12)));
+        assert!(common_denominator(&[]).structurally_eq(&Expr::from(1)));
+
+        // Test variable denominators are multiplied once
+        let exprs = [a.clone() / (c.clone() + 1), b.clone() / (c.clone() * 2 + 2), a.clone() / b.clone()];
+        let denom = common_denominator(&exprs);
+        println!("asserting the common denominator of {exprs:?} is {denom}");
+        assert_eq!(denom.equivalent(&((c.clone() + 1) * b.clone() * 2)), Some(true));
+        for expr in &exprs {
+            let scaled = expr.scale_to_integer(&denom);
+            assert_eq!(scaled.is_polynomial(), Some(true));
+            assert_eq!((scaled / denom.clone()).equivalent(expr), Some(true));
+        }
+
+        // Test a product beyond the term budget is kept unexpanded
+        let names = (0..4).map(|i| (0..8).map(|j| format!("v{i}_{j}")).collect::<Vec<_>>()).collect::<Vec<_>>();
+        let exprs = names.iter().map(|names| Expr::from(1) / (Expr::sum(names.iter().map(|name| Expr::var(name).positive())) + 1)).collect::<Vec<_>>();
+        let denom = common_denominator(&exprs);
+        println!("asserting the common denominator of four sums of 9 terms is kept unexpanded");
+        assert!(matches!(denom.canonicalize(), Err(Error::TooComplex)));
+        let ones = names.iter().flatten().map(|name| (name.as_str(), 1)).collect();
+        assert_eq!(denom.substitute(&ones), 9_usize.pow(4));
+    }
+
+    #[test]
+    fn test_holes() {
+        let n = Expr::var("n");
+        let c = Expr::var("c");
+        let value = HashMap::from([("n", 2), ("c", 3)]);
+
+        // Test taking a dimension out and forgetting to put it back fails loudly
+        let mut shape = [n.clone() * 4, c.clone()];
+        let taken = std::mem::take(&mut shape[0]);
+        let numel = shape.iter().cloned().reduce(|acc, dim| acc * dim).unwrap();
+        println!("asserting {numel} has a hole");
+        assert!(numel.has_holes());
+        assert_eq!(numel.to_string(), "?*c");
+        assert!(matches!(numel.canonicalize(), Err(Error::UnfilledHole)));
+        assert_eq!(numel.substitute_diagnosed(&value).unwrap_err().kind, EvalError::UnfilledHole);
+        assert_eq!(numel.checked_substitute(&value), None);
+        assert_eq!(numel.equivalent(&numel), None);
+        assert_eq!((numel.clone() * Expr::from(0)).equivalent(&Expr::from(0)), None);
+        assert!(std::panic::catch_unwind(|| numel.substitute(&value)).is_err());
+
+        // Test holes survive every operation until filled
+        let derived = (numel.clone() + n.clone()).pow(2).modulo(c.clone()).max(n.clone().abs());
+        assert!(derived.has_holes());
+        assert!(derived.partial_substitute(&value).unwrap().has_holes());
+        assert!(derived.replace_variable("n", &c).has_holes());
+        assert!(derived.sort_operands().has_holes());
+        assert!(!derived.fill_holes(n.clone()).has_holes());
+        let filled = numel.fill_holes(taken);
+        assert_eq!(filled.substitute(&value), 24);
+        assert_eq!(filled.equivalent(&(n.clone() * c.clone() * 4)), Some(true));
+        assert!(!Expr::from(3).has_holes());
+        assert!(Expr::default().structurally_eq(&Expr::Hole));
+    }
+
+    #[test]
+    fn test_lifted_arithmetic() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let known = || Some(a.clone());
+        let ok = || Ok::<_, &str>(b.clone());
+
+        // Test binary operations on known and unknown operands
+        println!("asserting lifted operations match the operators");
+        assert!(opt_add(known(), Some(b.clone())).unwrap().structurally_eq(&(a.clone() + b.clone())));
+        assert!(opt_sub(known(), Some(Expr::from(1))).unwrap().structurally_eq(&(a.clone() - 1)));
+        assert!(opt_mul(known(), known()).unwrap().structurally_eq(&(a.clone() * a.clone())));
+        assert!(opt_div(known(), Some(b.clone())).unwrap().structurally_eq(&(a.clone() / b.clone())));
+        assert!(opt_add(known(), None).is_none());
+        assert!(opt_div(None, known()).is_none());
+        assert!(res_mul(ok(), Ok(a.clone())).unwrap().structurally_eq(&(b.clone() * a.clone())));
+        assert!(res_sub(ok(), ok()).unwrap().structurally_eq(&(b.clone() - b.clone())));
+        assert_eq!(res_add(Err("lhs"), Err("rhs")).unwrap_err(), "lhs");
+        assert_eq!(res_div(ok(), Err("rhs")).unwrap_err(), "rhs");
+
+        // Test folds, with the identities of empty sums and products
+        let sum = sum_opts([known(), Some(b.clone()), Some(Expr::from(2))]).unwrap();
+        assert!(sum.structurally_eq(&(a.clone() + b.clone() + 2)));
+        assert!(product_opts([known(), Some(b.clone())]).unwrap().structurally_eq(&(a.clone() * b.clone())));
+        assert!(sum_opts([known(), None, known()]).is_none());
+        assert!(sum_opts([]).unwrap().structurally_eq(&Expr::Constant(0)));
+        assert!(product_opts([]).unwrap().structurally_eq(&Expr::Constant(1)));
+        assert!(product_results([ok(), ok()]).unwrap().structurally_eq(&(b.clone() * b.clone())));
+        assert_eq!(sum_results([ok(), Err("first"), Err("second")]).unwrap_err(), "first");
+        assert!(sum_results::<()>([]).unwrap().structurally_eq(&Expr::Constant(0)));
+        assert!(product_results::<()>([]).unwrap().structurally_eq(&Expr::Constant(1)));
+    }
+
+    #[test]
+    fn test_terms() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let term = |coef: Ratio<isize>, factors: &[(&str, isize)]| Term {
+            coef,
+            factors: factors.iter().map(|&(base, exponent)| (base.to_string(), exponent)).collect(),
+        };
+
+        // Test the terms of a polynomial, whatever the order it was written in
+        let expr = a.clone() * 2 + b.clone() * 3 + 5;
+        let terms = expr.terms().unwrap();
+        println!("asserting the terms of {expr} are {terms:?}");
+        let expected = [term(Ratio::from_integer(5), &[]), term(Ratio::from_integer(2), &[("a", 1)]), term(Ratio::from_integer(3), &[("b", 1)])];
+        assert_eq!(terms, expected);
+        assert_eq!((Expr::from(5) + b.clone() * 3 + a.clone() * 2).terms().unwrap(), expected);
+
+        // Test constant denominators are folded into the coefficients
+        let expr = (a.clone() * a.clone() * b.clone() * 3 - 1) / 6;
+        let expected = [term(Ratio::new(-1, 6), &[]), term(Ratio::new(1, 2), &[("a", 2), ("b", 1)])];
+        assert_eq!(expr.terms().unwrap(), expected);
+        assert_eq!((a.clone() - a.clone()).terms().unwrap(), []);
+
+        // Test denominators with variables and expressions without a rational form
+        assert_eq!((a.clone() / b.clone()).terms(), None);
+        assert_eq!(a.clone().modulo(b.clone()).terms(), None);
+    }
+
+    #[test]
+    fn test_simplified_arithmetic() {
+        let n = Expr::var("n");
+        let m = Expr::var("m");
+
+        // Test residuals are lowered to plain trees
+        let residual = (n.clone() * 4).sub_simplified(&(n.clone() * 3));
+        println!("asserting n*4 - n*3 is {residual}");
+        assert!(residual.structurally_eq(&n));
+        let total = (n.clone() + 1) * m.clone();
+        assert!(total.sub_simplified(&(n.clone() * m.clone() + m.clone())).structurally_eq(&Expr::Constant(0)));
+        assert!(Expr::from(total.canonicalize().unwrap()).sub_simplified(&total).structurally_eq(&Expr::Constant(0)));
+        assert_eq!(total.sub_simplified(&m).to_string(), "m*n");
+
+        // Test sums and products
+        assert_eq!((n.clone() + 1).add_simplified(&(Expr::from(1) - n.clone())).to_string(), "2");
+        assert!((n.clone() / m.clone()).mul_simplified(&m).structurally_eq(&n));
+        let product = (n.clone() + 1).mul_simplified(&(n.clone() - 1));
+        assert!(!matches!(product, Expr::Rational(_)));
+        assert_eq!(product.equivalent(&(n.clone() * n.clone() - 1)), Some(true));
+
+        // Test expressions without a rational form are combined as they are
+        let modulo = n.clone().modulo(m.clone());
+        assert!(modulo.sub_simplified(&n).structurally_eq(&(modulo.clone() - n.clone())));
+    }
+
+    #[test]
+    fn test_equivalent_with_aliases() {
+        let n = Expr::var("N");
+        let batch = Expr::var("batch");
+        let h = Expr::var("h");
+        let aliases = HashMap::from([("N", "batch"), ("H", "h")]);
+
+        // Test aliases on either side, or both
+        let cases = [
+            (n.clone() + 1, batch.clone() + 1, Some(true)),
+            (batch.clone() * h.clone(), n.clone() * Expr::var("H"), Some(true)),
+            (n.clone() * 2, n.clone() + batch.clone(), Some(true)),
+            (n.clone() + 1, batch.clone() + 2, Some(false)),
+            (n.clone() + h.clone(), batch.clone() + Expr::var("w"), None),
+        ];
+        for (lhs, rhs, expected) in cases {
+            println!("asserting {lhs} and {rhs} with aliases are {expected:?}");
+            assert_eq!(lhs.equivalent_with_aliases(&rhs, &aliases), expected);
+        }
+        assert_eq!((n.clone() + 1).equivalent_with_aliases(&(batch.clone() + 1), &HashMap::new()), None);
+    }
+
+    #[test]
+    fn test_normalize_names() {
+        let lower = |name: &str| name.to_lowercase();
+
+        // Test variables with the same normalized name merge
+        let expr1 = Expr::var("Batch") + Expr::var("batch");
+        let normalized = expr1.normalize_names(lower);
+        println!("asserting {expr1} normalizes to {normalized}");
+        assert_eq!(normalized.to_string(), "2*batch");
+        assert_eq!(normalized.variables(), BTreeSet::from(["batch"]));
+        let expr2 = Expr::var("N") * Expr::var("n") / (Expr::var("C") + 1) - Expr::var("c");
+        let normalized = expr2.normalize_names(lower);
+        assert_eq!(normalized.equivalent(&(Expr::var("n").pow(2) / (Expr::var("c") + 1) - Expr::var("c"))), Some(true));
+
+        // Test rational nodes and nodes without a rational form
+        let rational = Expr::from((Expr::var("A") * 2 + Expr::var("a")).canonicalize().unwrap());
+        assert_eq!(rational.normalize_names(lower).to_string(), "3*a");
+        let modulo = Expr::var("A").modulo(Expr::var("B") + Expr::var("b"));
+        assert_eq!(modulo.normalize_names(lower).to_string(), "mod(a, b + b)");
+    }
+
+    #[test]
+    fn test_match_up_to_rename() {
+        let [n0, c0, n1, c1] = ["n0", "c0", "n1", "c1"].map(Expr::var);
+
+        // Test a renaming between namespaces
+        let lhs = (n0.clone() + 2) * c0.clone();
+        let rhs = (n1.clone() + 2) * c1.clone();
+        let map = lhs.match_up_to_rename(&rhs).unwrap();
+        println!("asserting {lhs} matches {rhs} with {map:?}");
+        assert_eq!(map, BTreeMap::from([("n0".to_string(), "n1".to_string()), ("c0".to_string(), "c1".to_string())]));
+        let map = (n0.clone() / (c0.clone() + 1)).match_up_to_rename(&(c1.clone() / (n1.clone() + 1))).unwrap();
+        assert_eq!(map["n0"], "c1");
+        assert_eq!(map["c0"], "n1");
+
+        // Test expressions that do not match
+        assert_eq!((n0.clone() * 2).match_up_to_rename(&(n1.clone() * 3)), None);
+        assert_eq!((n0.clone() * c0.clone()).match_up_to_rename(&n1), None);
+        assert_eq!((n0.clone() + c0.clone() * 2).match_up_to_rename(&(n1.clone() * 2 + c1.clone() * 3)), None);
+        assert_eq!(n0.clone().modulo(c0.clone()).match_up_to_rename(&n1.clone().modulo(c1.clone())), None);
+
+        // Test symmetric expressions give any valid renaming
+        let lhs = n0.clone() * c0.clone() + n0.clone() + c0.clone();
+        let rhs = n1.clone() * c1.clone() + c1.clone() + n1.clone();
+        let map = lhs.match_up_to_rename(&rhs).unwrap();
+        let renamed = lhs.normalize_names(|name| map[name].clone());
+        assert_eq!(renamed.equivalent(&rhs), Some(true));
+
+        // Test the search gives up quickly on many variables of the same degree, as two cycles
+        // of products of 7 variables do not match one cycle of 14
+        let cycle = |names: &[String]| Expr::sum((0..names.len()).map(|i| (Expr::var(&names[i]) * Expr::var(&names[(i + 1) % names.len()])).positive()));
+        let xs = (0..14).map(|i| format!("x{i}")).collect::<Vec<_>>();
+        let ys = (0..14).map(|i| format!("y{i}")).collect::<Vec<_>>();
+        let mut steps = MAX_RENAME_STEPS;
+        println!("asserting the search over 14 variables gives up after {MAX_RENAME_STEPS} steps");
+        assert_eq!((cycle(&xs[..7]) + cycle(&xs[7..])).match_up_to_rename_within(&cycle(&ys), &mut steps), None);
+        assert_eq!(steps, 0);
+        let mut steps = MAX_RENAME_STEPS;
+        assert!(cycle(&xs[..7]).match_up_to_rename_within(&cycle(&ys[..7]), &mut steps).is_some());
+        assert!(steps > 0);
+    }
+
+    #[test]
+    fn test_proportional_to() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+
+        // Test constant multiples
+        let expr1 = a.clone() * 2 + b.clone() * 2;
+        println!("asserting {expr1} is 2 times a + b");
+        assert_eq!(expr1.proportional_to(&(a.clone() + b.clone())), Some(Ratio::from_integer(2)));
+        assert_eq!((a.clone() + b.clone()).proportional_to(&expr1), Some(Ratio::new(1, 2)));
+        assert_eq!((a.clone() / (c.clone() + 1)).proportional_to(&(a.clone() * 3 / (c.clone() * 2 + 2))), Some(Ratio::new(2, 3)));
+        assert_eq!(Expr::from(6).proportional_to(&Expr::from(4)), Some(Ratio::new(3, 2)));
+        assert_eq!((a.clone() - a.clone()).proportional_to(&b), Some(Ratio::from_integer(0)));
+
+        // Test expressions that are not proportional
+        assert_eq!((a.clone() + b.clone()).proportional_to(&(a.clone() + c.clone())), None);
+        assert_eq!((a.clone() * a.clone()).proportional_to(&a), None);
+        assert_eq!(a.proportional_to(&Expr::from(0)), None);
+        assert_eq!(a.clone().modulo(b.clone()).proportional_to(&a), None);
+    }
+
+    #[test]
+    fn test_exponents_of() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+
+        // Test exponents in the numerator terms
+        let expr1 = a.clone() + a.clone() * a.clone() + Expr::from(1) / a.clone();
+        println!("asserting {expr1} has exponents -1, 1, 2 of a");
+        assert_eq!(expr1.exponents_of("a"), BTreeSet::from([-1, 1, 2]));
+        assert_eq!((a.clone().pow(3) * b.clone() + b.clone()).exponents_of("a"), BTreeSet::from([3]));
+        assert_eq!((a.clone().pow(3) * b.clone() + b.clone()).exponents_of("b"), BTreeSet::from([1]));
+        assert_eq!((a.clone() / b.clone().pow(2)).exponents_of("b"), BTreeSet::from([-2]));
+
+        // Test denominators with several terms
+        let expr2 = a.clone() / (a.clone() * a.clone() + b.clone());
+        assert_eq!(expr2.exponents_of("a"), BTreeSet::from([-2, 1]));
+        assert_eq!(expr2.exponents_of("b"), BTreeSet::from([-1]));
+
+        // Test absent variables and cancellation
+        assert!(a.exponents_of("b").is_empty());
+        assert!((a.clone() * b.clone() / a.clone()).exponents_of("a").is_empty());
+        assert!(a.clone().modulo(b.clone()).exponents_of("a").is_empty());
+    }
+
+    #[test]
+    fn test_is_integer_valued() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+
+        let cases = [
+            (a.clone() + 1, Some(true)),
+            (a.clone() / 2, Some(false)),
+            (a.clone() / b.clone(), None),
+            (a.clone() * 2 / 2, Some(true)),
+            (a.clone() * (a.clone() + 1) / 2, Some(true)),
+            (a.clone() * b.clone() * (a.clone() + b.clone()) / 2, Some(true)),
+            ((a.clone() * 3 + b.clone()) / 3, Some(false)),
+            (a.clone() * b.clone() / b.clone(), Some(true)),
+            ((a.clone() * a.clone() - b.clone() * b.clone()) / (a.clone() - b.clone()), Some(true)),
+            (Expr::from(3) / 2, Some(false)),
+            (a.clone() / (b.clone() + 1), None),
+        ];
+        for (expr, expected) in cases {
+            println!("asserting {expr} is_integer_valued {expected:?}");
+            assert_eq!(expr.is_integer_valued(), expected);
+        }
+        assert_eq!(a.clone().modulo(b.clone()).is_integer_valued(), None);
+    }
+
+    #[test]
+    fn test_specialize() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+
+        // Test a single assumption combines like terms
+        let expr = a.clone() * b.clone() + c.clone();
+        let specialized = expr.specialize("a", 1).unwrap();
+        println!("asserting {specialized} is b + c");
+        assert_eq!(specialized.equivalent(&(b.clone() + c.clone())), Some(true));
+        assert_eq!(specialized.to_string(), "b + c");
+        let expr = a.clone() * b.clone() + b.clone() * 2;
+        assert_eq!(expr.specialize("a", 3).unwrap().equivalent(&(b.clone() * 5)), Some(true));
+
+        // Test several assumptions at once
+        let expr = (a.clone() + b.clone()) * c.clone() / 2;
+        let specialized = expr.specialize_all(&[("a", 1), ("b", 3)]).unwrap();
+        assert_eq!(specialized.equivalent(&(c.clone() * 2)), Some(true));
+        assert_eq!(specialized.variables(), BTreeSet::from(["c"]));
+        assert_eq!(expr.specialize_all(&[]).unwrap().equivalent(&expr), Some(true));
+    }
+
+    #[test]
+    fn test_sum_terms() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+
+        // Test the signed decomposition of sums and products
+        let expr = a.clone() - b.clone() + 1;
+        println!("asserting the terms of {expr}");
+        let terms = expr.as_sum_terms().unwrap();
+        assert_eq!(terms.iter().map(|(sign, _)| *sign).collect::<Vec<_>>(), [Sign::Positive, Sign::Negative, Sign::Positive]);
+        assert!(terms[1].1.structurally_eq(&b));
+        assert!(expr.as_product_factors().is_none());
+        let expr = a.clone() * 3 / b.clone();
+        let factors = expr.as_product_factors().unwrap();
+        assert_eq!(factors.len(), 3);
+        assert_eq!(factors[2].0, Sign::Negative);
+        assert!(expr.as_sum_terms().is_none());
+        assert!(a.as_sum_terms().is_none() && a.as_product_factors().is_none());
+
+        // Test the consuming variant wraps other expressions
+        let terms = (a.clone() - b.clone()).into_sum_terms();
+        assert_eq!(terms.len(), 2);
+        assert_eq!(terms[1].0, Sign::Negative);
+        let terms = (a.clone() * b.clone()).into_sum_terms();
+        assert_eq!(terms.len(), 1);
+        assert_eq!(terms[0].0, Sign::Positive);
+        assert!(terms[0].1.structurally_eq(&(a.clone() * b.clone())));
+
+        // Test the boolean signs of a - b + c
+        let c = Expr::var("c");
+        let signs = (a.clone() - b.clone() + c.clone()).sum_signs().unwrap();
+        assert_eq!(signs.iter().map(|(positive, _)| *positive).collect::<Vec<_>>(), [true, false, true]);
+        assert!(signs.iter().zip([&a, &b, &c]).all(|((_, term), expected)| term.structurally_eq(expected)));
+        assert!((a.clone() * b.clone()).sum_signs().is_none());
+    }
+
+    #[test]
+    fn test_recip() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+
+        // Test variables, trees and rational nodes
+        let recip = a.clone().recip().unwrap();
+        println!("asserting 1/a is {recip}");
+        assert_eq!(recip.equivalent(&(Expr::from(1) / a.clone())), Some(true));
+        assert_eq!((a.clone() / b.clone()).recip().unwrap().equivalent(&(b.clone() / a.clone())), Some(true));
+        let rational = Expr::from(((a.clone() + 1) / (b.clone() * 2)).canonicalize().unwrap());
+        let recip = rational.clone().recip().unwrap();
+        assert!(matches!(recip, Expr::Rational(_)));
+        assert_eq!(recip.equivalent(&(b.clone() * 2 / (a.clone() + 1))), Some(true));
+
+        // Test the reciprocal of the reciprocal
+        for expr in [a.clone(), a.clone() / b.clone(), rational, (a.clone() + b.clone()) * 3] {
+            println!("asserting 1/(1/({expr})) is {expr}");
+            assert_eq!(expr.clone().recip().unwrap().recip().unwrap().equivalent(&expr), Some(true));
+        }
+        let modulo = a.clone().modulo(b.clone());
+        let twice = modulo.clone().recip().unwrap().recip().unwrap();
+        assert!(twice.equal_on_grid(&modulo, &[HashMap::from([("a", 7), ("b", 3)]), HashMap::from([("a", 9), ("b", 4)])]));
+
+        // Test provably zero expressions
+        assert!(Expr::from(0).recip().is_none());
+        assert!((a.clone() * b.clone() - b.clone() * a.clone()).recip().is_none());
+        assert!(Expr::from((a.clone() - a.clone()).canonicalize().unwrap()).recip().is_none());
+        assert!(a.clone().modulo(b.clone()).recip().is_some());
+    }
+
+    #[test]
+    fn test_max() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+
+        // Test evaluation and display
+        let expr = a.clone().max(b.clone() - 1) + 1;
+        println!("asserting {expr} evaluates to the larger side");
+        assert_eq!(expr.to_string(), "max(a, b - 1) + 1");
+        assert_eq!(expr.to_string().parse::<Expr>().unwrap().to_string(), expr.to_string());
+        assert_eq!(expr.substitute(&HashMap::from([("a", 3), ("b", 7)])), 7);
+        assert_eq!(expr.checked_substitute(&HashMap::from([("a", 9), ("b", 7)])), Some(10));
+        assert_eq!(expr.variables(), BTreeSet::from(["a", "b"]));
+
+        // Test folding and comparison
+        let partial = expr.partial_substitute(&HashMap::from([("b", 5)])).unwrap();
+        assert_eq!(partial.to_string(), "max(a, 4) + 1");
+        assert_eq!(expr.partial_substitute(&HashMap::from([("a", 2), ("b", 5)])).unwrap().as_constant(), Some(5));
+        assert_eq!(a.clone().max(a.clone()).equivalent(&a), Some(true));
+        assert_eq!(a.clone().max(b.clone()).equivalent(&b.clone().max(a.clone())), Some(true));
+        assert_eq!(a.clone().max(b.clone()).equivalent(&a), None);
+
+        // Test opaque nodes are equivalent to the same tree up to the order of operands
+        let max = a.clone().max(b.clone());
+        assert_eq!(max.equivalent(&a.clone().max(b.clone())), Some(true));
+        assert_eq!((max.clone() + 1).equivalent(&(Expr::from(1) + b.clone().max(a.clone()))), Some(true));
+        assert_eq!((max.clone() * a.clone()).equivalent(&(a.clone() * max.clone())), Some(true));
+        let modulo = a.clone().modulo(b.clone());
+        assert_eq!((modulo.clone() + max.clone()).equivalent(&(max.clone() + modulo.clone())), Some(true));
+        assert_eq!(modulo.equivalent(&b.clone().modulo(a.clone())), None);
+        assert_eq!((max.clone() + 1).equivalent(&(max.clone() + 2)), None);
+
+        // Test trees dividing by zero stay undecidable in any operand order
+        let c = Expr::var("c");
+        let undefined = a.clone() / (b.clone() - b.clone());
+        assert_eq!((undefined.clone() + c.clone()).equivalent(&(c.clone() + undefined.clone())), None);
+        assert_eq!(undefined.equivalent(&(a.clone() / (b.clone() * 2 - b.clone() * 2))), None);
+        let opaque = max.clone() + a.clone().modulo(Expr::from(0));
+        assert_eq!(opaque.equivalent(&(a.clone().modulo(Expr::from(0)) + max.clone())), None);
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let n = Expr::var("n");
+        let m = Expr::var("m");
+        let one = Expr::from(1);
+
+        // Test a dimension of 1 stretches to the other one
+        println!("asserting 1 broadcasts to n");
+        assert!(n.broadcast(&one).unwrap().structurally_eq(&n));
+        assert!(one.broadcast(&n).unwrap().structurally_eq(&n));
+        assert_eq!(one.broadcast(&one).unwrap().as_constant(), Some(1));
+        assert_eq!((Expr::from(2) - 1).broadcast(&Expr::from(4)).unwrap().as_constant(), Some(4));
+
+        // Test equal dimensions
+        assert!(n.broadcast(&n).unwrap().structurally_eq(&n));
+        let doubled = n.clone() * 2;
+        assert_eq!(doubled.broadcast(&(n.clone() + n.clone())).unwrap().equivalent(&doubled), Some(true));
+        assert_eq!(Expr::from(3).broadcast(&Expr::from(3)).unwrap().as_constant(), Some(3));
+
+        // Test incompatible constants and unknown dimensions
+        assert!(Expr::from(3).broadcast(&Expr::from(4)).is_none());
+        assert!(Expr::from(0).broadcast(&Expr::from(2)).is_none());
+        let unknown = n.broadcast(&m).unwrap();
+        assert_eq!(unknown.to_string(), "max(n, m)");
+        assert_eq!(unknown.substitute(&HashMap::from([("n", 1), ("m", 6)])), 6);
+    }
+
+    #[test]
+    fn test_sort_operands() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+
+        // Test commuted inputs render the same
+        let pairs = [
+            (a.clone() + b.clone() * 2 + 1, Expr::from(1) + Expr::from(2) * b.clone() + a.clone()),
+            (a.clone() * b.clone() / c.clone(), b.clone() / c.clone() * a.clone()),
+            (a.clone() - b.clone() + c.clone(), c.clone() - b.clone() + a.clone()),
+            (a.clone().max(b.clone() + c.clone()), (c.clone() + b.clone()).max(a.clone())),
+        ];
+        let values = HashMap::from([("a", 12), ("b", 3), ("c", 2)]);
+        for (x, y) in pairs {
+            println!("asserting {x} and {y} sort the same");
+            assert_eq!(format!("{x:#}"), format!("{y:#}"));
+            assert!(x.sort_operands().structurally_eq(&y.sort_operands()));
+            assert_eq!(x.sort_operands().substitute(&values), x.substitute(&values));
+        }
+
+        // Test subtractions and divisions stay after their operands
+        assert_eq!(format!("{:#}", a.clone() - b.clone()), "a - b");
+        assert_eq!(format!("{:#}", Expr::from(0) - b.clone() + a.clone()), "a + 0 - b");
+        assert_eq!(format!("{:#}", Expr::from(6) / b.clone() * a.clone()), "a*6/b");
+        assert_eq!(format!("{:#}", (c.clone() + a.clone()).pow(2) * (b.clone() + a.clone())), "(a + c)^2*(a + b)");
+        assert_eq!(format!("{:#}", c.clone() + a.clone()), (c.clone() + a.clone()).sort_operands().to_string());
+
+        // Test the structure is kept without distributing or combining
+        assert!((b.clone() + a.clone()).sort_operands().structurally_eq(&(a.clone() + b.clone())));
+        let expr = Expr::from(2) * (a.clone() + 1);
+        assert!(expr.sort_operands().structurally_eq(&((a.clone() + 1) * 2)));
+        assert_eq!(expr.sort_operands().to_string(), "(a + 1)*2");
+        assert_eq!((a.clone() + a.clone()).sort_operands().to_string(), "a + a");
+    }
+
+    #[test]
+    fn test_gradient() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+
+        // Test the partials of a*b + c
+        let gradient = (a.clone() * b.clone() + c.clone()).gradient().unwrap();
+        println!("asserting the gradient of a*b + c is {{a: b, b: a, c: 1}}");
+        assert_eq!(gradient.len(), 3);
+        assert_eq!(gradient["a"].equivalent(&b), Some(true));
+        assert_eq!(gradient["b"].equivalent(&a), Some(true));
+        assert_eq!(gradient["c"].equivalent(&Expr::from(1)), Some(true));
+
+        // Test powers and the quotient rule
+        let expr = a.clone().pow(3) * b.clone() / c.clone() - b.clone() * 2;
+        let gradient = expr.gradient().unwrap();
+        assert_eq!(gradient["a"].equivalent(&(a.clone() * a.clone() * b.clone() * 3 / c.clone())), Some(true));
+        assert_eq!(gradient["b"].equivalent(&(a.clone().pow(3) / c.clone() - 2)), Some(true));
+        let expected = Expr::from(0) - a.clone().pow(3) * b.clone() / (c.clone() * c.clone());
+        assert_eq!(gradient["c"].equivalent(&expected), Some(true));
+        let quotient = (a.clone() / (a.clone() + b.clone())).derivative("a").unwrap();
+        let expected = b.clone() / ((a.clone() + b.clone()) * (a.clone() + b.clone()));
+        assert_eq!(quotient.equivalent(&expected), Some(true));
+
+        // Test constants and expressions without a rational form
+        assert_eq!(a.derivative("b").unwrap().equivalent(&Expr::from(0)), Some(true));
+        assert!(Expr::from(5).gradient().unwrap().is_empty());
+        assert!(a.clone().modulo(b.clone()).gradient().is_none());
+    }
+
+    #[test]
+    fn test_product_of_binomials() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+
+        // Test twenty binomials multiply out to the monomials a^i*b^j with i <= 14, j <= 13 and
+        // 7 <= i + j <= 20, and evaluate like the tree
+        let binomials = (0..20).map(|i| match i % 3 {
+            0 => a.clone() + b.clone(),
+            1 => a.clone() + 1,
+            _ => b.clone() + 2,
+        });
+        let product = binomials.fold(Expr::from(1), |acc, binomial| acc * binomial);
+        let canon = Expr::from(product.canonicalize().unwrap());
+        println!("asserting the product of 20 binomials has 154 terms");
+        assert_eq!(canon.terms().unwrap().len(), 154);
+        for (va, vb) in [(0, 0), (1, 2), (3, 1), (2, 3)] {
+            let values = HashMap::from([("a", va), ("b", vb)]);
+            assert_eq!(canon.substitute(&values), product.substitute(&values));
+        }
+
+        // Test products of distinct binomials stop at the term budget
+        let binomials = |n: usize| (0..n).fold(Expr::from(1), |acc, i| acc * (Expr::var(format!("x{i}")) + 1));
+        println!("asserting a product of 13 distinct binomials is too complex");
+        let err = binomials(13).canonicalize().unwrap_err();
+        assert!(matches!(err, Error::TooComplex));
+        assert_eq!(err.to_string(), "expression exceeds the exponent or expanded term limit");
+        assert_eq!(binomials(8).terms().unwrap().len(), 1 << 8);
+
+        // Test sums of fractions stop at the term budget of their common denominator
+        let fractions = |n: usize| Expr::sum((0..n).map(|i| (Expr::from(1) / (Expr::var(format!("v{i}")) + 1)).positive()));
+        println!("asserting a sum of 14 fractions with distinct denominators is too complex");
+        assert!(matches!(fractions(14).canonicalize(), Err(Error::TooComplex)));
+        assert_eq!(fractions(4).canonicalize().unwrap().rational().denom.len(), 1 << 4);
+
+        // Test scaling a long sum by a monomial is not limited
+        let long = (0..=MAX_EXPANDED_TERMS).map(|i| CanonicalTerm::with_var(1, format!("x{i}"))).collect::<Vec<_>>();
+        let scaled = CanonicalTerm::multiply_terms_within(&long, &[CanonicalTerm::with_var(2, "y".to_string())], MAX_EXPANDED_TERMS);
+        assert_eq!(scaled.unwrap().len(), MAX_EXPANDED_TERMS + 1);
+        let pair = [CanonicalTerm::new(1), CanonicalTerm::with_var(1, "y".to_string())];
+        assert!(CanonicalTerm::multiply_terms_within(&long, &pair, MAX_EXPANDED_TERMS).is_none());
+    }
+
+    #[test]
+    fn test_new_validated() {
+        let term = |coef: isize, factors: &[(&str, isize)]| -> RawTerm {
+            (Ratio::from_integer(coef), factors.iter().map(|&(base, e)| (base.to_string(), e)).collect())
+        };
+        let one = || vec![term(1, &[])];
+
+        // Test valid terms are kept as they are
+        let rational = RationalExpression::new_validated(vec![term(3, &[("a", 2), ("b", -1)]), term(1, &[])], vec![term(2, &[("c", 1)])]).unwrap();
+        println!("asserting {rational} is accepted");
+        let expected = (Expr::var("a") * Expr::var("a") * 3 / Expr::var("b") + 1) / (Expr::var("c") * 2);
+        assert_eq!(Expr::Rational(rational).equivalent(&expected), Some(true));
+
+        // Test each invariant violation
+        let unsorted = RationalExpression::new_validated(vec![term(1, &[("b", 1), ("a", 1)])], one());
+        assert_eq!(unsorted.unwrap_err(), InvariantError::UnsortedFactors);
+        let duplicate = RationalExpression::new_validated(one(), vec![term(1, &[("a", 1), ("a", 2)])]);
+        assert_eq!(duplicate.unwrap_err(), InvariantError::DuplicateFactor("a".to_string()));
+        let zero = RationalExpression::new_validated(vec![term(1, &[("a", 1), ("b", 0)])], one());
+        assert_eq!(zero.unwrap_err(), InvariantError::ZeroExponent("b".to_string()));
+        let cancelling = RationalExpression::new_validated(one(), vec![term(2, &[("a", 1)]), term(-2, &[("a", 1)])]);
+        assert_eq!(cancelling.unwrap_err(), InvariantError::ZeroDenominator);
+        assert_eq!(RationalExpression::new_validated(one(), vec![]).unwrap_err(), InvariantError::ZeroDenominator);
+
+        // Test normalizing accepts the same terms
+        let rational = RationalExpression::new_normalized(vec![term(2, &[("b", 1), ("a", 1), ("a", 1), ("c", 0)])], vec![term(4, &[("a", 1)])]).unwrap();
+        println!("asserting the normalized form is {rational}");
+        assert_eq!(Expr::Rational(rational).equivalent(&(Expr::var("a") * Expr::var("b") / 2)), Some(true));
+        let cancelling = RationalExpression::new_normalized(one(), vec![term(1, &[("a", 1)]), term(-1, &[("a", 2), ("a", -1)])]);
+        assert_eq!(cancelling.unwrap_err(), InvariantError::ZeroDenominator);
+
+        // Test exponents out of range, also when they are merged
+        assert!(RationalExpression::new_validated(vec![term(1, &[("a", -MAX_EXPONENT)])], one()).is_ok());
+        let huge = RationalExpression::new_validated(vec![term(1, &[("a", MAX_EXPONENT + 1)])], one());
+        assert_eq!(huge.unwrap_err(), InvariantError::ExponentOutOfRange("a".to_string()));
+        let min = RationalExpression::new_validated(one(), vec![term(1, &[("c", isize::MIN)])]);
+        assert_eq!(min.unwrap_err(), InvariantError::ExponentOutOfRange("c".to_string()));
+        let merged = RationalExpression::new_normalized(one(), vec![term(1, &[("b", MAX_EXPONENT), ("b", 1)])]);
+        assert_eq!(merged.unwrap_err(), InvariantError::ExponentOutOfRange("b".to_string()));
+        let min = RationalExpression::new_normalized(vec![term(1, &[("a", isize::MIN)])], one());
+        assert_eq!(min.unwrap_err(), InvariantError::ExponentOutOfRange("a".to_string()));
+
+        // Test overflowing sums of coefficients are errors
+        let sum = RationalExpression::new_normalized(vec![term(isize::MAX, &[]), term(isize::MAX, &[])], one());
+        assert_eq!(sum.unwrap_err(), InvariantError::CoefficientOverflow);
+        let denom = RationalExpression::new_normalized(one(), vec![term(isize::MAX, &[("a", 1)]), term(isize::MAX, &[("a", 1)])]);
+        assert_eq!(denom.unwrap_err(), InvariantError::CoefficientOverflow);
+    }
+
+    #[test]
+    fn test_exponent_bound() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let half = (MAX_EXPONENT / 2) as u32;
+
+        // Test exponents up to the bound are canonicalized
+        let near = a.clone().pow(half) * a.clone().pow(half);
+        println!("asserting {near} is canonicalized");
+        let canon = Expr::from(near.canonicalize().unwrap());
+        assert_eq!(canon.equivalent(&a.clone().pow(MAX_EXPONENT as u32)), Some(true));
+        assert_eq!(canon.checked_substitute(&HashMap::from([("a", 1), ("b", 1)])), Some(1));
+        assert_eq!(canon.checked_substitute(&HashMap::from([("a", 2), ("b", 1)])), None);
+
+        // Test exponents that could exceed the bound are refused
+        let over = [near.clone() * a.clone(), near.clone() / b.clone(), (a.clone() + b.clone()).pow(half + 1) * 2, (near.clone() - 1).pow(2)];
+        for expr in over {
+            println!("asserting {expr} is too complex");
+            assert!(matches!(expr.canonicalize(), Err(Error::TooComplex)));
+            assert_eq!(expr.equivalent(&a), None);
+        }
+        assert!(matches!(a.clone().modulo(b.clone()).canonicalize(), Err(Error::NotRational)));
+
+        // Test powers of the same base are merged before they are expanded
+        let merged = a.clone().pow(1000) * a.clone().pow(1000);
+        let canon = Expr::from(merged.canonicalize().unwrap());
+        assert_eq!(canon.terms().unwrap().len(), 1);
+        assert_eq!(canon.equivalent(&a.clone().pow(2000)), Some(true));
+        let sum = a.clone() + b.clone();
+        let merged = sum.clone().pow(2) * sum.clone() * sum.clone().pow(2);
+        assert_eq!(merged.equivalent(&sum.clone().pow(5)), Some(true));
+        assert_eq!(Expr::from(merged.canonicalize().unwrap()).terms().unwrap().len(), 6);
+
+        // Test powers expanding to too many terms are refused
+        let c = Expr::var("c");
+        let wide = (a.clone() + b.clone() + c.clone()).pow(60) * (a.clone() + b.clone() + c.clone()).pow(60);
+        println!("asserting {wide} is too complex");
+        assert!(matches!(wide.canonicalize(), Err(Error::TooComplex)));
+        assert_eq!(wide.equivalent(&a), None);
+        assert!((a.clone() + b.clone() + c.clone()).pow(20).canonicalize().is_ok());
+
+        // Test parsing refuses exponents out of range
+        assert!(format!("a^{MAX_EXPONENT}").parse::<Expr>().is_ok());
+        let text = format!("a^{}", MAX_EXPONENT + 1);
+        assert_eq!(text.parse::<Expr>().unwrap_err(), ParseError::Overflow { position: 2 });
+    }
+
+    #[test]
+    fn test_deterministic_output() {
+        use std::hash::RandomState;
+
+        // The same entries inserted in opposite orders into maps with different hasher seeds
+        let maps = |pairs: &[(&'static str, usize)]| {
+            let mut forward = HashMap::with_hasher(RandomState::new());
+            forward.extend(pairs.iter().copied());
+            let mut reversed = HashMap::with_hasher(RandomState::new());
+            reversed.extend(pairs.iter().rev().copied());
+            [forward, reversed]
+        };
+        let (a, b, c, d) = (Expr::var("a"), Expr::var("b"), Expr::var("c"), Expr::var("d"));
+        let expr = (d.clone() * c.clone() + b.clone() * a.clone() - 1) / (b.clone() + 2) + c.clone().max(a.clone());
+        let run = |env: &HashMap<&str, usize>| {
+            let coeffs = env.iter().map(|(&name, &value)| (name, value as isize - 2)).collect::<HashMap<_, _>>();
+            let named = [("expr", &expr), ("b", &b)];
+            let gradient = (d.clone() * c.clone() + b.clone() * a.clone()).gradient().unwrap();
+            let partial = HashMap::from_iter(env.iter().filter(|&(&name, _)| name != "c").map(|(&name, &value)| (name, value)));
+            [
+                format!("{}", expr.canonicalize().map(Expr::from).unwrap_or(expr.clone())),
+                format!("{:?}", expr.to_bytes()),
+                format!("{:?}", expr.partial_substitute(&partial).map(|e| e.to_string())),
+                format!("{:?}", expr.checked_substitute(env)),
+                format!("{:?}", validate_env(&named, &partial)),
+                format!("{:?}", (c.clone() + d.clone()).substitute_with_defaults(&HashMap::new(), &HashMap::new()).unwrap_err().to_string()),
+                format!("{}", Expr::from_linear(&coeffs, 1)),
+                format!("{gradient:?}"),
+            ]
+            .join("\n")
+        };
+
+        // Test two runs and both maps render byte-identical output
+        let [forward, reversed] = maps(&[("a", 3), ("b", 4), ("c", 5), ("d", 7)]);
+        let first = run(&forward);
+        println!("asserting the output is deterministic:\n{first}");
+        assert_eq!(run(&forward), first);
+        assert_eq!(run(&reversed), first);
+        for _ in 0..8 {
+            let [forward, reversed] = maps(&[("d", 7), ("c", 5), ("b", 4), ("a", 3)]);
+            assert_eq!(run(&forward), first);
+            assert_eq!(run(&reversed), first);
+        }
+    }
+
+    #[test]
+    fn test_partial_substitute_ratio() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let half = HashMap::from([("a", Ratio::new(1, 2))]);
+
+        // Test fractions combine exactly
+        let expr = a.clone() * 2;
+        println!("asserting {expr} with a = 1/2 is 1");
+        assert_eq!(expr.partial_substitute_ratio(&half).unwrap().as_constant(), Some(1));
+        let expr = a.clone() * a.clone() * b.clone() * 8 + b.clone();
+        let partial = expr.partial_substitute_ratio(&half).unwrap();
+        assert_eq!(partial.equivalent(&(b.clone() * 3)), Some(true));
+
+        // Test negative exponents divide by the fraction
+        let expr = b.clone() / (a.clone() * a.clone()) + 1;
+        let partial = expr.partial_substitute_ratio(&HashMap::from([("a", Ratio::new(2, 3))])).unwrap();
+        println!("asserting {expr} with a = 2/3 is {partial}");
+        assert_eq!(partial.equivalent(&(b.clone() * 9 / 4 + 1)), Some(true));
+        let expr = a.clone() / (b.clone() * 3 - 1);
+        let partial = expr.partial_substitute_ratio(&HashMap::from([("b", Ratio::new(1, 2))])).unwrap();
+        assert_eq!(partial.equivalent(&(a.clone() * 2)), Some(true));
+
+        // Test failures
+        assert!(expr.partial_substitute_ratio(&HashMap::from([("b", Ratio::new(1, 3))])).is_none());
+        assert!((b.clone() / a.clone()).partial_substitute_ratio(&HashMap::from([("a", Ratio::from_integer(0))])).is_none());
+        assert!(a.clone().modulo(b.clone()).partial_substitute_ratio(&half).is_none());
+        let c = Expr::var("c");
+        let sum = a.clone() * b.clone() + a.clone() * c.clone();
+        let large = HashMap::from([("b", Ratio::from_integer(1 << 62)), ("c", Ratio::from_integer(1 << 62))]);
+        println!("asserting {sum} overflows with b = c = 2^62");
+        assert!(sum.partial_substitute_ratio(&large).is_none());
+        assert!(sum.partial_substitute(&HashMap::from([("b", 1 << 62), ("c", 1 << 62)])).is_none());
+
+        // Test a baked scale factor evaluates later like the direct evaluation
+        let (n, scale) = (Expr::var("n"), Expr::var("scale"));
+        let expr = n.clone() * scale.clone() + b.clone() * scale.clone() * 3;
+        let baked = expr.partial_substitute_ratio(&HashMap::from([("scale", Ratio::new(2, 3))])).unwrap();
+        println!("asserting {expr} with scale = 2/3 is {baked}");
+        assert_eq!(baked.to_string(), "2*b + 2*n/3");
+        assert_eq!(baked.variables(), BTreeSet::from(["b", "n"]));
+        for (n_value, b_value) in [(3, 1), (6, 5), (0, 2)] {
+            let direct = HashMap::from([("n", Ratio::from_integer(n_value)), ("b", Ratio::from_integer(b_value)), ("scale", Ratio::new(2, 3))]);
+            let value = HashMap::from([("n", n_value as usize), ("b", b_value as usize)]);
+            assert_eq!(Ok(baked.substitute(&value)), expr.evaluate_with_ratios_integral(&direct));
+        }
+
+        // Test the natural substitution shares the exact one and rejects zero denominators
+        let expr = a.clone() / (b.clone() - 1);
+        assert!(expr.partial_substitute(&HashMap::from([("b", 1)])).is_none());
+        assert_eq!(expr.partial_substitute(&HashMap::from([("b", 3)])).unwrap().to_string(), "a/2");
+    }
+
+    #[test]
+    fn test_validate() {
+        let term = |coef: isize, factors: &[(&str, isize)]| CanonicalTerm {
+            coef: Ratio::from_integer(coef),
+            factors: factors.iter().map(|&(base, exponent)| Factor { base: base.to_string(), exponent }).collect(),
+        };
+        let rational = |numer, denom| Expr::Rational(RationalExpression { numer, denom });
+        let one = || vec![term(1, &[])];
+
+        // Test each invariant violation, also below other nodes
+        let valid = rational(vec![term(2, &[("a", 1), ("b", -1)])], one());
+        assert_eq!(valid.validate(), Ok(()));
+        assert_eq!((valid.clone().abs() + Expr::Hole).validate(), Ok(()));
+        let cases = [
+            (rational(vec![term(1, &[("b", 1), ("a", 1)])], one()), InvariantError::UnsortedFactors),
+            (rational(one(), vec![term(1, &[("a", 1), ("a", 1)])]), InvariantError::DuplicateFactor("a".to_string())),
+            (rational(vec![term(1, &[("a", 0)])], one()), InvariantError::ZeroExponent("a".to_string())),
+            (rational(vec![term(1, &[("a", MAX_EXPONENT + 1)])], one()), InvariantError::ExponentOutOfRange("a".to_string())),
+            (rational(vec![term(1, &[("a", isize::MIN)])], one()), InvariantError::ExponentOutOfRange("a".to_string())),
+            (rational(vec![term(0, &[])], one()), InvariantError::ZeroCoefficient),
+            (rational(one(), Vec::new()), InvariantError::ZeroDenominator),
+            (rational(one(), vec![term(1, &[("a", 1)]), term(-1, &[("a", 1)])]), InvariantError::ZeroDenominator),
+        ];
+        for (expr, expected) in cases {
+            println!("asserting the violation {expected}");
+            assert_eq!(expr.validate(), Err(expected.clone()));
+            assert_eq!((Expr::var("a") * expr.clone().max(valid.clone())).validate(), Err(expected));
+        }
+    }
+
+    #[test]
+    fn test_operations_keep_invariants() {
+        let mut seed = 2024_usize;
+        let mut next = || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            seed >> 33
+        };
+        for expr in corpus() {
+            let other = random_expr(&mut next, 2);
+            assert_operations_keep_invariants(&expr, &other);
+        }
+    }
+
+    fn assert_operations_keep_invariants(expr: &Expr, other: &Expr) {
+        let partial = HashMap::from([("a", 2)]);
+        let half = HashMap::from([("b", Ratio::new(1, 2))]);
+
+        // Test every derived expression keeps the invariants
+        let derived = [
+            Some(expr.sort_operands()),
+            expr.canonicalize().ok().map(Expr::from),
+            expr.partial_substitute(&partial),
+            expr.partial_substitute_ratio(&half),
+            expr.derivative("a"),
+            expr.clone().recip(),
+            Some(expr.add_simplified(other)),
+            Some(expr.sub_simplified(other)),
+            Some(expr.mul_simplified(other)),
+            Some(expr.primitive_part()),
+            Some(expr.normalize_names(|name| name.to_uppercase())),
+            Some(expr.replace_variable("b", other)),
+            Expr::from_bytes(&expr.to_bytes()).ok(),
+        ];
+        for derived in derived.into_iter().flatten() {
+            assert_eq!(derived.validate(), Ok(()), "{derived} derived from {expr}");
+        }
+
+        // Test display, parse and canonicalization round-trip
+        let text = expr.to_string();
+        let parsed = text.parse::<Expr>().unwrap();
+        let reparsed = parsed.to_string().parse::<Expr>().unwrap();
+        assert!(reparsed.structurally_eq(&parsed), "{text}");
+        assert_ne!(parsed.equivalent(expr), Some(false), "{text}");
+        if let Ok(canon) = expr.canonicalize() {
+            println!("asserting {text} round-trips");
+            let reparsed = Expr::from(parsed.canonicalize().unwrap());
+            assert_eq!(reparsed.equivalent(&canon.into()), Some(true), "{text}");
+        }
+    }
+
+    /// The trees of [`random_expr`] as a proptest strategy, which shrinks failures to small trees.
+    #[cfg(feature = "proptest")]
+    fn expr_strategy(depth: u32) -> impl proptest::strategy::Strategy<Value = Expr> {
+        use proptest::prelude::*;
+        let leaf = prop_oneof![(0..4_usize).prop_map(Expr::Constant), prop::sample::select(vec!["a", "b", "c"]).prop_map(Expr::var)];
+        leaf.prop_recursive(depth, 1 << depth, 2, |inner| {
+            prop_oneof![
+                (inner.clone(), inner.clone()).prop_map(|(lhs, rhs)| lhs + rhs),
+                (inner.clone(), inner.clone()).prop_map(|(lhs, rhs)| lhs - rhs),
+                (inner.clone(), inner.clone()).prop_map(|(lhs, rhs)| lhs * rhs),
+                (inner.clone(), inner.clone()).prop_map(|(lhs, rhs)| lhs / rhs),
+                (inner.clone(), 0..3_u32).prop_map(|(base, exponent)| base.pow(exponent)),
+                (inner.clone(), inner.clone()).prop_map(|(lhs, rhs)| lhs.modulo(rhs)),
+                (inner.clone(), inner.clone()).prop_map(|(lhs, rhs)| lhs.abs().max(rhs)),
+                inner.prop_map(|expr| expr.canonicalize().map_or(expr, Expr::from)),
+            ]
+        })
+    }
+
+    #[cfg(feature = "proptest")]
+    proptest::proptest! {
+        #[test]
+        fn proptest_operations_keep_invariants(expr in expr_strategy(3), other in expr_strategy(2)) {
+            assert_operations_keep_invariants(&expr, &other);
+        }
+    }
+
+    #[test]
+    fn test_shrink_to_fit() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let shrunk = |expr: &Expr| {
+            let mut shrunk = expr.clone();
+            shrunk.shrink_to_fit();
+            println!("asserting {expr} shrinks to {shrunk}");
+            assert_ne!(shrunk.equivalent(expr), Some(false));
+            shrunk
+        };
+        let canon = |expr: Expr| Expr::from(expr.canonicalize().unwrap());
+
+        // Test normal forms with a constant denominator are demoted
+        let expr = canon(a.clone() + 1);
+        assert!(matches!(expr, Expr::Rational(_)));
+        assert!(matches!(shrunk(&expr), Expr::Sum(_)));
+        assert!(matches!(shrunk(&canon(a.clone() * b.clone() * 3)), Expr::Product(_)));
+        assert!(matches!(shrunk(&canon(a.clone() - a.clone() + 4)), Expr::Constant(4)));
+        assert!(matches!(shrunk(&canon(a.clone() * b.clone() / b.clone())), Expr::Variable(_)));
+        assert!(matches!(shrunk(&((a.clone() + 1) * 2 - a.clone())), Expr::Sum(_)));
+        assert_eq!(shrunk(&canon((a.clone() + 1) / 2)).to_string(), "(1 + a)/2");
+
+        // Test other normal forms stay rational and other expressions are unchanged
+        let expr = (a.clone() + 1) / b.clone();
+        assert!(matches!(shrunk(&expr), Expr::Rational(_)));
+        assert_eq!(shrunk(&expr).validate(), Ok(()));
+        let expr = a.clone().modulo(b.clone()) + 1;
+        assert!(shrunk(&expr).structurally_eq(&expr));
+        assert!(shrunk(&(Expr::Hole + 1)).structurally_eq(&(Expr::Hole + 1)));
+    }
+
+    #[test]
+    fn test_split_constant() {
+        let n = Expr::var("n");
+        let c = Expr::var("c");
+        let split = |expr: &Expr| {
+            let (constant, symbolic) = expr.split_constant().unwrap();
+            println!("asserting {expr} splits into {constant} and {symbolic}");
+            assert_eq!(expr.constant_difference(&symbolic), Some(constant));
+            assert_eq!(symbolic.split_constant().unwrap().0, Ratio::from_integer(0));
+            (constant, symbolic.to_string())
+        };
+
+        assert_eq!(split(&(n.clone() * c.clone() + 128)), (Ratio::from_integer(128), "c*n".to_string()));
+        assert_eq!(split(&(n.clone() - n.clone() + 7)), (Ratio::from_integer(7), "0".to_string()));
+        assert_eq!(split(&(n.clone() * 3)), (Ratio::from_integer(0), "3*n".to_string()));
+        assert_eq!(split(&((n.clone() + 3) / 2)), (Ratio::new(3, 2), "n/2".to_string()));
+        assert_eq!(split(&(n.clone() - 5)), (Ratio::from_integer(-5), "n".to_string()));
+
+        // Test sums of variables and constants, pure variables and pure constants
+        let (a, b) = (Expr::var("a"), Expr::var("b"));
+        assert_eq!(split(&(a.clone() + b.clone() + 5)), (Ratio::from_integer(5), "a + b".to_string()));
+        assert_eq!(split(&a), (Ratio::from_integer(0), "a".to_string()));
+        assert_eq!(split(&Expr::from(9)), (Ratio::from_integer(9), "0".to_string()));
+        assert_eq!(split(&Expr::from(Ratio::new(5, 2))), (Ratio::new(5, 2), "0".to_string()));
+
+        // Test symbolic denominators and expressions without a rational form
+        assert_eq!((n.clone() + 1).recip().unwrap().split_constant(), None);
+        assert_eq!((n.clone() / c.clone() + 2).split_constant(), None);
+        assert_eq!(n.clone().modulo(c.clone()).split_constant(), None);
+    }
+
+    #[test]
+    fn test_substitute_huge_exponent() {
+        let power = |exponent: isize| {
+            let term = CanonicalTerm {
+                coef: Ratio::from_integer(3),
+                factors: [Factor { base: "a".to_string(), exponent }, Factor { base: "b".to_string(), exponent: 1 }].into_iter().collect(),
+            };
+            RationalExpression::new(vec![term], vec![CanonicalTerm::new(1)])
+        };
+
+        // Test huge powers fail fast instead of looping
+        let huge = power(1_000_000);
+        println!("asserting 3*a^1000000*b cannot be substituted with a = 2");
+        assert_eq!(huge.partial_substitute(&HashMap::from([("a", 2)])), None);
+        assert_eq!(huge.substitute(&HashMap::from([("a", 2), ("b", 1)])), None);
+        assert_eq!(Expr::Rational(huge.clone()).partial_substitute(&HashMap::from([("a", 2)])), None);
+        assert_eq!(power(-1_000_000).partial_substitute(&HashMap::from([("a", 0)])), None);
+
+        // Test powers of 1 and 0 are still computed
+        let result = huge.partial_substitute(&HashMap::from([("a", 1)])).unwrap();
+        assert_eq!(Expr::Rational(result), Expr::var("b") * 3);
+        assert_eq!(huge.substitute(&HashMap::from([("a", 1), ("b", 5)])), Some(Ratio::from_integer(15)));
+        assert_eq!(huge.substitute(&HashMap::from([("a", 0), ("b", 5)])), Some(Ratio::from_integer(0)));
+
+        // Test exponents beyond the limit are rejected whatever the value
+        let beyond = power(MAX_EXPONENT + 1);
+        assert_eq!(beyond.partial_substitute(&HashMap::from([("a", 1)])), None);
+        assert_eq!(beyond.partial_substitute(&HashMap::from([("b", 1)])).map(|r| r.numer.len()), Some(1));
+        assert_eq!(power(isize::MIN).partial_substitute(&HashMap::from([("a", 1)])), None);
+        assert_eq!(power(isize::MIN).substitute(&HashMap::from([("a", 1), ("b", 1)])), None);
+
+        // Test the limit is configurable
+        let value = HashMap::from([("a", 1)]);
+        assert_eq!(huge.partial_substitute_with(&value, 1_000_000).map(Expr::Rational), Some(Expr::var("b") * 3));
+        assert_eq!(huge.partial_substitute_with(&value, 999_999), None);
+        assert_eq!(Expr::Rational(huge.clone()).partial_substitute_with(&value, 999_999), None);
+        let tree = Expr::var("a").pow(1000).max(Expr::var("b"));
+        assert_eq!(tree.partial_substitute_with(&value, 1000).unwrap().to_string(), "max(1, b)");
+        assert_eq!(tree.partial_substitute_with(&value, 10), None);
+    }
+
+    #[test]
+    fn test_over_denominator() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let over = |expr: &Expr, denom: &Expr| {
+            let result = expr.over_denominator(denom)?;
+            println!("asserting {expr} over {denom} is {result}");
+            assert_eq!(result.equivalent(expr), Some(true));
+            Some(result.to_string())
+        };
+
+        // Test the fraction is kept over the requested denominator
+        assert_eq!(over(&(a.clone() / 2), &Expr::from(6)).as_deref(), Some("3*a/6"));
+        assert_eq!(over(&(a.clone() / 2 + b.clone() / 3), &Expr::from(12)).as_deref(), Some("(6*a + 4*b)/12"));
+        assert_eq!(over(&a, &Expr::from(4)).as_deref(), Some("4*a/4"));
+        assert_eq!(over(&(a.clone() / b.clone()), &(b.clone() * 2)).as_deref(), Some("2*a/(2*b)"));
+        assert_eq!(over(&((a.clone() + 1) / (b.clone() + 1)), &((b.clone() + 1) * a.clone())).as_deref(), Some("(a + a^2)/(a + a*b)"));
+
+        // Test denominators that are not multiples, zero, or not rational
+        assert_eq!(over(&(a.clone() / 4), &Expr::from(6)), None);
+        assert_eq!(over(&(a.clone() / b.clone()), &a), None);
+        assert_eq!(over(&(a.clone() / 2), &(a.clone() - a.clone())), None);
+        assert_eq!(over(&a, &a.clone().modulo(b.clone())), None);
+    }
+
+    #[test]
+    fn test_lower_bound() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let mins = HashMap::from([("a", 2), ("b", 3)]);
+
+        // Test the value at the lower bounds, with missing bounds at the default
+        let expr = a.clone() + b.clone() + 5;
+        println!("asserting {expr} is at least 10");
+        assert_eq!(expr.lower_bound(&mins), Some(10));
+        assert_eq!((a.clone() * b.clone() * 2 + a.clone().pow(2)).lower_bound(&mins), Some(16));
+        assert_eq!(((a.clone() + 1) / 2).lower_bound(&mins), Some(1));
+        assert_eq!((a.clone() * Expr::var("c") + 1).lower_bound(&mins), Some(1));
+        assert_eq!((a.clone() * Expr::var("c") + 1).lower_bound_with_default(&mins, 4), Some(9));
+        assert_eq!((a.clone() - a.clone()).lower_bound(&mins), Some(0));
+
+        // Test expressions that may decrease or have no rational form
+        assert_eq!((a.clone() - b.clone() + 10).lower_bound(&mins), None);
+        assert_eq!((Expr::from(8) / a.clone()).lower_bound(&mins), None);
+        assert_eq!(a.clone().modulo(b.clone()).lower_bound(&mins), None);
+        assert_eq!(a.clone().pow(40).lower_bound(&HashMap::from([("a", 1 << 20)])), None);
+    }
+
+    #[test]
+    fn test_from_ratio() {
+        let n = Expr::var("n");
+        for (ratio, expected) in [(Ratio::new(3, 2), "3/2"), (Ratio::new(4, 2), "2"), (Ratio::new(-6, 4), "-3/2"), (Ratio::from_integer(0), "0")] {
+            let expr = Expr::from(ratio);
+            println!("asserting {ratio} becomes {expr}");
+            assert_eq!(expr.to_string(), expected);
+            assert_eq!(expr.validate(), Ok(()));
+            assert_eq!(expr.to_rational().unwrap().substitute(&HashMap::new()), Some(ratio));
+            assert_eq!(expr.split_constant().unwrap().0, ratio);
+        }
+        assert_eq!(Expr::from(Ratio::new(4, 2)).equivalent(&Expr::from(2)), Some(true));
+        assert_eq!((n.clone() + Expr::from(Ratio::new(1, 2))).equivalent(&((n.clone() * 2 + 1) / 2)), Some(true));
+    }
+
+    #[test]
+    fn test_map_constants() {
+        let n = Expr::var("n");
+        let c = Expr::var("c");
+
+        // Test doubling the constants of (n + 4)/2 gives (n + 8)/4
+        let expr = (n.clone() + 4) / 2;
+        let doubled = expr.map_constants(|value| value * 2);
+        println!("asserting {expr} with doubled constants is {doubled}");
+        assert!(doubled.structurally_eq(&((n.clone() + 8) / 4)));
+        for value in (0..40).step_by(4) {
+            assert_eq!(doubled.substitute(&HashMap::from([("n", value)])), (value + 8) / 4);
+        }
+
+        // Test variables, exponents and opaque nodes are kept
+        let expr = n.clone().pow(2).modulo(c.clone() + 3).max(n.clone() * 2).abs();
+        let mapped = expr.map_constants(|value| value + 1);
+        assert_eq!(mapped.to_string(), expr.to_string().replace('3', "4").replace("*2", "*3"));
+        assert!(expr.map_constants(|value| value).structurally_eq(&expr));
+
+        // Test rational nodes become trees with their coefficients as leaves
+        let canon = Expr::from(((n.clone() * 3 + 1) / c.clone()).canonicalize().unwrap());
+        let mapped = canon.map_constants(|value| value * 10);
+        assert!(!matches!(mapped, Expr::Rational(_)));
+        assert_eq!(mapped.substitute(&HashMap::from([("n", 1), ("c", 2)])), (10 + 30) / 2);
+
+        // Test coefficients of the normal form, and denominators mapped to zero
+        let expr = (n.clone() * 3 + c.clone() * 6) / 2;
+        let mapped = expr.map_coefficients(|coef| coef.floor()).unwrap();
+        assert_eq!(mapped.equivalent(&(n.clone() + c.clone() * 3)), Some(true));
+        assert!((n.clone() / 3).map_coefficients(|coef| coef - 1).is_none());
+        assert!(n.clone().modulo(c.clone()).map_coefficients(|coef| coef).is_none());
+    }
+
+    #[test]
+    fn test_integrality() {
+        let n = Expr::var("n");
+        let c = Expr::var("c");
+
+        // Test a formula with a provable and an unprovable division reports the latter only
+        let expr = n.clone() * 4 / 2 + c.clone() / 3;
+        let Integrality::IfDivisible(divisions) = expr.integrality() else {
+            panic!("expected a division to check in {expr}");
+        };
+        println!("asserting {expr} is an integer if {} divides {}", divisions[0].1, divisions[0].0);
+        assert_eq!(divisions.len(), 1);
+        assert!(divisions[0].0.structurally_eq(&c) && divisions[0].1.structurally_eq(&Expr::from(3)));
+
+        // Test expressions without unproven divisions, and with holes
+        assert!(matches!((n.clone() * c.clone() + 1).integrality(), Integrality::Always));
+        assert!(matches!((n.clone() * c.clone() / c.clone()).integrality(), Integrality::Always));
+        assert!(matches!((n.clone() * 6 / 3).modulo(c.clone()).integrality(), Integrality::Always));
+        assert!(matches!((Expr::Hole / 2).integrality(), Integrality::Unknown));
+    }
+
+    #[test]
+    fn test_linear_relation() {
+        let i = Expr::var("i");
+        let j = Expr::var("j");
+        let ratio = |cx: isize, cy: isize, k: isize| Some((Ratio::from_integer(cx), Ratio::from_integer(cy), Ratio::from_integer(k)));
+
+        // Test variables tied by a reshape, with offsets and fractions
+        let expr = i.clone() - j.clone() * 4;
+        println!("asserting {expr} ties i and j");
+        assert_eq!(expr.linear_relation("i", "j"), ratio(1, -4, 0));
+        assert_eq!(expr.linear_relation("j", "i"), ratio(-4, 1, 0));
+        assert_eq!((j.clone() * 2 + 3 - i.clone() * 6).linear_relation("i", "j"), ratio(-6, 2, 3));
+        let half = ((i.clone() + 1) / 2 - j.clone()).linear_relation("i", "j").unwrap();
+        assert_eq!(half, (Ratio::new(1, 2), Ratio::from_integer(-1), Ratio::new(1, 2)));
+
+        // Test absent variables, and x equal to y
+        assert_eq!((i.clone() - 4).linear_relation("i", "j"), ratio(1, 0, -4));
+        assert_eq!(Expr::from(0).linear_relation("i", "j"), ratio(0, 0, 0));
+        assert_eq!((i.clone() * 3).linear_relation("i", "i"), ratio(3, 0, 0));
+
+        // Test other variables, nonlinear terms and symbolic denominators
+        assert_eq!((i.clone() - j.clone() + Expr::var("k")).linear_relation("i", "j"), None);
+        assert_eq!((i.clone() * j.clone() - 1).linear_relation("i", "j"), None);
+        assert_eq!((i.clone() * i.clone() - j.clone()).linear_relation("i", "j"), None);
+        assert_eq!((i.clone() / j.clone()).linear_relation("i", "j"), None);
+        assert_eq!(i.clone().modulo(j.clone()).linear_relation("i", "j"), None);
+    }
+
+    #[test]
+    fn test_cancel_operands() {
+        let [n, m, a, k] = ["n", "m", "a", "k"].map(Expr::var);
+
+        // Test sums cancel pairs and zeros regardless of the flag
+        let expr = m.clone() + (a.clone() - a.clone()) - n.clone() + n.clone();
+        println!("asserting {expr} cancels to m");
+        for assume_nonzero in [false, true] {
+            assert!(expr.cancel_operands(assume_nonzero).structurally_eq(&m));
+        }
+        assert_eq!((a.clone() - a.clone()).cancel_operands(false).as_constant(), Some(0));
+        let zero = (a.clone() + 1) * 2 - a.clone() * 2 - 2;
+        assert!((n.clone() * (zero.clone() + 1)).cancel_operands(false).structurally_eq(&n));
+
+        // Test products cancel pairs only for non-zero divisors
+        let expr = n.clone() * k.clone() / k.clone();
+        assert!(expr.cancel_operands(false).structurally_eq(&expr));
+        assert!(expr.cancel_operands(true).structurally_eq(&n));
+        assert!((n.clone() * 4 / 4).cancel_operands(false).structurally_eq(&n));
+        let zero_divisor = n.clone() * Expr::from(0) / Expr::from(0);
+        assert!(zero_divisor.cancel_operands(false).structurally_eq(&zero_divisor));
+        assert!((n.clone() * 1 / 1).cancel_operands(false).structurally_eq(&n));
+        let one = m.clone() + 1 - m.clone();
+        assert!((n.clone() * one.clone()).cancel_operands(false).structurally_eq(&n));
+        let one = (k.clone() + 1) / (k.clone() + 1);
+        assert_eq!((n.clone() * one.clone()).cancel_operands(false).to_string(), "n*(k + 1)/(k + 1)");
+        assert!((n.clone() * one.clone()).cancel_operands(true).structurally_eq(&n));
+
+        // Test nested and opaque nodes
+        let expr = (n.clone() * k.clone() / k.clone() + a.clone() - a.clone()).modulo(m.clone() + 0).max(Expr::from(3));
+        assert_eq!(expr.cancel_operands(true).to_string(), "max(mod(n, m), 3)");
+
+        // Test values are unchanged where the expression is defined
+        let expr = (n.clone() * k.clone() / k.clone() + a.clone() - a.clone()) * (m.clone() - 1 + 1) / (k.clone() + 1 - 1);
+        let cancelled = expr.cancel_operands(true);
+        println!("asserting {expr} cancels to {cancelled}");
+        for (n_value, k_value, m_value) in [(6, 1, 2), (6, 3, 1), (12, 4, 3), (5, 0, 1)] {
+            let value = HashMap::from([("n", n_value), ("k", k_value), ("m", m_value), ("a", 7)]);
+            if let Some(ans) = expr.checked_substitute(&value) {
+                assert_eq!(cancelled.checked_substitute(&value), Some(ans));
+            }
+        }
+    }
+
+    #[test]
+    fn test_free_of() {
+        let [i, j, n] = ["i", "j", "n"].map(Expr::var);
+
+        // Test variables that do not appear or cancel
+        let expr = (i.clone() - i.clone()) + n.clone();
+        println!("asserting {expr} is free of i");
+        assert_eq!(expr.free_of(&["i"]), Some(true));
+        assert_eq!((n.clone() * 4).free_of(&["i", "j"]), Some(true));
+        assert_eq!((i.clone() * 2 + j.clone() - (i.clone() + j.clone()) * 2 + i.clone()).free_of(&["i"]), Some(false));
+        assert_eq!(((i.clone() + n.clone()) * 2 - i.clone() * 2).free_of(&["i", "j"]), Some(true));
+        assert_eq!(n.clone().modulo(j.clone()).free_of(&["i"]), Some(true));
+
+        // Test genuine dependences
+        assert_eq!((i.clone() * n.clone()).free_of(&["i"]), Some(false));
+        assert_eq!((n.clone() + j.clone()).free_of(&["i", "j"]), Some(false));
+        assert_eq!((n.clone() / (i.clone() + 1)).free_of(&["i"]), Some(false));
+
+        // Test common factors cancel, as in equivalent
+        assert_eq!((i.clone() * n.clone() / i.clone()).free_of(&["i"]), Some(true));
+
+        // Test expressions that cannot be canonicalized
+        assert_eq!((n.clone().modulo(i.clone()) + i.clone() - i.clone()).free_of(&["i"]), None);
+        assert_eq!((i.clone() - i.clone() + Expr::Hole).free_of(&["i"]), None);
+    }
 }
@@ -0,0 +1,437 @@
+//! 常见张量运算的形状规则：矩阵乘法、拼接、切分与窗口的填充。
+//!
+//! Dimensions agree when they are proven [equivalent](Expr::equivalent). The `_with` variants
+//! also accept dimensions of unknown equivalence if `allow_unknown` is set, taking the
+//! dimension of the first operand.
+//!
+//! Window sizes round with `mod`, since expressions have no floor or ceiling: `floor(x/s)` is
+//! built as `(x - mod(x, s))/s`, which evaluates exactly.
+
+use crate::{Error, EvalError, Expr, Operand, Sign, Verdict, scaled::floor_div};
+use std::{collections::HashMap, fmt};
+
+/// Reason a shape rule does not apply.
+#[derive(Clone, Debug)]
+pub enum ShapeError {
+    /// The shape at the index has rank `found`, where at least `expected` is needed, or exactly
+    /// `expected` for [`concat`].
+    Rank { operand: usize, expected: usize, found: usize },
+    /// The axis is not an axis of shapes of the rank.
+    AxisOutOfRange { axis: usize, rank: usize },
+    /// There is no shape to concatenate.
+    NoShapes,
+    /// Two dimensions of the axis do not agree, neither being a broadcast `1` where allowed.
+    Mismatch { axis: usize, lhs: Expr, rhs: Expr, verdict: Verdict },
+    /// The dimension of the axis is not proven divisible into `parts`.
+    NotDivisible { axis: usize, dim: Expr, parts: usize, verdict: Verdict },
+}
+
+impl fmt::Display for ShapeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Rank { operand, expected, found } => write!(f, "shape {operand} has rank {found}, expected {expected}"),
+            Self::AxisOutOfRange { axis, rank } => write!(f, "axis {axis} is out of range for rank {rank}"),
+            Self::NoShapes => write!(f, "no shapes to concatenate"),
+            Self::Mismatch { axis, lhs, rhs, verdict } => {
+                let verdict = if *verdict == Verdict::NotEqual { "differ" } else { "may differ" };
+                write!(f, "axis {axis} has dimensions {lhs} and {rhs}, which {verdict}")
+            }
+            Self::NotDivisible { axis, dim, parts, verdict: Verdict::NotEqual } => write!(f, "axis {axis} of {dim} does not split into {parts}"),
+            Self::NotDivisible { axis, dim, parts, verdict: Verdict::Unknown } => {
+                write!(f, "axis {axis} of {dim} is not proven to split into {parts}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ShapeError {}
+
+/// The shape of the matrix product of `a` and `b`, which have at least 2 axes each.
+///
+/// The last axis of `a` must agree with the second last axis of `b`, reported as the last
+/// axis of `a`. Leading batch axes are aligned from the right and broadcast, a dimension `1`
+/// taking the other one and a missing axis counting as `1`; mismatches are reported with the
+/// axis of the output.
+///
+/// ```rust
+/// # use symbolic_expr::{Expr, ops};
+/// let [b, m, n, k] = ["b", "m", "n", "k"].map(Expr::var);
+/// let shape = ops::matmul(&[b.clone(), m.clone(), k.clone() * 2], &[k.clone() + k.clone(), n.clone()]).unwrap();
+/// assert_eq!(shape.iter().map(Expr::to_string).collect::<Vec<_>>(), ["b", "m", "n"]);
+/// ```
+pub fn matmul(a: &[Expr], b: &[Expr]) -> Result<Vec<Expr>, ShapeError> {
+    matmul_with(a, b, false)
+}
+
+/// [`matmul`], accepting dimensions of unknown equivalence if `allow_unknown` is set.
+pub fn matmul_with(a: &[Expr], b: &[Expr], allow_unknown: bool) -> Result<Vec<Expr>, ShapeError> {
+    for (operand, shape) in [a, b].into_iter().enumerate() {
+        if shape.len() < 2 {
+            return Err(ShapeError::Rank { operand, expected: 2, found: shape.len() });
+        }
+    }
+    let (a_batch, [m, k]) = a.split_at(a.len() - 2) else { unreachable!() };
+    let (b_batch, [k2, n]) = b.split_at(b.len() - 2) else { unreachable!() };
+    agree(a.len() - 1, k, k2, allow_unknown)?;
+
+    let rank = a_batch.len().max(b_batch.len());
+    let one = Expr::Constant(1);
+    let mut shape = Vec::with_capacity(rank + 2);
+    for axis in 0..rank {
+        let lhs = (axis + a_batch.len()).checked_sub(rank).map_or(&one, |i| &a_batch[i]);
+        let rhs = (axis + b_batch.len()).checked_sub(rank).map_or(&one, |i| &b_batch[i]);
+        shape.push(match (lhs.is_one(), rhs.is_one()) {
+            (true, _) => rhs.clone(),
+            (_, true) => lhs.clone(),
+            _ => agree(axis, lhs, rhs, allow_unknown).map(|()| lhs.clone())?,
+        });
+    }
+    shape.extend([m.clone(), n.clone()]);
+    Ok(shape)
+}
+
+/// The shape of the concatenation of `shapes` along `axis`, whose dimension is the sum of
+/// theirs, canonicalized where possible. All shapes must have the same rank, and the other
+/// axes must agree with those of the first shape.
+///
+/// ```rust
+/// # use symbolic_expr::{Expr, ops};
+/// let [n, c] = ["n", "c"].map(Expr::var);
+/// let shape = ops::concat(&[&[n.clone(), c.clone()], &[n.clone(), c.clone() * 2]], 1).unwrap();
+/// assert_eq!(shape[1].to_string(), "3*c");
+/// ```
+pub fn concat(shapes: &[&[Expr]], axis: usize) -> Result<Vec<Expr>, ShapeError> {
+    concat_with(shapes, axis, false)
+}
+
+/// [`concat`], accepting dimensions of unknown equivalence if `allow_unknown` is set.
+pub fn concat_with(shapes: &[&[Expr]], axis: usize, allow_unknown: bool) -> Result<Vec<Expr>, ShapeError> {
+    let first = shapes.first().ok_or(ShapeError::NoShapes)?;
+    if axis >= first.len() {
+        return Err(ShapeError::AxisOutOfRange { axis, rank: first.len() });
+    }
+    for (operand, shape) in shapes.iter().enumerate().skip(1) {
+        if shape.len() != first.len() {
+            return Err(ShapeError::Rank { operand, expected: first.len(), found: shape.len() });
+        }
+        for (i, (lhs, rhs)) in first.iter().zip(shape.iter()).enumerate() {
+            if i != axis {
+                agree(i, lhs, rhs, allow_unknown)?;
+            }
+        }
+    }
+    let mut sum = Expr::sum(shapes.iter().map(|shape| shape[axis].clone().positive()));
+    sum.shrink_to_fit();
+    let mut shape = first.to_vec();
+    shape[axis] = sum;
+    Ok(shape)
+}
+
+/// The shape of each of `parts` equal parts of `shape` split along `axis`, which is exact only
+/// if the dimension of the axis is proven divisible by `parts`, see [`Expr::divides`].
+/// A constant dimension that does not divide, or zero parts, is a definite failure.
+///
+/// ```rust
+/// # use symbolic_expr::{Expr, ops};
+/// let n = Expr::var("n");
+/// assert_eq!(ops::split(&[n.clone() * 6, n.clone()], 0, 3).unwrap()[0].to_string(), "2*n");
+/// assert!(ops::split(&[n.clone() * 6, n.clone()], 1, 3).is_err());
+/// ```
+pub fn split(shape: &[Expr], axis: usize, parts: usize) -> Result<Vec<Expr>, ShapeError> {
+    split_with(shape, axis, parts, false)
+}
+
+/// [`split`], accepting dimensions not proven divisible if `allow_unknown` is set, as long as
+/// they are not known to be indivisible.
+pub fn split_with(shape: &[Expr], axis: usize, parts: usize, allow_unknown: bool) -> Result<Vec<Expr>, ShapeError> {
+    let dim = shape.get(axis).ok_or(ShapeError::AxisOutOfRange { axis, rank: shape.len() })?;
+    let not_divisible = |verdict| ShapeError::NotDivisible { axis, dim: dim.clone(), parts, verdict };
+    if parts == 0 {
+        return Err(not_divisible(Verdict::NotEqual));
+    }
+    let part = match dim.scaled(1, parts).require_exact() {
+        Ok(part) => part,
+        Err(_) if dim.as_constant().is_some() => return Err(not_divisible(Verdict::NotEqual)),
+        Err(_) if allow_unknown => dim.clone() / parts,
+        Err(_) => return Err(not_divisible(Verdict::Unknown)),
+    };
+    let mut shape = shape.to_vec();
+    shape[axis] = part;
+    Ok(shape)
+}
+
+/// The output size of a window of size `k` and stride `s` over `n` without padding, the
+/// `VALID` padding of TF and ONNX: `floor((n - k)/s) + 1`, which requires `k <= n`.
+///
+/// Fails with [`Error::UnfilledHole`] if an argument has a hole, and a division by zero if `s`
+/// is zero.
+pub fn valid_out(n: &Expr, k: &Expr, s: &Expr) -> Result<Expr, Error> {
+    check_window([n, k, s], s)?;
+    Ok(fold(floor_div(n - k, s.clone()) + 1))
+}
+
+/// The output size of a stride `s` over `n` with the `SAME` padding of TF and ONNX:
+/// `ceil(n/s)`. Fails as [`valid_out`] does.
+pub fn same_out(n: &Expr, s: &Expr) -> Result<Expr, Error> {
+    check_window([n, s], s)?;
+    Ok(fold(ceil_div(n, s)))
+}
+
+/// The padding before and after `n` for a window of size `k` and stride `s` to give
+/// [`same_out`] outputs, as defined by TF and ONNX (`SAME_UPPER`): the total padding is
+/// `max((out - 1)*s + k - n, 0)`, and the extra unit of an odd total goes after.
+/// Fails as [`valid_out`] does.
+///
+/// ```rust
+/// # use std::collections::HashMap;
+/// # use symbolic_expr::{Expr, ops};
+/// let (before, after) = ops::same_padding(&Expr::var("n"), &Expr::from(3), &Expr::from(2)).unwrap();
+/// let value = HashMap::from([("n", 224)]);
+/// assert_eq!((before.substitute(&value), after.substitute(&value)), (0, 1));
+/// ```
+pub fn same_padding(n: &Expr, k: &Expr, s: &Expr) -> Result<(Expr, Expr), Error> {
+    check_window([n, k, s], s)?;
+    // max(out*s + k, n + s) - (n + s) in place of max((out - 1)*s + k - n, 0), so that no
+    // step is negative, also for n = 0 where out is 0
+    let covered = ceil_div(n, s) * s.clone() + k.clone();
+    let total = fold(covered.max(n + s) - n - s);
+    let before = fold(floor_div(total.clone(), Expr::from(2)));
+    let after = fold(total - before.clone());
+    Ok((before, after))
+}
+
+/// `ceil(n/s)`, as `floor((n + s - 1)/s)`.
+fn ceil_div(n: &Expr, s: &Expr) -> Expr {
+    floor_div(n + s - 1, s.clone())
+}
+
+/// Folds the constant subtrees of a window expression, all the way down, and merges adjacent
+/// constant operands of sums. The other operands keep their order, since sums are evaluated
+/// left to right and reordering them could make a step negative.
+fn fold(expr: Expr) -> Expr {
+    match expr.checked_substitute(&HashMap::new()) {
+        Some(value) if expr.variables().is_empty() => return Expr::Constant(value),
+        _ => {}
+    }
+    let single = |expr: Expr| match expr {
+        Expr::Sum(mut operands) | Expr::Product(mut operands) if operands.len() == 1 && operands[0].ty == Sign::Positive => {
+            operands.pop_front().unwrap().expr
+        }
+        expr => expr,
+    };
+    match expr {
+        Expr::Sum(operands) => {
+            let mut folded = Vec::new();
+            let mut run = Vec::new();
+            // A run of constants is replaced by its signed total, which leaves later steps as they are
+            let flush = |run: &mut Vec<Operand>, folded: &mut Vec<Operand>| {
+                let total = run.iter().try_fold(0i128, |acc, Operand { ty, expr }| match (ty, expr) {
+                    (Sign::Positive, &Expr::Constant(value)) => acc.checked_add(value as i128),
+                    (Sign::Negative, &Expr::Constant(value)) => acc.checked_sub(value as i128),
+                    _ => None,
+                });
+                match total.map(|total| (total > 0, usize::try_from(total.unsigned_abs()))) {
+                    Some((_, Ok(0))) => {}
+                    Some((true, Ok(value))) => folded.push(Expr::Constant(value).positive()),
+                    Some((false, Ok(value))) => folded.push(Expr::Constant(value).negative()),
+                    _ => folded.append(run),
+                }
+                run.clear();
+            };
+            for Operand { ty, expr } in operands {
+                match fold(expr) {
+                    expr @ Expr::Constant(_) => run.push(Operand { ty, expr }),
+                    expr => {
+                        flush(&mut run, &mut folded);
+                        folded.push(Operand { ty, expr });
+                    }
+                }
+            }
+            flush(&mut run, &mut folded);
+            single(Expr::sum(folded))
+        }
+        Expr::Product(operands) => {
+            let operands = operands.into_iter().map(|Operand { ty, expr }| Operand { ty, expr: fold(expr) });
+            single(Expr::product(operands.filter(|op| !matches!(op.expr, Expr::Constant(1)))))
+        }
+        Expr::Pow(base, exponent) => Expr::Pow(Box::new(fold(*base)), exponent),
+        Expr::Mod(lhs, modulus) => Expr::Mod(Box::new(fold(*lhs)), Box::new(fold(*modulus))),
+        Expr::Abs(operand) => Expr::Abs(Box::new(fold(*operand))),
+        Expr::Max(lhs, rhs) => Expr::Max(Box::new(fold(*lhs)), Box::new(fold(*rhs))),
+        Expr::Constant(_) | Expr::Variable(_) | Expr::Rational(_) | Expr::Hole => expr,
+    }
+}
+
+fn check_window<const N: usize>(args: [&Expr; N], stride: &Expr) -> Result<(), Error> {
+    if args.iter().any(|arg| arg.has_holes()) {
+        return Err(Error::UnfilledHole);
+    }
+    if stride.equivalent(&Expr::Constant(0)) == Some(true) {
+        return Err(Error::Eval { kind: EvalError::DivisionByZero, values: Vec::new() });
+    }
+    Ok(())
+}
+
+/// Checks the dimensions of the axis agree, see the [module documentation](self).
+fn agree(axis: usize, lhs: &Expr, rhs: &Expr, allow_unknown: bool) -> Result<(), ShapeError> {
+    let verdict = match lhs.equivalent(rhs) {
+        Some(true) => return Ok(()),
+        None if allow_unknown => return Ok(()),
+        Some(false) => Verdict::NotEqual,
+        None => Verdict::Unknown,
+    };
+    Err(ShapeError::Mismatch { axis, lhs: lhs.clone(), rhs: rhs.clone(), verdict })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shapes(shape: Vec<Expr>) -> Vec<String> {
+        shape.iter().map(Expr::to_string).collect()
+    }
+
+    #[test]
+    fn test_matmul() {
+        let [b, m, n, k] = ["b", "m", "n", "k"].map(Expr::var);
+
+        // Test symbolic inner dimensions and broadcast batch axes
+        let shape = matmul(&[m.clone(), k.clone() * 4 / 2], &[k.clone() * 2, n.clone()]).unwrap();
+        println!("asserting the matmul shape {shape:?}");
+        assert_eq!(shapes(shape), ["m", "n"]);
+        let shape = matmul(&[b.clone(), Expr::from(1), m.clone(), k.clone()], &[b.clone() * 2, k.clone(), n.clone()]).unwrap();
+        assert_eq!(shapes(shape), ["b", "b*2", "m", "n"]);
+
+        // Test definite failures, reported with their axes
+        let err = matmul(&[m.clone(), k.clone()], &[k.clone() + 1, n.clone()]).unwrap_err();
+        println!("asserting {err}");
+        assert!(matches!(err, ShapeError::Mismatch { axis: 1, verdict: Verdict::NotEqual, .. }));
+        let err = matmul(&[Expr::from(2), m.clone(), k.clone()], &[Expr::from(3), k.clone(), n.clone()]).unwrap_err();
+        assert!(matches!(err, ShapeError::Mismatch { axis: 0, verdict: Verdict::NotEqual, .. }));
+        assert!(matches!(matmul(std::slice::from_ref(&k), &[k.clone(), n.clone()]), Err(ShapeError::Rank { operand: 0, expected: 2, found: 1 })));
+
+        // Test unknown equivalence under both policies
+        let err = matmul(&[m.clone(), k.clone()], &[n.clone(), n.clone()]).unwrap_err();
+        let ShapeError::Mismatch { axis, lhs, rhs, verdict } = err else {
+            panic!("expected a mismatch, got {err}");
+        };
+        assert_eq!((axis, verdict), (1, Verdict::Unknown));
+        assert!(lhs.structurally_eq(&k) && rhs.structurally_eq(&n));
+        assert_eq!(shapes(matmul_with(&[m.clone(), k.clone()], &[n.clone(), n.clone()], true).unwrap()), ["m", "n"]);
+    }
+
+    #[test]
+    fn test_concat() {
+        let [n, c, h] = ["n", "c", "h"].map(Expr::var);
+
+        // Test the axis is summed and the others agree
+        let shape = concat(&[&[n.clone(), c.clone()], &[n.clone() * 2 / 2, c.clone() + 1], &[n.clone(), Expr::from(3)]], 1).unwrap();
+        assert_eq!(shapes(shape), ["n", "4 + 2*c"]);
+        assert_eq!(shapes(concat(&[&[n.clone(), c.clone()]], 0).unwrap()), ["n", "c"]);
+
+        // Test definite failures
+        let err = concat(&[&[n.clone(), c.clone()], &[n.clone() + 1, c.clone()]], 1).unwrap_err();
+        println!("asserting {err}");
+        assert!(matches!(err, ShapeError::Mismatch { axis: 0, verdict: Verdict::NotEqual, .. }));
+        assert!(matches!(concat(&[std::slice::from_ref(&n), &[n.clone(), c.clone()]], 0), Err(ShapeError::Rank { operand: 1, expected: 1, found: 2 })));
+        assert!(matches!(concat(&[std::slice::from_ref(&n)], 1), Err(ShapeError::AxisOutOfRange { axis: 1, rank: 1 })));
+        assert!(matches!(concat(&[], 0), Err(ShapeError::NoShapes)));
+
+        // Test unknown equivalence under both policies
+        let unknown: [&[Expr]; 2] = [&[n.clone(), c.clone()], &[h.clone(), c.clone()]];
+        assert!(matches!(concat(&unknown, 1), Err(ShapeError::Mismatch { axis: 0, verdict: Verdict::Unknown, .. })));
+        assert_eq!(shapes(concat_with(&unknown, 1, true).unwrap()), ["n", "2*c"]);
+    }
+
+    #[test]
+    fn test_split() {
+        let [n, c] = ["n", "c"].map(Expr::var);
+
+        // Test divisible axes
+        assert_eq!(shapes(split(&[n.clone() * 4 + 8, c.clone()], 0, 4).unwrap()), ["2 + n", "c"]);
+        assert_eq!(shapes(split(&[n.clone(), Expr::from(12)], 1, 3).unwrap()), ["n", "4"]);
+
+        // Test definite failures
+        let err = split(&[n.clone(), Expr::from(10)], 1, 3).unwrap_err();
+        println!("asserting {err}");
+        assert!(matches!(err, ShapeError::NotDivisible { axis: 1, parts: 3, verdict: Verdict::NotEqual, .. }));
+        assert!(matches!(split(std::slice::from_ref(&n), 0, 0), Err(ShapeError::NotDivisible { verdict: Verdict::NotEqual, .. })));
+        assert!(matches!(split(std::slice::from_ref(&n), 1, 2), Err(ShapeError::AxisOutOfRange { axis: 1, rank: 1 })));
+
+        // Test unknown divisibility under both policies
+        let err = split(&[n.clone() * c.clone()], 0, 2).unwrap_err();
+        let ShapeError::NotDivisible { dim, verdict, .. } = err else {
+            panic!("expected a divisibility error, got {err}");
+        };
+        assert_eq!(verdict, Verdict::Unknown);
+        assert!(dim.structurally_eq(&(n.clone() * c.clone())));
+        assert_eq!(shapes(split_with(&[n.clone() * c.clone()], 0, 2, true).unwrap()), ["n*c/2"]);
+        assert!(split_with(&[Expr::from(7)], 0, 2, true).is_err());
+    }
+
+    #[test]
+    fn test_same_padding() {
+        let [n, k, s] = ["n", "k", "s"].map(Expr::var);
+        let (before, after) = same_padding(&n, &k, &s).unwrap();
+        let same = same_out(&n, &s).unwrap();
+        let valid = valid_out(&n, &k, &s).unwrap();
+
+        // Test a table of cases, including s = 1 and k > n with more padding after
+        let table = [
+            // n, k, s, same out, before, after
+            (224, 3, 2, 112, 0, 1),
+            (5, 3, 1, 5, 1, 1),
+            (5, 2, 1, 5, 0, 1),
+            (2, 4, 1, 2, 1, 2),
+            (5, 6, 2, 3, 2, 3),
+            (7, 3, 3, 3, 1, 1),
+            (8, 1, 3, 3, 0, 0),
+            (1, 3, 2, 1, 1, 1),
+        ];
+        for (n, k, s, out, pad_before, pad_after) in table {
+            let value = HashMap::from([("n", n), ("k", k), ("s", s)]);
+            println!("asserting n = {n}, k = {k}, s = {s} gives {out} outputs padded by ({pad_before}, {pad_after})");
+            assert_eq!(same.substitute(&value), out);
+            assert_eq!((before.substitute(&value), after.substitute(&value)), (pad_before, pad_after));
+        }
+
+        // Test every small case against the definition, symbolic and with constant arguments
+        for (n_value, k_value, s_value) in (0..24_usize).flat_map(|n| (1..8).flat_map(move |k| (1..5).map(move |s| (n, k, s)))) {
+            let value = HashMap::from([("n", n_value), ("k", k_value), ("s", s_value)]);
+            let out = n_value.div_ceil(s_value);
+            let total = ((out as isize - 1) * s_value as isize + k_value as isize - n_value as isize).max(0) as usize;
+            let expected = (total / 2, total - total / 2);
+            assert_eq!(same.substitute(&value), out);
+            assert_eq!((before.substitute(&value), after.substitute(&value)), expected);
+            if n_value > 0 {
+                assert_eq!((n_value + before.substitute(&value) + after.substitute(&value) - k_value) / s_value + 1, out);
+            }
+            if k_value <= n_value {
+                assert_eq!(valid.substitute(&value), (n_value - k_value) / s_value + 1);
+            }
+            let [n, k, s] = [n_value, k_value, s_value].map(Expr::from);
+            let (before, after) = same_padding(&n, &k, &s).unwrap();
+            assert!(matches!((&before, &after), (Expr::Constant(_), Expr::Constant(_))), "{before}, {after}");
+            assert_eq!((before.substitute(&value), after.substitute(&value)), expected);
+            assert!(matches!(same_out(&n, &s).unwrap(), Expr::Constant(value) if value == out));
+            if k_value <= n_value {
+                assert!(matches!(valid_out(&n, &k, &s).unwrap(), Expr::Constant(value) if value == (n_value - k_value) / s_value + 1));
+            }
+        }
+
+        // Test an empty input needs no padding unless the window is wider than the stride
+        let zero = HashMap::from([("n", 0)]);
+        let (before, after) = same_padding(&n, &Expr::from(3), &Expr::from(2)).unwrap();
+        assert_eq!((before.checked_substitute(&zero), after.checked_substitute(&zero)), (Some(0), Some(1)));
+        let (before, after) = same_padding(&n, &Expr::from(1), &Expr::from(3)).unwrap();
+        assert_eq!((before.substitute(&zero), after.substitute(&zero)), (0, 0));
+        assert_eq!(same_out(&n, &Expr::from(2)).unwrap().to_string(), "(n + 1 - mod(n + 1, 2))/2");
+
+        // Test constant arguments fold and invalid ones fail
+        let (before, after) = same_padding(&Expr::from(224), &Expr::from(3), &Expr::from(2)).unwrap();
+        assert_eq!((before.to_string(), after.to_string()), ("0".to_string(), "1".to_string()));
+        assert_eq!(valid_out(&Expr::from(10), &Expr::from(3), &Expr::from(2)).unwrap().to_string(), "4");
+        assert!(matches!(same_out(&n, &(s.clone() - s.clone())), Err(Error::Eval { kind: EvalError::DivisionByZero, .. })));
+        assert!(matches!(same_padding(&Expr::Hole, &k, &s), Err(Error::UnfilledHole)));
+    }
+}
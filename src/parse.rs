@@ -0,0 +1,297 @@
+//! 从文本解析表达式。
+//!
+//! 语法与 [`Display`](std::fmt::Display) 的输出一致：`+ - * /`、整数次幂 `^`、括号、
+//! 函数 `mod(a, n)`、`abs(a)` 与 `max(a, b)`，和式开头的负号，以及表示空位的 `?`。
+
+use crate::{Expr, MAX_EXPONENT, Operand, Sign};
+use std::{fmt, str::FromStr};
+
+/// Nesting limit of parentheses and function calls, so a hostile text cannot overflow the stack.
+const MAX_DEPTH: usize = 256;
+
+/// Reason the text of an expression could not be parsed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// An unexpected character at the byte offset.
+    Unexpected { position: usize, found: char },
+    /// The text ended in the middle of an expression.
+    UnexpectedEnd,
+    /// A number at the byte offset does not fit the integer type.
+    Overflow { position: usize },
+    /// A call of an unknown function, or with a wrong number of arguments, at the byte offset.
+    UnknownFunction { position: usize, name: String },
+    /// Parentheses or function calls at the byte offset are nested too deeply.
+    TooDeep { position: usize },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unexpected { position, found } => write!(f, "unexpected {found:?} at {position}"),
+            Self::UnexpectedEnd => write!(f, "unexpected end of expression"),
+            Self::Overflow { position } => write!(f, "number at {position} is too large"),
+            Self::UnknownFunction { position, name } => write!(f, "unknown function \"{name}\" at {position}"),
+            Self::TooDeep { position } => write!(f, "expression at {position} is nested too deeply"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl FromStr for Expr {
+    type Err = ParseError;
+
+    /// Parses the text of an expression, see the [module documentation](self) for the syntax.
+    ///
+    /// ```rust
+    /// # use std::collections::HashMap;
+    /// # use symbolic_expr::Expr;
+    /// let expr = "(a + 1)*3/(b + 1)".parse::<Expr>().unwrap();
+    /// assert_eq!(expr.to_string(), "(a + 1)*3/(b + 1)");
+    /// assert_eq!(expr.substitute(&HashMap::from([("a", 7), ("b", 5)])), 4);
+    /// ```
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser { text: s, position: 0, depth: 0 };
+        let expr = parser.sum()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(found) => Err(ParseError::Unexpected { position: parser.position, found }),
+        }
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    position: usize,
+    /// Parentheses and function calls entered.
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// The next non-whitespace character, without consuming it.
+    fn peek(&mut self) -> Option<char> {
+        let rest = &self.text[self.position..];
+        self.position += rest.len() - rest.trim_start().len();
+        self.text[self.position..].chars().next()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(c);
+        if found {
+            self.position += c.len_utf8();
+        }
+        found
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), ParseError> {
+        match self.peek() {
+            Some(found) if found == c => {
+                self.position += c.len_utf8();
+                Ok(())
+            }
+            Some(found) => Err(ParseError::Unexpected { position: self.position, found }),
+            None => Err(ParseError::UnexpectedEnd),
+        }
+    }
+
+    /// Parses a sum nested in the parentheses or function call at `position`.
+    fn nested_sum(&mut self, position: usize) -> Result<Expr, ParseError> {
+        if self.depth == MAX_DEPTH {
+            return Err(ParseError::TooDeep { position });
+        }
+        self.depth += 1;
+        let ans = self.sum();
+        self.depth -= 1;
+        ans
+    }
+
+    /// sum := ['-'] product (('+' | '-') product)*
+    fn sum(&mut self) -> Result<Expr, ParseError> {
+        let mut ans = if self.eat('-') {
+            Expr::Sum([Operand { ty: Sign::Negative, expr: self.product()? }].into())
+        } else {
+            self.product()?
+        };
+        loop {
+            if self.eat('+') {
+                ans = ans + self.product()?;
+            } else if self.eat('-') {
+                ans = ans - self.product()?;
+            } else {
+                break Ok(ans);
+            }
+        }
+    }
+
+    /// product := power (('*' | '/') power)*
+    fn product(&mut self) -> Result<Expr, ParseError> {
+        let mut ans = self.power()?;
+        loop {
+            if self.eat('*') {
+                ans = ans * self.power()?;
+            } else if self.eat('/') {
+                ans = ans / self.power()?;
+            } else {
+                break Ok(ans);
+            }
+        }
+    }
+
+    /// power := atom ('^' number)*
+    fn power(&mut self) -> Result<Expr, ParseError> {
+        let mut ans = self.atom()?;
+        while self.eat('^') {
+            let position = self.position;
+            let exponent = self.number()?;
+            let exponent = u32::try_from(exponent)
+                .ok()
+                .filter(|&exponent| exponent as isize <= MAX_EXPONENT)
+                .ok_or(ParseError::Overflow { position })?;
+            ans = ans.pow(exponent);
+        }
+        Ok(ans)
+    }
+
+    /// atom := number | name | name '(' sum (',' sum)* ')' | '(' sum ')' | '?'
+    fn atom(&mut self) -> Result<Expr, ParseError> {
+        match self.peek() {
+            None => Err(ParseError::UnexpectedEnd),
+            Some('(') => {
+                let position = self.position;
+                self.position += 1;
+                let ans = self.nested_sum(position)?;
+                self.expect(')')?;
+                Ok(ans)
+            }
+            Some('?') => {
+                self.position += 1;
+                Ok(Expr::Hole)
+            }
+            Some(c) if c.is_ascii_digit() => self.number().map(Expr::Constant),
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                let position = self.position;
+                let name = self.name();
+                if !self.eat('(') {
+                    return Ok(Expr::var(name));
+                }
+                let mut args = vec![self.nested_sum(position)?];
+                while self.eat(',') {
+                    args.push(self.nested_sum(position)?);
+                }
+                self.expect(')')?;
+                call(name, args).ok_or_else(|| ParseError::UnknownFunction { position, name: name.to_string() })
+            }
+            Some(found) => Err(ParseError::Unexpected { position: self.position, found }),
+        }
+    }
+
+    fn number(&mut self) -> Result<usize, ParseError> {
+        let position = self.position;
+        let digits = self.take_while(|c| c.is_ascii_digit());
+        match digits {
+            "" => match self.peek() {
+                Some(found) => Err(ParseError::Unexpected { position, found }),
+                None => Err(ParseError::UnexpectedEnd),
+            },
+            digits => digits.parse().map_err(|_| ParseError::Overflow { position }),
+        }
+    }
+
+    fn name(&mut self) -> &'a str {
+        self.take_while(|c| c.is_alphanumeric() || c == '_')
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+        self.peek();
+        let start = self.position;
+        let rest = &self.text[start..];
+        let len = rest.find(|c| !f(c)).unwrap_or(rest.len());
+        self.position += len;
+        &self.text[start..self.position]
+    }
+}
+
+/// Builds the node of a function call.
+fn call(name: &str, args: Vec<Expr>) -> Option<Expr> {
+    let mut args = args.into_iter();
+    let ans = match name {
+        "mod" => args.next()?.modulo(args.next()?),
+        "abs" => args.next()?.abs(),
+        "max" => args.next()?.max(args.next()?),
+        _ => return None,
+    };
+    args.next().is_none().then_some(ans)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+
+        // Test precedence and associativity
+        let expr = "a + b*2 - c/(a - 1)^2".parse::<Expr>().unwrap();
+        println!("asserting {expr} is a + b*2 - c/(a - 1)^2");
+        assert!(expr.structurally_eq(&(a.clone() + b.clone() * 2 - c.clone() / (a.clone() - 1).pow(2))));
+        assert!("a - b - c".parse::<Expr>().unwrap().structurally_eq(&(a.clone() - b.clone() - c.clone())));
+        assert!("a/b/c".parse::<Expr>().unwrap().structurally_eq(&(a.clone() / b.clone() / c.clone())));
+        assert!("mod(a + 3, b)".parse::<Expr>().unwrap().structurally_eq(&(a.clone() + 3).modulo(b.clone())));
+        assert_eq!("-a + b".parse::<Expr>().unwrap().equivalent(&(b.clone() - a.clone())), Some(true));
+        assert!("  x_1 *   4 ".parse::<Expr>().unwrap().structurally_eq(&(Expr::var("x_1") * 4)));
+        assert!("(? + a)*2".parse::<Expr>().unwrap().structurally_eq(&((Expr::Hole + a.clone()) * 2)));
+
+        // Test errors
+        assert_eq!("a + ".parse::<Expr>().unwrap_err(), ParseError::UnexpectedEnd);
+        assert_eq!("a + )".parse::<Expr>().unwrap_err(), ParseError::Unexpected { position: 4, found: ')' });
+        assert_eq!("(a".parse::<Expr>().unwrap_err(), ParseError::UnexpectedEnd);
+        assert_eq!("a b".parse::<Expr>().unwrap_err(), ParseError::Unexpected { position: 2, found: 'b' });
+        assert_eq!("a^x".parse::<Expr>().unwrap_err(), ParseError::Unexpected { position: 2, found: 'x' });
+        assert_eq!("99999999999999999999999".parse::<Expr>().unwrap_err(), ParseError::Overflow { position: 0 });
+        assert_eq!(
+            "1 + sqrt(a, b)".parse::<Expr>().unwrap_err(),
+            ParseError::UnknownFunction { position: 4, name: "sqrt".to_string() },
+        );
+        assert!(matches!("mod(a)".parse::<Expr>(), Err(ParseError::UnknownFunction { .. })));
+
+        // Test deep nesting is an error rather than a stack overflow
+        let deep = "(".repeat(200_000) + "a" + &")".repeat(200_000);
+        assert_eq!(deep.parse::<Expr>().unwrap_err(), ParseError::TooDeep { position: MAX_DEPTH });
+        let calls = "abs(".repeat(MAX_DEPTH + 1) + "a" + &")".repeat(MAX_DEPTH + 1);
+        assert!(matches!(calls.parse::<Expr>(), Err(ParseError::TooDeep { .. })));
+        let within = "(".repeat(MAX_DEPTH) + "a" + &")".repeat(MAX_DEPTH);
+        assert!(within.parse::<Expr>().unwrap().structurally_eq(&a));
+    }
+
+    #[test]
+    fn test_parse_display() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+
+        let exprs = [
+            (a.clone() + 1) * 3 / (b.clone() + 1),
+            a.clone() * (b.clone() - c.clone()) - a.clone().pow(3),
+            (a.clone() + c.clone()).modulo(b.clone() * 2),
+            Expr::from(((a.clone() * 3 - b.clone()) / (c.clone() + 2)).canonicalize().unwrap()),
+            (a.clone() * a.clone() * b.clone() + a.clone() * c.clone()) / (b.clone() * 2 + 2),
+        ];
+        for expr in exprs {
+            // Test the displayed text parses back to the same tree or an equivalent expression
+            let text = expr.to_string();
+            println!("asserting {text} parses back");
+            let parsed = text.parse::<Expr>().unwrap();
+            assert_eq!(parsed.to_string(), text);
+            assert_ne!(parsed.equivalent(&expr), Some(false));
+
+            // Test the factored display is equivalent
+            let factored = expr.display_factored();
+            if expr.canonicalize().is_ok() {
+                assert_eq!(factored.parse::<Expr>().unwrap().equivalent(&expr), Some(true));
+            }
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! 按比例缩放的表达式，记录累计的缩放比例，只在能证明整除时给出精确结果。
+
+use crate::Expr;
+use num_rational::Ratio;
+use std::fmt;
+
+/// The scaled expression is not proven to be an integer, see [`ScaledExpr::require_exact`].
+#[derive(Clone, Debug)]
+pub struct NonExact {
+    /// The expression times the numerator of the accumulated scale.
+    pub numerator: Expr,
+    /// The denominator of the accumulated scale, which is not proven to divide `numerator`.
+    pub divisor: usize,
+}
+
+impl fmt::Display for NonExact {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} is not proven divisible by {}", self.numerator, self.divisor)
+    }
+}
+
+impl std::error::Error for NonExact {}
+
+/// An expression with the scale accumulated over several scalings, such as repeated halvings
+/// of a dimension, so that the division happens once and only where it is known to be exact.
+///
+/// ```rust
+/// # use symbolic_expr::Expr;
+/// let scaled = (Expr::var("n") * 2).scaled(1, 2).scaled(1, 2).unwrap();
+/// assert!(scaled.require_exact().is_err());
+/// assert_eq!(scaled.floor().to_string(), "(2*n - mod(2*n, 4))/4");
+/// ```
+#[derive(Clone, Debug)]
+pub struct ScaledExpr {
+    expr: Expr,
+    scale: Ratio<usize>,
+}
+
+impl Expr {
+    /// Scales the expression by `num/den`, see [`ScaledExpr`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `den` is zero.
+    pub fn scaled(&self, num: usize, den: usize) -> ScaledExpr {
+        ScaledExpr { expr: self.clone(), scale: Ratio::new(num, den) }
+    }
+}
+
+impl ScaledExpr {
+    /// Scales again by `num/den`, accumulating the scale.
+    /// Returns None if the accumulated scale overflows.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `den` is zero.
+    pub fn scaled(&self, num: usize, den: usize) -> Option<Self> {
+        let scale = num_traits::CheckedMul::checked_mul(&self.scale, &Ratio::new(num, den))?;
+        Some(Self { expr: self.expr.clone(), scale })
+    }
+
+    /// The expression before scaling.
+    pub fn expr(&self) -> &Expr {
+        &self.expr
+    }
+
+    /// The accumulated scale, in lowest terms.
+    pub fn scale(&self) -> Ratio<usize> {
+        self.scale
+    }
+
+    /// The scaled expression, if the denominator of the scale is proven to divide the
+    /// expression times the numerator by [`Expr::divides`].
+    pub fn require_exact(&self) -> Result<Expr, NonExact> {
+        let numerator = self.numerator();
+        let divisor = *self.scale.denom();
+        if !Expr::from(divisor).divides(&numerator) {
+            return Err(NonExact { numerator, divisor });
+        }
+        let mut quotient = numerator / divisor;
+        quotient.shrink_to_fit();
+        Ok(quotient)
+    }
+
+    /// The scaled expression rounded down, exact for natural values of the expression.
+    pub fn floor(&self) -> Expr {
+        self.require_exact().unwrap_or_else(|NonExact { numerator, divisor }| floor_div(numerator, Expr::from(divisor)))
+    }
+
+    /// The scaled expression rounded up, exact for natural values of the expression.
+    pub fn ceil(&self) -> Expr {
+        self.require_exact().unwrap_or_else(|NonExact { numerator, divisor }| {
+            let mut numerator = numerator + (divisor - 1);
+            numerator.shrink_to_fit();
+            floor_div(numerator, Expr::from(divisor))
+        })
+    }
+
+    fn numerator(&self) -> Expr {
+        let mut numerator = self.expr.clone() * *self.scale.numer();
+        numerator.shrink_to_fit();
+        numerator
+    }
+}
+
+/// `numerator/divisor` rounded down, as an exact division of the numerator less its remainder.
+pub(crate) fn floor_div(numerator: Expr, divisor: Expr) -> Expr {
+    (numerator.clone() - numerator.modulo(divisor.clone())) / divisor
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_scaled() {
+        let n = Expr::var("n");
+
+        // Test three halvings of n*8 stay exact
+        let mut scaled = (n.clone() * 8).scaled(1, 1);
+        for expected in ["4*n", "2*n", "n"] {
+            scaled = scaled.scaled(1, 2).unwrap();
+            println!("asserting n*8 scaled by {} is {expected}", scaled.scale());
+            assert_eq!(scaled.require_exact().unwrap().to_string(), expected);
+            assert_eq!(scaled.floor().to_string(), expected);
+            assert_eq!(scaled.ceil().to_string(), expected);
+        }
+        assert_eq!(scaled.scale(), Ratio::new(1, 8));
+
+        // Test halving n*2 is exact once, then falls back to rounding
+        let scaled = (n.clone() * 2).scaled(1, 2);
+        assert_eq!(scaled.require_exact().unwrap().to_string(), "n");
+        let scaled = scaled.scaled(1, 2).unwrap();
+        let err = scaled.require_exact().unwrap_err();
+        println!("asserting {err}");
+        assert_eq!((err.numerator.to_string(), err.divisor), ("2*n".to_string(), 4));
+        for value in 1..10 {
+            let env = HashMap::from([("n", value)]);
+            assert_eq!(scaled.floor().substitute(&env), value * 2 / 4);
+            assert_eq!(scaled.ceil().substitute(&env), (value * 2).div_ceil(4));
+        }
+
+        // Test scales are kept in lowest terms and scaling up is exact
+        let scaled = n.scaled(3, 6).scaled(4, 1).unwrap();
+        assert_eq!(scaled.scale(), Ratio::from_integer(2));
+        assert_eq!(scaled.require_exact().unwrap().to_string(), "2*n");
+        assert!(scaled.expr().structurally_eq(&n));
+
+        // Test scales that overflow
+        println!("asserting scaling n by usize::MAX twice overflows");
+        let scaled = n.scaled(usize::MAX, 1);
+        assert!(scaled.scaled(2, 1).is_none());
+        assert_eq!(scaled.scaled(1, usize::MAX).unwrap().scale(), Ratio::from_integer(1));
+    }
+}
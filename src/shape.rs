@@ -0,0 +1,246 @@
+//! 形状的元素个数，以及忽略轴顺序比较两个形状的因子结构。
+
+use crate::Expr;
+
+/// Most equivalence checks of a factor matching, after which the search gives up.
+const SEARCH_BUDGET: usize = 4096;
+/// Most axes grouped to match a single axis of the other shape.
+const MAX_GROUP: usize = 4;
+
+/// Whether two shapes have the same number of elements and the same factor structure, up to
+/// the order of the axes, such as `[n, h*w, c]` and `[n, c, h, w]`.
+///
+/// Returns `Some(false)` if the products of the shapes are known to differ, as decided by
+/// [`Expr::equivalent`], and None if that is unknown or no matching is found by
+/// [`shape_factor_matching`].
+///
+/// ```rust
+/// # use symbolic_expr::{Expr, shapes_factor_equivalent};
+/// let [n, c, h, w] = ["n", "c", "h", "w"].map(Expr::var);
+/// let flat = [n.clone(), h.clone() * w.clone(), c.clone()];
+/// assert_eq!(shapes_factor_equivalent(&flat, &[n.clone(), c.clone(), h.clone(), w.clone()]), Some(true));
+/// assert_eq!(shapes_factor_equivalent(&flat, &[n * h * w * c + 1]), Some(false));
+/// ```
+pub fn shapes_factor_equivalent(a: &[Expr], b: &[Expr]) -> Option<bool> {
+    if !shape_product(a, 0..a.len()).equivalent(&shape_product(b, 0..b.len()))? {
+        return Some(false);
+    }
+    shape_factor_matching(a, b).map(|_| true)
+}
+
+/// Matches the axes of two shapes into groups of equivalent products, each pairing one axis
+/// of a shape with one or more axes of the other, such as `([1], [2, 3])` for `h*w` in
+/// `[n, h*w, c]` and `h, w` in `[n, c, h, w]`.
+///
+/// The groups are ordered by their first axis of `a`, and single axes are tried before products.
+/// Products have at most 4 axes, and the search gives up after a fixed number of equivalence
+/// checks, returning None as it does when there is no matching.
+pub fn shape_factor_matching(a: &[Expr], b: &[Expr]) -> Option<Vec<(Vec<usize>, Vec<usize>)>> {
+    let mut matcher = Matcher {
+        a,
+        b,
+        used_a: vec![false; a.len()],
+        used_b: vec![false; b.len()],
+        groups: Vec::new(),
+        budget: SEARCH_BUDGET,
+    };
+    matcher.search().then_some(matcher.groups)
+}
+
+/// The number of elements of a shape, as the product of its dimensions with one operand per
+/// dimension, or `1` for a scalar.
+///
+/// The product is evaluated dimension by dimension, so an overflow makes
+/// [`Expr::checked_substitute`] return None, and the first index of the
+/// [`path`](crate::EvalDiagnostic::path) from [`Expr::substitute_diagnosed`] is the dimension
+/// that overflows.
+///
+/// ```rust
+/// # use std::collections::HashMap;
+/// # use symbolic_expr::{Expr, total_size};
+/// let numel = total_size(&[Expr::var("n"), Expr::var("c") * 2, Expr::from(3)]);
+/// assert_eq!(numel.checked_substitute(&HashMap::from([("n", 4), ("c", 5)])), Some(120));
+/// let err = numel.substitute_diagnosed(&HashMap::from([("n", usize::MAX / 4), ("c", 5)])).unwrap_err();
+/// assert_eq!(err.path[0], 1);
+/// ```
+pub fn total_size(dims: &[Expr]) -> Expr {
+    shape_product(dims, 0..dims.len())
+}
+
+fn shape_product(shape: &[Expr], axes: impl IntoIterator<Item = usize>) -> Expr {
+    Expr::product(axes.into_iter().map(|axis| shape[axis].clone().positive()))
+}
+
+/// A depth-first search of a factor matching, matching the first free axis of `a` each step.
+struct Matcher<'a> {
+    a: &'a [Expr],
+    b: &'a [Expr],
+    used_a: Vec<bool>,
+    used_b: Vec<bool>,
+    groups: Vec<(Vec<usize>, Vec<usize>)>,
+    budget: usize,
+}
+
+impl Matcher<'_> {
+    fn search(&mut self) -> bool {
+        let Some(axis) = self.used_a.iter().position(|&used| !used) else {
+            return !self.used_b.contains(&false);
+        };
+        let free = |used: &[bool]| (0..used.len()).filter(|&i| !used[i]).collect::<Vec<_>>();
+        let free_b = free(&self.used_b);
+        // One axis of `a` against axes of `b`
+        for group in subsets(&free_b, MAX_GROUP) {
+            if self.budget == 0 {
+                return false;
+            }
+            if self.try_group(vec![axis], group) {
+                return true;
+            }
+        }
+        // Axes of `a` against one axis of `b`
+        let free_a = free(&self.used_a).into_iter().filter(|&i| i != axis).collect::<Vec<_>>();
+        for &target in &free_b {
+            for rest in subsets(&free_a, MAX_GROUP - 1) {
+                if self.budget == 0 {
+                    return false;
+                }
+                if self.try_group([axis].into_iter().chain(rest).collect(), vec![target]) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Spends one unit of the budget on checking a candidate group.
+    fn try_group(&mut self, group_a: Vec<usize>, group_b: Vec<usize>) -> bool {
+        self.budget -= 1;
+        let lhs = shape_product(self.a, group_a.iter().copied());
+        if lhs.equivalent(&shape_product(self.b, group_b.iter().copied())) != Some(true) {
+            return false;
+        }
+        set_used(&mut self.used_a, &group_a, true);
+        set_used(&mut self.used_b, &group_b, true);
+        self.groups.push((group_a, group_b));
+        if self.search() {
+            return true;
+        }
+        let (group_a, group_b) = self.groups.pop().unwrap();
+        set_used(&mut self.used_a, &group_a, false);
+        set_used(&mut self.used_b, &group_b, false);
+        false
+    }
+}
+
+fn set_used(used: &mut [bool], group: &[usize], value: bool) {
+    group.iter().for_each(|&i| used[i] = value);
+}
+
+/// The non-empty subsets of `items` with at most `max` elements, smaller ones first,
+/// generated one at a time so a search that stops early does not build the rest.
+fn subsets(items: &[usize], max: usize) -> Subsets<'_> {
+    Subsets { items, max: max.min(items.len()), positions: Vec::new() }
+}
+
+/// The iterator of [`subsets`], over the positions in `items` of the current subset.
+struct Subsets<'a> {
+    items: &'a [usize],
+    max: usize,
+    positions: Vec<usize>,
+}
+
+impl Iterator for Subsets<'_> {
+    type Item = Vec<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let n = self.items.len();
+        let len = self.positions.len();
+        // Advance the last position that can move right, then reset the ones after it
+        match (0..len).rev().find(|&i| self.positions[i] < n - len + i) {
+            Some(i) => {
+                self.positions[i] += 1;
+                for j in i + 1..len {
+                    self.positions[j] = self.positions[j - 1] + 1;
+                }
+            }
+            None if len < self.max => self.positions = (0..len + 1).collect(),
+            None => return None,
+        }
+        Some(self.positions.iter().map(|&i| self.items[i]).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EvalError;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_shapes_factor_equivalent() {
+        let [n, c, h, w] = ["n", "c", "h", "w"].map(Expr::var);
+
+        // Test axes are matched one to one or to products, in either direction
+        let flat = [n.clone(), h.clone() * w.clone(), c.clone()];
+        let split = [n.clone(), c.clone(), h.clone(), w.clone()];
+        println!("asserting [n, h*w, c] matches [n, c, h, w]");
+        assert_eq!(shapes_factor_equivalent(&flat, &split), Some(true));
+        assert_eq!(shape_factor_matching(&flat, &split).unwrap(), [(vec![0], vec![0]), (vec![1], vec![2, 3]), (vec![2], vec![1])]);
+        assert_eq!(shape_factor_matching(&split, &flat).unwrap(), [(vec![0], vec![0]), (vec![1], vec![2]), (vec![2, 3], vec![1])]);
+        assert_eq!(shapes_factor_equivalent(&[n.clone() * c.clone() * 2], &[c.clone() * n.clone(), Expr::from(2)]), Some(true));
+        assert_eq!(shapes_factor_equivalent(&[n.clone() * 2, c.clone()], &[c.clone() * n.clone(), Expr::from(2)]), None);
+        assert_eq!(shape_factor_matching(&[], &[]), Some(Vec::new()));
+
+        // Test shapes with different products
+        assert_eq!(shapes_factor_equivalent(&[n.clone() * c.clone() + 1], &[n.clone(), c.clone()]), Some(false));
+        assert_eq!(shapes_factor_equivalent(&[n.clone(), Expr::from(2)], &[n.clone() + 1, Expr::from(2)]), Some(false));
+        assert_eq!(shapes_factor_equivalent(&flat, &[n.clone(), c.clone(), h.clone()]), None);
+
+        // Test equal products without a factor matching, or without a rational form
+        let [a, b, d] = ["a", "b", "d"].map(Expr::var);
+        let crossed = [a.clone() * c.clone(), b.clone() * d.clone()];
+        assert_eq!(shapes_factor_equivalent(&[a.clone() * b.clone(), c.clone() * d.clone()], &crossed), None);
+        assert_eq!(shape_factor_matching(&[a.clone() * b.clone(), c.clone() * d.clone()], &crossed), None);
+        assert_eq!(shapes_factor_equivalent(&[n.clone().modulo(c.clone())], std::slice::from_ref(&n)), None);
+    }
+
+    #[test]
+    fn test_total_size() {
+        let [a, b, c] = ["a", "b", "c"].map(Expr::var);
+
+        // Test the product substitutes like the dimensions
+        let numel = total_size(&[a.clone(), b.clone(), c.clone()]);
+        println!("asserting {numel} is the number of elements of [a, b, c]");
+        assert_eq!(numel.checked_substitute(&HashMap::from([("a", 2), ("b", 3), ("c", 7)])), Some(42));
+        assert_eq!(numel.equivalent(&(a.clone() * b.clone() * c.clone())), Some(true));
+        assert_eq!(total_size(&[]).as_constant(), Some(1));
+
+        // Test an overflowing product names the dimension that overflows
+        let value = HashMap::from([("a", usize::MAX / 2), ("b", 1), ("c", 3)]);
+        assert_eq!(numel.checked_substitute(&value), None);
+        let err = numel.substitute_diagnosed(&value).unwrap_err();
+        assert_eq!((err.kind, err.path, err.accumulator), (EvalError::Overflow, vec![2], Some(usize::MAX / 2)));
+    }
+
+    #[test]
+    fn test_subsets() {
+        println!("asserting subsets come smaller ones first");
+        let all = subsets(&[1, 2, 3], 2).collect::<Vec<_>>();
+        assert_eq!(all, [vec![1], vec![2], vec![3], vec![1, 2], vec![1, 3], vec![2, 3]]);
+        assert_eq!(subsets(&[1, 2, 3], 4).last(), Some(vec![1, 2, 3]));
+        assert_eq!(subsets(&[], MAX_GROUP).next(), None);
+
+        // Test subsets of many axes are generated on demand
+        let items = (0..1000).collect::<Vec<_>>();
+        assert_eq!(subsets(&items, MAX_GROUP).nth(1000), Some(vec![0, 1]));
+    }
+
+    #[test]
+    fn test_shape_factor_matching_budget() {
+        // Test the search over unrelated axes stops at the budget
+        let a = (0..40).map(|i| Expr::var(format!("x{i}"))).collect::<Vec<_>>();
+        let b = (0..40).map(|i| Expr::var(format!("y{i}"))).collect::<Vec<_>>();
+        println!("asserting 40 unrelated axes have no matching");
+        assert_eq!(shape_factor_matching(&a, &b), None);
+    }
+}
@@ -0,0 +1,238 @@
+//! 转换为 SMT-LIB 表达式，以便用外部求解器验证。
+
+use crate::{CanonicalTerm, Error, Expr, Operand, RationalExpression, Sign};
+use num_integer::Integer;
+use std::collections::VecDeque;
+
+/// Powers with larger exponents are rendered as `(^ base k)` instead of expanded products.
+const MAX_EXPANDED_EXPONENT: u32 = 8;
+
+impl Expr {
+    /// Renders the expression as an SMT-LIB term over integer or real arithmetic.
+    ///
+    /// Sums and products are rendered left to right as written, so `a + 2*b` is `(+ a (* 2 b))`.
+    /// Divisions are `div` over integers and `/` over reals, powers up to the 8th are expanded into
+    /// products and higher powers are `(^ base k)`. A hole is the undeclared symbol `|?|`.
+    ///
+    /// Fails with [`Error::InvalidSymbol`] if a variable name contains `|` or `\`, which no
+    /// SMT-LIB symbol can.
+    ///
+    /// ```rust
+    /// # use symbolic_expr::Expr;
+    /// let expr = (Expr::var("a") + 1) / 2;
+    /// assert_eq!(expr.to_smtlib(true).unwrap(), "(div (+ a 1) 2)");
+    /// assert_eq!(expr.to_smtlib(false).unwrap(), "(/ (+ a 1.0) 2.0)");
+    /// ```
+    pub fn to_smtlib(&self, int_sort: bool) -> Result<String, Error> {
+        Ok(match self {
+            Self::Constant(value) => constant(*value as isize, int_sort),
+            Self::Variable(name) => symbol(name)?,
+            Self::Sum(operands) => fold_operands(operands, "+", "-", &constant(0, int_sort), int_sort)?,
+            Self::Product(operands) => {
+                let div = if int_sort { "div" } else { "/" };
+                fold_operands(operands, "*", div, &constant(1, int_sort), int_sort)?
+            }
+            Self::Rational(rational) => rational.to_smtlib(int_sort)?,
+            Self::Pow(base, exponent) => match exponent {
+                0 => constant(1, int_sort),
+                _ => power(base.to_smtlib(int_sort)?, *exponent, int_sort),
+            },
+            Self::Mod(lhs, modulus) if int_sort => {
+                apply("mod", vec![lhs.to_smtlib(int_sort)?, modulus.to_smtlib(int_sort)?])
+            }
+            Self::Mod(lhs, modulus) => {
+                let to_int = |expr: &Self| expr.to_smtlib(int_sort).map(|expr| apply("to_int", vec![expr]));
+                apply("to_real", vec![apply("mod", vec![to_int(lhs)?, to_int(modulus)?])])
+            }
+            Self::Abs(operand) if int_sort => apply("abs", vec![operand.to_smtlib(int_sort)?]),
+            Self::Abs(operand) => {
+                // Real arithmetic has no abs
+                let operand = operand.to_smtlib(int_sort)?;
+                let negated = apply("-", vec![operand.clone()]);
+                format!("(ite (>= {operand} {}) {operand} {negated})", constant(0, int_sort))
+            }
+            Self::Max(lhs, rhs) => {
+                let (lhs, rhs) = (lhs.to_smtlib(int_sort)?, rhs.to_smtlib(int_sort)?);
+                format!("(ite (>= {lhs} {rhs}) {lhs} {rhs})")
+            }
+            // Never declared, so solvers reject terms with holes
+            Self::Hole => symbol("?")?,
+        })
+    }
+}
+
+/// Renders an SMT-LIB query whose `(check-sat)` is `unsat` exactly when `a` and `b` are equal
+/// for every assignment of positive integers to their variables.
+///
+/// Every variable of either expression is declared once, in alphabetical order.
+/// Fails like [`Expr::to_smtlib`].
+pub fn equivalence_query(a: &Expr, b: &Expr) -> Result<String, Error> {
+    let mut variables = a.variables();
+    b.append_variables(&mut variables);
+
+    let mut query = String::new();
+    for name in &variables {
+        query += &format!("(declare-const {} Int)\n", symbol(name)?);
+    }
+    for name in &variables {
+        query += &format!("(assert (> {} 0))\n", symbol(name)?);
+    }
+    query += &format!("(assert (not (= {} {})))\n", a.to_smtlib(true)?, b.to_smtlib(true)?);
+    query += "(check-sat)\n";
+    Ok(query)
+}
+
+impl RationalExpression {
+    /// Renders the normal form, scaled to integer coefficients.
+    fn to_smtlib(&self, int_sort: bool) -> Result<String, Error> {
+        let rational = self.normalize();
+        let lcm = rational.numer.iter().chain(&rational.denom).fold(1, |lcm, term| lcm.lcm(term.coef.denom()));
+        let numer = terms_to_smtlib(&rational.numer, lcm, int_sort)?;
+        if rational.denom == [CanonicalTerm::new(1)] && lcm == 1 {
+            return Ok(numer);
+        }
+        let denom = terms_to_smtlib(&rational.denom, lcm, int_sort)?;
+        Ok(apply(if int_sort { "div" } else { "/" }, vec![numer, denom]))
+    }
+}
+
+/// Renders the sum of `terms` with their coefficients multiplied by `scale`.
+fn terms_to_smtlib(terms: &[CanonicalTerm], scale: isize, int_sort: bool) -> Result<String, Error> {
+    let terms = terms
+        .iter()
+        .map(|term| {
+            let coef = (term.coef * scale).to_integer();
+            let mut product = Vec::new();
+            if coef.abs() != 1 || term.factors.is_empty() {
+                product.push(constant(coef.abs(), int_sort));
+            }
+            for factor in &term.factors {
+                // Normalized terms have no negative exponents
+                product.push(power(symbol(&factor.base)?, factor.exponent as u32, int_sort));
+            }
+            let ty = if coef < 0 { Sign::Negative } else { Sign::Positive };
+            Ok((ty, apply("*", product)))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    Ok(fold_rendered(terms, "+", "-", &constant(0, int_sort)))
+}
+
+fn fold_operands(operands: &VecDeque<Operand>, positive: &str, negative: &str, identity: &str, int_sort: bool) -> Result<String, Error> {
+    let operands = operands
+        .iter()
+        .map(|Operand { ty, expr }| Ok((*ty, expr.to_smtlib(int_sort)?)))
+        .collect::<Result<_, Error>>()?;
+    Ok(fold_rendered(operands, positive, negative, identity))
+}
+
+/// Renders a positive power of `base`, expanded into a product if the exponent is small.
+fn power(base: String, exponent: u32, int_sort: bool) -> String {
+    if exponent <= MAX_EXPANDED_EXPONENT {
+        apply("*", vec![base; exponent as usize])
+    } else {
+        apply("^", vec![base, constant(exponent as isize, int_sort)])
+    }
+}
+
+/// Applies the operators left to right, merging consecutive operands of the same sign into one
+/// n-ary application.
+fn fold_rendered(operands: Vec<(Sign, String)>, positive: &str, negative: &str, identity: &str) -> String {
+    let mut operands = operands.into_iter().peekable();
+    let mut acc = match operands.next() {
+        None => return identity.to_string(),
+        Some((Sign::Positive, first)) => first,
+        Some((Sign::Negative, first)) => apply(negative, vec![identity.to_string(), first]),
+    };
+    while let Some((ty, first)) = operands.next() {
+        let mut args = vec![acc, first];
+        while let Some((_, next)) = operands.next_if(|(next, _)| *next == ty) {
+            args.push(next);
+        }
+        acc = apply(if ty == Sign::Positive { positive } else { negative }, args);
+    }
+    acc
+}
+
+fn apply(op: &str, args: Vec<String>) -> String {
+    match &*args {
+        [] => "1".to_string(),
+        [arg] if op == "*" => arg.clone(),
+        _ => format!("({op} {})", args.join(" ")),
+    }
+}
+
+fn constant(value: isize, int_sort: bool) -> String {
+    let abs = if int_sort { value.abs().to_string() } else { format!("{}.0", value.abs()) };
+    if value < 0 { format!("(- {abs})") } else { abs }
+}
+
+/// Quotes names that are not simple SMT-LIB symbols, quoted symbols cannot contain `|` or `\`.
+fn symbol(name: &str) -> Result<String, Error> {
+    if name.contains(['|', '\\']) {
+        return Err(Error::InvalidSymbol(name.to_string()));
+    }
+    let simple = name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && name.chars().next().is_some_and(|c| !c.is_ascii_digit());
+    Ok(if simple { name.to_string() } else { format!("|{name}|") })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_smtlib() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let c = Expr::var("c");
+
+        // Test trees with divisions over both sorts
+        let expr1 = (a.clone() + b.clone() * 2) * 3 / (c.clone() - 1);
+        println!("asserting {expr1} renders left to right");
+        assert_eq!(expr1.to_smtlib(true).unwrap(), "(div (* (+ a (* b 2)) 3) (- c 1))");
+        assert_eq!(expr1.to_smtlib(false).unwrap(), "(/ (* (+ a (* b 2.0)) 3.0) (- c 1.0))");
+        assert_eq!((a.clone() - b.clone() - c.clone() + 1).to_smtlib(true).unwrap(), "(+ (- a b c) 1)");
+        assert_eq!(a.clone().pow(3).to_smtlib(true).unwrap(), "(* a a a)");
+        assert_eq!(a.clone().modulo(b.clone()).to_smtlib(true).unwrap(), "(mod a b)");
+
+        // Test large powers are not expanded
+        println!("asserting a^1000000 renders as one power");
+        assert_eq!(a.clone().pow(1_000_000).to_smtlib(true).unwrap(), "(^ a 1000000)");
+        assert_eq!(a.clone().pow(9).to_smtlib(false).unwrap(), "(^ a 9.0)");
+        let expr4 = (a.clone().pow(20) * 2 + 1).canonicalize().unwrap();
+        assert_eq!(expr4.to_smtlib(true).unwrap(), "(+ 1 (* 2 (^ a 20)))");
+
+        // Test names that cannot be quoted
+        println!("asserting names with | or \\ are rejected");
+        assert!(matches!(Expr::var("a|b").to_smtlib(true), Err(Error::InvalidSymbol(name)) if name == "a|b"));
+        assert!(matches!(equivalence_query(&Expr::var("a\\b"), &a), Err(Error::InvalidSymbol(_))));
+
+        // Test rational forms are scaled to integer coefficients
+        let expr2 = ((a.clone() * 3 - b.clone()) / 2).canonicalize().unwrap();
+        assert_eq!(expr2.to_smtlib(true).unwrap(), "(div (- (* 3 a) b) 2)");
+        let expr3 = (a.clone() * a.clone() + 1).canonicalize().unwrap();
+        assert_eq!(expr3.to_smtlib(true).unwrap(), "(+ 1 (* a a))");
+    }
+
+    #[test]
+    fn test_equivalence_query() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let n = Expr::var("n 0");
+
+        let lhs = (a.clone() + b.clone()) * n.clone();
+        let rhs = a.clone() * n.clone() + b.clone() * n.clone() / a.clone() * a.clone();
+        println!("asserting the query declares a, b and n 0 once");
+        assert_eq!(
+            equivalence_query(&lhs, &rhs).unwrap(),
+            "(declare-const a Int)\n\
+             (declare-const b Int)\n\
+             (declare-const |n 0| Int)\n\
+             (assert (> a 0))\n\
+             (assert (> b 0))\n\
+             (assert (> |n 0| 0))\n\
+             (assert (not (= (* (+ a b) |n 0|) (+ (* a |n 0|) (* (div (* b |n 0|) a) a)))))\n\
+             (check-sat)\n",
+        );
+    }
+}
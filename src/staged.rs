@@ -0,0 +1,139 @@
+//! 分阶段求值的表达式。
+
+use crate::{CanonicalTerm, Error, EvalError, Expr, RationalExpression, ValueSource};
+use num_rational::Ratio;
+use std::collections::{BTreeSet, HashMap};
+
+/// An expression whose variables are resolved over several stages, such as model-load time
+/// and request time.
+///
+/// The expression is canonicalized once on construction, then every stage substitutes into the
+/// reduced form left by the previous one.
+///
+/// ```rust
+/// # use std::collections::HashMap;
+/// # use symbolic_expr::{Expr, StagedExpr};
+/// let expr = Expr::var("n") * Expr::var("c") * Expr::var("k") / 2;
+/// let mut staged = StagedExpr::new(expr);
+/// staged.stage(&HashMap::from([("c", 64), ("k", 3)])).unwrap();
+/// assert_eq!(staged.value(), None);
+/// staged.stage(&HashMap::from([("n", 4)])).unwrap();
+/// assert_eq!(staged.value(), Some(384));
+/// ```
+#[derive(Clone, Debug)]
+pub struct StagedExpr {
+    expr: Expr,
+}
+
+impl StagedExpr {
+    /// Prepares the expression for staged resolution.
+    pub fn new(expr: Expr) -> Self {
+        let expr = match expr.canonicalize() {
+            Ok(canon) => canon.into(),
+            Err(_) => expr,
+        };
+        Self { expr }
+    }
+
+    /// Substitutes the values of a stage into the reduced form.
+    ///
+    /// Fails if a division by zero appears, or the last variables are resolved to a value that
+    /// is not a natural number. The reduced form is unchanged on failure.
+    pub fn stage(&mut self, env: &HashMap<&str, usize>) -> Result<(), Error> {
+        let fail = |kind| Error::Eval {
+            kind,
+            values: self
+                .remaining_variables()
+                .into_iter()
+                .filter_map(|name| Some((name.to_string(), *env.get(name)?, ValueSource::Env)))
+                .collect(),
+        };
+        let reduced = match &self.expr {
+            Expr::Rational(rational) => {
+                // A zero in a denominator would divide the coefficients by zero
+                let divides_by_zero = rational.numer.iter().chain(&rational.denom).any(|term| {
+                    term.factors.iter().any(|f| f.exponent < 0 && env.get(&*f.base) == Some(&0))
+                });
+                if divides_by_zero {
+                    return Err(fail(EvalError::DivisionByZero));
+                }
+                let denom = RationalExpression::new(rational.denom.clone(), vec![CanonicalTerm::new(1)]);
+                if denom.partial_substitute(env).is_none_or(|denom| denom.constant() == Some(Ratio::from_integer(0))) {
+                    return Err(fail(EvalError::DivisionByZero));
+                }
+                let reduced = rational.partial_substitute(env).ok_or_else(|| fail(EvalError::NonDivisible))?;
+                Expr::Rational(reduced)
+            }
+            expr => expr.partial_substitute(env).ok_or_else(|| fail(EvalError::NonDivisible))?,
+        };
+        if reduced.variables().is_empty() {
+            reduced.substitute_diagnosed(&HashMap::new()).map_err(|diagnostic| fail(diagnostic.kind))?;
+        }
+        self.expr = reduced;
+        Ok(())
+    }
+
+    /// The value, once every variable is resolved.
+    pub fn value(&self) -> Option<u64> {
+        if !self.expr.variables().is_empty() {
+            return None;
+        }
+        let value = self.expr.checked_substitute(&HashMap::new())?;
+        u64::try_from(value).ok()
+    }
+
+    /// The variables not resolved yet.
+    pub fn remaining_variables(&self) -> BTreeSet<&str> {
+        self.expr.variables()
+    }
+
+    /// The reduced form after the stages so far.
+    pub fn expr(&self) -> &Expr {
+        &self.expr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_staged() {
+        let n = Expr::var("n");
+        let s = Expr::var("s");
+        let c = Expr::var("c");
+        let k = Expr::var("k");
+
+        // Test two stages give the same value as a single substitution
+        let expr = n.clone() * (s.clone() - k.clone() + 1) * c.clone() / (k.clone() * 2);
+        let load = HashMap::from([("c", 64), ("k", 4)]);
+        let request = HashMap::from([("n", 3), ("s", 7)]);
+        let mut staged = StagedExpr::new(expr.clone());
+        staged.stage(&load).unwrap();
+        println!("asserting {} remains after loading", staged.expr());
+        assert_eq!(staged.remaining_variables(), BTreeSet::from(["n", "s"]));
+        assert_eq!(staged.value(), None);
+        staged.stage(&request).unwrap();
+        assert!(staged.remaining_variables().is_empty());
+        let all = load.into_iter().chain(request).collect::<HashMap<_, _>>();
+        assert_eq!(staged.value(), Some(expr.substitute(&all) as u64));
+
+        // Test failures leave the reduced form unchanged
+        let mut staged = StagedExpr::new(n.clone() / (k.clone() - 1) - s.clone());
+        assert!(matches!(staged.stage(&HashMap::from([("k", 1)])), Err(Error::Eval { kind: EvalError::DivisionByZero, .. })));
+        staged.stage(&HashMap::from([("k", 3)])).unwrap();
+        let err = staged.stage(&HashMap::from([("n", 5), ("s", 1)])).unwrap_err();
+        assert!(matches!(err, Error::Eval { kind: EvalError::NonDivisible, .. }));
+        let err = staged.stage(&HashMap::from([("n", 2), ("s", 2)])).unwrap_err();
+        assert!(matches!(err, Error::Eval { kind: EvalError::Negative, .. }));
+        assert_eq!(staged.remaining_variables(), BTreeSet::from(["n", "s"]));
+        staged.stage(&HashMap::from([("n", 6), ("s", 3)])).unwrap();
+        assert_eq!(staged.value(), Some(0));
+
+        // Test expressions without a rational form
+        let mut staged = StagedExpr::new(n.clone().modulo(c.clone()) + 1);
+        staged.stage(&HashMap::from([("c", 4)])).unwrap();
+        staged.stage(&HashMap::from([("n", 10)])).unwrap();
+        assert_eq!(staged.value(), Some(3));
+    }
+}
@@ -0,0 +1,156 @@
+//! 降级为只支持二元运算的目标语言的栈式指令，供无法直接使用 [`Expr`] 的代码生成器接入。
+
+use crate::{Error, Expr, Operand, Sign};
+use std::collections::VecDeque;
+
+/// Most instructions emitted for one expression, which powers expanded into products reach
+/// quickly.
+const MAX_TARGET_OPS: usize = 1 << 16;
+
+/// An instruction of a stack machine with binary operations only.
+/// Binary operations pop the right operand, then the left one, and push the result.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TargetOp {
+    /// Pushes a constant.
+    PushConst(usize),
+    /// Pushes the value of a variable.
+    PushVar(String),
+    /// Pushes the sum of the two operands.
+    BinAdd,
+    /// Pushes the difference of the two operands.
+    BinSub,
+    /// Pushes the product of the two operands.
+    BinMul,
+    /// Pushes the quotient of the two operands, which is exact in well-formed expressions.
+    BinDiv,
+}
+
+impl Expr {
+    /// Lowers the expression to stack machine instructions, passing them to `emit` in order.
+    ///
+    /// Sums and products are folded left to right into binary operations, a leading negative
+    /// operand is subtracted from 0 or divides 1, and powers are expanded into products.
+    /// Returns [`Error::NotRational`] without emitting anything if the expression has `mod`,
+    /// `abs` or `max`, which have no instruction, [`Error::UnfilledHole`] if it has a hole, and
+    /// [`Error::TooComplex`] if it expands to more than 65536 instructions.
+    ///
+    /// ```rust
+    /// # use symbolic_expr::{Expr, TargetOp};
+    /// let mut ops = Vec::new();
+    /// (Expr::var("a") - 2).to_target_ops(&mut |op| ops.push(op)).unwrap();
+    /// assert_eq!(ops, [TargetOp::PushVar("a".to_string()), TargetOp::PushConst(2), TargetOp::BinSub]);
+    /// ```
+    pub fn to_target_ops(&self, emit: &mut impl FnMut(TargetOp)) -> Result<(), Error> {
+        if self.has_holes() {
+            return Err(Error::UnfilledHole);
+        }
+        if self.lowered_len().ok_or(Error::NotRational)? > MAX_TARGET_OPS {
+            return Err(Error::TooComplex);
+        }
+        self.lower(emit);
+        Ok(())
+    }
+
+    /// A bound of the number of instructions `lower` emits, exact for powers and one or two
+    /// over for sums and products, or None if a node has no instruction.
+    fn lowered_len(&self) -> Option<usize> {
+        match self {
+            Self::Constant(_) | Self::Variable(_) | Self::Pow(_, 0) => Some(1),
+            Self::Rational(rational) => rational.to_tree().lowered_len(),
+            Self::Sum(operands) | Self::Product(operands) => operands
+                .iter()
+                .try_fold(1, |len: usize, op| Some(len.saturating_add(op.expr.lowered_len()?).saturating_add(1))),
+            Self::Pow(base, exponent) => {
+                let len = base.lowered_len()?.saturating_add(1);
+                Some(len.saturating_mul(*exponent as usize) - 1)
+            }
+            Self::Mod(..) | Self::Abs(_) | Self::Max(..) | Self::Hole => None,
+        }
+    }
+
+    fn lower(&self, emit: &mut impl FnMut(TargetOp)) {
+        match self {
+            &Self::Constant(value) => emit(TargetOp::PushConst(value)),
+            Self::Variable(name) => emit(TargetOp::PushVar(name.clone())),
+            Self::Sum(operands) => lower_operands(operands, 0, TargetOp::BinAdd, TargetOp::BinSub, emit),
+            Self::Product(operands) => lower_operands(operands, 1, TargetOp::BinMul, TargetOp::BinDiv, emit),
+            Self::Rational(rational) => rational.to_tree().lower(emit),
+            Self::Pow(_, 0) => emit(TargetOp::PushConst(1)),
+            Self::Pow(base, exponent) => {
+                base.lower(emit);
+                for _ in 1..*exponent {
+                    base.lower(emit);
+                    emit(TargetOp::BinMul);
+                }
+            }
+            Self::Mod(..) | Self::Abs(_) | Self::Max(..) | Self::Hole => unreachable!("checked by lowered_len"),
+        }
+    }
+}
+
+/// Folds the operands left to right, starting from the identity if there are none or the
+/// first one is negative.
+fn lower_operands(operands: &VecDeque<Operand>, identity: usize, positive: TargetOp, negative: TargetOp, emit: &mut impl FnMut(TargetOp)) {
+    match operands.front() {
+        None => return emit(TargetOp::PushConst(identity)),
+        Some(first) if first.ty == Sign::Negative => emit(TargetOp::PushConst(identity)),
+        Some(first) => first.expr.lower(emit),
+    }
+    let skip = usize::from(operands[0].ty == Sign::Positive);
+    for Operand { ty, expr } in operands.iter().skip(skip) {
+        expr.lower(emit);
+        emit(match ty {
+            Sign::Positive => positive.clone(),
+            Sign::Negative => negative.clone(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TargetOp::*;
+
+    #[test]
+    fn test_to_target_ops() {
+        let a = Expr::var("a");
+        let b = Expr::var("b");
+        let collect = |expr: &Expr| {
+            let mut ops = Vec::new();
+            expr.to_target_ops(&mut |op| ops.push(op)).map(|()| ops)
+        };
+        let var = |name: &str| PushVar(name.to_string());
+
+        // Test n-ary nodes are folded into binary operations
+        let expr = (a.clone() + 1) * 3;
+        println!("asserting the instructions of {expr}");
+        assert_eq!(collect(&expr).unwrap(), [var("a"), PushConst(1), BinAdd, PushConst(3), BinMul]);
+        let expr = a.clone() - b.clone() / 2 + 4;
+        assert_eq!(collect(&expr).unwrap(), [var("a"), var("b"), PushConst(2), BinDiv, BinSub, PushConst(4), BinAdd]);
+
+        // Test leading negative operands, empty nodes and powers
+        let negated = Expr::Sum([Operand { ty: Sign::Negative, expr: a.clone() }].into());
+        assert_eq!(collect(&negated).unwrap(), [PushConst(0), var("a"), BinSub]);
+        assert_eq!(collect(&Expr::Product(VecDeque::new())).unwrap(), [PushConst(1)]);
+        assert_eq!(collect(&b.clone().pow(3)).unwrap(), [var("b"), var("b"), BinMul, var("b"), BinMul]);
+        assert_eq!(collect(&b.clone().pow(0)).unwrap(), [PushConst(1)]);
+
+        // Test powers expanding to too many instructions emit nothing
+        let mut emitted = 0;
+        println!("asserting b^1000000 is too complex to lower");
+        assert!(matches!(b.clone().pow(1_000_000).to_target_ops(&mut |_| emitted += 1), Err(Error::TooComplex)));
+        assert!(matches!(b.clone().pow(300).pow(300).to_target_ops(&mut |_| emitted += 1), Err(Error::TooComplex)));
+        assert_eq!(emitted, 0);
+        assert_eq!(collect(&(a.clone() + 1).pow(64)).unwrap().len(), 64 * 4 - 1);
+
+        // Test rational forms are lowered through their tree
+        let rational = Expr::from((a.clone() * 2 / b.clone()).canonicalize().unwrap());
+        assert_eq!(collect(&rational).unwrap(), [PushConst(2), var("a"), BinMul, var("b"), BinDiv]);
+
+        // Test nodes without instructions emit nothing
+        let mut emitted = 0;
+        let expr = a.clone() + (a.clone() - b.clone()).abs();
+        assert!(matches!(expr.to_target_ops(&mut |_| emitted += 1), Err(Error::NotRational)));
+        assert_eq!(emitted, 0);
+    }
+}
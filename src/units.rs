@@ -0,0 +1,165 @@
+//! 带单位的表达式，在类型上区分元素个数与字节数。
+//!
+//! A count of elements only becomes a size in bytes by multiplying it by [`BytesPerElem`],
+//! and only sizes in bytes can be aligned. Mixing the units does not compile:
+//!
+//! ```compile_fail
+//! # use symbolic_expr::{ByteSize, ElemCount, Expr};
+//! let _ = ElemCount::new(Expr::var("n")) + ByteSize::new(Expr::var("n"));
+//! ```
+//!
+//! ```compile_fail
+//! # use symbolic_expr::{BytesPerElem, ByteSize, Expr};
+//! let _ = ByteSize::new(Expr::var("n")) * BytesPerElem(4);
+//! ```
+//!
+//! ```compile_fail
+//! # use symbolic_expr::{ElemCount, Expr};
+//! let _ = ElemCount::new(Expr::var("n")).align_up(64);
+//! ```
+
+use crate::{Expr, scaled::floor_div};
+use std::{
+    collections::HashMap,
+    fmt,
+    ops::{Add, Mul},
+};
+
+/// A number of elements.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ElemCount(Expr);
+
+/// A size in bytes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ByteSize(Expr);
+
+/// The size of an element in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BytesPerElem(pub usize);
+
+macro_rules! impl_unit {
+    ($ty:ident) => {
+        impl $ty {
+            /// 带单位的表达式。
+            pub fn new(expr: Expr) -> Self {
+                Self(expr)
+            }
+
+            /// The expression without its unit.
+            pub fn as_expr(&self) -> &Expr {
+                &self.0
+            }
+
+            /// The expression without its unit.
+            pub fn into_expr(self) -> Expr {
+                self.0
+            }
+
+            /// Substitute variables with their values, see [`Expr::substitute`].
+            pub fn substitute(&self, value: &HashMap<&str, usize>) -> usize {
+                self.0.substitute(value)
+            }
+
+            /// Substitute variables with their values, see [`Expr::checked_substitute`].
+            pub fn checked_substitute(&self, value: &HashMap<&str, usize>) -> Option<usize> {
+                self.0.checked_substitute(value)
+            }
+        }
+
+        impl fmt::Display for $ty {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                fmt::Display::fmt(&self.0, f)
+            }
+        }
+
+        impl Add for $ty {
+            type Output = Self;
+
+            fn add(self, rhs: Self) -> Self {
+                Self(self.0 + rhs.0)
+            }
+        }
+
+        /// Scales by a number of the same unit, such as the count of buffers.
+        impl Mul<usize> for $ty {
+            type Output = Self;
+
+            fn mul(self, rhs: usize) -> Self {
+                Self(self.0 * rhs)
+            }
+        }
+    };
+}
+
+impl_unit!(ElemCount);
+impl_unit!(ByteSize);
+
+impl Mul<BytesPerElem> for ElemCount {
+    type Output = ByteSize;
+
+    fn mul(self, rhs: BytesPerElem) -> ByteSize {
+        ByteSize(self.0 * rhs.0)
+    }
+}
+
+impl ByteSize {
+    /// Whether the size is proven a multiple of `align` bytes, see [`Expr::divides`].
+    pub fn is_aligned(&self, align: usize) -> bool {
+        Expr::from(align).divides(&self.0)
+    }
+
+    /// The size rounded up to a multiple of `align` bytes, unchanged if it is proven aligned.
+    /// The rounding is built with `mod`, see [`ScaledExpr::ceil`](crate::ScaledExpr::ceil).
+    ///
+    /// ```rust
+    /// # use std::collections::HashMap;
+    /// # use symbolic_expr::{BytesPerElem, ElemCount, Expr};
+    /// let size = ElemCount::new(Expr::var("n") * 3) * BytesPerElem(2);
+    /// let aligned = size.align_up(64);
+    /// assert_eq!(aligned.substitute(&HashMap::from([("n", 7)])), 64);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `align` is zero.
+    pub fn align_up(&self, align: usize) -> Self {
+        assert_ne!(align, 0, "alignment cannot be zero");
+        if self.is_aligned(align) {
+            return self.clone();
+        }
+        Self(floor_div(self.0.clone() + (align - 1), Expr::from(align)) * align)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_units() {
+        let n = Expr::var("n");
+        let c = Expr::var("c");
+
+        // Test counts become sizes through the element size
+        let count = ElemCount::new(n.clone()) * 2 + ElemCount::new(c.clone());
+        let size = count.clone() * BytesPerElem(4);
+        println!("asserting {count} elements are {size} bytes");
+        assert_eq!(size.as_expr().equivalent(&((n.clone() * 2 + c.clone()) * 4)), Some(true));
+        assert_eq!(size.to_string(), "(n*2 + c)*4");
+        let value = HashMap::from([("n", 3), ("c", 5)]);
+        assert_eq!(count.substitute(&value), 11);
+        assert_eq!(size.checked_substitute(&value), Some(44));
+        let total = size.clone() + ByteSize::new(Expr::from(16));
+        assert_eq!(total.substitute(&value), 60);
+        assert!(total.into_expr().structurally_eq(&(size.as_expr().clone() + 16)));
+
+        // Test alignment keeps proven multiples and rounds up the rest
+        assert!(size.is_aligned(4) && !size.is_aligned(8));
+        assert!(size.align_up(4).as_expr().structurally_eq(size.as_expr()));
+        let aligned = size.align_up(64);
+        for (n_value, expected) in [(1, 64), (3, 64), (6, 64), (7, 128), (14, 128), (15, 192)] {
+            let value = HashMap::from([("n", n_value), ("c", 4)]);
+            assert_eq!(aligned.substitute(&value), expected);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! 供浏览器使用的 wasm-bindgen 接口。
+
+use crate::Expr;
+use js_sys::{Object, Reflect};
+use std::collections::HashMap;
+use wasm_bindgen::prelude::*;
+
+/// An expression exposed to JavaScript.
+#[wasm_bindgen]
+pub struct JsExpr(Expr);
+
+/// Parses the text of an expression, throwing the parse error as an exception.
+#[wasm_bindgen]
+pub fn parse(expr: &str) -> Result<JsExpr, JsError> {
+    parse_expr(expr).map(JsExpr).map_err(|e| JsError::new(&e))
+}
+
+/// The parsing behind [`parse`], with the message of the exception as the error.
+fn parse_expr(expr: &str) -> Result<Expr, String> {
+    expr.parse().map_err(|e| format!("failed to parse expression: {e}"))
+}
+
+#[wasm_bindgen]
+impl JsExpr {
+    /// Evaluates the expression with the values of an object like `{ a: 7, b: 5 }`,
+    /// throwing an exception if a value is not a non-negative integer or the evaluation fails.
+    pub fn evaluate(&self, env: JsValue) -> Result<u64, JsError> {
+        let object = env.dyn_into::<Object>().map_err(|_| JsError::new("environment is not an object"))?;
+        let get = |name: &str| Reflect::get(&object, &JsValue::from_str(name)).ok().and_then(|v| v.as_f64());
+        evaluate_with(&self.0, get).map_err(|e| JsError::new(&e))
+    }
+
+    /// Checks equivalence, see [`Expr::equivalent`].
+    pub fn equivalent(&self, other: &JsExpr) -> Option<bool> {
+        self.0.equivalent(&other.0)
+    }
+
+    /// Renders the expression as text that [`parse`] accepts.
+    #[wasm_bindgen(js_name = toString)]
+    #[allow(clippy::inherent_to_string)]
+    pub fn to_string(&self) -> String {
+        self.0.to_string()
+    }
+}
+
+/// The evaluation behind [`JsExpr::evaluate`], reading the numbers of the variables through
+/// `get`, with the message of the exception as the error.
+fn evaluate_with(expr: &Expr, get: impl Fn(&str) -> Option<f64>) -> Result<u64, String> {
+    let names = expr.variables();
+    let mut values = HashMap::with_capacity(names.len());
+    for name in names {
+        let Some(value) = get(name) else {
+            continue;
+        };
+        if value < 0. || value.fract() != 0. || value > usize::MAX as f64 {
+            return Err(format!("value of \"{name}\" is not a non-negative integer"));
+        }
+        values.insert(name, value as usize);
+    }
+    match expr.substitute_diagnosed(&values) {
+        Ok(value) => Ok(value as u64),
+        Err(e) => Err(format!("failed to evaluate expression: {} at {:?}", e.kind, e.path)),
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod host_tests {
+    use super::*;
+
+    /// The JavaScript calls cannot run on the host, so this tests what they wrap.
+    #[test]
+    fn test_wasm_host() {
+        let expr = parse_expr("(a + 1)*3/(b + 1)").unwrap();
+        let env = |values: &'static [(&'static str, f64)]| move |name: &str| values.iter().find(|(n, _)| *n == name).map(|&(_, v)| v);
+
+        // Test evaluation and errors
+        println!("asserting {expr} evaluates to 4 with a = 7, b = 5");
+        assert_eq!(evaluate_with(&expr, env(&[("a", 7.), ("b", 5.)])), Ok(4));
+        assert!(evaluate_with(&expr, env(&[("a", 7.)])).unwrap_err().starts_with("failed to evaluate expression"));
+        assert!(evaluate_with(&expr, env(&[("a", 6.), ("b", 5.)])).is_err());
+        assert_eq!(evaluate_with(&expr, env(&[("a", -1.), ("b", 5.)])), Err("value of \"a\" is not a non-negative integer".to_string()));
+        assert!(evaluate_with(&expr, env(&[("a", 0.5), ("b", 5.)])).is_err());
+        assert!(parse_expr("a + ").unwrap_err().starts_with("failed to parse expression"));
+    }
+}
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    fn env(values: &[(&str, f64)]) -> JsValue {
+        let object = Object::new();
+        for &(name, value) in values {
+            Reflect::set(&object, &name.into(), &value.into()).unwrap();
+        }
+        object.into()
+    }
+
+    #[wasm_bindgen_test]
+    fn test_wasm() {
+        let expr = parse("(a + 1)*3/(b + 1)").unwrap();
+        assert_eq!(expr.to_string(), "(a + 1)*3/(b + 1)");
+
+        // Test evaluation and errors
+        assert_eq!(expr.evaluate(env(&[("a", 7.), ("b", 5.)])).ok(), Some(4));
+        assert!(expr.evaluate(env(&[("a", 7.)])).is_err());
+        assert!(expr.evaluate(env(&[("a", 6.), ("b", 5.)])).is_err());
+        assert!(expr.evaluate(env(&[("a", -1.), ("b", 5.)])).is_err());
+        assert!(expr.evaluate(JsValue::from(1)).is_err());
+        assert!(parse("a + ").is_err());
+
+        // Test comparison
+        assert_eq!(expr.equivalent(&parse("(3*a + 3)/(b + 1)").unwrap()), Some(true));
+        assert_eq!(expr.equivalent(&parse("a + 1").unwrap()), Some(false));
+    }
+}
@@ -0,0 +1,58 @@
+//! 统计规范化的堆分配次数，计数分配器只安装在这个测试程序中。
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+use symbolic_expr::Expr;
+
+/// Allocations of canonicalizing the corpus, as measured with factors stored inline.
+const INLINE_ALLOCATIONS: usize = 746;
+/// Allocations of canonicalizing the corpus, as measured with factors on the heap.
+const HEAP_ALLOCATIONS: usize = 1093;
+/// Halfway between the two, so the count fails if factors move back to the heap, but not
+/// over small changes elsewhere.
+const MAX_ALLOCATIONS: usize = (INLINE_ALLOCATIONS + HEAP_ALLOCATIONS) / 2;
+
+/// Counts the allocations of the current thread, so tests running in parallel do not
+/// disturb each other.
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|n| n.set(n.get() + 1));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+#[test]
+fn test_allocations() {
+    let (a, b, c) = (Expr::var("a"), Expr::var("b"), Expr::var("c"));
+    let exprs = [
+        (a.clone() + 1) * 2,
+        (a.clone() * a.clone() + a.clone() * b.clone()) / (a.clone() * c.clone() + a.clone()),
+        (a.clone() + b.clone() - c.clone()) * (a.clone() - 1) / 2,
+        (a.clone() * b.clone() * c.clone() + b.clone() * 3) / (b.clone() * 2),
+        (a.clone() + b.clone()).pow(3) - a.clone() * b.clone() * 3,
+    ];
+    let before = ALLOCATIONS.with(Cell::get);
+    for expr in exprs {
+        std::hint::black_box(expr.canonicalize().unwrap());
+    }
+    let count = ALLOCATIONS.with(Cell::get) - before;
+    println!("asserting canonicalizing the corpus allocates {count} times");
+    if cfg!(feature = "smallvec") {
+        assert!(count <= MAX_ALLOCATIONS, "{count} allocations, at most {MAX_ALLOCATIONS} expected");
+    }
+}